@@ -0,0 +1,280 @@
+//! The parser as it was before the zero-copy rewrite, kept as a baseline
+//! for the benchmarks. Fields are copied into fixed capacity strings and
+//! looked up by label when mapping. Only the mapping of `Bmv700` and
+//! `MPPT` is kept, into the current types.
+
+use std::str::from_utf8;
+
+use vedirect::{
+    Bmv700, ErrorCode, OffReason, StateOfOperation, TrackerOperationMode, VEError, MPPT,
+};
+
+const MAX_FIELDS: usize = 32;
+const MAX_LABEL_LEN: usize = 16;
+const MAX_VALUE_LEN: usize = 48;
+const BUFFER_SIZE: usize = 512;
+
+const CR: u8 = 13;
+const LF: u8 = 10;
+const TAB: u8 = 9;
+const COLON: u8 = 58;
+const A: u8 = 65;
+
+struct Field {
+    label: heapless::String<MAX_LABEL_LEN>,
+    value: heapless::Vec<u8, MAX_VALUE_LEN>,
+}
+
+#[derive(Default)]
+pub struct Fields {
+    entries: heapless::Vec<Field, MAX_FIELDS>,
+}
+
+impl Fields {
+    fn get(&self, label: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|f| f.label == label)
+            .map(|f| f.value.as_slice())
+    }
+
+    fn insert(&mut self, label: &str, value: &[u8]) -> Result<(), VEError> {
+        let value = heapless::Vec::from_slice(value).map_err(|_| VEError::CapacityExceeded)?;
+        if let Some(existing) = self.entries.iter_mut().find(|f| f.label == label) {
+            existing.value = value;
+            return Ok(());
+        }
+        let mut owned_label = heapless::String::new();
+        owned_label
+            .push_str(label)
+            .map_err(|_| VEError::CapacityExceeded)?;
+        self.entries
+            .push(Field {
+                label: owned_label,
+                value,
+            })
+            .map_err(|_| VEError::CapacityExceeded)
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Mapping of the baseline, `VEDirectData::fill` before the rewrite
+pub trait Fill: Sized {
+    fn fill(fields: &Fields) -> Result<Self, VEError>;
+}
+
+/// Parser of the baseline, calling `on_block` for every mapped block
+pub struct Parser<D, F> {
+    first_parse: bool,
+    skip_block: bool,
+    parse_buf: heapless::Vec<u8, BUFFER_SIZE>,
+    fields: Fields,
+    on_block: F,
+    blocks: std::marker::PhantomData<D>,
+}
+
+impl<D: Fill, F: FnMut(D)> Parser<D, F> {
+    pub fn new(on_block: F) -> Self {
+        Parser {
+            first_parse: true,
+            skip_block: false,
+            parse_buf: heapless::Vec::new(),
+            fields: Fields::default(),
+            on_block,
+            blocks: std::marker::PhantomData,
+        }
+    }
+
+    fn parse_field(data: &[u8], read_pos: usize) -> Result<(&str, &[u8], usize), VEError> {
+        if read_pos + 1 >= data.len() {
+            return Err(VEError::NeedMoreData);
+        }
+        let mut cp = read_pos;
+        if data[cp] != CR || data[cp + 1] != LF {
+            return Err(VEError::Parse("Illegal field start".into()));
+        }
+        cp += 2;
+        let pos = data[cp..]
+            .iter()
+            .position(|&c| c == TAB)
+            .ok_or(VEError::NeedMoreData)?;
+        let label = from_utf8(&data[cp..(cp + pos)])
+            .map_err(|e| VEError::Parse(format!("label string was invalid UTF-8: {}", e)))?;
+        cp = cp + pos + 1;
+        match data[cp..].iter().position(|&c| c == CR) {
+            Some(endpos) => Ok((label, &data[cp..(cp + endpos)], cp + endpos)),
+            None if label == "Checksum" => Ok((label, &data[cp..], data.len())),
+            None => Err(VEError::NeedMoreData),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), VEError> {
+        let mut data = data;
+        if self.first_parse {
+            match data.iter().position(|&c| c == CR) {
+                Some(pos) => data = &data[pos..],
+                None => return Err(VEError::NeedMoreData),
+            }
+            self.first_parse = false;
+        }
+        while !data.is_empty() {
+            let take = data.len().min(BUFFER_SIZE - self.parse_buf.len());
+            let _ = self.parse_buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            self.parse_buffered();
+            if self.first_parse {
+                break;
+            }
+            if self.parse_buf.is_full() {
+                self.reset_after_error();
+                self.skip_block = true;
+                match data.iter().position(|&c| c == CR) {
+                    Some(pos) => data = &data[pos..],
+                    None => break,
+                }
+                self.first_parse = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn reset_after_error(&mut self) {
+        self.parse_buf.clear();
+        self.fields.clear();
+        self.first_parse = true;
+    }
+
+    fn consume(&mut self, count: usize) {
+        let count = count.min(self.parse_buf.len());
+        self.parse_buf.copy_within(count.., 0);
+        self.parse_buf.truncate(self.parse_buf.len() - count);
+    }
+
+    fn parse_buffered(&mut self) {
+        let mut cp = 0;
+        loop {
+            // skip hex mode messages
+            while cp + 1 < self.parse_buf.len()
+                && self.parse_buf[cp] == COLON
+                && self.parse_buf[cp + 1] == A
+            {
+                match self.parse_buf[cp..].iter().position(|&c| c == LF) {
+                    Some(pos) => cp = cp + pos + 1,
+                    None => {
+                        self.consume(cp);
+                        return;
+                    }
+                }
+            }
+            match Self::parse_field(&self.parse_buf, cp) {
+                Ok((label, value, read_pos)) => {
+                    cp = read_pos;
+                    if label == "Checksum" {
+                        if self.skip_block {
+                            self.skip_block = false;
+                        } else if let Ok(mapped) = D::fill(&self.fields) {
+                            (self.on_block)(mapped);
+                        }
+                        self.fields.clear();
+                    } else if !self.skip_block && self.fields.insert(label, value).is_err() {
+                        self.fields.clear();
+                        self.skip_block = true;
+                    }
+                }
+                Err(VEError::NeedMoreData) => {
+                    self.consume(cp);
+                    break;
+                }
+                Err(_) => {
+                    self.reset_after_error();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn raw<'a>(fields: &'a Fields, label: &str) -> Result<&'a str, VEError> {
+    let raw = fields
+        .get(label)
+        .ok_or_else(|| VEError::MissingField(label.into()))?;
+    from_utf8(raw).map_err(|e| VEError::Parse(format!("Failed to parse {} - {}", label, e)))
+}
+
+fn number<T: std::str::FromStr>(fields: &Fields, label: &str) -> Result<T, VEError> {
+    raw(fields, label)?
+        .parse()
+        .map_err(|_| VEError::Parse(format!("Failed to parse {}", label)))
+}
+
+fn on_off(fields: &Fields, label: &str) -> Result<bool, VEError> {
+    match raw(fields, label)? {
+        "ON" => Ok(true),
+        "OFF" => Ok(false),
+        s => Err(VEError::OnOffExpected(s.into())),
+    }
+}
+
+impl Fill for Bmv700 {
+    fn fill(fields: &Fields) -> Result<Self, VEError> {
+        let soc = match raw(fields, "SOC")? {
+            "---" => None,
+            _ => Some(number::<f32>(fields, "SOC")? / 10.0),
+        };
+        Ok(Bmv700 {
+            voltage: number::<f32>(fields, "V")? / 10.0,
+            power: number(fields, "P")?,
+            consumed: Some(raw(fields, "CE")?.into()),
+            soc,
+            ttg: number(fields, "TTG")?,
+        })
+    }
+}
+
+impl Fill for MPPT {
+    fn fill(fields: &Fields) -> Result<Self, VEError> {
+        let off_reason = match raw(fields, "OR")? {
+            "0x00000000" => OffReason::None,
+            "0x00000001" => OffReason::NoInputPower,
+            "0x00000002" => OffReason::SwitchedOffPowerSwitch,
+            "0x00000004" => OffReason::SwitchedOffDMR,
+            "0x00000008" => OffReason::RemoteInput,
+            "0x00000010" => OffReason::ProtectionActive,
+            "0x00000020" => OffReason::Paygo,
+            "0x00000040" => OffReason::BMS,
+            "0x00000080" => OffReason::EngineShutdownDetection,
+            "0x00000100" => OffReason::AnalysingInputVoltage,
+            s => return Err(VEError::UnknownCode(s.into())),
+        };
+        Ok(MPPT {
+            channel1_voltage: number::<f32>(fields, "V")? / 1000.0,
+            panel_voltage: number::<f32>(fields, "VPV")? / 1000.0,
+            panel_power: number(fields, "PPV")?,
+            battery_current: number::<f32>(fields, "I")? / 1000.0,
+            load_current: number::<f32>(fields, "IL")? / 1000.0,
+            load_output_state: on_off(fields, "LOAD")?,
+            relay_state: match fields.get("Relay") {
+                Some(_) => Some(on_off(fields, "Relay")?),
+                None => None,
+            },
+            off_reason,
+            yield_total: number(fields, "H19")?,
+            yield_today: number(fields, "H20")?,
+            max_power_today: number(fields, "H21")?,
+            yield_yesterday: number(fields, "H22")?,
+            max_power_yesterday: number(fields, "H23")?,
+            error_code: ErrorCode::from_repr(number(fields, "ERR")?).unwrap_or(ErrorCode::NoError),
+            state_of_operation: StateOfOperation::from_repr(number(fields, "CS")?)
+                .unwrap_or(StateOfOperation::Off),
+            firmware: number(fields, "FW")?,
+            product_id: raw(fields, "PID")?.into(),
+            serial_number: raw(fields, "SER#")?.into(),
+            day_sequence: number(fields, "HSDS")?,
+            tracker_mode: TrackerOperationMode::from_repr(number(fields, "MPPT")?)
+                .unwrap_or(TrackerOperationMode::Off),
+        })
+    }
+}
@@ -0,0 +1,92 @@
+mod baseline;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use vedirect::{Bmv700, Events, Parser, MPPT};
+
+const BMV_BLOCKS: &str = "\r\nPID\t0xA381\r\nV\t12282\r\nVS\t29\r\nI\t-2288\r\nP\t-28\r\nCE\t-74900\r\nSOC\t916\r\nTTG\t10350\r\nAlarm\tOFF\r\nRelay\tOFF\r\nAR\t0\r\nBMV\t712 Smart\r\nFW\t0403\r\nChecksum\t~\r\nH1\t-76138\r\nH2\t-76138\r\nH3\t0\r\nH4\t0\r\nH5\t0\r\nH6\t-1876218\r\nH7\t12171\r\nH8\t20418\r\nH9\t1199744\r\nH10\t0\r\nH11\t0\r\nH12\t0\r\nH15\t20\r\nH16\t21033\r\nH17\t2404\r\nH18\t2415\r\nChecksum\t\u{3}";
+
+const MPPT_BLOCK: &str = "\r\nPID\t0xA053\r\nFW\t159\r\nSER#\tHQ2132QY2KR\r\nV\t12540\r\nI\t40\r\nVPV\t18540\r\nPPV\t5\r\nCS\t3\r\nMPPT\t2\r\nOR\t0x00000000\r\nERR\t0\r\nLOAD\tON\r\nIL\t300\r\nH19\t144\r\nH20\t1\r\nH21\t6\r\nH22\t4\r\nH23\t14\r\nHSDS\t16\r\nChecksum\t?";
+
+const HEX_FRAME: &str =
+    ":A4F1000010000000000AD000000AD000000E508AE05139D04FFFFFFFFFFFFFFFFFFFFFFFFFF4A\n";
+
+struct Counter(usize);
+
+impl Events<Bmv700> for Counter {
+    fn on_complete_block(&mut self, _block: Bmv700) {
+        self.0 += 1;
+    }
+}
+
+impl Events<MPPT> for Counter {
+    fn on_complete_block(&mut self, _block: MPPT) {
+        self.0 += 1;
+    }
+}
+
+fn stream(block: &str, count: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    for _ in 0..count {
+        data.extend_from_slice(block.as_bytes());
+        data.extend_from_slice(HEX_FRAME.as_bytes());
+    }
+    data
+}
+
+fn feed<D: vedirect::VEDirectData>(data: &[u8], chunk_size: usize) -> usize
+where
+    Counter: Events<D>,
+{
+    let mut counter = Counter(0);
+    let mut parser = Parser::<D, _>::new(&mut counter);
+    for chunk in data.chunks(chunk_size) {
+        let _ = parser.feed(chunk);
+    }
+    counter.0
+}
+
+fn feed_baseline<D: baseline::Fill>(data: &[u8], chunk_size: usize) -> usize {
+    let mut count = 0;
+    let mut parser = baseline::Parser::<D, _>::new(|_| count += 1);
+    for chunk in data.chunks(chunk_size) {
+        let _ = parser.feed(chunk);
+    }
+    drop(parser);
+    count
+}
+
+fn bench_parser(c: &mut Criterion) {
+    let bmv = stream(BMV_BLOCKS, 100);
+    let mppt = stream(MPPT_BLOCK, 100);
+
+    let mut group = c.benchmark_group("feed");
+    for chunk_size in [1, 16, 1024] {
+        group.throughput(Throughput::Bytes(bmv.len() as u64));
+        group.bench_with_input(BenchmarkId::new("bmv", chunk_size), &chunk_size, |b, &n| {
+            b.iter(|| feed::<Bmv700>(&bmv, n))
+        });
+        group.throughput(Throughput::Bytes(mppt.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("mppt", chunk_size),
+            &chunk_size,
+            |b, &n| b.iter(|| feed::<MPPT>(&mppt, n)),
+        );
+        // the parser before the zero-copy rewrite, for comparison
+        group.throughput(Throughput::Bytes(bmv.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("bmv-baseline", chunk_size),
+            &chunk_size,
+            |b, &n| b.iter(|| feed_baseline::<Bmv700>(&bmv, n)),
+        );
+        group.throughput(Throughput::Bytes(mppt.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("mppt-baseline", chunk_size),
+            &chunk_size,
+            |b, &n| b.iter(|| feed_baseline::<MPPT>(&mppt, n)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parser);
+criterion_main!(benches);
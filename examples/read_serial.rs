@@ -1,7 +1,8 @@
 // serialport = "4.1"
 
-use serialport::SerialPort;
-use vedirect::{Events, VEError};
+use std::time::Duration;
+
+use vedirect::{reconnect::ReconnectingReader, Events, VEError};
 
 struct Listener;
 
@@ -11,25 +12,26 @@ impl Events<vedirect::Bmv700> for Listener {
     }
 
     fn on_parse_error(&mut self, _error: VEError, _parse_buf: &[u8]) {}
-}
 
-pub fn record(mut port: Box<dyn SerialPort>) -> anyhow::Result<()> {
-    let mut buf: Vec<u8> = vec![0; 1024];
-    let mut listener = Listener {};
-    let mut parser = vedirect::Parser::new(&mut listener);
-    loop {
-        let r = port.read(buf.as_mut_slice())?;
-        parser.feed(&buf[..r])?;
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    fn on_stale(&mut self, elapsed: Duration) {
+        println!("No data for {:?}", elapsed);
     }
 }
 
-fn main() -> anyhow::Result<()> {
-    let port = serialport::new("/dev/ttyUSB1", 19_200)
-        .data_bits(serialport::DataBits::Eight)
-        .timeout(core::time::Duration::from_secs(2))
-        .open()
-        .expect("Failed to open vedirect serial port");
-    record(port)?;
-    Ok(())
+fn main() {
+    let open = || {
+        serialport::new("/dev/ttyUSB1", 19_200)
+            .data_bits(serialport::DataBits::Eight)
+            .timeout(Duration::from_secs(2))
+            .open()
+            .map_err(std::io::Error::from)
+    };
+    // the port is opened again if the adapter is unplugged
+    let mut reader =
+        ReconnectingReader::new(open, Listener).with_stale_timeout(Duration::from_secs(10));
+    loop {
+        if let Err(e) = reader.poll() {
+            eprintln!("vedirect serial port: {}", e);
+        }
+    }
 }
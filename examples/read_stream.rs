@@ -0,0 +1,16 @@
+// Reads VE.Direct data from stdin, for example:
+//   stty -F /dev/ttyUSB1 19200 raw && cargo run --features tokio --example read_stream < /dev/ttyUSB1
+
+use futures_util::StreamExt;
+use vedirect::{BlockStream, MPPT};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut blocks = BlockStream::<_, MPPT>::new(tokio::io::stdin());
+    while let Some(block) = blocks.next().await {
+        match block {
+            Ok(mppt) => println!("Mapped data {:#?}", &mppt),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
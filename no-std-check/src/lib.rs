@@ -0,0 +1,35 @@
+//! Uses the parser from a `no_std` crate, to check that the library
+//! builds without `std`
+#![no_std]
+
+use vedirect::{Events, Parser, Text, VEError, MPPT};
+
+#[derive(Default)]
+pub struct Listener {
+    pub last: Option<MPPT>,
+    pub errors: usize,
+}
+
+impl Events<MPPT> for Listener {
+    fn on_complete_block(&mut self, block: MPPT) {
+        self.last = Some(block);
+    }
+
+    fn on_missing_field(&mut self, _label: Text) {
+        self.errors += 1;
+    }
+
+    fn on_mapping_error(&mut self, _error: VEError) {
+        self.errors += 1;
+    }
+
+    fn on_parse_error(&mut self, _error: VEError, _parse_buf: &[u8]) {
+        self.errors += 1;
+    }
+}
+
+pub fn parse(data: &[u8]) -> Listener {
+    let mut parser = Parser::<MPPT, _, 128>::with_buffer_size(Listener::default());
+    let _ = parser.feed(data);
+    parser.into_listener()
+}
@@ -0,0 +1,501 @@
+//! Alarms raised and cleared by rules over the fields of mapped data.
+//!
+//! A [`Rule`] compares a field of a device type with a value, and can
+//! require the condition to hold for some time before the alarm is
+//! raised:
+//!
+//! ```text
+//! Bmv700.soc < 30 for 5min
+//! MPPT.error_code != NoError
+//! MPPT.off_reason contains ProtectionActive
+//! ```
+//!
+//! Fields are named as by [`Inspect`], so rules apply to any type
+//! implementing it. Enumerations compare by the name of their variant,
+//! booleans with `true`/`false` or `ON`/`OFF`. The operators are `<`,
+//! `<=`, `>`, `>=`, `==`, `!=` and `contains`. Durations are written
+//! like `30s`, `5min` or `2h`.
+//!
+//! An [`AlarmEngine`] evaluates its rules for every timestamped block
+//! (see [`clock`](crate::clock)), separately for each device by serial
+//! number, and emits an [`Alarm`] when one is raised or cleared. With
+//! [`Rule::with_hysteresis`], a numeric alarm only clears once the
+//! value is past the threshold by the hysteresis, and with
+//! [`Rule::with_clear_delay`] once the condition stopped holding for
+//! that long. Blocks in which the field has no value, such as the state
+//! of charge of a battery monitor which is not synchronised, leave the
+//! alarm as it is.
+//!
+//! ```rust
+//! use vedirect::alarm::{AlarmEngine, Rule};
+//! use vedirect::clock::{SystemClock, Timestamper};
+//! use vedirect::{Device, Parser};
+//!
+//! let engine = AlarmEngine::new()
+//!     .with_rule(Rule::parse("Bmv700.soc < 30 for 5min").unwrap().with_hysteresis(5.0))
+//!     .with_rule(Rule::parse("MPPT.error_code != NoError").unwrap());
+//! let mut parser = Parser::<Device, _>::new(Timestamper::new(SystemClock, engine));
+//! # let data = [];
+//! parser.feed(&data).ok();
+//! for alarm in parser.listener_mut().listener_mut().take_alarms() {
+//!     println!("{} {:?}", alarm.rule, alarm.state);
+//! }
+//! ```
+
+use std::time::Duration;
+
+use crate::{
+    clock::{Timestamp, Timestamped},
+    inspect::{self, Inspect, Value},
+    Events, VEError,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Operator {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+    Contains,
+}
+
+impl Operator {
+    fn parse(operator: &str) -> Option<Operator> {
+        Some(match operator {
+            "<" => Operator::Less,
+            "<=" => Operator::LessOrEqual,
+            ">" => Operator::Greater,
+            ">=" => Operator::GreaterOrEqual,
+            "==" => Operator::Equal,
+            "!=" => Operator::NotEqual,
+            "contains" => Operator::Contains,
+            _ => return None,
+        })
+    }
+}
+
+fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" | "sec" => number,
+        "m" | "min" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// A condition over a field, see the [module documentation](self)
+#[derive(Clone, PartialEq, Debug)]
+pub struct Rule {
+    name: String,
+    kind: String,
+    field: String,
+    operator: Operator,
+    operand: Value,
+    raise_delay: Duration,
+    clear_delay: Duration,
+    hysteresis: f64,
+}
+
+impl Rule {
+    /// Parse a rule such as `Bmv700.soc < 30 for 5min`. The rule is
+    /// named after its text. The type must be `Bmv700`, `MPPT` or
+    /// `Frame`, and the field one of [`inspect::field_names`] or, for
+    /// frames, any label.
+    pub fn parse(text: &str) -> Result<Rule, VEError> {
+        let error = |what: &str| VEError::Parse(text!("{} in rule {:?}", what, text));
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        let (field, operator, operand, rest) = match tokens.as_slice() {
+            [field, operator, operand, rest @ ..] => (*field, *operator, *operand, rest),
+            _ => return Err(error("expected <type>.<field> <operator> <value>")),
+        };
+        let (kind, field) = field
+            .split_once('.')
+            .ok_or_else(|| error("expected <type>.<field>"))?;
+        if kind != "Frame" {
+            let names = inspect::field_names(kind).ok_or_else(|| error("unknown type"))?;
+            if !names.contains(&field) {
+                return Err(error("unknown field"));
+            }
+        }
+        let operator = Operator::parse(operator).ok_or_else(|| error("unknown operator"))?;
+        let raise_delay = match rest {
+            [] => Duration::ZERO,
+            ["for", duration] => {
+                parse_duration(duration).ok_or_else(|| error("invalid duration"))?
+            }
+            _ => return Err(error("expected `for <duration>`")),
+        };
+        let operand = Value::parse(operand);
+        let ordered = matches!(
+            operator,
+            Operator::Less | Operator::LessOrEqual | Operator::Greater | Operator::GreaterOrEqual
+        );
+        if ordered && !matches!(operand, Value::Number(_)) {
+            return Err(error("expected a number"));
+        }
+        Ok(Rule {
+            name: text.into(),
+            kind: kind.into(),
+            field: field.into(),
+            operator,
+            operand,
+            raise_delay,
+            clear_delay: Duration::ZERO,
+            hysteresis: 0.0,
+        })
+    }
+
+    /// Name of the rule in alarms
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Only clear a numeric alarm once the value is past the threshold
+    /// by `hysteresis`, e.g. at 35 for `soc < 30` with a hysteresis of 5
+    pub fn with_hysteresis(mut self, hysteresis: f64) -> Self {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    /// Only clear the alarm once the condition stopped holding for `delay`
+    pub fn with_clear_delay(mut self, delay: Duration) -> Self {
+        self.clear_delay = delay;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the condition holds for `value`, with the threshold
+    /// moved by `hysteresis` to keep a raised alarm
+    fn holds(&self, value: &Value, hysteresis: f64) -> bool {
+        match (value, &self.operand) {
+            (Value::Number(value), Value::Number(threshold)) => match self.operator {
+                Operator::Less => *value < threshold + hysteresis,
+                Operator::LessOrEqual => *value <= threshold + hysteresis,
+                Operator::Greater => *value > threshold - hysteresis,
+                Operator::GreaterOrEqual => *value >= threshold - hysteresis,
+                Operator::Equal => value == threshold,
+                Operator::NotEqual => value != threshold,
+                Operator::Contains => false,
+            },
+            (Value::Text(value), Value::Text(operand)) => match self.operator {
+                Operator::Equal => value == operand,
+                Operator::NotEqual => value != operand,
+                Operator::Contains => value.contains(operand.as_str()),
+                _ => false,
+            },
+            (value, operand) => match self.operator {
+                Operator::Equal => value == operand,
+                Operator::NotEqual => value != operand,
+                _ => false,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AlarmState {
+    Raised,
+    Cleared,
+}
+
+/// An alarm raised or cleared by a rule
+#[derive(Clone, PartialEq, Debug)]
+pub struct Alarm<T> {
+    /// Name of the rule
+    pub rule: String,
+    /// Serial number of the device, if it has one
+    pub serial: Option<String>,
+    pub state: AlarmState,
+    /// Value of the field which raised or cleared the alarm
+    pub value: Value,
+    /// Receive time of the block which raised or cleared the alarm
+    pub at: T,
+}
+
+/// State of a rule for one device
+struct Tracker<T> {
+    rule: usize,
+    serial: Option<String>,
+    raised: bool,
+    /// Since when the alarm should change state
+    pending: Option<T>,
+}
+
+/// Evaluates rules for timestamped blocks, see the
+/// [module documentation](self)
+pub struct AlarmEngine<T> {
+    rules: Vec<Rule>,
+    trackers: Vec<Tracker<T>>,
+    alarms: Vec<Alarm<T>>,
+}
+
+impl<T: Timestamp> Default for AlarmEngine<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Timestamp> AlarmEngine<T> {
+    pub fn new() -> Self {
+        AlarmEngine {
+            rules: Vec::new(),
+            trackers: Vec::new(),
+            alarms: Vec::new(),
+        }
+    }
+
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Evaluate the rules for the type of `block`, returning the alarms
+    /// raised or cleared by it
+    pub fn evaluate<D: Inspect>(&mut self, block: &Timestamped<D, T>) -> Vec<Alarm<T>> {
+        let data = &block.data;
+        let now = block.received;
+        let mut alarms = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.kind != data.kind() {
+                continue;
+            }
+            let Some(value) = data.field(&rule.field) else {
+                continue;
+            };
+            let serial = data.serial();
+            let tracker = match self
+                .trackers
+                .iter()
+                .position(|t| t.rule == index && t.serial.as_deref() == serial)
+            {
+                Some(position) => &mut self.trackers[position],
+                None => {
+                    self.trackers.push(Tracker {
+                        rule: index,
+                        serial: serial.map(String::from),
+                        raised: false,
+                        pending: None,
+                    });
+                    self.trackers.last_mut().unwrap()
+                }
+            };
+            let (change, delay) = if tracker.raised {
+                (!rule.holds(&value, rule.hysteresis), rule.clear_delay)
+            } else {
+                (rule.holds(&value, 0.0), rule.raise_delay)
+            };
+            if !change {
+                tracker.pending = None;
+                continue;
+            }
+            let since = *tracker.pending.get_or_insert(now);
+            // a clock going back restarts the delay
+            match now.duration_since(since) {
+                Some(elapsed) if elapsed >= delay => {}
+                Some(_) => continue,
+                None => {
+                    tracker.pending = Some(now);
+                    continue;
+                }
+            }
+            tracker.raised = !tracker.raised;
+            tracker.pending = None;
+            alarms.push(Alarm {
+                rule: rule.name.clone(),
+                serial: tracker.serial.clone(),
+                state: match tracker.raised {
+                    true => AlarmState::Raised,
+                    false => AlarmState::Cleared,
+                },
+                value,
+                at: now,
+            });
+        }
+        alarms
+    }
+
+    /// Alarms which are raised, as rule names and serial numbers
+    pub fn active(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.trackers
+            .iter()
+            .filter(|t| t.raised)
+            .map(move |t| (self.rules[t.rule].name(), t.serial.as_deref()))
+    }
+
+    /// The alarms raised or cleared by blocks received as a parser
+    /// listener since the last call
+    pub fn take_alarms(&mut self) -> Vec<Alarm<T>> {
+        std::mem::take(&mut self.alarms)
+    }
+}
+
+impl<D: Inspect, T: Timestamp> Events<Timestamped<D, T>> for AlarmEngine<T> {
+    fn on_complete_block(&mut self, block: Timestamped<D, T>) {
+        let alarms = self.evaluate(&block);
+        self.alarms.extend(alarms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::{clock::Timestamper, Bmv700, Device, Parser};
+
+    /// The MPPT block, with an off reason and an error
+    fn fault() -> String {
+        MPPT_BLOCK
+            .replace("OR\t0x00000000", "OR\t0x00000010")
+            .replace("ERR\t0", "ERR\t2")
+    }
+
+    fn battery(soc: Option<f32>) -> Bmv700 {
+        Bmv700 {
+            voltage: 12.5,
+            power: -100,
+            consumed: None,
+            soc,
+            ttg: -1,
+        }
+    }
+
+    fn at<D>(seconds: u64, data: D) -> Timestamped<D, Duration> {
+        Timestamped {
+            received: Duration::from_secs(seconds),
+            data,
+        }
+    }
+
+    fn states(alarms: Vec<Alarm<Duration>>) -> Vec<(AlarmState, u64)> {
+        alarms.iter().map(|a| (a.state, a.at.as_secs())).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let rule = Rule::parse("Bmv700.soc < 30 for 5min").unwrap();
+        assert_eq!(rule.name(), "Bmv700.soc < 30 for 5min");
+        assert_eq!(rule.kind, "Bmv700");
+        assert_eq!(rule.field, "soc");
+        assert_eq!(rule.operator, Operator::Less);
+        assert_eq!(rule.operand, Value::Number(30.0));
+        assert_eq!(rule.raise_delay, Duration::from_secs(300));
+
+        let rule = Rule::parse("MPPT.load_output_state == OFF").unwrap();
+        assert_eq!(rule.operand, Value::Bool(false));
+        assert!(Rule::parse("Frame.SOC < 300").is_ok());
+        assert_eq!(parse_duration("1.5h"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+
+        for invalid in [
+            "Bmv700.soc < ",
+            "soc < 30",
+            "Bmv700.soc ~ 30",
+            "Bmv700.soc < low",
+            "Bmv700.soc < 30 for",
+            "Bmv700.soc < 30 for 5 min",
+            "Bmv700.soc < 30 during 5min",
+            "Bmv700.socc < 30",
+            "BMV700.soc < 30",
+            "Device.soc < 30",
+        ] {
+            assert!(Rule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_debounce_and_hysteresis() {
+        let rule = Rule::parse("Bmv700.soc < 30 for 5min")
+            .unwrap()
+            .with_hysteresis(5.0);
+        let mut engine = AlarmEngine::new().with_rule(rule);
+
+        assert!(engine.evaluate(&at(0, battery(Some(29.0)))).is_empty());
+        // recovered before the delay
+        assert!(engine.evaluate(&at(100, battery(Some(31.0)))).is_empty());
+        assert!(engine.evaluate(&at(200, battery(Some(28.0)))).is_empty());
+        assert!(engine.evaluate(&at(400, battery(Some(27.0)))).is_empty());
+        let alarms = engine.evaluate(&at(500, battery(Some(26.0))));
+        assert_eq!(states(alarms.clone()), vec![(AlarmState::Raised, 500)]);
+        assert_eq!(alarms[0].value, Value::Number(26.0));
+        assert_eq!(alarms[0].serial, None);
+        assert_eq!(engine.active().count(), 1);
+
+        // within the hysteresis, or unknown
+        assert!(engine.evaluate(&at(600, battery(Some(34.0)))).is_empty());
+        assert!(engine.evaluate(&at(700, battery(None))).is_empty());
+        let alarms = engine.evaluate(&at(800, battery(Some(35.0))));
+        assert_eq!(states(alarms), vec![(AlarmState::Cleared, 800)]);
+        assert_eq!(engine.active().count(), 0);
+    }
+
+    #[test]
+    fn test_clear_delay() {
+        let rule = Rule::parse("Bmv700.power < 0")
+            .unwrap()
+            .with_clear_delay(Duration::from_secs(60));
+        let mut engine = AlarmEngine::new().with_rule(rule);
+        let mut charging = battery(None);
+        charging.power = 50;
+
+        let alarms = engine.evaluate(&at(0, battery(None)));
+        assert_eq!(states(alarms), vec![(AlarmState::Raised, 0)]);
+        assert!(engine.evaluate(&at(10, charging.clone())).is_empty());
+        assert!(engine.evaluate(&at(20, battery(None))).is_empty());
+        assert!(engine.evaluate(&at(30, charging.clone())).is_empty());
+        let alarms = engine.evaluate(&at(90, charging));
+        assert_eq!(states(alarms), vec![(AlarmState::Cleared, 90)]);
+    }
+
+    #[test]
+    fn test_parser() {
+        let mut ticks = 0;
+        let clock = move || {
+            ticks += 1;
+            Duration::from_secs(ticks)
+        };
+        let engine = AlarmEngine::new()
+            .with_rule(Rule::parse("MPPT.error_code != NoError").unwrap())
+            .with_rule(
+                Rule::parse("MPPT.off_reason contains Protection")
+                    .unwrap()
+                    .with_name("protection"),
+            )
+            .with_rule(Rule::parse("MPPT.load_output_state == OFF").unwrap())
+            .with_rule(Rule::parse("Bmv700.soc < 30").unwrap());
+        let mut parser = Parser::<Device, _>::new(Timestamper::new(clock, engine));
+        parser.feed(fault().as_bytes()).unwrap();
+        parser.feed(fault().as_bytes()).unwrap();
+
+        let engine = parser.listener_mut().listener_mut();
+        let alarms = engine.take_alarms();
+        assert_eq!(alarms.len(), 2);
+        assert_eq!(alarms[0].rule, "MPPT.error_code != NoError");
+        assert_eq!(alarms[0].serial.as_deref(), Some("HQ2132QY2KR"));
+        assert_eq!(alarms[0].value, Value::Text("BatteryVoltageTooHigh".into()));
+        assert_eq!(alarms[1].rule, "protection");
+        assert_eq!(alarms[1].value.to_string(), "ProtectionActive");
+        assert!(engine.take_alarms().is_empty());
+        assert_eq!(
+            engine.active().collect::<Vec<_>>(),
+            vec![
+                ("MPPT.error_code != NoError", Some("HQ2132QY2KR")),
+                ("protection", Some("HQ2132QY2KR"))
+            ]
+        );
+    }
+}
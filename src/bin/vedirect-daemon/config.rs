@@ -0,0 +1,319 @@
+//! Configuration file of the daemon
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use serde::Deserialize;
+use vedirect::{alarm::Rule, VEError};
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub site: Site,
+    #[serde(rename = "port")]
+    pub ports: Vec<Port>,
+    #[serde(default)]
+    pub sinks: Sinks,
+    #[serde(rename = "alarm", default)]
+    pub alarms: Vec<Alarm>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct Site {
+    pub name: String,
+    /// Seconds between two publications of the site state
+    pub interval: u64,
+    /// Seconds before the first attempt to open a port again, doubled
+    /// after each failure up to a minute
+    pub reconnect: u64,
+    /// Seconds without data after which a port is reported as stale
+    pub stale: u64,
+    /// Battery capacity in Ah, to estimate the autonomy
+    pub capacity: Option<f32>,
+}
+
+impl Default for Site {
+    fn default() -> Self {
+        Site {
+            name: "site".into(),
+            interval: 10,
+            reconnect: 5,
+            stale: 30,
+            capacity: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Port {
+    pub path: String,
+    /// Name in the site state, the file name of the path by default
+    pub name: Option<String>,
+    #[serde(default = "default_baud")]
+    pub baud: u32,
+}
+
+fn default_baud() -> u32 {
+    19_200
+}
+
+impl Port {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_else(|| {
+            Path::new(&self.path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(&self.path)
+        })
+    }
+}
+
+/// Rule raising an alarm, see `vedirect::alarm`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Alarm {
+    /// e.g. `Bmv700.soc < 30 for 5min`
+    pub rule: String,
+    /// Name in the site state and log, the rule by default
+    pub name: Option<String>,
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Seconds the rule must stop holding before the alarm clears
+    #[serde(default)]
+    pub clear_delay: u64,
+}
+
+impl Alarm {
+    pub fn rule(&self) -> Result<Rule, VEError> {
+        let mut rule = Rule::parse(&self.rule)?
+            .with_hysteresis(self.hysteresis)
+            .with_clear_delay(Duration::from_secs(self.clear_delay));
+        if let Some(name) = &self.name {
+            rule = rule.with_name(name);
+        }
+        Ok(rule)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Sinks {
+    pub stdout: Option<Stdout>,
+    pub mqtt: Option<Mqtt>,
+    pub influxdb: Option<Influxdb>,
+    pub prometheus: Option<Listen>,
+    pub csv: Option<Csv>,
+    pub sqlite: Option<Sqlite>,
+    pub modbus: Option<Modbus>,
+    pub signalk: Option<Signalk>,
+}
+
+/// Prints the site state as JSON
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Stdout {
+    /// Print every block received as well
+    #[serde(default)]
+    pub blocks: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "mqtt"), allow(dead_code))]
+pub struct Mqtt {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub client_id: Option<String>,
+    pub prefix: Option<String>,
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: String,
+    /// Seconds between two publications for a device
+    #[serde(default)]
+    pub min_interval: u64,
+    /// Announce devices to Home Assistant
+    #[serde(default)]
+    pub discovery: bool,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "influxdb"), allow(dead_code))]
+pub struct Influxdb {
+    pub url: String,
+    pub token: Option<String>,
+    pub measurement: Option<String>,
+    pub batch_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "prometheus"), allow(dead_code))]
+pub struct Listen {
+    /// Address to listen on, e.g. `0.0.0.0:9109`
+    pub listen: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "csv"), allow(dead_code))]
+pub struct Csv {
+    pub directory: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+pub struct Sqlite {
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "modbus"), allow(dead_code))]
+pub struct Modbus {
+    pub listen: String,
+    /// Unit IDs by serial number
+    #[serde(default)]
+    pub unit_ids: HashMap<String, u8>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "signalk"), allow(dead_code))]
+pub struct Signalk {
+    /// Address of the TCP interface of the server, e.g. `localhost:8375`
+    pub address: String,
+    pub label: Option<String>,
+    /// Ids in the Signal K paths, by serial number
+    #[serde(default)]
+    pub ids: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> anyhow::Result<Config> {
+        let config: Config = toml::from_str(text)?;
+        if config.ports.is_empty() {
+            anyhow::bail!("no [[port]] configured");
+        }
+        let mut names: Vec<&str> = config.ports.iter().map(Port::name).collect();
+        names.sort_unstable();
+        if let Some(name) = names.windows(2).find(|w| w[0] == w[1]) {
+            anyhow::bail!("more than one port named {}", name[0]);
+        }
+        if let Some(capacity) = config.site.capacity {
+            if !(capacity.is_finite() && capacity > 0.0) {
+                anyhow::bail!("[site] capacity must be positive, not {}", capacity);
+            }
+        }
+        for alarm in &config.alarms {
+            alarm.rule()?;
+        }
+        Ok(config)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.site.interval.max(1))
+    }
+
+    pub fn reconnect(&self) -> Duration {
+        Duration::from_secs(self.site.reconnect.max(1))
+    }
+
+    pub fn stale(&self) -> Duration {
+        Duration::from_secs(self.site.stale.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            [site]
+            name = "cabin"
+            interval = 30
+            capacity = 200.0
+
+            [[port]]
+            path = "/dev/ttyUSB0"
+            name = "house"
+
+            [[port]]
+            path = "/dev/serial/by-id/usb-VictronEnergy_BV_VE_Direct_cable_VE123-if00-port0"
+            baud = 9600
+
+            [sinks.stdout]
+
+            [sinks.mqtt]
+            host = "localhost"
+            discovery = true
+
+            [sinks.modbus]
+            listen = "0.0.0.0:502"
+            unit_ids = { HQ2132QY2KR = 239 }
+
+            [[alarm]]
+            rule = "Bmv700.soc < 30 for 5min"
+            name = "battery low"
+            hysteresis = 5.0
+
+            [[alarm]]
+            rule = "MPPT.error_code != NoError"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.site.name, "cabin");
+        assert_eq!(config.site.capacity, Some(200.0));
+        assert_eq!(config.interval(), Duration::from_secs(30));
+        assert_eq!(config.reconnect(), Duration::from_secs(5));
+        assert_eq!(config.stale(), Duration::from_secs(30));
+        assert_eq!(config.ports[0].name(), "house");
+        assert_eq!(config.ports[0].baud, 19_200);
+        assert_eq!(
+            config.ports[1].name(),
+            "usb-VictronEnergy_BV_VE_Direct_cable_VE123-if00-port0"
+        );
+        assert_eq!(config.ports[1].baud, 9600);
+        assert!(!config.sinks.stdout.unwrap().blocks);
+        let mqtt = config.sinks.mqtt.unwrap();
+        assert_eq!(mqtt.port, 1883);
+        assert!(mqtt.discovery);
+        assert_eq!(config.sinks.modbus.unwrap().unit_ids["HQ2132QY2KR"], 239);
+        assert!(config.sinks.influxdb.is_none());
+        assert_eq!(config.alarms[0].rule().unwrap().name(), "battery low");
+        assert_eq!(
+            config.alarms[1].rule().unwrap().name(),
+            "MPPT.error_code != NoError"
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Config::parse("").is_err());
+        let twice = "[[port]]\npath = \"/dev/ttyUSB0\"\n[[port]]\npath = \"/tmp/ttyUSB0\"\n";
+        let error = Config::parse(twice).unwrap_err().to_string();
+        assert!(error.contains("ttyUSB0"), "{}", error);
+        let unknown = "[[port]]\npath = \"/dev/ttyUSB0\"\n[sinks.email]\nto = \"me\"\n";
+        assert!(Config::parse(unknown).is_err());
+        let alarm = "[[port]]\npath = \"/dev/ttyUSB0\"\n[[alarm]]\nrule = \"Bmv700.soc <\"\n";
+        assert!(Config::parse(alarm).is_err());
+        for capacity in ["0.0", "-100.0", "nan", "inf"] {
+            let site = format!(
+                "[site]\ncapacity = {}\n[[port]]\npath = \"/dev/ttyUSB0\"\n",
+                capacity
+            );
+            assert!(Config::parse(&site).is_err(), "{}", capacity);
+        }
+    }
+}
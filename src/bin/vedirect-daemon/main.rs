@@ -0,0 +1,485 @@
+//! Daemon reading several VE.Direct ports and publishing the combined
+//! state of the site to the configured sinks
+
+mod config;
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../../fixtures.rs"]
+mod fixtures;
+mod sink;
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError, SyncSender},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use clap::Parser as ClapParser;
+use serde_json::{json, Map, Value};
+use vedirect::{
+    alarm::{AlarmEngine, AlarmState},
+    clock::{SystemClock, Timestamped, Timestamper},
+    json,
+    reconnect::{ReconnectingReader, Status},
+    site::{Balance, SiteModel},
+    widen, Device, Events, Text, VEError,
+};
+
+use config::Config;
+use sink::Sink;
+
+#[derive(ClapParser)]
+#[command(
+    version,
+    about = "Read several VE.Direct ports and publish the state of the site"
+)]
+struct Args {
+    /// Configuration file
+    #[arg(short, long, default_value = "/etc/vedirect/daemon.toml")]
+    config: PathBuf,
+}
+
+/// Sent by the thread of a port, identified by its index in the
+/// configuration
+#[derive(Debug)]
+enum Message {
+    Connected(usize),
+    Disconnected(usize, String),
+    Block(usize, Device, SystemTime),
+    /// A block which was not mapped, such as one of an inverter
+    Unmapped(usize, SystemTime),
+    /// No block was mapped for the stale timeout
+    Stale(usize),
+}
+
+/// Parser listener sending the blocks of a port to the main thread
+struct Forwarder {
+    port: usize,
+    sender: SyncSender<Message>,
+}
+
+impl Events<Timestamped<Device, SystemTime>> for Forwarder {
+    fn on_complete_block(&mut self, block: Timestamped<Device, SystemTime>) {
+        let _ = self
+            .sender
+            .send(Message::Block(self.port, block.data, block.received));
+    }
+
+    fn on_missing_field(&mut self, _label: Text) {
+        let _ = self
+            .sender
+            .send(Message::Unmapped(self.port, SystemTime::now()));
+    }
+
+    fn on_mapping_error(&mut self, _error: VEError) {
+        let _ = self
+            .sender
+            .send(Message::Unmapped(self.port, SystemTime::now()));
+    }
+
+    fn on_stale(&mut self, _elapsed: Duration) {
+        let _ = self.sender.send(Message::Stale(self.port));
+    }
+}
+
+type Source = Box<dyn Read + Send>;
+
+/// Longest delay between attempts to open a port
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Messages waiting for the main thread. When sinks are slow, the
+/// threads of the ports wait rather than queuing blocks without limit.
+const QUEUE_SIZE: usize = 64;
+
+fn open_port(path: &str, baud: u32) -> io::Result<Source> {
+    let port = serialport::new(path, baud)
+        .data_bits(serialport::DataBits::Eight)
+        .timeout(Duration::from_secs(1))
+        .open()?;
+    Ok(Box::new(port))
+}
+
+/// Read from the sources returned by `open`, opening them again after
+/// failures, until the main thread is gone
+fn read_port(
+    port: usize,
+    open: impl FnMut() -> io::Result<Source>,
+    reconnect: Duration,
+    stale: Duration,
+    sender: SyncSender<Message>,
+) {
+    let forwarder = Forwarder {
+        port,
+        sender: sender.clone(),
+    };
+    let listener = Timestamper::new(SystemClock, forwarder);
+    let mut reader = ReconnectingReader::new(open, listener)
+        .with_backoff(reconnect, reconnect.max(MAX_BACKOFF))
+        .with_stale_timeout(stale);
+    loop {
+        let message = match reader.poll() {
+            Ok(Status::Opened) => Message::Connected(port),
+            Ok(_) => continue,
+            Err(e) => Message::Disconnected(port, e.to_string()),
+        };
+        if sender.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+/// The energy balance of the site, with units in the names like the
+/// serialized fields
+fn balance(balance: &Balance) -> Value {
+    json!({
+        "solar_power_w": balance.solar_power,
+        "chargers": balance.chargers,
+        "battery_power_w": balance.battery_power,
+        "battery_voltage_v": balance.battery_voltage.map(widen),
+        "soc_percent": balance.soc.map(widen),
+        "load_power_w": balance.load_power,
+        "autonomy_s": balance.autonomy.map(|autonomy| autonomy.as_secs()),
+    })
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Default)]
+struct PortState {
+    name: String,
+    path: String,
+    connected: bool,
+    /// No block was mapped for the stale timeout
+    stale: bool,
+    /// Why the port was last disconnected
+    error: Option<String>,
+    blocks: u64,
+    last_block: Option<SystemTime>,
+    device: Option<Device>,
+}
+
+/// State of the ports, and the sinks it is published to
+struct Daemon {
+    site: String,
+    ports: Vec<PortState>,
+    model: SiteModel<SystemTime>,
+    alarms: AlarmEngine<SystemTime>,
+    sinks: Vec<Box<dyn Sink>>,
+    /// Last error of each sink, to log errors once until they change
+    sink_errors: Vec<Option<String>>,
+}
+
+impl Daemon {
+    fn new(config: &Config, sinks: Vec<Box<dyn Sink>>) -> Self {
+        Daemon {
+            site: config.site.name.clone(),
+            // the capacity was checked when parsing the configuration
+            model: config
+                .site
+                .capacity
+                .and_then(|capacity| SiteModel::new().with_capacity(capacity).ok())
+                .unwrap_or_default(),
+            // rules were checked when parsing the configuration
+            alarms: config
+                .alarms
+                .iter()
+                .filter_map(|alarm| alarm.rule().ok())
+                .fold(AlarmEngine::new(), AlarmEngine::with_rule),
+            ports: config
+                .ports
+                .iter()
+                .map(|port| PortState {
+                    name: port.name().into(),
+                    path: port.path.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            sink_errors: vec![None; sinks.len()],
+            sinks,
+        }
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Connected(index) => {
+                let port = &mut self.ports[index];
+                eprintln!("{}: connected to {}", port.name, port.path);
+                port.connected = true;
+                port.error = None;
+            }
+            Message::Disconnected(index, error) => {
+                let port = &mut self.ports[index];
+                // only log the first failure of attempts to reconnect
+                if port.connected || port.error.as_ref() != Some(&error) {
+                    eprintln!("{}: {}", port.name, error);
+                }
+                port.connected = false;
+                port.error = Some(error);
+            }
+            Message::Stale(index) => {
+                let port = &mut self.ports[index];
+                eprintln!("{}: no data", port.name);
+                port.stale = true;
+            }
+            Message::Unmapped(index, timestamp) => {
+                let port = &mut self.ports[index];
+                port.blocks += 1;
+                port.last_block = Some(timestamp);
+            }
+            Message::Block(index, device, timestamp) => {
+                let port = &mut self.ports[index];
+                port.stale = false;
+                port.blocks += 1;
+                port.last_block = Some(timestamp);
+                let name = port.name.clone();
+                self.each_sink(|sink| sink.write(&name, &device, timestamp));
+                let block = Timestamped {
+                    received: timestamp,
+                    data: device.clone(),
+                };
+                for alarm in self.alarms.evaluate(&block) {
+                    let state = match alarm.state {
+                        AlarmState::Raised => "raised",
+                        AlarmState::Cleared => "cleared",
+                    };
+                    eprintln!(
+                        "{}: alarm {} {} at {}",
+                        name, alarm.rule, state, alarm.value
+                    );
+                }
+                self.model.update(block);
+                self.ports[index].device = Some(device);
+            }
+        }
+    }
+
+    /// The combined state of the ports
+    fn state(&self, now: SystemTime) -> Value {
+        let ports: Map<String, Value> = self
+            .ports
+            .iter()
+            .map(|port| {
+                let mut state = json!({
+                    "path": port.path,
+                    "connected": port.connected,
+                    "stale": port.stale,
+                    "error": port.error,
+                    "blocks": port.blocks,
+                    "last_block": port.last_block.map(unix_seconds),
+                });
+                if let Some(device) = &port.device {
+                    state["device"] = device.kind().name().into();
+                    state["serial"] = device.serial_number().into();
+                    state["data"] = json::device_fields(device)
+                        .map(Value::Object)
+                        .unwrap_or(Value::Null);
+                }
+                (port.name.clone(), state)
+            })
+            .collect();
+        json!({
+            "site": self.site,
+            "timestamp": unix_seconds(now),
+            "ports": ports,
+            "balance": balance(&self.model.balance()),
+            "alarms": self
+                .alarms
+                .active()
+                .map(|(rule, serial)| json!({ "rule": rule, "serial": serial }))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    fn publish(&mut self, now: SystemTime) {
+        let state = self.state(now);
+        self.each_sink(|sink| sink.publish_site(&state).and_then(|_| sink.flush()));
+    }
+
+    fn each_sink(&mut self, mut f: impl FnMut(&mut dyn Sink) -> Result<(), VEError>) {
+        for (sink, last_error) in self.sinks.iter_mut().zip(&mut self.sink_errors) {
+            match f(sink.as_mut()) {
+                Ok(()) => {
+                    if last_error.take().is_some() {
+                        eprintln!("{}: recovered", sink.name());
+                    }
+                }
+                Err(e) => {
+                    let error = e.to_string();
+                    if last_error.as_ref() != Some(&error) {
+                        eprintln!("{}: {}", sink.name(), error);
+                        *last_error = Some(error);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let text = fs::read_to_string(&args.config)
+        .with_context(|| format!("reading {}", args.config.display()))?;
+    let config = Config::parse(&text).with_context(|| format!("in {}", args.config.display()))?;
+    let sinks = sink::open(&config)?;
+    if sinks.is_empty() {
+        eprintln!("warning: no [sinks] configured");
+    }
+
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    for (index, port) in config.ports.iter().enumerate() {
+        let (path, baud, sender) = (port.path.clone(), port.baud, sender.clone());
+        let (reconnect, stale) = (config.reconnect(), config.stale());
+        thread::Builder::new()
+            .name(port.name().into())
+            .spawn(move || read_port(index, || open_port(&path, baud), reconnect, stale, sender))?;
+    }
+    drop(sender);
+
+    let mut daemon = Daemon::new(&config, sinks);
+    let mut next_publication = Instant::now() + config.interval();
+    loop {
+        let timeout = next_publication.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(timeout) {
+            Ok(message) => daemon.handle(message),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => anyhow::bail!("all ports stopped"),
+        }
+        if Instant::now() >= next_publication {
+            daemon.publish(SystemTime::now());
+            next_publication += config.interval();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, MPPT_BLOCK};
+    use std::{io::Cursor, sync::mpsc::Receiver};
+
+    const CONFIG: &str = "[site]\nname = \"cabin\"\n[[port]]\npath = \"/dev/ttyUSB0\"\nname = \"solar\"\n[[port]]\npath = \"/dev/ttyUSB1\"\n[[alarm]]\nrule = \"MPPT.panel_power < 10\"\nname = \"no sun\"\n";
+
+    /// Records what it is given
+    #[derive(Default)]
+    struct Recorder {
+        blocks: Vec<(String, Device)>,
+        states: Vec<Value>,
+    }
+
+    impl Sink for std::rc::Rc<std::cell::RefCell<Recorder>> {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn write(&mut self, port: &str, device: &Device, _: SystemTime) -> Result<(), VEError> {
+            self.borrow_mut().blocks.push((port.into(), device.clone()));
+            Ok(())
+        }
+
+        fn publish_site(&mut self, state: &Value) -> Result<(), VEError> {
+            self.borrow_mut().states.push(state.clone());
+            Ok(())
+        }
+    }
+
+    fn mppt() -> Device {
+        fixtures::parse(MPPT_BLOCK)
+    }
+
+    fn messages(receiver: &Receiver<Message>, count: usize) -> Vec<Message> {
+        (0..count)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_read_port() {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let mut attempts = 0;
+        let open = move || {
+            attempts += 1;
+            match attempts {
+                // the device is unplugged at first
+                1 => Err(io::Error::from(io::ErrorKind::NotFound)),
+                _ => Ok(Box::new(Cursor::new(MPPT_BLOCK)) as Source),
+            }
+        };
+        thread::spawn(move || {
+            read_port(
+                1,
+                open,
+                Duration::from_millis(1),
+                Duration::from_secs(60),
+                sender,
+            )
+        });
+
+        let messages = messages(&receiver, 6);
+        drop(receiver);
+        assert!(matches!(messages[0], Message::Disconnected(1, _)));
+        assert!(matches!(messages[1], Message::Connected(1)));
+        assert!(matches!(
+            &messages[2],
+            Message::Block(1, Device::Mppt(_), _)
+        ));
+        assert!(
+            matches!(&messages[3], Message::Disconnected(1, e) if e == "unexpected end of file")
+        );
+        // and a new parser after reconnecting
+        assert!(matches!(messages[4], Message::Connected(1)));
+        assert!(matches!(
+            &messages[5],
+            Message::Block(1, Device::Mppt(_), _)
+        ));
+    }
+
+    #[test]
+    fn test_site_state() {
+        let config = Config::parse(CONFIG).unwrap();
+        let recorder = std::rc::Rc::new(std::cell::RefCell::new(Recorder::default()));
+        let mut daemon = Daemon::new(&config, vec![Box::new(recorder.clone())]);
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        daemon.handle(Message::Connected(0));
+        daemon.handle(Message::Stale(0));
+        daemon.handle(Message::Block(0, mppt(), timestamp));
+        daemon.handle(Message::Disconnected(1, "No such file or directory".into()));
+        daemon.handle(Message::Stale(1));
+        daemon.publish(timestamp + Duration::from_secs(10));
+
+        let recorder = recorder.borrow();
+        assert_eq!(recorder.blocks, vec![("solar".to_string(), mppt())]);
+        let state = &recorder.states[0];
+        assert_eq!(state["site"], "cabin");
+        assert_eq!(state["timestamp"], 1_700_000_010);
+        let solar = &state["ports"]["solar"];
+        assert_eq!(solar["connected"], true);
+        assert_eq!(solar["stale"], false);
+        assert_eq!(solar["blocks"], 1);
+        assert_eq!(solar["last_block"], 1_700_000_000);
+        assert_eq!(solar["device"], "mppt");
+        assert_eq!(solar["serial"], "HQ2132QY2KR");
+        assert_eq!(solar["data"]["battery_voltage_v"], json!(12.54));
+        let unplugged = &state["ports"]["ttyUSB1"];
+        assert_eq!(unplugged["connected"], false);
+        assert_eq!(unplugged["stale"], true);
+        assert_eq!(unplugged["error"], "No such file or directory");
+        assert!(unplugged["device"].is_null());
+        let balance = &state["balance"];
+        assert_eq!(balance["solar_power_w"], json!(5.0));
+        assert_eq!(balance["chargers"], 1);
+        assert!(balance["battery_power_w"].is_null());
+        assert_eq!(
+            state["alarms"],
+            json!([{ "rule": "no sun", "serial": "HQ2132QY2KR" }])
+        );
+    }
+}
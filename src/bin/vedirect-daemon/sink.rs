@@ -0,0 +1,535 @@
+//! Destinations of the data received, built from the `[sinks]` section
+//! of the configuration. Sinks whose feature is disabled are rejected.
+
+use std::time::SystemTime;
+
+use serde_json::Value;
+use vedirect::{Device, VEError};
+
+use crate::config::Config;
+
+pub trait Sink {
+    fn name(&self) -> &'static str;
+
+    /// Handle a block received from `port`
+    fn write(&mut self, port: &str, device: &Device, timestamp: SystemTime) -> Result<(), VEError>;
+
+    /// Publish the combined state of all ports
+    fn publish_site(&mut self, _state: &Value) -> Result<(), VEError> {
+        Ok(())
+    }
+
+    /// Send data held back, such as batched lines
+    fn flush(&mut self) -> Result<(), VEError> {
+        Ok(())
+    }
+}
+
+/// The sinks of `config`
+pub fn open(config: &Config) -> anyhow::Result<Vec<Box<dyn Sink>>> {
+    let sinks = &config.sinks;
+    let mut open: Vec<Box<dyn Sink>> = vec![];
+    if let Some(stdout) = &sinks.stdout {
+        open.push(Box::new(Stdout {
+            blocks: stdout.blocks,
+        }));
+    }
+    macro_rules! sink {
+        ($config:ident, $feature:literal, $open:path) => {
+            if let Some(config) = &sinks.$config {
+                #[cfg(feature = $feature)]
+                open.push(Box::new($open(config)?));
+                #[cfg(not(feature = $feature))]
+                {
+                    let _ = config;
+                    anyhow::bail!(
+                        "[sinks.{}] needs vedirect-daemon built with the `{}` feature",
+                        stringify!($config),
+                        $feature
+                    );
+                }
+            }
+        };
+    }
+    sink!(mqtt, "mqtt", mqtt::open);
+    sink!(influxdb, "influxdb", influxdb::open);
+    sink!(prometheus, "prometheus", prometheus::open);
+    sink!(csv, "csv", csv::open);
+    sink!(sqlite, "sqlite", sqlite::open);
+    sink!(modbus, "modbus", modbus::open);
+    sink!(signalk, "signalk", signalk::open);
+    Ok(open)
+}
+
+/// Prints the site state, and optionally every block, as JSON lines
+struct Stdout {
+    blocks: bool,
+}
+
+impl Sink for Stdout {
+    fn name(&self) -> &'static str {
+        "stdout"
+    }
+
+    fn write(
+        &mut self,
+        port: &str,
+        device: &Device,
+        _timestamp: SystemTime,
+    ) -> Result<(), VEError> {
+        if self.blocks {
+            println!("{}", block(port, device)?);
+        }
+        Ok(())
+    }
+
+    fn publish_site(&mut self, state: &Value) -> Result<(), VEError> {
+        println!("{}", state);
+        Ok(())
+    }
+}
+
+/// A block printed by [`Stdout`]: the fields of the device, its port
+/// and its kind
+fn block(port: &str, device: &Device) -> Result<Value, VEError> {
+    let mut block = vedirect::json::device_fields(device)?;
+    block.insert("port".into(), port.into());
+    block.insert("device".into(), device.kind().name().into());
+    Ok(Value::Object(block))
+}
+
+#[cfg(feature = "mqtt")]
+mod mqtt {
+    use super::*;
+    use std::{io, time::Duration};
+    use vedirect::{
+        homeassistant::DISCOVERY_PREFIX,
+        mqtt::{MqttClient, MqttConfig, Publisher, ReconnectingClient},
+    };
+
+    pub fn open(config: &crate::config::Mqtt) -> anyhow::Result<Publisher<ReconnectingClient>> {
+        let mut mqtt = MqttConfig::new(&config.host, config.port)
+            .with_min_interval(Duration::from_secs(config.min_interval));
+        if let Some(client_id) = &config.client_id {
+            mqtt = mqtt.with_client_id(client_id);
+        }
+        if let Some(prefix) = &config.prefix {
+            mqtt = mqtt.with_prefix(prefix);
+        }
+        if let Some(username) = &config.username {
+            mqtt = mqtt.with_credentials(username, &config.password);
+        }
+        if config.discovery {
+            mqtt = mqtt.with_discovery(DISCOVERY_PREFIX);
+        }
+        Ok(Publisher::connect(mqtt)?)
+    }
+
+    /// Publishes the site state to `<prefix>/site`, retained
+    impl Sink for Publisher<ReconnectingClient> {
+        fn name(&self) -> &'static str {
+            "mqtt"
+        }
+
+        /// Devices without serial number, such as BMVs, are published
+        /// under the name of their port
+        fn write(&mut self, port: &str, device: &Device, _: SystemTime) -> Result<(), VEError> {
+            let id = self
+                .config()
+                .device_id(device)
+                .unwrap_or_else(|_| port.into());
+            self.publish_as(&id, device).map(|_| ())
+        }
+
+        fn publish_site(&mut self, state: &Value) -> Result<(), VEError> {
+            let topic = format!("{}/site", self.config().prefix);
+            MqttClient::publish(
+                self.client_mut(),
+                &topic,
+                state.to_string().as_bytes(),
+                true,
+            )
+        }
+
+        /// Reports messages dropped while the broker is unreachable
+        fn flush(&mut self) -> Result<(), VEError> {
+            match self.client_mut().take_dropped() {
+                0 => Ok(()),
+                _ => Err(VEError::Io(io::Error::other(
+                    "messages dropped, the broker is unreachable",
+                ))),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "influxdb")]
+mod influxdb {
+    use super::*;
+    use vedirect::influxdb::{InfluxConfig, Writer};
+
+    pub fn open(config: &crate::config::Influxdb) -> anyhow::Result<Writer> {
+        let mut influx = InfluxConfig::new(&config.url);
+        if let Some(token) = &config.token {
+            influx = influx.with_token(token);
+        }
+        if let Some(measurement) = &config.measurement {
+            influx = influx.with_measurement(measurement);
+        }
+        if let Some(batch_size) = config.batch_size {
+            influx = influx.with_batch_size(batch_size);
+        }
+        Ok(Writer::new(influx)?)
+    }
+
+    impl Sink for Writer {
+        fn name(&self) -> &'static str {
+            "influxdb"
+        }
+
+        fn write(
+            &mut self,
+            _port: &str,
+            device: &Device,
+            timestamp: SystemTime,
+        ) -> Result<(), VEError> {
+            Writer::write(self, device, timestamp)
+        }
+
+        fn flush(&mut self) -> Result<(), VEError> {
+            Writer::flush(self)
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+mod prometheus {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+    use vedirect::prometheus::{serve, Metrics};
+
+    pub struct Prometheus(Arc<Mutex<Metrics>>);
+
+    pub fn open(config: &crate::config::Listen) -> anyhow::Result<Prometheus> {
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        serve(TcpListener::bind(&config.listen)?, metrics.clone());
+        Ok(Prometheus(metrics))
+    }
+
+    impl Sink for Prometheus {
+        fn name(&self) -> &'static str {
+            "prometheus"
+        }
+
+        /// Devices are labelled with the name of their port, which
+        /// keeps apart those without serial number, such as BMVs
+        fn write(&mut self, port: &str, device: &Device, _: SystemTime) -> Result<(), VEError> {
+            let mut metrics = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            metrics.record_as(port, device);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "csv")]
+mod csv {
+    use super::*;
+    use vedirect::csv::CsvLogger;
+
+    pub fn open(config: &crate::config::Csv) -> anyhow::Result<CsvLogger> {
+        Ok(CsvLogger::new(&config.directory)?)
+    }
+
+    impl Sink for CsvLogger {
+        fn name(&self) -> &'static str {
+            "csv"
+        }
+
+        fn write(
+            &mut self,
+            _port: &str,
+            device: &Device,
+            timestamp: SystemTime,
+        ) -> Result<(), VEError> {
+            CsvLogger::write(self, device, timestamp).map(|_| ())
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::*;
+    use vedirect::sqlite::Store;
+
+    pub fn open(config: &crate::config::Sqlite) -> anyhow::Result<Store> {
+        Ok(Store::open(&config.path)?)
+    }
+
+    impl Sink for Store {
+        fn name(&self) -> &'static str {
+            "sqlite"
+        }
+
+        fn write(
+            &mut self,
+            _port: &str,
+            device: &Device,
+            timestamp: SystemTime,
+        ) -> Result<(), VEError> {
+            self.insert(device, timestamp)
+        }
+
+        fn flush(&mut self) -> Result<(), VEError> {
+            self.commit()
+        }
+    }
+}
+
+#[cfg(feature = "modbus")]
+mod modbus {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+    use vedirect::{
+        modbus::{serve, Registers},
+        Events,
+    };
+
+    pub struct Modbus(Arc<Mutex<Registers>>);
+
+    pub fn open(config: &crate::config::Modbus) -> anyhow::Result<Modbus> {
+        let registers = config
+            .unit_ids
+            .iter()
+            .fold(Registers::new(), |registers, (serial, unit_id)| {
+                registers.with_unit_id(serial, *unit_id)
+            });
+        let registers = Arc::new(Mutex::new(registers));
+        serve(TcpListener::bind(&config.listen)?, registers.clone());
+        Ok(Modbus(registers))
+    }
+
+    impl Sink for Modbus {
+        fn name(&self) -> &'static str {
+            "modbus"
+        }
+
+        fn write(&mut self, _port: &str, device: &Device, _: SystemTime) -> Result<(), VEError> {
+            self.0.on_complete_block(device.clone());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "signalk")]
+mod signalk {
+    use super::*;
+    use std::{
+        io,
+        net::{TcpStream, ToSocketAddrs},
+        time::{Duration, Instant},
+    };
+    use vedirect::signalk::{DeltaWriter, SOURCE_LABEL};
+
+    /// Timeout of connecting to the server, which blocks the main loop
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+    /// Timeout of writing a delta to a server which stopped reading
+    const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Delay before connecting again after a failed attempt, doubled
+    /// after every failure up to `MAX_BACKOFF`
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// Connects to the server when needed, and again after errors,
+    /// waiting longer after every failed attempt
+    pub struct Signalk {
+        address: String,
+        label: String,
+        ids: Vec<(String, String)>,
+        writer: Option<DeltaWriter<TcpStream>>,
+        backoff: Duration,
+        /// When to connect again, and why the last attempt failed
+        retry: Option<(Instant, io::ErrorKind, String)>,
+    }
+
+    pub fn open(config: &crate::config::Signalk) -> anyhow::Result<Signalk> {
+        Ok(Signalk {
+            address: config.address.clone(),
+            label: config.label.clone().unwrap_or_else(|| SOURCE_LABEL.into()),
+            ids: config.ids.clone().into_iter().collect(),
+            writer: None,
+            backoff: MIN_BACKOFF,
+            retry: None,
+        })
+    }
+
+    impl Signalk {
+        fn connect(&mut self) -> io::Result<&mut DeltaWriter<TcpStream>> {
+            if let Some((at, kind, error)) = &self.retry {
+                if Instant::now() < *at {
+                    // the same error, so it is only logged once
+                    return Err(io::Error::new(*kind, error.clone()));
+                }
+            }
+            match connect(&self.address) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    self.backoff = MIN_BACKOFF;
+                    self.retry = None;
+                    let writer = self.ids.iter().fold(
+                        DeltaWriter::new(stream).with_label(&self.label),
+                        |w, (serial, id)| w.with_id(serial, id),
+                    );
+                    Ok(self.writer.insert(writer))
+                }
+                Err(e) => {
+                    self.retry = Some((Instant::now() + self.backoff, e.kind(), e.to_string()));
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Connect to the first address of `address` which accepts the
+    /// connection
+    fn connect(address: &str) -> io::Result<TcpStream> {
+        let mut error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no address for {}", address),
+        );
+        for address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    impl Sink for Signalk {
+        fn name(&self) -> &'static str {
+            "signalk"
+        }
+
+        fn write(
+            &mut self,
+            _port: &str,
+            device: &Device,
+            timestamp: SystemTime,
+        ) -> Result<(), VEError> {
+            let writer = match &mut self.writer {
+                Some(writer) => writer,
+                None => self.connect()?,
+            };
+            let result = writer.write(device, timestamp);
+            if result.is_err() {
+                // connect again at the next block
+                self.writer = None;
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, MPPT_BLOCK};
+
+    const PORT: &str = "[[port]]\npath = \"/dev/ttyUSB0\"\n";
+
+    #[test]
+    fn test_stdout_block() {
+        let mppt: Device = fixtures::parse(MPPT_BLOCK);
+        let block = block("solar", &mppt).unwrap();
+        assert_eq!(block["port"], "solar");
+        assert_eq!(block["device"], "mppt");
+        assert_eq!(block["serial_number"], "HQ2132QY2KR");
+        assert_eq!(block["battery_voltage_v"], serde_json::json!(12.54));
+    }
+
+    #[test]
+    fn test_disabled_feature() {
+        let directory = std::env::temp_dir().join(format!("vedirect-sink-{}", std::process::id()));
+        let config = Config::parse(&format!(
+            "{}[sinks.stdout]\n[sinks.csv]\ndirectory = {:?}\n",
+            PORT, directory
+        ))
+        .unwrap();
+        let result = open(&config);
+        if cfg!(feature = "csv") {
+            let names: Vec<_> = result.unwrap().iter().map(|sink| sink.name()).collect();
+            assert_eq!(names, vec!["stdout", "csv"]);
+            std::fs::remove_dir_all(&directory).unwrap();
+        } else {
+            assert_eq!(
+                result.err().unwrap().to_string(),
+                "[sinks.csv] needs vedirect-daemon built with the `csv` feature"
+            );
+        }
+    }
+
+    #[cfg(feature = "signalk")]
+    #[test]
+    fn test_signalk_reconnect() {
+        use std::{
+            io::{BufRead, BufReader},
+            net::TcpListener,
+            thread,
+            time::Duration,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut sink = signalk::open(&crate::config::Signalk {
+            address: address.to_string(),
+            label: None,
+            ids: [("HQ2132QY2KR".to_string(), "house".to_string())].into(),
+        })
+        .unwrap();
+        let mppt: Device = fixtures::parse(MPPT_BLOCK);
+        let now = SystemTime::now();
+        let read_delta = |listener: &TcpListener| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            assert!(line.contains("electrical.solar.house.voltage"), "{}", line);
+        };
+
+        sink.write("solar", &mppt, now).unwrap();
+        read_delta(&listener);
+        // the server closed the connection, which fails a later write,
+        // and the next block connects again
+        let failed = (0..50).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            sink.write("solar", &mppt, now).is_err()
+        });
+        assert!(failed);
+        sink.write("solar", &mppt, now).unwrap();
+        read_delta(&listener);
+
+        // without a server, attempts wait for the backoff, failing with
+        // the error of the last attempt
+        drop(listener);
+        let failed = (0..50).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            sink.write("solar", &mppt, now).is_err()
+        });
+        assert!(failed);
+        let refused = sink.write("solar", &mppt, now).unwrap_err().to_string();
+        let listener = TcpListener::bind(address).unwrap();
+        let error = sink.write("solar", &mppt, now).unwrap_err();
+        assert_eq!(error.to_string(), refused);
+        thread::sleep(Duration::from_millis(1100));
+        sink.write("solar", &mppt, now).unwrap();
+        read_delta(&listener);
+    }
+}
@@ -0,0 +1,624 @@
+//! Command-line tool for dumping and monitoring VE.Direct devices
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use clap::{Parser as ClapParser, Subcommand, ValueEnum};
+use serde::Serialize;
+use serde_json::Value;
+use vedirect::{
+    capture::{self, CaptureReader, CaptureWriter, Speed},
+    hex::{self, register, DayHistory, HexFrame, Register, Response},
+    json, Bmv700, Device, DeviceKind, Events, Frame, Parser, VEDirectData, VEError, MPPT,
+};
+
+#[derive(ClapParser)]
+#[command(version, about = "Read data from Victron Energy VE.Direct devices")]
+struct Args {
+    /// Serial port the device is connected to, or `-` to read stdin
+    #[arg(short, long, global = true, default_value = "/dev/ttyUSB0")]
+    port: String,
+
+    /// Baud rate of the serial port
+    #[arg(short, long, global = true, default_value_t = 19_200)]
+    baud: u32,
+
+    /// Type of the connected device
+    #[arg(short, long, global = true, value_enum, default_value_t = DeviceType::Auto)]
+    device: DeviceType,
+
+    /// Read from a capture made with `record` instead of the port
+    #[arg(short, long, global = true)]
+    replay: Option<PathBuf>,
+
+    /// Replay speed, relative to the original capture. 0 replays as
+    /// fast as possible.
+    #[arg(short, long, global = true, default_value_t = 1.0)]
+    speed: f64,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print every block as received, without mapping it
+    Dump,
+    /// Show the mapped data of the device in a table, refreshed in
+    /// place as blocks arrive
+    Watch,
+    /// Print each mapped block as a JSON object, one per line
+    Json,
+    /// Write the data received to a capture file, which can be
+    /// replayed with `--replay`
+    Record {
+        /// File to write to
+        file: PathBuf,
+
+        /// Stop after this many seconds
+        #[arg(long)]
+        duration: Option<u64>,
+    },
+    /// Send HEX mode commands to the device
+    Hex {
+        #[command(subcommand)]
+        command: HexCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum HexCommand {
+    /// Check the device answers
+    Ping,
+    /// Show the firmware version and product ID
+    Version,
+    /// Read a register, given by name (see `registers`) or id (e.g. 0xEDF7)
+    Get { register: String },
+    /// Write a register, with the value in the register's unit
+    Set {
+        register: String,
+        #[arg(allow_negative_numbers = true)]
+        value: f32,
+    },
+    /// Restart the device
+    Restart,
+    /// Show the day history of a solar charger
+    History {
+        /// Number of days, up to 31
+        #[arg(default_value_t = 31)]
+        days: u16,
+    },
+    /// List known registers
+    Registers,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DeviceType {
+    /// Detect from the product ID
+    Auto,
+    Bmv700,
+    Mppt,
+}
+
+fn open_port(args: &Args, timeout: Duration) -> anyhow::Result<Box<dyn serialport::SerialPort>> {
+    serialport::new(&args.port, args.baud)
+        .data_bits(serialport::DataBits::Eight)
+        .timeout(timeout)
+        .open()
+        .map_err(|e| anyhow::anyhow!("failed to open {}: {}", args.port, e))
+}
+
+/// Where the data comes from
+enum Input {
+    Stream(Box<dyn Read>),
+    Replay(CaptureReader<BufReader<File>>, Speed),
+}
+
+fn open(args: &Args) -> anyhow::Result<Input> {
+    if let Some(path) = &args.replay {
+        let reader = CaptureReader::new(BufReader::new(File::open(path)?))?;
+        let speed = if args.speed == 1.0 {
+            Speed::Original
+        } else if args.speed > 0.0 {
+            Speed::Accelerated(args.speed)
+        } else {
+            Speed::Unlimited
+        };
+        return Ok(Input::Replay(reader, speed));
+    }
+    if args.port == "-" {
+        return Ok(Input::Stream(Box::new(io::stdin())));
+    }
+    Ok(Input::Stream(Box::new(open_port(
+        args,
+        Duration::from_secs(2),
+    )?)))
+}
+
+/// Read from `source` until end of file, passing the data to `on_data`.
+/// Serial ports time out while the device is quiet, which is not an
+/// error.
+fn read_all(
+    mut source: Box<dyn Read>,
+    mut on_data: impl FnMut(&[u8]) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; 1024];
+    loop {
+        let count = match source.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if !on_data(&buf[..count])? {
+            return Ok(());
+        }
+    }
+}
+
+fn run<D: VEDirectData, F: FnMut(D) -> anyhow::Result<()>>(
+    input: Input,
+    print: F,
+) -> anyhow::Result<()> {
+    let error = RefCell::new(None);
+    let mut parser = Parser::new(Printer {
+        print,
+        error: &error,
+    });
+    match input {
+        Input::Stream(source) => read_all(source, |data| {
+            match parser.feed(data) {
+                Ok(()) | Err(VEError::NeedMoreData) => {}
+                Err(e) => return Err(e.into()),
+            }
+            Ok(error.borrow().is_none())
+        })?,
+        Input::Replay(reader, speed) => {
+            let records = reader.take_while(|_| error.borrow().is_none());
+            capture::replay(records, &mut parser, speed)?;
+        }
+    }
+    match error.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Listener printing each block with `print`, until it fails
+struct Printer<'a, F> {
+    print: F,
+    /// The first error of `print`, which ends reading
+    error: &'a RefCell<Option<anyhow::Error>>,
+}
+
+impl<D: VEDirectData, F: FnMut(D) -> anyhow::Result<()>> Events<D> for Printer<'_, F> {
+    fn on_complete_block(&mut self, block: D) {
+        let mut error = self.error.borrow_mut();
+        if error.is_none() {
+            *error = (self.print)(block).err();
+        }
+    }
+
+    // Expected when starting mid-block, and for blocks such as the BMV
+    // history which are not mapped
+    fn on_missing_field(&mut self, _label: vedirect::Text) {}
+
+    fn on_mapping_error(&mut self, error: VEError) {
+        eprintln!("mapping error: {}", error);
+    }
+
+    fn on_parse_error(&mut self, error: VEError, _parse_buf: &[u8]) {
+        eprintln!("parse error: {}", error);
+    }
+}
+
+/// Mapped data as a flat JSON object, with a `device` member naming
+/// the type of device
+trait ToJson {
+    fn to_json(&self) -> Result<Value, VEError>;
+}
+
+fn tagged(device: &str, data: &impl Serialize) -> Result<Value, VEError> {
+    let mut map = json::to_map(data)?;
+    map.shift_insert(0, "device".into(), device.into());
+    Ok(Value::Object(map))
+}
+
+impl ToJson for Bmv700 {
+    fn to_json(&self) -> Result<Value, VEError> {
+        tagged("bmv700", self)
+    }
+}
+
+impl ToJson for MPPT {
+    fn to_json(&self) -> Result<Value, VEError> {
+        tagged("mppt", self)
+    }
+}
+
+impl ToJson for Device {
+    fn to_json(&self) -> Result<Value, VEError> {
+        match self {
+            Device::Bmv700(bmv) => bmv.to_json(),
+            Device::Mppt(mppt) => mppt.to_json(),
+        }
+    }
+}
+
+/// Split a serialized field name into a description and its unit,
+/// e.g. `battery_voltage_v` into `battery voltage` and `V`
+fn split_unit(name: &str) -> (String, &'static str) {
+    let (name, unit) = vedirect::split_unit(name);
+    (name.replace('_', " "), unit)
+}
+
+fn table(data: &Value) -> String {
+    let mut out = String::new();
+    if let Value::Object(map) = data {
+        for (name, value) in map {
+            let (name, unit) = split_unit(name);
+            let value = match value {
+                Value::String(s) => s.clone(),
+                Value::Null => "---".into(),
+                other => other.to_string(),
+            };
+            out.push_str(&format!("{:<24}{:>16} {}\n", name, value, unit));
+        }
+    }
+    out
+}
+
+fn dump_frame(frame: Frame) -> anyhow::Result<()> {
+    let mut out = io::stdout().lock();
+    for (label, value) in frame.iter() {
+        writeln!(out, "{:<10}{}", label, value)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+fn print_json<D: ToJson>(block: D) -> anyhow::Result<()> {
+    println!("{}", block.to_json()?);
+    Ok(())
+}
+
+fn print_table<D: ToJson>(block: D) -> anyhow::Result<()> {
+    let mut out = io::stdout().lock();
+    // clear the screen and move to the top left
+    write!(out, "\x1b[2J\x1b[H{}", table(&block.to_json()?))?;
+    out.flush()?;
+    Ok(())
+}
+
+fn run_typed<D: VEDirectData + ToJson>(
+    source: Input,
+    print: fn(D) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    run(source, print)
+}
+
+fn record(input: Input, file: PathBuf, duration: Option<u64>) -> anyhow::Result<()> {
+    let source = match input {
+        Input::Stream(source) => source,
+        Input::Replay(..) => anyhow::bail!("cannot record a replayed capture"),
+    };
+    let mut out = CaptureWriter::new(io::BufWriter::new(File::create(&file)?))?;
+    let end = duration.map(|secs| Instant::now() + Duration::from_secs(secs));
+    read_all(source, |data| {
+        out.record(data)?;
+        Ok(end.is_none_or(|end| Instant::now() < end))
+    })?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Collects the HEX messages received, ignoring Text mode blocks
+struct Replies {
+    frames: VecDeque<HexFrame>,
+}
+
+impl Events<Frame> for Replies {
+    fn on_hex_frame(&mut self, frame: HexFrame) {
+        self.frames.push_back(frame);
+    }
+}
+
+/// Sends HEX commands and waits for their answers
+struct HexClient {
+    port: Box<dyn serialport::SerialPort>,
+    parser: Parser<Frame, Replies>,
+}
+
+impl HexClient {
+    const ATTEMPTS: usize = 3;
+    const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn new(port: Box<dyn serialport::SerialPort>) -> Self {
+        HexClient {
+            port,
+            parser: Parser::new(Replies {
+                frames: VecDeque::new(),
+            }),
+        }
+    }
+
+    fn send(&mut self, request: &HexFrame) -> anyhow::Result<()> {
+        self.port.write_all(request.to_string().as_bytes())?;
+        self.port.flush()?;
+        Ok(())
+    }
+
+    /// Send `request` until the device answers with a message accepted
+    /// by `is_reply`, or with an Unknown or Error response
+    fn request(
+        &mut self,
+        request: &HexFrame,
+        is_reply: impl Fn(&Response) -> bool,
+    ) -> anyhow::Result<HexFrame> {
+        let mut buf = [0u8; 256];
+        for _ in 0..Self::ATTEMPTS {
+            self.send(request)?;
+            let deadline = Instant::now() + Self::REPLY_TIMEOUT;
+            while Instant::now() < deadline {
+                while let Some(frame) = self.parser.listener_mut().frames.pop_front() {
+                    match frame.response() {
+                        Ok(Response::Unknown) => anyhow::bail!("device does not know the command"),
+                        Ok(Response::Error) => {
+                            anyhow::bail!("device could not process the command")
+                        }
+                        Ok(response) if is_reply(&response) => return Ok(frame),
+                        _ => {}
+                    }
+                }
+                let count = match self.port.read(&mut buf) {
+                    Ok(count) => count,
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                match self.parser.feed(&buf[..count]) {
+                    Ok(()) | Err(VEError::NeedMoreData) => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        anyhow::bail!("no answer from device")
+    }
+
+    fn get(&mut self, id: u16) -> anyhow::Result<Vec<u8>> {
+        let reply = self.request(
+            &HexFrame::get(id),
+            |r| matches!(r, Response::Get { register, .. } if *register == id),
+        )?;
+        match reply.response()? {
+            Response::Get {
+                flags: 0, value, ..
+            } => Ok(value.to_vec()),
+            Response::Get { flags, .. } => Err(flag_error(flags)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn set(&mut self, id: u16, value: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let reply = self.request(
+            &HexFrame::set(id, value),
+            |r| matches!(r, Response::Set { register, .. } if *register == id),
+        )?;
+        match reply.response()? {
+            Response::Set {
+                flags: 0, value, ..
+            } => Ok(value.to_vec()),
+            Response::Set { flags, .. } => Err(flag_error(flags)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+fn flag_error(flags: u8) -> anyhow::Error {
+    if flags & hex::flags::UNKNOWN_ID != 0 {
+        anyhow::anyhow!("unknown register")
+    } else if flags & hex::flags::NOT_SUPPORTED != 0 {
+        anyhow::anyhow!("register not supported by this device")
+    } else if flags & hex::flags::PARAMETER_ERROR != 0 {
+        anyhow::anyhow!("invalid value")
+    } else {
+        anyhow::anyhow!("device returned flags {:#04x}", flags)
+    }
+}
+
+fn find_register(name: &str) -> anyhow::Result<Register> {
+    register::find(name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "unknown register {}, see `vedirect hex registers` for known ones",
+            name
+        )
+    })
+}
+
+/// A register value with as many decimals as its resolution
+fn format_value(register: &Register, raw: &[u8]) -> String {
+    let decimals = register.divisor.log10().round().max(0.0) as usize;
+    match register.decode(raw) {
+        Some(value) => format!("{:.*} {}", decimals, value, register.unit),
+        None => format!("invalid value {:02X?}", raw),
+    }
+}
+
+fn run_hex(args: &Args, command: &HexCommand) -> anyhow::Result<()> {
+    if let HexCommand::Registers = command {
+        for r in register::ALL {
+            let access = if r.writable { "rw" } else { "r" };
+            println!("{:#06X}  {:<26}{:<3} {}", r.id, r.name, access, r.unit);
+        }
+        return Ok(());
+    }
+    if args.port == "-" {
+        anyhow::bail!("HEX commands need a serial port");
+    }
+    let mut client = HexClient::new(open_port(args, Duration::from_millis(100))?);
+    match command {
+        HexCommand::Ping => {
+            let reply =
+                client.request(&HexFrame::ping(), |r| matches!(r, Response::Ping { .. }))?;
+            if let Response::Ping { version } = reply.response()? {
+                println!("pong, firmware {}", hex::format_version(version));
+            }
+        }
+        HexCommand::Version => {
+            let done = |r: &Response| matches!(r, Response::Done(_));
+            let reply = client.request(&HexFrame::app_version(), done)?;
+            if let Response::Done([lo, hi, ..]) = reply.response()? {
+                println!(
+                    "firmware    {}",
+                    hex::format_version(u16::from_le_bytes([*lo, *hi]))
+                );
+            }
+            let reply = client.request(&HexFrame::product_id(), done)?;
+            if let Response::Done([lo, hi, ..]) = reply.response()? {
+                let pid = u16::from_le_bytes([*lo, *hi]);
+                let kind = match DeviceKind::from_pid(pid) {
+                    Some(DeviceKind::Bmv700) => " (battery monitor)",
+                    Some(DeviceKind::Mppt) => " (solar charger)",
+                    None => "",
+                };
+                println!("product id  {:#06X}{}", pid, kind);
+            }
+        }
+        HexCommand::Get { register } => {
+            let register = find_register(register)?;
+            let value = client.get(register.id)?;
+            println!("{}: {}", register.name, format_value(&register, &value));
+        }
+        HexCommand::Set { register, value } => {
+            let register = find_register(register)?;
+            if !register.writable {
+                anyhow::bail!("{} is read only", register.name);
+            }
+            let raw = register.encode(*value).ok_or_else(|| {
+                anyhow::anyhow!("{} is out of range for {}", value, register.name)
+            })?;
+            let value = client.set(register.id, &raw)?;
+            println!("{}: {}", register.name, format_value(&register, &value));
+        }
+        HexCommand::Restart => client.send(&HexFrame::restart())?,
+        HexCommand::History { days } => {
+            println!(
+                "{:>4} {:>10} {:>10} {:>9} {:>9} {:>9} {:>6} {:>6} {:>6}",
+                "day",
+                "yield kWh",
+                "load kWh",
+                "max W",
+                "Vbat max",
+                "Vbat min",
+                "bulk",
+                "abs",
+                "float"
+            );
+            for day in 0..(*days).min(31) {
+                let raw = match client.get(register::DAY_HISTORY + day) {
+                    Ok(raw) => raw,
+                    // fewer days recorded than requested
+                    Err(_) if day > 0 => break,
+                    Err(e) => return Err(e),
+                };
+                let h = DayHistory::decode(&raw)
+                    .ok_or_else(|| anyhow::anyhow!("day history record too short"))?;
+                println!(
+                    "{:>4} {:>10.2} {:>10.2} {:>9} {:>9.2} {:>9.2} {:>6} {:>6} {:>6}",
+                    h.day_sequence,
+                    h.yield_kwh,
+                    h.consumed_kwh,
+                    h.max_power,
+                    h.battery_voltage_max,
+                    h.battery_voltage_min,
+                    h.time_bulk,
+                    h.time_absorption,
+                    h.time_float
+                );
+            }
+        }
+        HexCommand::Registers => unreachable!(),
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    if let Command::Hex { command } = &args.command {
+        return run_hex(&args, command);
+    }
+    let source = open(&args)?;
+    match (args.command, args.device) {
+        (Command::Dump, _) => run(source, dump_frame),
+        (Command::Watch, DeviceType::Auto) => run_typed::<Device>(source, print_table),
+        (Command::Watch, DeviceType::Bmv700) => run_typed::<Bmv700>(source, print_table),
+        (Command::Watch, DeviceType::Mppt) => run_typed::<MPPT>(source, print_table),
+        (Command::Json, DeviceType::Auto) => run_typed::<Device>(source, print_json),
+        (Command::Json, DeviceType::Bmv700) => run_typed::<Bmv700>(source, print_json),
+        (Command::Json, DeviceType::Mppt) => run_typed::<MPPT>(source, print_json),
+        (Command::Record { file, duration }, _) => record(source, file, duration),
+        (Command::Hex { .. }, _) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_unit() {
+        assert_eq!(
+            split_unit("battery_voltage_v"),
+            ("battery voltage".into(), "V")
+        );
+        assert_eq!(split_unit("yield_today_kwh"), ("yield today".into(), "kWh"));
+        assert_eq!(split_unit("serial_number"), ("serial number".into(), ""));
+    }
+
+    #[test]
+    fn test_args() {
+        let args =
+            Args::try_parse_from(["vedirect", "json", "-p", "/dev/ttyS0", "-d", "mppt"]).unwrap();
+        assert_eq!(args.port, "/dev/ttyS0");
+        assert_eq!(args.baud, 19_200);
+        assert!(args.device == DeviceType::Mppt);
+        assert!(matches!(args.command, Command::Json));
+        assert!(args.replay.is_none());
+
+        let args =
+            Args::try_parse_from(["vedirect", "dump", "--replay", "log.cap", "-s", "10"]).unwrap();
+        assert_eq!(args.replay, Some(PathBuf::from("log.cap")));
+        assert_eq!(args.speed, 10.0);
+
+        let args =
+            Args::try_parse_from(["vedirect", "hex", "set", "absorption-voltage", "14.4"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::Hex { command: HexCommand::Set { ref register, value } }
+                if register == "absorption-voltage" && value == 14.4
+        ));
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(
+            format_value(&register::ABSORPTION_VOLTAGE, &[0xA0, 0x05]),
+            "14.40 V"
+        );
+        assert_eq!(format_value(&register::CURRENT, &[0xF6, 0xFF]), "-1.0 A");
+        assert_eq!(
+            format_value(&register::MAX_POWER_TODAY, &[0x10, 0x01]),
+            "272 W"
+        );
+        assert_eq!(
+            format_value(&register::FLOAT_VOLTAGE, &[0x01]),
+            "invalid value [01]"
+        );
+    }
+}
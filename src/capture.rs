@@ -0,0 +1,335 @@
+//! Capture files, recording data received from a device so it can be
+//! replayed into a [`Parser`] later, with the original timing and
+//! chunk boundaries.
+//!
+//! A capture file starts with the 8 byte magic `VEDCAP01`, followed by
+//! one record per chunk of data. Each record is, all integers little
+//! endian:
+//!
+//! | bytes | content                                                |
+//! |-------|--------------------------------------------------------|
+//! | 8     | monotonic time since the capture started, nanoseconds  |
+//! | 8     | wall clock time, microseconds since the UNIX epoch     |
+//! | 4     | length of the data                                     |
+//! | n     | data, exactly as passed to [`Parser::feed`]            |
+//!
+//! Records longer than [`MAX_RECORD`] are rejected when reading.
+//!
+//! # Example
+//! ```rust
+//! use vedirect::capture::{self, CaptureReader, CaptureWriter, Speed};
+//! use vedirect::{Events, Parser, MPPT};
+//!
+//! # struct Listener;
+//! # impl Events<MPPT> for Listener {}
+//! let mut file = vec![];
+//! let mut writer = CaptureWriter::new(&mut file).unwrap();
+//! let mut parser = Parser::new(Listener);
+//! writer.feed(&mut parser, b"\r\nPID\t0xA053\r\nV\t12540").unwrap();
+//!
+//! let reader = CaptureReader::new(&file[..]).unwrap();
+//! let mut parser = Parser::new(Listener);
+//! capture::replay(reader, &mut parser, Speed::Unlimited).unwrap();
+//! ```
+
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{data::VEDirectData, Events, Parser, VEError};
+
+/// First bytes of every capture file, including the format version
+pub const MAGIC: &[u8; 8] = b"VEDCAP01";
+
+/// Largest record accepted when reading, far more than a serial port
+/// delivers in one read. A larger length means the file is corrupted.
+pub const MAX_RECORD: u32 = 1 << 20;
+
+/// One chunk of recorded data
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Record {
+    /// Time since the capture started
+    pub monotonic: Duration,
+    /// Wall clock time the data was received
+    pub wall_clock: SystemTime,
+    pub data: Vec<u8>,
+}
+
+/// Writes capture files
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+    start: Instant,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Start a capture, writing the file header to `writer`
+    pub fn new(mut writer: W) -> Result<Self, VEError> {
+        writer.write_all(MAGIC)?;
+        Ok(CaptureWriter {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Record a chunk of data received now
+    pub fn record(&mut self, data: &[u8]) -> Result<(), VEError> {
+        let monotonic = self.start.elapsed().as_nanos() as u64;
+        let wall_clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        let len = u32::try_from(data.len())
+            .map_err(|_| VEError::Parse(text!("chunk too large to capture")))?;
+        self.writer.write_all(&monotonic.to_le_bytes())?;
+        self.writer.write_all(&wall_clock.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(data)?;
+        Ok(())
+    }
+
+    /// Record `data` and feed it to `parser`
+    pub fn feed<D: VEDirectData, E: Events<D>, const N: usize>(
+        &mut self,
+        parser: &mut Parser<D, E, N>,
+        data: &[u8],
+    ) -> Result<(), VEError> {
+        self.record(data)?;
+        parser.feed(data)
+    }
+
+    pub fn flush(&mut self) -> Result<(), VEError> {
+        Ok(self.writer.flush()?)
+    }
+
+    /// Finish the capture, returning the underlying writer
+    pub fn into_inner(mut self) -> Result<W, VEError> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the records of a capture file
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Open a capture, checking the file header
+    pub fn new(mut reader: R) -> Result<Self, VEError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(VEError::Parse(text!("not a capture file")));
+        }
+        Ok(CaptureReader { reader })
+    }
+
+    /// The next record, or `None` at the end of the capture
+    pub fn read_record(&mut self) -> Result<Option<Record>, VEError> {
+        let mut header = [0u8; 20];
+        // a capture may end at any record boundary
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+        let u64_at = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&header[i..i + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let monotonic = Duration::from_nanos(u64_at(0));
+        let wall_clock = UNIX_EPOCH + Duration::from_micros(u64_at(8));
+        let len = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+        if len > MAX_RECORD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("capture record of {} bytes", len),
+            )
+            .into());
+        }
+        let mut data = vec![0u8; len as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(Record {
+            monotonic,
+            wall_clock,
+            data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Record, VEError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// How fast to replay a capture
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Speed {
+    /// With the timing of the original capture
+    Original,
+    /// This many times faster than the original capture
+    Accelerated(f64),
+    /// Without waiting between records
+    Unlimited,
+}
+
+/// Feed the records of a capture, usually a [`CaptureReader`], to
+/// `parser`, one [`Parser::feed`] call per record, returning the number
+/// of records replayed
+pub fn replay<I, D, E, const N: usize>(
+    records: I,
+    parser: &mut Parser<D, E, N>,
+    speed: Speed,
+) -> Result<usize, VEError>
+where
+    I: IntoIterator<Item = Result<Record, VEError>>,
+    D: VEDirectData,
+    E: Events<D>,
+{
+    let factor = match speed {
+        Speed::Original => Some(1.0),
+        Speed::Accelerated(factor) if factor > 0.0 => Some(factor),
+        Speed::Accelerated(_) | Speed::Unlimited => None,
+    };
+    let start = Instant::now();
+    let mut count = 0;
+    for record in records {
+        let record = record?;
+        if let Some(factor) = factor {
+            let due = record.monotonic.div_f64(factor);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        match parser.feed(&record.data) {
+            Ok(()) | Err(VEError::NeedMoreData) => {}
+            Err(e) => return Err(e),
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::MPPT;
+
+    /// Records the events in the order they are received
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl Events<MPPT> for Recorder {
+        fn on_complete_block(&mut self, block: MPPT) {
+            self.events.push(format!("block {}", block.battery_current));
+        }
+
+        fn on_parse_error(&mut self, error: VEError, _parse_buf: &[u8]) {
+            self.events.push(format!("error {}", error));
+        }
+    }
+
+    fn capture(chunks: &[&[u8]], gap: Duration) -> Vec<u8> {
+        let mut writer = CaptureWriter::new(vec![]).unwrap();
+        let mut parser = Parser::new(Recorder::default());
+        for chunk in chunks {
+            writer.feed(&mut parser, chunk).unwrap_or(());
+            std::thread::sleep(gap);
+        }
+        writer.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let second = MPPT_BLOCK.replace("\r\nI\t40", "\r\nI\t110");
+        let chunks: Vec<&[u8]> = vec![
+            &MPPT_BLOCK.as_bytes()[50..],
+            &MPPT_BLOCK.as_bytes()[..7],
+            &MPPT_BLOCK.as_bytes()[7..],
+            b"",
+            second.as_bytes(),
+        ];
+        let before = SystemTime::now();
+        let file = capture(&chunks, Duration::ZERO);
+        assert_eq!(&file[..8], MAGIC);
+
+        let records: Vec<Record> = CaptureReader::new(&file[..])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 5);
+        for (record, chunk) in records.iter().zip(&chunks) {
+            assert_eq!(&record.data[..], *chunk);
+            assert!(record.wall_clock >= before - Duration::from_secs(1));
+        }
+        assert!(records[0].monotonic <= records[4].monotonic);
+
+        let mut live = Parser::new(Recorder::default());
+        for chunk in &chunks {
+            let _ = live.feed(chunk);
+        }
+        let mut replayed = Parser::new(Recorder::default());
+        let count = replay(
+            CaptureReader::new(&file[..]).unwrap(),
+            &mut replayed,
+            Speed::Unlimited,
+        )
+        .unwrap();
+        assert_eq!(count, 5);
+        assert_eq!(replayed.listener().events, live.listener().events);
+        assert_eq!(replayed.listener().events, vec!["block 0.04", "block 0.11"]);
+    }
+
+    #[test]
+    fn test_replay_speed() {
+        let chunks: Vec<&[u8]> = vec![b"\r\nV\t1", b"2540", b"\r\n"];
+        let file = capture(&chunks, Duration::from_millis(40));
+
+        let start = Instant::now();
+        let mut parser = Parser::new(Recorder::default());
+        replay(
+            CaptureReader::new(&file[..]).unwrap(),
+            &mut parser,
+            Speed::Accelerated(4.0),
+        )
+        .unwrap();
+        // the last record was captured 80 ms after the first. Only the
+        // lower bound holds on a busy machine.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(20), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            CaptureReader::new(&b"VEDCAP99"[..]),
+            Err(VEError::Parse(_))
+        ));
+        assert!(matches!(
+            CaptureReader::new(&b"VED"[..]),
+            Err(VEError::Io(_))
+        ));
+
+        let mut file = capture(&[b"\r\nV\t12540"], Duration::ZERO);
+        file.pop();
+        let mut reader = CaptureReader::new(&file[..]).unwrap();
+        assert!(matches!(reader.next(), Some(Err(VEError::Io(_)))));
+
+        // a corrupted length is not allocated
+        let mut file = capture(&[b"\r\nV\t12540"], Duration::ZERO);
+        file[24..28].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = CaptureReader::new(&file[..]).unwrap();
+        match reader.next() {
+            Some(Err(VEError::Io(e))) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            other => panic!("{:?}", other),
+        }
+    }
+}
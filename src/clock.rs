@@ -0,0 +1,220 @@
+//! Receive timestamps for blocks.
+//!
+//! A [`Timestamper`] is a parser listener which reads a [`Clock`] when
+//! a block is complete, and passes it on as [`Timestamped`] data to
+//! its own listener. Any mapped type works, including raw
+//! [`Frame`](crate::Frame)s. The timestamp is that of the block's
+//! checksum being parsed, so it stays close to the time of reception
+//! as long as data is fed to the parser as soon as it is read.
+//!
+//! Clocks can be [`SystemClock`] or [`MonotonicClock`] with `std`, or
+//! any closure returning a timestamp, such as the tick counter of a
+//! microcontroller:
+//!
+//! ```rust
+//! use vedirect::clock::{Timestamped, Timestamper};
+//! use vedirect::{Events, Parser, MPPT};
+//!
+//! struct Listener;
+//!
+//! impl Events<Timestamped<MPPT, u32>> for Listener {
+//!     fn on_complete_block(&mut self, block: Timestamped<MPPT, u32>) {
+//!         println!("{} W at tick {}", block.data.panel_power, block.received);
+//!     }
+//! }
+//!
+//! # fn ticks() -> u32 { 0 }
+//! let mut parser = Parser::new(Timestamper::new(ticks, Listener));
+//! # let data = [];
+//! parser.feed(&data).ok();
+//! ```
+
+use core::time::Duration;
+
+use crate::{data::VEDirectData, hex::HexFrame, Events, Text, VEError};
+
+/// Source of receive timestamps
+pub trait Clock {
+    type Instant: Copy;
+
+    fn now(&mut self) -> Self::Instant;
+}
+
+/// Closures returning a timestamp, e.g. a tick count
+impl<T: Copy, F: FnMut() -> T> Clock for F {
+    type Instant = T;
+
+    fn now(&mut self) -> T {
+        self()
+    }
+}
+
+/// Wall clock time, [`std::time::SystemTime`]
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    type Instant = std::time::SystemTime;
+
+    fn now(&mut self) -> Self::Instant {
+        std::time::SystemTime::now()
+    }
+}
+
+/// Monotonic time, [`std::time::Instant`], for measuring the time
+/// between blocks
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MonotonicClock;
+
+#[cfg(feature = "std")]
+impl Clock for MonotonicClock {
+    type Instant = std::time::Instant;
+
+    fn now(&mut self) -> Self::Instant {
+        std::time::Instant::now()
+    }
+}
+
+/// Timestamps which can be subtracted, to integrate readings over time
+pub trait Timestamp: Copy {
+    /// Time elapsed since `earlier`, `None` if it is later than `self`
+    fn duration_since(&self, earlier: Self) -> Option<Duration>;
+}
+
+/// Time since an arbitrary point, such as the start of a
+/// microcontroller
+impl Timestamp for Duration {
+    fn duration_since(&self, earlier: Self) -> Option<Duration> {
+        self.checked_sub(earlier)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Timestamp for std::time::Instant {
+    fn duration_since(&self, earlier: Self) -> Option<Duration> {
+        self.checked_duration_since(earlier)
+    }
+}
+
+/// Going back when the system clock is set
+#[cfg(feature = "std")]
+impl Timestamp for std::time::SystemTime {
+    fn duration_since(&self, earlier: Self) -> Option<Duration> {
+        std::time::SystemTime::duration_since(self, earlier).ok()
+    }
+}
+
+/// A block with the time it was received
+#[derive(Clone, PartialEq, Debug)]
+pub struct Timestamped<D, T> {
+    pub received: T,
+    pub data: D,
+}
+
+/// Parser listener timestamping blocks for `E`, see the
+/// [module documentation](self)
+pub struct Timestamper<C, E> {
+    clock: C,
+    listener: E,
+}
+
+impl<C: Clock, E> Timestamper<C, E> {
+    pub fn new(clock: C, listener: E) -> Self {
+        Timestamper { clock, listener }
+    }
+
+    /// The listener receiving the timestamped blocks
+    pub fn listener(&self) -> &E {
+        &self.listener
+    }
+
+    /// Mutable access to the listener receiving the timestamped blocks
+    pub fn listener_mut(&mut self) -> &mut E {
+        &mut self.listener
+    }
+
+    /// Consume the timestamper, returning the listener
+    pub fn into_listener(self) -> E {
+        self.listener
+    }
+}
+
+impl<D, C, E> Events<D> for Timestamper<C, E>
+where
+    D: VEDirectData,
+    C: Clock,
+    E: Events<Timestamped<D, C::Instant>>,
+{
+    fn on_complete_block(&mut self, block: D) {
+        let received = self.clock.now();
+        self.listener.on_complete_block(Timestamped {
+            received,
+            data: block,
+        })
+    }
+    fn on_missing_field(&mut self, label: Text) {
+        self.listener.on_missing_field(label)
+    }
+    fn on_mapping_error(&mut self, error: VEError) {
+        self.listener.on_mapping_error(error)
+    }
+    fn on_parse_error(&mut self, error: VEError, parse_buf: &[u8]) {
+        self.listener.on_parse_error(error, parse_buf)
+    }
+    fn on_hex_frame(&mut self, frame: HexFrame) {
+        self.listener.on_hex_frame(frame)
+    }
+    fn on_stale(&mut self, elapsed: Duration) {
+        self.listener.on_stale(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Collector, MPPT_BLOCK};
+    use crate::{Frame, Parser, MPPT};
+    use std::time::{Instant, SystemTime};
+
+    #[test]
+    fn test_ticks() {
+        let mut ticks = 0u32;
+        let clock = move || {
+            ticks += 10;
+            ticks
+        };
+        let collector = Collector::<Timestamped<MPPT, u32>>::default();
+        let mut parser = Parser::new(Timestamper::new(clock, collector));
+        // the second block arrives in two chunks
+        let (start, end) = MPPT_BLOCK.as_bytes().split_at(100);
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(start).unwrap();
+        parser.feed(end).unwrap();
+
+        let blocks = parser.into_listener().into_listener().blocks;
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].received, 10);
+        assert_eq!(blocks[1].received, 20);
+        assert_eq!(blocks[1].data.serial_number, "HQ2132QY2KR");
+    }
+
+    #[test]
+    fn test_std_clocks() {
+        let before = SystemTime::now();
+        let collector = Collector::<Timestamped<Frame, SystemTime>>::default();
+        let mut parser = Parser::new(Timestamper::new(SystemClock, collector));
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let frame = &parser.listener().listener().blocks[0];
+        assert!(frame.received >= before);
+        assert_eq!(frame.data.get("PPV"), Some("5"));
+
+        let before = Instant::now();
+        let collector = Collector::<Timestamped<MPPT, Instant>>::default();
+        let mut parser = Parser::new(Timestamper::new(MonotonicClock, collector));
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        assert!(parser.listener().listener().blocks[0].received >= before);
+    }
+}
@@ -0,0 +1,332 @@
+//! Logging device data to CSV files, enabled with the `csv` feature.
+//!
+//! A [`CsvLogger`] is a parser listener writing one row per block to a
+//! file per device and per day, e.g. `mppt_HQ2132QY2KR_2023-11-14.csv`.
+//! The columns are the timestamp followed by the serialized fields of
+//! the device's data, with their unit:
+//!
+//! ```text
+//! timestamp,battery_voltage [V],panel_voltage [V],panel_power [W],...
+//! 2023-11-14T22:13:20Z,12.54,18.54,5,...
+//! ```
+//!
+//! Every block of a kind of device has the same fields, so the header
+//! of a file never changes. Values which are not known are left empty.
+//! Dates and timestamps are in UTC.
+//!
+//! Files of the current day are appended to after a restart. When the
+//! fields changed in the meantime, e.g. after an upgrade, rows are
+//! written to a new file with a number suffix, such as
+//! `mppt_HQ2132QY2KR_2023-11-14.1.csv`. A last row cut short by a
+//! power cut is ended with a newline first, so it does not run into the
+//! next row.
+//!
+//! ```rust,no_run
+//! use vedirect::csv::CsvLogger;
+//! use vedirect::Parser;
+//!
+//! let mut parser = Parser::new(CsvLogger::new("/media/sd/vedirect").unwrap());
+//! # let data = [];
+//! parser.feed(&data).unwrap();
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde_json::Value;
+
+use crate::{
+    datetime::{self, Date},
+    json, split_unit, Device, Events, VEError,
+};
+
+/// Name of the first column
+pub const TIMESTAMP: &str = "timestamp";
+
+/// Default of [`CsvLogger::with_sync_interval`]
+pub const DEFAULT_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Columns of the data of `device`, after the timestamp, e.g.
+/// `battery_voltage [V]`
+pub fn columns(device: &Device) -> Result<Vec<String>, VEError> {
+    Ok(json::device_fields(device)?
+        .keys()
+        .map(|field| match split_unit(field) {
+            (name, "") => name.into(),
+            (name, unit) => format!("{} [{}]", name, unit),
+        })
+        .collect())
+}
+
+/// Writes device data to daily CSV files, see the
+/// [module documentation](self)
+///
+/// Every row is written to its file at once, and files are synced to
+/// the disk at most every [sync interval](Self::with_sync_interval), so
+/// the rows of the last interval may be lost when the power is cut.
+pub struct CsvLogger {
+    directory: PathBuf,
+    sync_interval: Duration,
+    /// The file of every device, by the start of its name
+    files: HashMap<String, DailyFile>,
+    error: Option<VEError>,
+}
+
+struct DailyFile {
+    date: Date,
+    path: PathBuf,
+    file: File,
+    synced: Instant,
+}
+
+impl CsvLogger {
+    /// Log to files in `directory`, which is created if needed
+    pub fn new(directory: impl Into<PathBuf>) -> Result<Self, VEError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(CsvLogger {
+            directory,
+            sync_interval: DEFAULT_SYNC_INTERVAL,
+            files: HashMap::new(),
+            error: None,
+        })
+    }
+
+    /// Sync files to the disk at most this often, [`DEFAULT_SYNC_INTERVAL`]
+    /// by default. Every sync is a write to the disk, which wears out SD
+    /// cards; zero syncs every row.
+    pub fn with_sync_interval(mut self, sync_interval: Duration) -> Self {
+        self.sync_interval = sync_interval;
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// Append a row with the data of a device, received at `timestamp`,
+    /// returning the path of the file written to
+    pub fn write(&mut self, device: &Device, timestamp: SystemTime) -> Result<&Path, VEError> {
+        let date = Date::of(timestamp)?;
+        let fields = json::device_fields(device)?;
+        let mut row = datetime::rfc3339(timestamp)?;
+        for value in fields.values() {
+            row.push(',');
+            match value {
+                Value::Null => {}
+                Value::String(s) => row.push_str(&quote(s)),
+                other => row.push_str(&other.to_string()),
+            }
+        }
+        row.push('\n');
+
+        let name = file_name(device);
+        let current = self.files.get(&name).is_some_and(|file| file.date == date);
+        if !current {
+            let mut header = String::from(TIMESTAMP);
+            for column in columns(device)? {
+                header.push(',');
+                header.push_str(&quote(&column));
+            }
+            let file = DailyFile::open(&self.directory, &name, date, &header)?;
+            if let Some(previous) = self.files.insert(name.clone(), file) {
+                previous.file.sync_data()?;
+            }
+        }
+        let file = self.files.get_mut(&name).expect("file opened above");
+        file.file.write_all(row.as_bytes())?;
+        if file.synced.elapsed() >= self.sync_interval {
+            file.file.sync_data()?;
+            file.synced = Instant::now();
+        }
+        Ok(&file.path)
+    }
+
+    /// The first error which occurred while writing blocks received as
+    /// a parser listener, if any
+    pub fn take_error(&mut self) -> Option<VEError> {
+        self.error.take()
+    }
+}
+
+impl Events<Device> for CsvLogger {
+    fn on_complete_block(&mut self, block: Device) {
+        if let Err(e) = self.write(&block, SystemTime::now()) {
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+impl DailyFile {
+    /// Open the file of `date` with `header` for appending, writing the
+    /// header to new files and ending a row cut short
+    fn open(directory: &Path, name: &str, date: Date, header: &str) -> Result<Self, VEError> {
+        for n in 0.. {
+            let path = match n {
+                0 => directory.join(format!("{}_{}.csv", name, date)),
+                n => directory.join(format!("{}_{}.{}.csv", name, date, n)),
+            };
+            let mut file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&path)?;
+            let mut first_line = String::new();
+            BufReader::new(&file).read_line(&mut first_line)?;
+            if first_line.is_empty() {
+                writeln!(file, "{}", header)?;
+            } else if first_line.trim_end() != header {
+                continue;
+            } else {
+                let mut last = [0u8];
+                file.seek(SeekFrom::End(-1))?;
+                file.read_exact(&mut last)?;
+                if last[0] != b'\n' {
+                    file.write_all(b"\n")?;
+                }
+            }
+            return Ok(DailyFile {
+                date,
+                path,
+                file,
+                synced: Instant::now(),
+            });
+        }
+        unreachable!()
+    }
+}
+
+/// e.g. `mppt_HQ2132QY2KR`, or `bmv700` for devices without a serial
+/// number
+fn file_name(device: &Device) -> String {
+    let mut name = device.kind().name().to_string();
+    if let Some(serial) = device.serial_number() {
+        name.push('_');
+        name.extend(
+            serial
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }),
+        );
+    }
+    name
+}
+
+fn quote(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::{Bmv700, Parser};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// An empty directory for one test
+    fn directory(test: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("vedirect-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn bmv(voltage: f32) -> Device {
+        Device::Bmv700(Bmv700 {
+            voltage,
+            power: -20,
+            consumed: Some("-5300".into()),
+            soc: None,
+            ttg: 600,
+        })
+    }
+
+    #[test]
+    fn test_csv_logger() {
+        let directory = directory("csv");
+        let mut parser = Parser::new(CsvLogger::new(&directory).unwrap());
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let mut logger = parser.into_listener();
+        assert!(logger.take_error().is_none());
+
+        let mppt = fs::read_dir(&directory).unwrap().next().unwrap().unwrap();
+        let mppt = fs::read_to_string(mppt.path()).unwrap();
+        let mut lines = mppt.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            "timestamp,battery_voltage [V],panel_voltage [V],panel_power [W],\
+             battery_current [A],load_current [A],load_output_on,relay_on,\
+             off_reason,yield_total [kWh],yield_today [kWh],max_power_today [W],\
+             yield_yesterday [kWh],max_power_yesterday [W],error_code,\
+             state_of_operation,firmware,product_id,serial_number,day_sequence,\
+             tracker_mode"
+        );
+        let row = lines.next().unwrap();
+        assert!(
+            row.ends_with(
+                "Z,12.54,18.54,5,0.04,0.3,true,,None,1.44,0.01,6,0.04,14,\
+             NoError,Bulk,159,0xA053,HQ2132QY2KR,16,MPPTrackerActive"
+            ),
+            "{}",
+            row
+        );
+
+        // rows of the same day are appended, the next day starts a file
+        let day = UNIX_EPOCH + Duration::from_secs(19_675 * 86400);
+        let path = logger.write(&bmv(12.5), day).unwrap().to_owned();
+        assert_eq!(path, directory.join("bmv700_2023-11-14.csv"));
+        let later = day + Duration::from_secs(86399);
+        assert_eq!(logger.write(&bmv(12.6), later).unwrap(), path);
+        let next_day = logger.write(&bmv(12.7), later + Duration::from_secs(1));
+        assert_eq!(next_day.unwrap(), directory.join("bmv700_2023-11-15.csv"));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "timestamp,voltage [V],power [W],consumed [mAh],soc [%],time_to_go [min]\n\
+             2023-11-14T00:00:00Z,12.5,-20,-5300,,600\n\
+             2023-11-14T23:59:59Z,12.6,-20,-5300,,600\n"
+        );
+
+        // after a restart, the file is appended to without a header
+        let mut logger = CsvLogger::new(&directory).unwrap();
+        logger.write(&bmv(12.8), later).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+
+        // a row cut short is ended before appending
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"2023-11-14T23:59:59Z,12.").unwrap();
+        let mut logger = CsvLogger::new(&directory)
+            .unwrap()
+            .with_sync_interval(Duration::ZERO);
+        logger.write(&bmv(12.9), later).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(
+            content.ends_with("Z,12.\n2023-11-14T23:59:59Z,12.9,-20,-5300,,600\n"),
+            "{}",
+            content
+        );
+
+        // a file with other columns is left alone
+        fs::write(&path, "timestamp,voltage [V]\n").unwrap();
+        let mut logger = CsvLogger::new(&directory).unwrap();
+        assert_eq!(
+            logger.write(&bmv(12.8), later).unwrap(),
+            directory.join("bmv700_2023-11-14.1.csv")
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("Bulk"), "Bulk");
+        assert_eq!(quote("a,b"), "\"a,b\"");
+        assert_eq!(quote("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -1,8 +1,16 @@
-use std::{collections::hash_map::HashMap, str::from_utf8};
+// the placeholders for unmapped devices keep their doc comments
+#![allow(clippy::empty_line_after_doc_comments)]
 
+use core::str::from_utf8;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use strum_macros::FromRepr;
 
-use crate::VEError;
+use crate::{
+    encode::{scale, BlockWriter, Encode},
+    Fields, Label, Text, VEError,
+};
 
 // Data types
 type Watt = i32;
@@ -13,19 +21,20 @@ type Minute = i32;
 type KiloWattHours = i32;
 
 // Type conversion errors
-impl From<std::num::ParseIntError> for VEError {
-    fn from(src: std::num::ParseIntError) -> VEError {
-        VEError::Parse(format!("Error parsing integer: {}", src))
+impl From<core::num::ParseIntError> for VEError {
+    fn from(src: core::num::ParseIntError) -> VEError {
+        VEError::Parse(text!("Error parsing integer: {}", src))
     }
 }
 
-impl From<std::num::ParseFloatError> for VEError {
-    fn from(src: std::num::ParseFloatError) -> VEError {
-        VEError::Parse(format!("Error parsing float: {}", src))
+impl From<core::num::ParseFloatError> for VEError {
+    fn from(src: core::num::ParseFloatError) -> VEError {
+        VEError::Parse(text!("Error parsing float: {}", src))
     }
 }
 
-#[derive(FromRepr, PartialEq, Eq, Debug)]
+#[derive(FromRepr, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum OffReason {
     None = 0x0,
     NoInputPower = 0x00000001,
@@ -39,14 +48,16 @@ pub enum OffReason {
     AnalysingInputVoltage = 0x000000100,
 }
 
-#[derive(FromRepr, PartialEq, Eq, Debug)]
+#[derive(FromRepr, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum TrackerOperationMode {
     Off = 0,
     VoltageOrCurrentLimited = 1,
     MPPTrackerActive = 2,
 }
 
-#[derive(FromRepr, PartialEq, Eq, Debug)]
+#[derive(FromRepr, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ErrorCode {
     NoError = 0,
     BatteryVoltageTooHigh = 2,
@@ -70,7 +81,8 @@ pub enum ErrorCode {
     UserSettingsInvalid = 119,
 }
 
-#[derive(FromRepr, PartialEq, Eq, Debug)]
+#[derive(FromRepr, Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum StateOfOperation {
     Off = 0,
     LowPower = 1,
@@ -90,7 +102,7 @@ pub enum StateOfOperation {
 }
 
 pub trait VEDirectData {
-    fn fill(fields: &HashMap<String, Vec<u8>>) -> Result<Self, VEError>
+    fn fill(fields: &Fields<'_>) -> Result<Self, VEError>
     where
         Self: Sized;
 }
@@ -99,101 +111,180 @@ pub trait VEDirectData {
 // struct Bmv600 {}
 
 /// Data for BMV 700 battery monitor series
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Bmv700 {
     /// Main (channel 1) battery voltage. Labelled `V`
     /// Units: V
     /// Available on: BMV 600, BMV 700, MPPT, Inverter
+    #[cfg_attr(feature = "serde", serde(rename = "voltage_v"))]
     pub voltage: Volt,
 
     /// Instantaneous power. Labelled `P`
     /// Units: W
     /// Available on: BMV 700
+    #[cfg_attr(feature = "serde", serde(rename = "power_w"))]
     pub power: Watt,
 
     /// Consumed Amp Hours. Labelled `CE`
     /// Units: mAh (When the BMV is not synchronised, these statistics have no meaning, so "---" will be sent instead of a value)
-    pub consumed: Option<String>,
+    #[cfg_attr(feature = "serde", serde(rename = "consumed_mah"))]
+    pub consumed: Option<Text>,
 
     /// State of charge. Labelled `SOC`
     /// Unit: Percent (When the BMV is not synchronised, these statistics have no meaning, so "---" will be sent instead of a value)
     /// Available on: BMV 600, BMV 700
+    #[cfg_attr(feature = "serde", serde(rename = "soc_percent"))]
     pub soc: Option<Percent>,
 
     /// Time-to-go. Labelled `TTG`
     /// Units: Minutes (When the battery is not discharging the time-to-go is infinite. This is represented as -1)
     /// Available on: BMV 600, BMV 700
+    #[cfg_attr(feature = "serde", serde(rename = "time_to_go_min"))]
     pub ttg: Minute,
 }
 
 impl VEDirectData for Bmv700 {
-    fn fill(fields: &HashMap<String, Vec<u8>>) -> Result<Self, VEError> {
+    fn fill(fields: &Fields<'_>) -> Result<Self, VEError> {
         Ok(Bmv700 {
-            voltage: convert_volt(fields, "V", 10.0)?,
-            power: convert_watt(fields, "P")?,
-            consumed: Some(convert_string(fields, "CE")?),
-            soc: convert_percentage(fields, "SOC")?,
-            ttg: convert_ttg(fields, "TTG")?,
+            voltage: convert_volt(fields, Label::V, 1000.0)?,
+            power: convert_watt(fields, Label::P)?,
+            consumed: Some(convert_string(fields, Label::CE)?),
+            soc: convert_percentage(fields, Label::SOC)?,
+            ttg: convert_ttg(fields, Label::TTG)?,
         })
     }
 }
 
+/// Written with the product ID of a BMV-700, as the type does not keep
+/// the one of the monitor it was read from
+impl Encode for Bmv700 {
+    fn encode_fields(&self, block: &mut BlockWriter<'_>) -> Result<(), VEError> {
+        block.field(Label::PID, "0x0203")?;
+        block.field(Label::V, scale(self.voltage, 1000.0))?;
+        block.field(Label::P, self.power)?;
+        match &self.consumed {
+            Some(consumed) => block.field(Label::CE, consumed)?,
+            None => block.field(Label::CE, "---")?,
+        }
+        match self.soc {
+            Some(soc) => block.field(Label::SOC, scale(soc, 10.0))?,
+            None => block.field(Label::SOC, "---")?,
+        }
+        block.field(Label::TTG, self.ttg)
+    }
+}
+
 /// Data for all MPPT solar charge controller
-#[derive(Debug)]
+///
+/// The yields (`yield_total`, `yield_today`, `yield_yesterday`) are in
+/// units of 0.01 kWh as sent by the device. When serialized with the
+/// `serde` feature they are converted to kWh.
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MPPT {
+    #[cfg_attr(feature = "serde", serde(rename = "battery_voltage_v"))]
     pub channel1_voltage: Volt,
+    #[cfg_attr(feature = "serde", serde(rename = "panel_voltage_v"))]
     pub panel_voltage: Volt,
+    #[cfg_attr(feature = "serde", serde(rename = "panel_power_w"))]
     pub panel_power: Watt,
+    #[cfg_attr(feature = "serde", serde(rename = "battery_current_a"))]
     pub battery_current: Ampere,
+    #[cfg_attr(feature = "serde", serde(rename = "load_current_a"))]
     pub load_current: Ampere,
+    #[cfg_attr(feature = "serde", serde(rename = "load_output_on"))]
     pub load_output_state: bool,
+    #[cfg_attr(feature = "serde", serde(rename = "relay_on"))]
     pub relay_state: Option<bool>,
     pub off_reason: OffReason,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "yield_total_kwh", with = "centi_kwh")
+    )]
     pub yield_total: KiloWattHours,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "yield_today_kwh", with = "centi_kwh")
+    )]
     pub yield_today: KiloWattHours,
+    #[cfg_attr(feature = "serde", serde(rename = "max_power_today_w"))]
     pub max_power_today: Watt,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "yield_yesterday_kwh", with = "centi_kwh")
+    )]
     pub yield_yesterday: KiloWattHours,
+    #[cfg_attr(feature = "serde", serde(rename = "max_power_yesterday_w"))]
     pub max_power_yesterday: Watt,
     pub error_code: ErrorCode,
     pub state_of_operation: StateOfOperation,
     pub firmware: u16,
-    pub product_id: String,
-    pub serial_number: String,
+    pub product_id: Text,
+    pub serial_number: Text,
     pub day_sequence: u16,
     pub tracker_mode: TrackerOperationMode,
 }
 
 impl VEDirectData for MPPT {
-    fn fill(fields: &HashMap<String, Vec<u8>>) -> Result<Self, VEError> {
+    fn fill(fields: &Fields<'_>) -> Result<Self, VEError> {
         Ok(MPPT {
-            channel1_voltage: convert_volt(fields, "V", 1000.0)?,
-            panel_voltage: convert_volt(fields, "VPV", 1000.0)?,
-            panel_power: convert_watt(fields, "PPV")?,
-            battery_current: convert_ampere(fields, "I", 1000.0)?,
-            load_current: convert_ampere(fields, "IL", 1000.0)?,
-            load_output_state: convert_bool(fields, "LOAD")?,
-            relay_state: if fields.contains_key("Relay") {
-                Some(convert_bool(fields, "Relay")?)
+            channel1_voltage: convert_volt(fields, Label::V, 1000.0)?,
+            panel_voltage: convert_volt(fields, Label::VPV, 1000.0)?,
+            panel_power: convert_watt(fields, Label::PPV)?,
+            battery_current: convert_ampere(fields, Label::I, 1000.0)?,
+            load_current: convert_ampere(fields, Label::IL, 1000.0)?,
+            load_output_state: convert_bool(fields, Label::LOAD)?,
+            relay_state: if fields.contains_key(Label::Relay) {
+                Some(convert_bool(fields, Label::Relay)?)
             } else {
                 None
             },
-            off_reason: convert_off_reason(fields, "OR")?,
-            yield_total: convert_watt(fields, "H19")?,
-            yield_today: convert_watt(fields, "H20")?,
-            max_power_today: convert_watt(fields, "H21")?,
-            yield_yesterday: convert_watt(fields, "H22")?,
-            max_power_yesterday: convert_watt(fields, "H23")?,
-            error_code: convert_error_code(fields, "ERR")?,
-            state_of_operation: convert_state_of_operation(fields, "CS")?,
-            firmware: convert_u16(fields, "FW")?,
-            product_id: convert_string(fields, "PID")?,
-            serial_number: convert_string(fields, "SER#")?,
-            day_sequence: convert_u16(fields, "HSDS")?,
-            tracker_mode: convert_tracker_mode(fields, "MPPT")?,
+            off_reason: convert_off_reason(fields, Label::OR)?,
+            yield_total: convert_watt(fields, Label::H19)?,
+            yield_today: convert_watt(fields, Label::H20)?,
+            max_power_today: convert_watt(fields, Label::H21)?,
+            yield_yesterday: convert_watt(fields, Label::H22)?,
+            max_power_yesterday: convert_watt(fields, Label::H23)?,
+            error_code: convert_error_code(fields, Label::ERR)?,
+            state_of_operation: convert_state_of_operation(fields, Label::CS)?,
+            firmware: convert_u16(fields, Label::FW)?,
+            product_id: convert_string(fields, Label::PID)?,
+            serial_number: convert_string(fields, Label::SerialNumber)?,
+            day_sequence: convert_u16(fields, Label::HSDS)?,
+            tracker_mode: convert_tracker_mode(fields, Label::MPPT)?,
         })
     }
 }
 
+impl Encode for MPPT {
+    fn encode_fields(&self, block: &mut BlockWriter<'_>) -> Result<(), VEError> {
+        let on_off = |on: bool| if on { "ON" } else { "OFF" };
+        block.field(Label::PID, &self.product_id)?;
+        block.field(Label::FW, self.firmware)?;
+        block.field(Label::SerialNumber, &self.serial_number)?;
+        block.field(Label::V, scale(self.channel1_voltage, 1000.0))?;
+        block.field(Label::I, scale(self.battery_current, 1000.0))?;
+        block.field(Label::VPV, scale(self.panel_voltage, 1000.0))?;
+        block.field(Label::PPV, self.panel_power)?;
+        block.field(Label::CS, self.state_of_operation as u8)?;
+        block.field(Label::MPPT, self.tracker_mode as u8)?;
+        block.field(Label::OR, format_args!("0x{:08X}", self.off_reason as u32))?;
+        block.field(Label::ERR, self.error_code as u8)?;
+        block.field(Label::LOAD, on_off(self.load_output_state))?;
+        block.field(Label::IL, scale(self.load_current, 1000.0))?;
+        if let Some(relay) = self.relay_state {
+            block.field(Label::Relay, on_off(relay))?;
+        }
+        block.field(Label::H19, self.yield_total)?;
+        block.field(Label::H20, self.yield_today)?;
+        block.field(Label::H21, self.max_power_today)?;
+        block.field(Label::H22, self.yield_yesterday)?;
+        block.field(Label::H23, self.max_power_yesterday)?;
+        block.field(Label::HSDS, self.day_sequence)
+    }
+}
+
 /// Data for Phoenix Inverters
 // struct PhoenixInverter {}
 
@@ -203,18 +294,62 @@ impl VEDirectData for MPPT {
 /// Data for all devices
 // pub struct Everything {}
 
+/// Serializes yields received in 0.01 kWh as kWh
+#[cfg(feature = "serde")]
+mod centi_kwh {
+    use super::KiloWattHours;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &KiloWattHours, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(f64::from(*value) / 100.0)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<KiloWattHours, D::Error> {
+        let centi = f64::deserialize(d)? * 100.0;
+        // without `f64::round`, which needs std
+        if centi < 0.0 {
+            Ok((centi - 0.5) as KiloWattHours)
+        } else {
+            Ok((centi + 0.5) as KiloWattHours)
+        }
+    }
+}
+
+/// Split a serialized field name into its name and unit, e.g.
+/// `battery_voltage_v` into `battery_voltage` and `V`. Fields without
+/// a unit are returned unchanged, with an empty unit.
+#[cfg(feature = "serde")]
+pub fn split_unit(field: &str) -> (&str, &'static str) {
+    const UNITS: &[(&str, &str)] = &[
+        ("_v", "V"),
+        ("_a", "A"),
+        ("_w", "W"),
+        ("_kwh", "kWh"),
+        ("_mah", "mAh"),
+        ("_percent", "%"),
+        ("_min", "min"),
+    ];
+    UNITS
+        .iter()
+        .find_map(|(suffix, unit)| field.strip_suffix(suffix).map(|f| (f, *unit)))
+        .unwrap_or((field, ""))
+}
+
+/// `value` as an `f64`, without the digits added by widening it, e.g.
+/// 12.54 rather than 12.539999961853027
+#[cfg(feature = "std")]
+pub fn widen(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or(f64::NAN)
+}
+
 /// "When the BMV is not synchronised, these statistics have no meaning, so "---" will be sent instead of a value"
-fn convert_percentage(
-    rawkeys: &HashMap<String, Vec<u8>>,
-    label: &str,
-) -> Result<Option<Percent>, VEError> {
+fn convert_percentage(rawkeys: &Fields<'_>, label: Label) -> Result<Option<Percent>, VEError> {
     let raw = rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
 
-    let s = from_utf8(raw).map_err(|e| {
-        VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e))
-    })?;
+    let s = from_utf8(raw)
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?;
     if s == "---" {
         Ok(None)
     } else {
@@ -222,113 +357,98 @@ fn convert_percentage(
     }
 }
 
-fn convert_volt(
-    rawkeys: &HashMap<String, Vec<u8>>,
-    label: &str,
-    factor: f32,
-) -> Result<Volt, VEError> {
+fn convert_volt(rawkeys: &Fields<'_>, label: Label, factor: f32) -> Result<Volt, VEError> {
     let raw = rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
     let cleaned = from_utf8(raw)
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
         .parse::<Volt>()?
         / factor;
     Ok(cleaned)
 }
 
-fn convert_ampere(
-    rawkeys: &HashMap<String, Vec<u8>>,
-    label: &str,
-    factor: f32,
-) -> Result<Ampere, VEError> {
-    let raw = (*rawkeys)
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+fn convert_ampere(rawkeys: &Fields<'_>, label: Label, factor: f32) -> Result<Ampere, VEError> {
+    let raw = rawkeys
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
     let cleaned = from_utf8(raw)
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
         .parse::<Ampere>()?
         / factor;
     Ok(cleaned)
 }
 
-fn convert_watt(rawkeys: &HashMap<String, Vec<u8>>, label: &str) -> Result<Watt, VEError> {
-    let raw = (*rawkeys)
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+fn convert_watt(rawkeys: &Fields<'_>, label: Label) -> Result<Watt, VEError> {
+    let raw = rawkeys
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
     let cleaned = from_utf8(raw)
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
         .parse::<Watt>()?;
     Ok(cleaned)
 }
 
-fn convert_u16(rawkeys: &HashMap<String, Vec<u8>>, label: &str) -> Result<u16, VEError> {
-    let raw = (*rawkeys)
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+fn convert_u16(rawkeys: &Fields<'_>, label: Label) -> Result<u16, VEError> {
+    let raw = rawkeys
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
     let cleaned = from_utf8(raw)
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
         .parse::<u16>()?;
     Ok(cleaned)
 }
 
-fn convert_string(rawkeys: &HashMap<String, Vec<u8>>, label: &str) -> Result<String, VEError> {
-    let raw = &*rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
-    String::from_utf8(raw.clone())
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))
+fn convert_string(rawkeys: &Fields<'_>, label: Label) -> Result<Text, VEError> {
+    let raw = rawkeys
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
+    from_utf8(raw)
+        .map(|s| text!("{}", s))
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))
 }
 
-fn convert_bool(rawkeys: &HashMap<String, Vec<u8>>, label: &str) -> Result<bool, VEError> {
-    let raw = &*rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
-    let s = from_utf8(raw).map_err(|e| {
-        VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e))
-    })?;
+fn convert_bool(rawkeys: &Fields<'_>, label: Label) -> Result<bool, VEError> {
+    let raw = rawkeys
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
+    let s = from_utf8(raw)
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?;
     if s == "ON" {
         Ok(true)
     } else if s == "OFF" {
         Ok(false)
     } else {
-        Err(VEError::OnOffExpected(String::from(s)))
+        Err(VEError::OnOffExpected(text!("{}", s)))
     }
 }
 
-fn convert_ttg(rawkeys: &HashMap<String, Vec<u8>>, label: &str) -> Result<Minute, VEError> {
+fn convert_ttg(rawkeys: &Fields<'_>, label: Label) -> Result<Minute, VEError> {
     let raw = rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
     let cleaned = from_utf8(raw)
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
         .parse::<Minute>()?;
     Ok(cleaned)
 }
 
-fn convert_error_code(
-    rawkeys: &HashMap<String, Vec<u8>>,
-    label: &str,
-) -> Result<ErrorCode, VEError> {
+fn convert_error_code(rawkeys: &Fields<'_>, label: Label) -> Result<ErrorCode, VEError> {
     let raw = rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
     let cleaned = from_utf8(raw)
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
         .parse::<usize>()?;
     Ok(ErrorCode::from_repr(cleaned).unwrap_or(ErrorCode::NoError))
 }
 
-fn convert_off_reason(
-    rawkeys: &HashMap<String, Vec<u8>>,
-    label: &str,
-) -> Result<OffReason, VEError> {
+fn convert_off_reason(rawkeys: &Fields<'_>, label: Label) -> Result<OffReason, VEError> {
     let raw = rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
-    let cleaned = from_utf8(raw).map_err(|e| {
-        VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e))
-    })?;
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
+    let cleaned = from_utf8(raw)
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?;
     match cleaned {
         "0x00000000" => Ok(OffReason::None),
         "0x00000001" => Ok(OffReason::NoInputPower),
@@ -340,39 +460,41 @@ fn convert_off_reason(
         "0x00000040" => Ok(OffReason::BMS),
         "0x00000080" => Ok(OffReason::EngineShutdownDetection),
         "0x00000100" => Ok(OffReason::AnalysingInputVoltage),
-        _ => Err(VEError::UnknownCode(cleaned.to_string())),
+        _ => Err(VEError::UnknownCode(text!("{}", cleaned))),
     }
 }
 
 fn convert_state_of_operation(
-    rawkeys: &HashMap<String, Vec<u8>>,
-    label: &str,
+    rawkeys: &Fields<'_>,
+    label: Label,
 ) -> Result<StateOfOperation, VEError> {
     let raw = rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
     let cleaned = from_utf8(raw)
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
         .parse::<usize>()?;
     Ok(StateOfOperation::from_repr(cleaned).unwrap_or(StateOfOperation::Off))
 }
 
 fn convert_tracker_mode(
-    rawkeys: &HashMap<String, Vec<u8>>,
-    label: &str,
+    rawkeys: &Fields<'_>,
+    label: Label,
 ) -> Result<TrackerOperationMode, VEError> {
     let raw = rawkeys
-        .get(label)
-        .ok_or_else(|| VEError::MissingField(label.into()))?;
+        .get_label(label)
+        .ok_or_else(|| VEError::MissingField(text!("{}", label)))?;
     let cleaned = from_utf8(raw)
-        .map_err(|e| VEError::Parse(format!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
+        .map_err(|e| VEError::Parse(text!("Failed to parse {} from {:?} - {}", label, &raw, e)))?
         .parse::<usize>()?;
     Ok(TrackerOperationMode::from_repr(cleaned).unwrap_or(TrackerOperationMode::Off))
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants, clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
+    use crate::fixtures::{parse, MPPT_BLOCK};
     use crate::Events;
 
     struct CheckerBmv700 {
@@ -396,7 +518,7 @@ mod tests {
 
     #[test]
     fn test_mapping() {
-        let input = "\r\nP\t123\r\nCE\t53\r\nSOC\t452\r\nTTG\t60\r\nRelay\tOFF\r\nAlarm\tOFF\r\nV\t232\r\nChecksum\t12";
+        let input = "\r\nP\t123\r\nCE\t53\r\nSOC\t452\r\nTTG\t60\r\nRelay\tOFF\r\nAlarm\tOFF\r\nV\t23200\r\nChecksum\t12";
         let mut checker = CheckerBmv700 { block_count: 0 };
         let mut parser = crate::Parser::new(&mut checker);
         parser.feed(input.as_bytes()).unwrap();
@@ -432,10 +554,104 @@ mod tests {
     }
     #[test]
     fn test_mapping_mppt() {
-        let input = "\r\nPID\t0xA053\r\nFW\t159\r\nSER#\tHQ2132QY2KR\r\nV\t12540\r\nI\t40\r\nVPV\t18540\r\nPPV\t5\r\nCS\t3\r\nMPPT\t2\r\nOR\t0x00000000\r\nERR\t0\r\nLOAD\tON\r\nIL\t300\r\nH19\t144\r\nH20\t1\r\nH21\t6\r\nH22\t4\r\nH23\t14\r\nHSDS\t16\r\nChecksum\t?";
         let mut checker = CheckerMPPT { block_count: 0 };
         let mut parser = crate::Parser::new(&mut checker);
-        parser.feed(input.as_bytes()).unwrap();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
         assert_eq!(checker.block_count, 1);
     }
+
+    fn encode(data: &impl Encode) -> heapless::Vec<u8, 512> {
+        let mut buf = [0u8; 512];
+        let len = data.encode(&mut buf).unwrap();
+        let block = heapless::Vec::from_slice(&buf[..len]).unwrap();
+        let sum = block.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        assert_eq!(sum, 0, "checksum of {:?}", block);
+        block
+    }
+
+    #[test]
+    fn test_encode_mppt() {
+        let input = MPPT_BLOCK.trim_end_matches('?');
+        let mppt: MPPT = parse(MPPT_BLOCK);
+        let block = encode(&mppt);
+        assert_eq!(&block[..block.len() - 1], input.as_bytes());
+
+        // round trips with other values
+        let mut seed = 12345u32;
+        let mut next = |max: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 8) % max
+        };
+        for _ in 0..100 {
+            let mut mppt = mppt.clone();
+            mppt.channel1_voltage = next(60_000) as f32 / 1000.0;
+            mppt.panel_voltage = next(150_000) as f32 / 1000.0;
+            mppt.battery_current = (next(200_000) as f32 - 100_000.0) / 1000.0;
+            mppt.load_current = next(20_000) as f32 / 1000.0;
+            mppt.panel_power = next(5000) as i32;
+            mppt.load_output_state = next(2) == 1;
+            mppt.relay_state = [None, Some(true), Some(false)][next(3) as usize];
+            mppt.off_reason = OffReason::from_repr(1 << next(9)).unwrap();
+            mppt.state_of_operation = StateOfOperation::Float;
+            mppt.error_code = ErrorCode::InputCurrentTooHigh;
+            mppt.yield_today = next(10_000) as i32;
+            assert_eq!(parse::<MPPT>(&encode(&mppt)), mppt);
+        }
+    }
+
+    #[test]
+    fn test_encode_bmv700() {
+        let input = "\r\nPID\t0x0203\r\nV\t12540\r\nP\t-123\r\nCE\t-53\r\nSOC\t452\r\nTTG\t60\r\nChecksum\t";
+        let bmv: Bmv700 = parse([input.as_bytes(), b"?"].concat());
+        let block = encode(&bmv);
+        assert_eq!(&block[..block.len() - 1], input.as_bytes());
+
+        let unsynchronised = Bmv700 {
+            soc: None,
+            consumed: None,
+            ..bmv
+        };
+        let block = encode(&unsynchronised);
+        assert!(
+            block[..block.len() - 1].ends_with(b"\r\nCE\t---\r\nSOC\t---\r\nTTG\t60\r\nChecksum\t")
+        );
+        let back: Bmv700 = parse(&block);
+        assert_eq!(back.soc, None);
+        assert_eq!(back.consumed, Some("---".into()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_mppt() {
+        let mppt: MPPT = parse(MPPT_BLOCK);
+
+        let text = serde_json::to_string(&mppt).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(json["battery_voltage_v"], 12.54);
+        assert_eq!(json["yield_total_kwh"], 1.44);
+        assert_eq!(json["load_output_on"], true);
+        assert_eq!(json["state_of_operation"], "Bulk");
+        assert_eq!(json["serial_number"], "HQ2132QY2KR");
+
+        let back: MPPT = serde_json::from_value(json).unwrap();
+        assert_eq!(back, mppt);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_bmv700() {
+        let bmv = Bmv700 {
+            voltage: 23.2,
+            power: 123,
+            consumed: None,
+            soc: Some(45.2),
+            ttg: -1,
+        };
+        let json = serde_json::to_string(&bmv).unwrap();
+        assert_eq!(
+            json,
+            r#"{"voltage_v":23.2,"power_w":123,"consumed_mah":null,"soc_percent":45.2,"time_to_go_min":-1}"#
+        );
+        assert_eq!(serde_json::from_str::<Bmv700>(&json).unwrap(), bmv);
+    }
 }
@@ -0,0 +1,94 @@
+//! UTC dates and timestamps, for the exporters
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::VEError;
+
+/// Seconds since the Unix epoch
+pub(crate) fn unix_seconds(timestamp: SystemTime) -> Result<u64, VEError> {
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .map_err(|_| VEError::Parse(text!("timestamp before 1970")))
+}
+
+/// e.g. `2023-11-14T22:13:20Z`
+pub(crate) fn rfc3339(timestamp: SystemTime) -> Result<String, VEError> {
+    let seconds = unix_seconds(timestamp)?;
+    let time = seconds % 86400;
+    Ok(format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        Date::from_days(seconds / 86400),
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    ))
+}
+
+/// A day of the proleptic Gregorian calendar
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Date {
+    year: u64,
+    month: u64,
+    day: u64,
+}
+
+impl Date {
+    /// The date of `timestamp` in UTC
+    #[cfg(feature = "csv")]
+    pub(crate) fn of(timestamp: SystemTime) -> Result<Date, VEError> {
+        Ok(Date::from_days(unix_seconds(timestamp)? / 86400))
+    }
+
+    /// The date `days` after 1970-01-01
+    fn from_days(days: u64) -> Date {
+        // from http://howardhinnant.github.io/date_algorithms.html,
+        // counting from 0000-03-01 so leap days end the year
+        let days = days + 719_468;
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = era * 400 + year_of_era + u64::from(month <= 2);
+        Date { year, month, day }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_date() {
+        assert_eq!(Date::from_days(0).to_string(), "1970-01-01");
+        assert_eq!(Date::from_days(19_675).to_string(), "2023-11-14");
+        assert_eq!(Date::from_days(19_782).to_string(), "2024-02-29");
+        assert_eq!(Date::from_days(19_783).to_string(), "2024-03-01");
+        assert_eq!(Date::from_days(11_016).to_string(), "2000-02-29");
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(rfc3339(timestamp).unwrap(), "2023-11-14T22:13:20Z");
+        assert!(rfc3339(UNIX_EPOCH - Duration::from_secs(1)).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+//! Change detection, passing on only the fields which changed.
+//!
+//! Devices send a block every second, mostly with the same values. A
+//! [`ChangeFilter`] is a parser listener which compares each block
+//! with the values it last passed on for the same device, and passes
+//! on a [`Delta`] with the changed fields only, or nothing if no field
+//! changed. Any type implementing [`Inspect`] works, including raw
+//! [`Frame`](crate::Frame)s.
+//!
+//! Numeric fields can be given a deadband, such as 0.05 V or 1 W:
+//! changes smaller than it are left out. They are compared with the
+//! value passed on last, so a slow drift is passed on once it adds up
+//! to the deadband. Every few blocks, and for the first block of a
+//! device or after the data stopped (see
+//! [`Events::on_stale`]), a snapshot with all fields is passed on, so a
+//! receiver which missed an update catches up.
+//!
+//! ```rust
+//! use vedirect::delta::{ChangeFilter, Delta};
+//! use vedirect::{Events, Parser, MPPT};
+//!
+//! struct Uplink;
+//!
+//! impl Events<Delta<MPPT>> for Uplink {
+//!     fn on_complete_block(&mut self, delta: Delta<MPPT>) {
+//!         for change in delta.changes {
+//!             println!("{} = {:?}", change.field, change.value);
+//!         }
+//!     }
+//! }
+//!
+//! let filter = ChangeFilter::new(Uplink)
+//!     .with_deadband("channel1_voltage", 0.05)
+//!     .with_deadband("panel_power", 1.0)
+//!     .with_snapshot_interval(300);
+//! let mut parser = Parser::new(filter);
+//! # let data = [];
+//! parser.feed(&data).ok();
+//! ```
+
+use std::time::Duration;
+
+use crate::{
+    hex::HexFrame,
+    inspect::{Inspect, Value},
+    Events, Text, VEError,
+};
+
+/// Default number of blocks of a device between two snapshots
+pub const DEFAULT_SNAPSHOT_INTERVAL: u32 = 60;
+
+/// A changed field
+#[derive(Clone, PartialEq, Debug)]
+pub struct Change {
+    /// Name of the field, see [`Inspect`]
+    pub field: String,
+    /// `None` if the field no longer has a value
+    pub value: Option<Value>,
+}
+
+/// A block with the fields which changed since the last one passed on,
+/// see the [module documentation](self)
+#[derive(Clone, PartialEq, Debug)]
+pub struct Delta<D> {
+    pub data: D,
+    /// Whether `changes` has all fields, rather than the changed ones
+    pub snapshot: bool,
+    pub changes: Vec<Change>,
+}
+
+/// Values passed on last for a device
+struct Reported {
+    kind: &'static str,
+    serial: Option<String>,
+    fields: Vec<(String, Value)>,
+    /// Blocks since the last snapshot
+    blocks: u32,
+}
+
+/// Parser listener passing on changed fields to `E`, see the
+/// [module documentation](self)
+pub struct ChangeFilter<E> {
+    listener: E,
+    deadbands: Vec<(String, f64)>,
+    snapshot_interval: u32,
+    devices: Vec<Reported>,
+}
+
+impl<E> ChangeFilter<E> {
+    pub fn new(listener: E) -> Self {
+        ChangeFilter {
+            listener,
+            deadbands: Vec::new(),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            devices: Vec::new(),
+        }
+    }
+
+    /// Leave out changes of the numeric field `field` smaller than
+    /// `deadband`, in the unit of the field
+    pub fn with_deadband(mut self, field: &str, deadband: f64) -> Self {
+        self.deadbands.retain(|(f, _)| f != field);
+        self.deadbands.push((field.into(), deadband));
+        self
+    }
+
+    /// Pass on a snapshot every `blocks` blocks of a device, or only
+    /// for the first one if 0
+    pub fn with_snapshot_interval(mut self, blocks: u32) -> Self {
+        self.snapshot_interval = blocks;
+        self
+    }
+
+    /// Pass on a snapshot for the next block of each device
+    pub fn reset(&mut self) {
+        self.devices.clear();
+    }
+
+    /// The listener receiving the deltas
+    pub fn listener(&self) -> &E {
+        &self.listener
+    }
+
+    /// Mutable access to the listener receiving the deltas
+    pub fn listener_mut(&mut self) -> &mut E {
+        &mut self.listener
+    }
+
+    /// Consume the filter, returning the listener
+    pub fn into_listener(self) -> E {
+        self.listener
+    }
+
+    fn changed(&self, field: &str, last: &Value, value: &Value) -> bool {
+        let deadband = self
+            .deadbands
+            .iter()
+            .find(|(f, _)| f == field)
+            .map_or(0.0, |(_, deadband)| *deadband);
+        match (last.as_number(), value.as_number()) {
+            (Some(last), Some(value)) if deadband > 0.0 => (value - last).abs() >= deadband,
+            _ => last != value,
+        }
+    }
+
+    /// The changes of `data` to pass on, `None` if there are none
+    fn delta<D: Inspect>(&mut self, data: &D) -> Option<(bool, Vec<Change>)> {
+        let fields = data.fields();
+        let (kind, serial) = (data.kind(), data.serial());
+        let position = self
+            .devices
+            .iter()
+            .position(|d| d.kind == kind && d.serial.as_deref() == serial);
+        let mut reported = match position {
+            Some(position) => self.devices.swap_remove(position),
+            None => Reported {
+                kind,
+                serial: serial.map(String::from),
+                fields: Vec::new(),
+                blocks: self.snapshot_interval,
+            },
+        };
+        let snapshot = position.is_none()
+            || (self.snapshot_interval > 0 && reported.blocks + 1 >= self.snapshot_interval);
+        reported.blocks = if snapshot { 0 } else { reported.blocks + 1 };
+
+        let mut changes = Vec::new();
+        if snapshot {
+            changes.extend(
+                reported
+                    .fields
+                    .iter()
+                    .filter(|(name, _)| fields.iter().all(|(field, _)| field != name))
+                    .map(|(name, _)| Change {
+                        field: name.clone(),
+                        value: None,
+                    }),
+            );
+            reported.fields = fields
+                .iter()
+                .map(|(field, value)| (field.to_string(), value.clone()))
+                .collect();
+            changes.extend(reported.fields.iter().map(|(field, value)| Change {
+                field: field.clone(),
+                value: Some(value.clone()),
+            }));
+        } else {
+            reported.fields.retain(|(name, _)| {
+                let kept = fields.iter().any(|(field, _)| field == name);
+                if !kept {
+                    changes.push(Change {
+                        field: name.clone(),
+                        value: None,
+                    });
+                }
+                kept
+            });
+            for (field, value) in fields {
+                match reported.fields.iter_mut().find(|(name, _)| name == field) {
+                    Some((_, last)) if !self.changed(field, last, &value) => continue,
+                    Some((_, last)) => *last = value.clone(),
+                    None => reported.fields.push((field.into(), value.clone())),
+                }
+                changes.push(Change {
+                    field: field.into(),
+                    value: Some(value),
+                });
+            }
+        }
+        self.devices.push(reported);
+        (snapshot || !changes.is_empty()).then_some((snapshot, changes))
+    }
+}
+
+impl<D, E> Events<D> for ChangeFilter<E>
+where
+    D: Inspect,
+    E: Events<Delta<D>>,
+{
+    fn on_complete_block(&mut self, block: D) {
+        if let Some((snapshot, changes)) = self.delta(&block) {
+            self.listener.on_complete_block(Delta {
+                data: block,
+                snapshot,
+                changes,
+            })
+        }
+    }
+    fn on_missing_field(&mut self, label: Text) {
+        self.listener.on_missing_field(label)
+    }
+    fn on_mapping_error(&mut self, error: VEError) {
+        self.listener.on_mapping_error(error)
+    }
+    fn on_parse_error(&mut self, error: VEError, parse_buf: &[u8]) {
+        self.listener.on_parse_error(error, parse_buf)
+    }
+    fn on_hex_frame(&mut self, frame: HexFrame) {
+        self.listener.on_hex_frame(frame)
+    }
+    fn on_stale(&mut self, elapsed: Duration) {
+        // the receiver may have missed blocks as well
+        self.reset();
+        self.listener.on_stale(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Collector, MPPT_BLOCK};
+    use crate::{Bmv700, Frame, Parser};
+
+    fn battery(voltage: f32, soc: Option<f32>) -> Bmv700 {
+        Bmv700 {
+            voltage,
+            power: -100,
+            consumed: None,
+            soc,
+            ttg: -1,
+        }
+    }
+
+    fn changes<D>(delta: &Delta<D>) -> Vec<(&str, Option<f64>)> {
+        delta
+            .changes
+            .iter()
+            .map(|c| {
+                (
+                    c.field.as_str(),
+                    c.value.as_ref().and_then(Value::as_number),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_deadband() {
+        let collector = Collector::default();
+        let mut filter = ChangeFilter::new(collector).with_deadband("voltage", 0.05);
+        for voltage in [12.5, 12.53, 12.56, 12.57, 12.5] {
+            filter.on_complete_block(battery(voltage, Some(80.0)));
+        }
+        filter.on_complete_block(battery(12.5, None));
+
+        let deltas = &filter.listener().blocks;
+        assert_eq!(deltas.len(), 4);
+        assert!(deltas[0].snapshot);
+        assert_eq!(deltas[0].changes.len(), 4);
+        // drifted past the deadband of the first voltage
+        assert!(!deltas[1].snapshot);
+        assert_eq!(changes(&deltas[1]), vec![("voltage", Some(12.56))]);
+        assert_eq!(changes(&deltas[2]), vec![("voltage", Some(12.5))]);
+        assert_eq!(changes(&deltas[3]), vec![("soc", None)]);
+        assert_eq!(deltas[3].data.soc, None);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let collector = Collector::default();
+        let mut filter = ChangeFilter::new(collector).with_snapshot_interval(3);
+        for _ in 0..7 {
+            filter.on_complete_block(battery(12.5, Some(80.0)));
+        }
+        filter.on_stale(Duration::from_secs(10));
+        filter.on_complete_block(battery(12.5, None));
+
+        let deltas = &filter.listener().blocks;
+        assert_eq!(deltas.len(), 4);
+        assert!(deltas.iter().all(|d| d.snapshot));
+        // blocks 0, 3 and 6, and the first block after the data stopped
+        assert_eq!(deltas[2].changes.len(), 4);
+        assert_eq!(changes(&deltas[3]).len(), 3);
+    }
+
+    #[test]
+    fn test_parser() {
+        let collector = Collector::<Delta<Frame>>::default();
+        let filter = ChangeFilter::new(collector).with_deadband("PPV", 10.0);
+        let mut parser = Parser::new(filter);
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let changed = MPPT_BLOCK
+            .replace("PPV\t5", "PPV\t9")
+            .replace("LOAD\tON", "LOAD\tOFF");
+        parser.feed(changed.as_bytes()).unwrap();
+
+        let deltas = &parser.listener().listener().blocks;
+        assert_eq!(deltas.len(), 2);
+        assert!(deltas[0].snapshot);
+        assert_eq!(deltas[0].data.get("SER#"), Some("HQ2132QY2KR"));
+        assert_eq!(
+            deltas[1].changes,
+            vec![Change {
+                field: "LOAD".into(),
+                value: Some(Value::Bool(false)),
+            }]
+        );
+    }
+}
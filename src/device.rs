@@ -0,0 +1,167 @@
+//! Detecting which kind of device sent a block
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{BlockWriter, Bmv700, Encode, Fields, Label, VEDirectData, VEError, MPPT};
+
+/// Device families with a typed data mapping
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceKind {
+    /// BMV 700 series battery monitors and SmartShunts
+    Bmv700,
+    /// BlueSolar and SmartSolar MPPT solar charge controllers
+    Mppt,
+}
+
+impl DeviceKind {
+    /// Short name, as used to tag serialized data, e.g. `mppt`
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceKind::Bmv700 => "bmv700",
+            DeviceKind::Mppt => "mppt",
+        }
+    }
+
+    /// Device family of a product ID, as sent in the `PID` field
+    pub fn from_pid(pid: u16) -> Option<DeviceKind> {
+        match pid {
+            0x0203..=0x0205 | 0xA380..=0xA3BF => Some(DeviceKind::Bmv700),
+            0x0300 | 0xA040..=0xA1FF => Some(DeviceKind::Mppt),
+            _ => None,
+        }
+    }
+
+    /// Detect the device family of a block from its product ID. Blocks
+    /// with an unknown product ID are detected from fields which are
+    /// only sent by one family.
+    pub fn detect(fields: &Fields<'_>) -> Option<DeviceKind> {
+        let by_pid = fields
+            .get_label(Label::PID)
+            .and_then(|raw| core::str::from_utf8(raw).ok())
+            .and_then(|pid| {
+                let hex = pid.strip_prefix("0x").or_else(|| pid.strip_prefix("0X"))?;
+                u16::from_str_radix(hex, 16).ok()
+            })
+            .and_then(DeviceKind::from_pid);
+        by_pid.or_else(|| {
+            if fields.contains_key(Label::VPV) {
+                Some(DeviceKind::Mppt)
+            } else if fields.contains_key(Label::SOC) || fields.contains_key(Label::BMV) {
+                Some(DeviceKind::Bmv700)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Data of any device with a typed mapping, for when the kind of
+/// device connected is not known in advance
+///
+/// ```rust
+/// use vedirect::{Device, Events, Parser};
+///
+/// struct Listener;
+///
+/// impl Events<Device> for Listener {
+///     fn on_complete_block(&mut self, block: Device) {
+///         if let Device::Mppt(mppt) = block {
+///             println!("Panel power {} W", mppt.panel_power);
+///         }
+///     }
+/// }
+///
+/// let mut parser = Parser::new(Listener);
+/// parser.feed(b"\r\nPID\t0xA053\r\nV\t12540\r\nChecksum\t?").unwrap();
+/// ```
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Device {
+    Bmv700(Bmv700),
+    Mppt(MPPT),
+}
+
+impl Device {
+    pub fn kind(&self) -> DeviceKind {
+        match self {
+            Device::Bmv700(_) => DeviceKind::Bmv700,
+            Device::Mppt(_) => DeviceKind::Mppt,
+        }
+    }
+
+    /// Product ID as sent in the `PID` field, e.g. `0xA053`, if the
+    /// mapped data keeps it
+    pub fn product_id(&self) -> Option<&str> {
+        match self {
+            Device::Bmv700(_) => None,
+            Device::Mppt(mppt) => Some(&mppt.product_id),
+        }
+    }
+
+    /// Serial number, if the device sends it in its main block
+    pub fn serial_number(&self) -> Option<&str> {
+        match self {
+            Device::Bmv700(_) => None,
+            Device::Mppt(mppt) => Some(&mppt.serial_number),
+        }
+    }
+}
+
+impl VEDirectData for Device {
+    fn fill(fields: &Fields<'_>) -> Result<Self, VEError> {
+        match DeviceKind::detect(fields) {
+            Some(DeviceKind::Bmv700) => Bmv700::fill(fields).map(Device::Bmv700),
+            Some(DeviceKind::Mppt) => MPPT::fill(fields).map(Device::Mppt),
+            // e.g. the history block of a BMV, which carries no PID
+            None => Err(VEError::MissingField(text!("{}", Label::PID))),
+        }
+    }
+}
+
+impl Encode for Device {
+    fn encode_fields(&self, block: &mut BlockWriter<'_>) -> Result<(), VEError> {
+        match self {
+            Device::Bmv700(bmv) => bmv.encode_fields(block),
+            Device::Mppt(mppt) => mppt.encode_fields(block),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Collector, MPPT_BLOCK};
+    use crate::Parser;
+
+    #[test]
+    fn test_from_pid() {
+        assert_eq!(DeviceKind::from_pid(0xA053), Some(DeviceKind::Mppt));
+        assert_eq!(DeviceKind::from_pid(0x0300), Some(DeviceKind::Mppt));
+        assert_eq!(DeviceKind::from_pid(0x0203), Some(DeviceKind::Bmv700));
+        assert_eq!(DeviceKind::from_pid(0xA381), Some(DeviceKind::Bmv700));
+        assert_eq!(DeviceKind::from_pid(0xA201), None);
+    }
+
+    #[test]
+    fn test_detect() {
+        // no PID, detected from the SOC field
+        let bmv = "\r\nP\t123\r\nCE\t53\r\nSOC\t452\r\nTTG\t60\r\nV\t232\r\nChecksum\t?";
+        let history = "\r\nH1\t-1234\r\nH2\t0\r\nChecksum\t?";
+
+        let mut collector = Collector::<Device>::default();
+        let mut parser = Parser::new(&mut collector);
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(bmv.as_bytes()).unwrap();
+        parser.feed(history.as_bytes()).unwrap();
+
+        assert_eq!(collector.blocks.len(), 2);
+        assert_eq!(collector.blocks[0].kind(), DeviceKind::Mppt);
+        assert!(matches!(&collector.blocks[1], Device::Bmv700(b) if b.power == 123));
+        assert_eq!(collector.missing_fields, 1);
+    }
+}
@@ -0,0 +1,295 @@
+//! Readers for embedded serial interfaces, enabled with the
+//! `embedded-io` and `embedded-hal-nb` features.
+//!
+//! Both readers pull bytes from the serial interface one at a time
+//! and feed them to a [`Parser`], returning mapped blocks. The parser
+//! buffer only needs to hold the labels and values of one block, so
+//! when reading a single known device it can be reduced below the
+//! default (see [`Parser::with_buffer_size`]).
+
+use crate::{data::VEDirectData, Events, Parser, Text, VEError, DEFAULT_BUFFER_SIZE};
+
+/// Errors from the embedded readers
+#[derive(Debug)]
+pub enum ReadError<E> {
+    /// The serial interface returned an error
+    Serial(E),
+
+    /// The serial interface reached end of file
+    EndOfStream,
+
+    /// Received data could not be parsed or mapped. This is not fatal,
+    /// reading can continue with the next block.
+    Data(VEError),
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for ReadError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReadError::Serial(e) => write!(f, "serial error: {:?}", e),
+            ReadError::EndOfStream => write!(f, "end of stream"),
+            ReadError::Data(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Holds the event produced by the last byte fed to the parser. As
+/// bytes are fed one at a time, there is at most one.
+struct Slot<D> {
+    item: Option<Result<D, VEError>>,
+}
+
+impl<D: VEDirectData> Events<D> for Slot<D> {
+    fn on_complete_block(&mut self, block: D) {
+        self.item = Some(Ok(block));
+    }
+
+    // Expected when starting mid-block or on devices which split their
+    // data over several blocks
+    fn on_missing_field(&mut self, _label: Text) {}
+
+    fn on_mapping_error(&mut self, error: VEError) {
+        self.item = Some(Err(error));
+    }
+
+    fn on_parse_error(&mut self, error: VEError, _parse_buf: &[u8]) {
+        self.item = Some(Err(error));
+    }
+}
+
+/// Feed a single byte, returning the resulting block or error if any
+fn feed_byte<D: VEDirectData, const N: usize>(
+    parser: &mut Parser<D, Slot<D>, N>,
+    byte: u8,
+) -> Option<Result<D, VEError>> {
+    // NeedMoreData while searching for the first field is expected
+    let _ = parser.feed(&[byte]);
+    parser.listener_mut().item.take()
+}
+
+/// Reads blocks from a blocking [`embedded_io::Read`] implementation
+///
+/// ```rust
+/// use vedirect::{IoReader, MPPT};
+///
+/// let data: &[u8] = b"\r\nPID\t0xA053\r\nFW\t159\r\nSER#\tHQ2132QY2KR\r\nV\t12540\r\nI\t40\r\nVPV\t18540\r\nPPV\t5\r\nCS\t3\r\nMPPT\t2\r\nOR\t0x00000000\r\nERR\t0\r\nLOAD\tON\r\nIL\t300\r\nH19\t144\r\nH20\t1\r\nH21\t6\r\nH22\t4\r\nH23\t14\r\nHSDS\t16\r\nChecksum\t?";
+/// let mut reader = IoReader::<_, MPPT>::new(data);
+/// let mppt = reader.read_block().unwrap();
+/// assert_eq!(mppt.panel_power, 5);
+/// ```
+#[cfg(feature = "embedded-io")]
+pub struct IoReader<R, D: VEDirectData, const N: usize = DEFAULT_BUFFER_SIZE> {
+    reader: R,
+    parser: Parser<D, Slot<D>, N>,
+}
+
+#[cfg(feature = "embedded-io")]
+impl<R: embedded_io::Read, D: VEDirectData> IoReader<R, D> {
+    /// Create reader with the default parser buffer size
+    pub fn new(reader: R) -> Self {
+        IoReader::with_buffer_size(reader)
+    }
+}
+
+#[cfg(feature = "embedded-io")]
+impl<R: embedded_io::Read, D: VEDirectData, const N: usize> IoReader<R, D, N> {
+    /// Create reader with a parser buffer of `N` bytes
+    pub fn with_buffer_size(reader: R) -> Self {
+        IoReader {
+            reader,
+            parser: Parser::with_buffer_size(Slot { item: None }),
+        }
+    }
+
+    /// Block until the next block is received and mapped
+    pub fn read_block(&mut self) -> Result<D, ReadError<R::Error>> {
+        let mut byte = [0u8];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return Err(ReadError::EndOfStream),
+                Ok(_) => {}
+                Err(e) => return Err(ReadError::Serial(e)),
+            }
+            if let Some(item) = feed_byte(&mut self.parser, byte[0]) {
+                return item.map_err(ReadError::Data);
+            }
+        }
+    }
+
+    /// Consume the reader, returning the serial interface
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Reads blocks from a non-blocking [`embedded_hal_nb::serial::Read`]
+/// implementation, such as a microcontroller's UART
+///
+/// ```rust,ignore
+/// let mut reader = NbReader::<_, MPPT>::new(uart);
+/// loop {
+///     match nb::block!(reader.poll()) {
+///         Ok(mppt) => { /* ... */ }
+///         Err(e) => { /* ... */ }
+///     }
+/// }
+/// ```
+#[cfg(feature = "embedded-hal-nb")]
+pub struct NbReader<S, D: VEDirectData, const N: usize = DEFAULT_BUFFER_SIZE> {
+    serial: S,
+    parser: Parser<D, Slot<D>, N>,
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<S: embedded_hal_nb::serial::Read<u8>, D: VEDirectData> NbReader<S, D> {
+    /// Create reader with the default parser buffer size
+    pub fn new(serial: S) -> Self {
+        NbReader::with_buffer_size(serial)
+    }
+}
+
+#[cfg(feature = "embedded-hal-nb")]
+impl<S: embedded_hal_nb::serial::Read<u8>, D: VEDirectData, const N: usize> NbReader<S, D, N> {
+    /// Create reader with a parser buffer of `N` bytes
+    pub fn with_buffer_size(serial: S) -> Self {
+        NbReader {
+            serial,
+            parser: Parser::with_buffer_size(Slot { item: None }),
+        }
+    }
+
+    /// Read all currently available bytes, returning a block once one
+    /// is complete and `WouldBlock` if more data is needed
+    pub fn poll(&mut self) -> embedded_hal_nb::nb::Result<D, ReadError<S::Error>> {
+        use embedded_hal_nb::nb;
+
+        loop {
+            let byte = self.serial.read().map_err(|e| match e {
+                nb::Error::WouldBlock => nb::Error::WouldBlock,
+                nb::Error::Other(e) => nb::Error::Other(ReadError::Serial(e)),
+            })?;
+            if let Some(item) = feed_byte(&mut self.parser, byte) {
+                return item.map_err(|e| nb::Error::Other(ReadError::Data(e)));
+            }
+        }
+    }
+
+    /// Consume the reader, returning the serial interface
+    pub fn into_inner(self) -> S {
+        self.serial
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::MPPT;
+
+    /// Mock UART which has a byte available on every other read
+    struct MockSerial {
+        data: Vec<u8>,
+        pos: usize,
+        #[cfg(feature = "embedded-hal-nb")]
+        ready: bool,
+    }
+
+    impl MockSerial {
+        fn new(data: &str) -> Self {
+            MockSerial {
+                data: data.as_bytes().to_vec(),
+                pos: 0,
+                #[cfg(feature = "embedded-hal-nb")]
+                ready: false,
+            }
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::ErrorType for MockSerial {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::Read for MockSerial {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            // Like a UART, only ever return a few bytes at a time
+            let count = buf.len().min(3).min(self.data.len() - self.pos);
+            buf[..count].copy_from_slice(&self.data[self.pos..self.pos + count]);
+            self.pos += count;
+            Ok(count)
+        }
+    }
+
+    #[cfg(feature = "embedded-hal-nb")]
+    impl embedded_hal_nb::serial::ErrorType for MockSerial {
+        type Error = embedded_hal_nb::serial::ErrorKind;
+    }
+
+    #[cfg(feature = "embedded-hal-nb")]
+    impl embedded_hal_nb::serial::Read<u8> for MockSerial {
+        fn read(&mut self) -> embedded_hal_nb::nb::Result<u8, Self::Error> {
+            use embedded_hal_nb::nb;
+
+            self.ready = !self.ready;
+            if !self.ready || self.pos >= self.data.len() {
+                return Err(nb::Error::WouldBlock);
+            }
+            self.pos += 1;
+            Ok(self.data[self.pos - 1])
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn test_io_reader() {
+        let stream = format!(
+            "{}{}",
+            &MPPT_BLOCK[30..],
+            MPPT_BLOCK.replace("\r\nI\t40", "\r\nI\t110")
+        );
+        let mut reader = IoReader::<_, MPPT, 128>::with_buffer_size(MockSerial::new(&stream));
+
+        // The first block is incomplete, so the first mapped block is
+        // the second one
+        let block = reader.read_block().unwrap();
+        assert_eq!(block.battery_current, 0.11);
+        assert_eq!(block.serial_number, "HQ2132QY2KR");
+        assert!(matches!(reader.read_block(), Err(ReadError::EndOfStream)));
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn test_io_reader_mapping_error() {
+        let stream = MPPT_BLOCK.replace("LOAD\tON", "LOAD\tMAYBE");
+        let mut reader = IoReader::<_, MPPT>::new(MockSerial::new(&stream));
+        assert!(matches!(
+            reader.read_block(),
+            Err(ReadError::Data(VEError::OnOffExpected(_)))
+        ));
+    }
+
+    #[cfg(feature = "embedded-hal-nb")]
+    #[test]
+    fn test_nb_reader() {
+        use embedded_hal_nb::nb;
+
+        let stream = format!("{}{}", MPPT_BLOCK, MPPT_BLOCK);
+        let mut reader = NbReader::<_, MPPT, 128>::with_buffer_size(MockSerial::new(&stream));
+
+        let mut polls = 0;
+        let mut blocks = vec![];
+        while blocks.len() < 2 {
+            polls += 1;
+            match reader.poll() {
+                Ok(block) => blocks.push(block),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(e)) => panic!("unexpected error {}", e),
+            }
+            assert!(polls < 10_000);
+        }
+        assert!(polls > 2);
+        assert_eq!(blocks[1].panel_voltage, 18.54);
+        assert!(matches!(reader.poll(), Err(nb::Error::WouldBlock)));
+    }
+}
@@ -0,0 +1,139 @@
+//! Encoding data back to Text mode blocks
+
+use core::fmt::{self, Write};
+
+use crate::{Label, VEError};
+
+/// Types which can be written as a Text mode block, the counterpart of
+/// [`VEDirectData::fill`](crate::VEDirectData::fill). Values are scaled
+/// as `fill` expects them, so parsing an encoded block gives back the
+/// same data.
+pub trait Encode {
+    /// Write the fields of the block, in the order devices send them
+    fn encode_fields(&self, block: &mut BlockWriter<'_>) -> Result<(), VEError>;
+
+    /// Encode a complete block, including its checksum, into `buf`,
+    /// returning the number of bytes written
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, VEError> {
+        let mut block = BlockWriter::new(buf);
+        self.encode_fields(&mut block)?;
+        block.finish()
+    }
+
+    /// Encode a complete block, including its checksum
+    #[cfg(feature = "std")]
+    fn to_block(&self) -> Result<Vec<u8>, VEError> {
+        let mut buf = [0u8; crate::DEFAULT_BUFFER_SIZE];
+        let len = self.encode(&mut buf)?;
+        Ok(buf[..len].to_vec())
+    }
+}
+
+impl<T: Encode> Encode for &T {
+    fn encode_fields(&self, block: &mut BlockWriter<'_>) -> Result<(), VEError> {
+        (**self).encode_fields(block)
+    }
+}
+
+/// Writes the fields of a block to a buffer, followed by the checksum
+/// making all bytes of the block add up to 0 modulo 256
+pub struct BlockWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> BlockWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        BlockWriter { buf, len: 0 }
+    }
+
+    /// Write a field. Values should not contain tabs or line breaks.
+    pub fn field(&mut self, label: Label, value: impl fmt::Display) -> Result<(), VEError> {
+        self.field_str(label.as_str(), value)
+    }
+
+    /// Write a field whose label is not defined by [`Label`]
+    pub fn field_str(&mut self, label: &str, value: impl fmt::Display) -> Result<(), VEError> {
+        write!(self, "\r\n{}\t{}", label, value).map_err(|_| VEError::CapacityExceeded)
+    }
+
+    /// Write the checksum field, returning the length of the block
+    pub fn finish(mut self) -> Result<usize, VEError> {
+        self.write_str("\r\nChecksum\t")
+            .map_err(|_| VEError::CapacityExceeded)?;
+        let sum = self.buf[..self.len]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let checksum = self
+            .buf
+            .get_mut(self.len)
+            .ok_or(VEError::CapacityExceeded)?;
+        *checksum = 0u8.wrapping_sub(sum);
+        Ok(self.len + 1)
+    }
+}
+
+impl Write for BlockWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// `value * factor`, rounded half away from zero, for values sent as
+/// integers in a smaller unit
+pub(crate) fn scale(value: f32, factor: f32) -> i64 {
+    let raw = value * factor;
+    // without `f32::round`, which needs std
+    if raw < 0.0 {
+        (raw - 0.5) as i64
+    } else {
+        (raw + 0.5) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Pair;
+
+    impl Encode for Pair {
+        fn encode_fields(&self, block: &mut BlockWriter<'_>) -> Result<(), VEError> {
+            block.field(Label::V, 12540)?;
+            block.field_str("XYZ", "ON")
+        }
+    }
+
+    #[test]
+    fn test_block_writer() {
+        let mut buf = [0u8; 64];
+        let len = Pair.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..len - 1], b"\r\nV\t12540\r\nXYZ\tON\r\nChecksum\t");
+        let sum = buf[..len].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        assert_eq!(sum, 0);
+
+        // too small for the checksum byte, or for the fields
+        assert!(matches!(
+            Pair.encode(&mut buf[..len - 1]),
+            Err(VEError::CapacityExceeded)
+        ));
+        assert!(matches!(
+            Pair.encode(&mut buf[..8]),
+            Err(VEError::CapacityExceeded)
+        ));
+    }
+
+    #[test]
+    fn test_scale() {
+        assert_eq!(scale(12.54, 1000.0), 12540);
+        assert_eq!(scale(-0.04, 1000.0), -40);
+        assert_eq!(scale(-1.25, 10.0), -13);
+        assert_eq!(scale(0.0, 1000.0), 0);
+    }
+}
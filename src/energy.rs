@@ -0,0 +1,354 @@
+//! Energy and charge integrated from live readings.
+//!
+//! Devices report their yield in 0.01 kWh, too coarse for small
+//! panels. An [`Integrator`] accumulates the readings of one device
+//! between timestamped blocks (see [`clock`](crate::clock)) instead,
+//! using the average of consecutive readings:
+//!
+//! | counter         | from                                          |
+//! |-----------------|-----------------------------------------------|
+//! | `solar_wh`      | panel power `PPV` of a solar charger          |
+//! | `charged_ah`    | battery current `I` into the battery          |
+//! | `discharged_ah` | battery current `I` out of the battery        |
+//! | `load_wh`       | load current `IL` × battery voltage `V`       |
+//!
+//! Battery monitors do not map their current, so it is computed from
+//! their power `P` and voltage `V`. When the battery current changes
+//! direction between two readings, the interval is split where it
+//! crosses zero, so that charge and discharge do not cancel out.
+//!
+//! Readings further apart than the maximum gap, as when a cable was
+//! unplugged, are not integrated. The counters of today are moved to
+//! yesterday when a solar charger's day sequence number `HSDS`
+//! changes, or when [`Integrator::roll_over`] is called, as battery
+//! monitors have no such number.
+//!
+//! ```rust
+//! use vedirect::clock::{SystemClock, Timestamper};
+//! use vedirect::energy::Integrator;
+//! use vedirect::{Parser, MPPT};
+//!
+//! let mut parser = Parser::<MPPT, _>::new(Timestamper::new(SystemClock, Integrator::new()));
+//! # let data = [];
+//! parser.feed(&data).ok();
+//! let integrator = parser.listener().listener();
+//! println!("{:.1} Wh today", integrator.today().solar_wh);
+//! ```
+
+use core::time::Duration;
+
+use crate::{
+    clock::{Timestamp, Timestamped},
+    Bmv700, Device, Events, MPPT,
+};
+
+/// Default longest time between two readings which are integrated
+pub const DEFAULT_MAX_GAP: Duration = Duration::from_secs(60);
+
+/// Accumulated energy and charge, see the
+/// [module documentation](self)
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub struct Counters {
+    pub solar_wh: f64,
+    pub charged_ah: f64,
+    pub discharged_ah: f64,
+    pub load_wh: f64,
+}
+
+impl Counters {
+    fn add(&mut self, other: &Counters) {
+        self.solar_wh += other.solar_wh;
+        self.charged_ah += other.charged_ah;
+        self.discharged_ah += other.discharged_ah;
+        self.load_wh += other.load_wh;
+    }
+}
+
+/// Readings integrated between blocks
+#[derive(Clone, Copy, Debug)]
+struct Reading<T> {
+    at: T,
+    solar_w: f64,
+    battery_a: f64,
+    load_w: f64,
+}
+
+impl<T> Reading<T> {
+    fn mppt(mppt: &MPPT, at: T) -> Self {
+        Reading {
+            at,
+            solar_w: f64::from(mppt.panel_power),
+            battery_a: f64::from(mppt.battery_current),
+            load_w: f64::from(mppt.load_current) * f64::from(mppt.channel1_voltage),
+        }
+    }
+
+    fn bmv700(bmv: &Bmv700, at: T) -> Self {
+        let battery_a = match bmv.voltage {
+            v if v > 0.0 => f64::from(bmv.power) / f64::from(v),
+            _ => 0.0,
+        };
+        Reading {
+            at,
+            solar_w: 0.0,
+            battery_a,
+            load_w: 0.0,
+        }
+    }
+}
+
+/// Integrates the readings of one device, see the
+/// [module documentation](self)
+#[derive(Clone, Debug)]
+pub struct Integrator<T> {
+    max_gap: Duration,
+    last: Option<Reading<T>>,
+    day_sequence: Option<u16>,
+    today: Counters,
+    yesterday: Option<Counters>,
+    total: Counters,
+}
+
+impl<T: Timestamp> Default for Integrator<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Timestamp> Integrator<T> {
+    pub fn new() -> Self {
+        Integrator {
+            max_gap: DEFAULT_MAX_GAP,
+            last: None,
+            day_sequence: None,
+            today: Counters::default(),
+            yesterday: None,
+            total: Counters::default(),
+        }
+    }
+
+    /// Do not integrate between readings further apart than `max_gap`
+    pub fn with_max_gap(mut self, max_gap: Duration) -> Self {
+        self.max_gap = max_gap;
+        self
+    }
+
+    pub fn add_mppt(&mut self, block: &Timestamped<MPPT, T>) {
+        let day_sequence = block.data.day_sequence;
+        if self.day_sequence.is_some_and(|day| day != day_sequence) {
+            self.roll_over();
+        }
+        self.day_sequence = Some(day_sequence);
+        self.add(Reading::mppt(&block.data, block.received));
+    }
+
+    pub fn add_bmv700(&mut self, block: &Timestamped<Bmv700, T>) {
+        self.add(Reading::bmv700(&block.data, block.received));
+    }
+
+    pub fn add_device(&mut self, block: &Timestamped<Device, T>) {
+        match &block.data {
+            Device::Mppt(mppt) => self.add_mppt(&Timestamped {
+                received: block.received,
+                data: mppt.clone(),
+            }),
+            Device::Bmv700(bmv) => self.add(Reading::bmv700(bmv, block.received)),
+        }
+    }
+
+    fn add(&mut self, reading: Reading<T>) {
+        let last = self.last.replace(reading);
+        let Some(last) = last else { return };
+        let Some(elapsed) = reading.at.duration_since(last.at) else {
+            return;
+        };
+        if elapsed > self.max_gap {
+            return;
+        }
+        let hours = elapsed.as_secs_f64() / 3600.0;
+        let average = |a: f64, b: f64| (a + b) / 2.0 * hours;
+        let mut interval = Counters {
+            solar_wh: average(last.solar_w, reading.solar_w),
+            load_wh: average(last.load_w, reading.load_w),
+            ..Counters::default()
+        };
+        let (charged, discharged) = split_at_zero(last.battery_a, reading.battery_a);
+        interval.charged_ah = charged * hours;
+        interval.discharged_ah = discharged * hours;
+        self.today.add(&interval);
+        self.total.add(&interval);
+    }
+
+    /// Counters since the start of the day
+    pub fn today(&self) -> &Counters {
+        &self.today
+    }
+
+    /// Counters of the previous day, once a day was rolled over
+    pub fn yesterday(&self) -> Option<&Counters> {
+        self.yesterday.as_ref()
+    }
+
+    /// Counters since the integrator was created or reset
+    pub fn total(&self) -> &Counters {
+        &self.total
+    }
+
+    /// Start a new day, moving the counters of today to yesterday
+    pub fn roll_over(&mut self) {
+        self.yesterday = Some(core::mem::take(&mut self.today));
+    }
+
+    pub fn reset_today(&mut self) {
+        self.today = Counters::default();
+    }
+
+    pub fn reset_total(&mut self) {
+        self.total = Counters::default();
+    }
+}
+
+/// Averages of the positive part and of the negative part, negated, of
+/// a value changing linearly from `a` to `b`
+fn split_at_zero(a: f64, b: f64) -> (f64, f64) {
+    if a >= 0.0 && b >= 0.0 {
+        ((a + b) / 2.0, 0.0)
+    } else if a <= 0.0 && b <= 0.0 {
+        (0.0, -(a + b) / 2.0)
+    } else {
+        // triangles on both sides of the zero crossing
+        let crossing = a / (a - b);
+        let (first, second) = (a * crossing / 2.0, b * (1.0 - crossing) / 2.0);
+        (first.max(second), -first.min(second))
+    }
+}
+
+impl<T: Timestamp> Events<Timestamped<MPPT, T>> for Integrator<T> {
+    fn on_complete_block(&mut self, block: Timestamped<MPPT, T>) {
+        self.add_mppt(&block)
+    }
+}
+
+impl<T: Timestamp> Events<Timestamped<Bmv700, T>> for Integrator<T> {
+    fn on_complete_block(&mut self, block: Timestamped<Bmv700, T>) {
+        self.add_bmv700(&block)
+    }
+}
+
+impl<T: Timestamp> Events<Timestamped<Device, T>> for Integrator<T> {
+    fn on_complete_block(&mut self, block: Timestamped<Device, T>) {
+        self.add_device(&block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{parse, MPPT_BLOCK};
+    use crate::{clock::Timestamper, Parser};
+
+    fn mppt(panel_power: i32, battery_current: f32, day_sequence: u16) -> MPPT {
+        let mut mppt: MPPT = parse(MPPT_BLOCK);
+        mppt.panel_power = panel_power;
+        mppt.battery_current = battery_current;
+        mppt.day_sequence = day_sequence;
+        mppt
+    }
+
+    fn at<D>(seconds: u64, data: D) -> Timestamped<D, Duration> {
+        Timestamped {
+            received: Duration::from_secs(seconds),
+            data,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        // readings are `f32`
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_integrate() {
+        let mut integrator = Integrator::new();
+        integrator.add_mppt(&at(0, mppt(100, 5.0, 16)));
+        assert_eq!(integrator.today(), &Counters::default());
+        integrator.add_mppt(&at(60, mppt(200, -1.0, 16)));
+
+        let today = *integrator.today();
+        assert_close(today.solar_wh, 150.0 / 60.0);
+        // from 5 A to -1 A, crossing zero after 50 s
+        assert_close(today.charged_ah, 5.0 * 50.0 / 2.0 / 3600.0);
+        assert_close(today.discharged_ah, 1.0 * 10.0 / 2.0 / 3600.0);
+        // 0.3 A at 12.54 V
+        assert_close(today.load_wh, 0.3 * 12.54 / 60.0);
+
+        // not integrated across a gap
+        integrator.add_mppt(&at(200, mppt(200, -1.0, 16)));
+        assert_close(integrator.today().solar_wh, today.solar_wh);
+        integrator.add_mppt(&at(236, mppt(200, -1.0, 16)));
+        assert_close(integrator.today().solar_wh, today.solar_wh + 2.0);
+        assert_close(integrator.today().discharged_ah, today.discharged_ah + 0.01);
+        assert_eq!(integrator.total(), integrator.today());
+    }
+
+    #[test]
+    fn test_roll_over() {
+        let mut integrator = Integrator::new();
+        integrator.add_mppt(&at(0, mppt(360, 0.0, 16)));
+        integrator.add_mppt(&at(10, mppt(360, 0.0, 16)));
+        assert!(integrator.yesterday().is_none());
+
+        // the charger started a new day
+        integrator.add_mppt(&at(20, mppt(360, 0.0, 17)));
+        assert_close(integrator.yesterday().unwrap().solar_wh, 1.0);
+        assert_close(integrator.today().solar_wh, 1.0);
+        assert_close(integrator.total().solar_wh, 2.0);
+
+        integrator.roll_over();
+        assert_close(integrator.yesterday().unwrap().solar_wh, 1.0);
+        assert_eq!(integrator.today(), &Counters::default());
+        integrator.reset_total();
+        assert_eq!(integrator.total(), &Counters::default());
+    }
+
+    #[test]
+    fn test_bmv700() {
+        // discharging at 10 A
+        let bmv = Bmv700 {
+            voltage: 12.0,
+            power: -120,
+            consumed: None,
+            soc: None,
+            ttg: -1,
+        };
+        let mut integrator = Integrator::new();
+        integrator.add_bmv700(&at(0, bmv.clone()));
+        integrator.add_device(&at(36, Device::Bmv700(bmv)));
+        let today = integrator.today();
+        assert_close(today.discharged_ah, 0.1);
+        assert_close(today.charged_ah, 0.0);
+        assert_close(today.solar_wh, 0.0);
+    }
+
+    #[test]
+    fn test_parser() {
+        let mut seconds = 0;
+        let clock = move || {
+            seconds += 36;
+            Duration::from_secs(seconds)
+        };
+        let mut parser = Parser::<Device, _>::new(Timestamper::new(clock, Integrator::new()));
+        for _ in 0..3 {
+            parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        }
+        // 5 W and 0.04 A for 72 s
+        let today = parser.listener().listener().today();
+        assert_close(today.solar_wh, 0.1);
+        assert_close(today.charged_ah, 0.0008);
+    }
+}
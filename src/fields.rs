@@ -0,0 +1,262 @@
+//! Field labels, and the raw fields of a received block
+
+use core::{fmt, str::FromStr};
+
+use strum_macros::{EnumString, IntoStaticStr};
+
+/// Maximum number of distinct fields in one block. The protocol
+/// specification limits blocks to 22 fields, so this leaves some
+/// headroom for devices with newer firmware.
+pub const MAX_FIELDS: usize = 32;
+
+/// Maximum length of a field label. The specification allows labels
+/// of up to 9 characters.
+pub const MAX_LABEL_LEN: usize = 16;
+
+/// Maximum length of a field value. The specification allows values
+/// of up to 33 characters.
+pub const MAX_VALUE_LEN: usize = 48;
+
+/// Field labels defined by the protocol specification. Labels are
+/// interned to this enum as they are received, so mapping a block to
+/// typed data does not involve string comparisons.
+#[derive(EnumString, IntoStaticStr, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Label {
+    V,
+    V2,
+    V3,
+    VS,
+    VM,
+    DM,
+    VPV,
+    PPV,
+    I,
+    I2,
+    I3,
+    IL,
+    LOAD,
+    T,
+    P,
+    CE,
+    SOC,
+    TTG,
+    Alarm,
+    Relay,
+    AR,
+    OR,
+    H1,
+    H2,
+    H3,
+    H4,
+    H5,
+    H6,
+    H7,
+    H8,
+    H9,
+    H10,
+    H11,
+    H12,
+    H13,
+    H14,
+    H15,
+    H16,
+    H17,
+    H18,
+    H19,
+    H20,
+    H21,
+    H22,
+    H23,
+    ERR,
+    CS,
+    BMV,
+    FW,
+    FWE,
+    PID,
+    #[strum(serialize = "SER#")]
+    SerialNumber,
+    HSDS,
+    MODE,
+    #[strum(serialize = "AC_OUT_V")]
+    AcOutV,
+    #[strum(serialize = "AC_OUT_I")]
+    AcOutI,
+    #[strum(serialize = "AC_OUT_S")]
+    AcOutS,
+    WARN,
+    MPPT,
+    MON,
+    #[strum(serialize = "DC_IN_V")]
+    DcInV,
+    #[strum(serialize = "DC_IN_I")]
+    DcInI,
+    #[strum(serialize = "DC_IN_P")]
+    DcInP,
+    Checksum,
+    /// Any label not defined by the specification
+    #[strum(disabled)]
+    Other,
+}
+
+impl Label {
+    /// Intern a received label, returning [`Label::Other`] if it is
+    /// not known
+    pub fn from_bytes(label: &[u8]) -> Label {
+        core::str::from_utf8(label)
+            .ok()
+            .and_then(|s| Label::from_str(s).ok())
+            .unwrap_or(Label::Other)
+    }
+
+    /// The label as sent by the device. [`Label::Other`] has no fixed
+    /// text, and is returned as `"?"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Label::Other => "?",
+            known => known.into(),
+        }
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Location of a received field in the parser's buffer
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FieldRef {
+    pub(crate) label: Label,
+    pub(crate) label_start: u16,
+    pub(crate) value_start: u16,
+    pub(crate) value_end: u16,
+}
+
+/// Raw label/value pairs of a block as received from the device,
+/// which [`VEDirectData`](crate::VEDirectData) implementations map to
+/// typed data. Labels and values are borrowed from the parser's
+/// buffer.
+#[derive(Clone, Copy)]
+pub struct Fields<'b> {
+    buf: &'b [u8],
+    entries: &'b [FieldRef],
+}
+
+impl<'b> Fields<'b> {
+    pub(crate) fn new(buf: &'b [u8], entries: &'b [FieldRef]) -> Self {
+        Fields { buf, entries }
+    }
+
+    /// Raw value of a known field, if it was received. If a label
+    /// occurs more than once in a block, the last value is returned.
+    pub fn get_label(&self, label: Label) -> Option<&'b [u8]> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|f| f.label == label)
+            .map(|f| &self.buf[f.value_start as usize..f.value_end as usize])
+    }
+
+    /// Raw value of the field `label`, if it was received. Also works
+    /// for labels which are not known to [`Label`].
+    pub fn get(&self, label: &str) -> Option<&'b [u8]> {
+        match Label::from_bytes(label.as_bytes()) {
+            Label::Other => self
+                .iter()
+                .filter(|(l, _)| *l == label.as_bytes())
+                .last()
+                .map(|(_, v)| v),
+            known => self.get_label(known),
+        }
+    }
+
+    pub fn contains_key(&self, label: Label) -> bool {
+        self.get_label(label).is_some()
+    }
+
+    /// Iterate over the raw label/value pairs in the order they were
+    /// received
+    pub fn iter(&self) -> impl Iterator<Item = (&'b [u8], &'b [u8])> + 'b {
+        let buf = self.buf;
+        self.entries.iter().map(move |f| {
+            (
+                &buf[f.label_start as usize..f.value_start as usize],
+                &buf[f.value_start as usize..f.value_end as usize],
+            )
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl fmt::Debug for Fields<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.iter().map(|(l, v)| {
+                (
+                    core::str::from_utf8(l).unwrap_or("?"),
+                    core::str::from_utf8(v).unwrap_or("?"),
+                )
+            }))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label() {
+        assert_eq!(Label::from_bytes(b"V"), Label::V);
+        assert_eq!(Label::from_bytes(b"SER#"), Label::SerialNumber);
+        assert_eq!(Label::from_bytes(b"AC_OUT_V"), Label::AcOutV);
+        assert_eq!(Label::from_bytes(b"Other"), Label::Other);
+        assert_eq!(Label::from_bytes(b"XYZ"), Label::Other);
+        assert_eq!(Label::SerialNumber.as_str(), "SER#");
+        assert_eq!(Label::H19.to_string(), "H19");
+    }
+
+    #[test]
+    fn test_get() {
+        let buf = b"V12540XYZ1V12541";
+        let entries = [
+            FieldRef {
+                label: Label::V,
+                label_start: 0,
+                value_start: 1,
+                value_end: 6,
+            },
+            FieldRef {
+                label: Label::Other,
+                label_start: 6,
+                value_start: 9,
+                value_end: 10,
+            },
+            FieldRef {
+                label: Label::V,
+                label_start: 10,
+                value_start: 11,
+                value_end: 16,
+            },
+        ];
+        let fields = Fields::new(buf, &entries);
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields.get_label(Label::V), Some(&b"12541"[..]));
+        assert_eq!(fields.get("V"), Some(&b"12541"[..]));
+        assert_eq!(fields.get("XYZ"), Some(&b"1"[..]));
+        assert_eq!(fields.get("I"), None);
+        assert!(!fields.contains_key(Label::I));
+        assert_eq!(
+            fields.iter().map(|(l, _)| l).collect::<Vec<_>>(),
+            vec![&b"V"[..], b"XYZ", b"V"]
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Data and listeners shared by the tests. The tests of
+//! `vedirect-daemon` include this file as well, so the crate is named
+//! `vedirect` here.
+
+use core::time::Duration;
+
+use vedirect::{Events, HexFrame, Parser, Text, VEDirectData, VEError};
+
+/// A block of a SmartSolar MPPT 100|20, bulk charging
+pub const MPPT_BLOCK: &str = "\r\nPID\t0xA053\r\nFW\t159\r\nSER#\tHQ2132QY2KR\r\nV\t12540\r\nI\t40\r\nVPV\t18540\r\nPPV\t5\r\nCS\t3\r\nMPPT\t2\r\nOR\t0x00000000\r\nERR\t0\r\nLOAD\tON\r\nIL\t300\r\nH19\t144\r\nH20\t1\r\nH21\t6\r\nH22\t4\r\nH23\t14\r\nHSDS\t16\r\nChecksum\t?";
+
+/// Keeps the last block received
+pub struct Last<D>(pub Option<D>);
+
+impl<D> Events<D> for Last<D> {
+    fn on_complete_block(&mut self, block: D) {
+        self.0 = Some(block);
+    }
+}
+
+/// Keeps all blocks and HEX messages received, in order, and counts
+/// the errors
+pub struct Collector<D> {
+    pub blocks: Vec<D>,
+    pub hex: Vec<HexFrame>,
+    pub stale: Vec<Duration>,
+    pub missing_fields: usize,
+    pub parse_errors: usize,
+}
+
+impl<D> Default for Collector<D> {
+    fn default() -> Self {
+        Collector {
+            blocks: vec![],
+            hex: vec![],
+            stale: vec![],
+            missing_fields: 0,
+            parse_errors: 0,
+        }
+    }
+}
+
+impl<D> Events<D> for Collector<D> {
+    fn on_complete_block(&mut self, block: D) {
+        self.blocks.push(block);
+    }
+
+    fn on_missing_field(&mut self, _label: Text) {
+        self.missing_fields += 1;
+    }
+
+    fn on_parse_error(&mut self, _error: VEError, _parse_buf: &[u8]) {
+        self.parse_errors += 1;
+    }
+
+    fn on_hex_frame(&mut self, frame: HexFrame) {
+        self.hex.push(frame);
+    }
+
+    fn on_stale(&mut self, elapsed: Duration) {
+        self.stale.push(elapsed);
+    }
+}
+
+/// The last block of `data`, mapped to `D`
+pub fn parse<D: VEDirectData>(data: impl AsRef<[u8]>) -> D {
+    let mut parser = Parser::new(Last(None));
+    parser.feed(data.as_ref()).unwrap();
+    parser.into_listener().0.expect("no block mapped")
+}
@@ -0,0 +1,86 @@
+//! Blocks as received, without mapping to a device type
+
+use crate::{BlockWriter, Encode, Fields, VEDirectData, VEError};
+
+/// A block with its labels and values copied to owned strings, in the
+/// order they were received. Mapping never fails, so this can be used
+/// to display or record data from any device.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Frame {
+    pub fields: Vec<(String, String)>,
+}
+
+impl Frame {
+    /// Value of the field `label`. If a label occurs more than once,
+    /// the last value is returned.
+    pub fn get(&self, label: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .rev()
+            .find(|(l, _)| l == label)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Iterate over the labels and values
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(l, v)| (l.as_str(), v.as_str()))
+    }
+}
+
+impl VEDirectData for Frame {
+    fn fill(fields: &Fields<'_>) -> Result<Self, VEError> {
+        Ok(Frame {
+            fields: fields
+                .iter()
+                .map(|(l, v)| {
+                    (
+                        String::from_utf8_lossy(l).into_owned(),
+                        String::from_utf8_lossy(v).into_owned(),
+                    )
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Encode for Frame {
+    fn encode_fields(&self, block: &mut BlockWriter<'_>) -> Result<(), VEError> {
+        // a frame built by hand may have kept the checksum field
+        self.iter()
+            .filter(|(label, _)| *label != "Checksum")
+            .try_for_each(|(label, value)| block.field_str(label, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Collector;
+    use crate::Parser;
+
+    #[test]
+    fn test_frame() {
+        let mut parser = Parser::new(Collector::default());
+        parser
+            .feed(
+                b"\r\nPID\t0xA053\r\nXYZ\t\xff1\r\nV\t12540\r\nChecksum\t?\r\nV\t1\r\nChecksum\t?",
+            )
+            .unwrap();
+
+        let frames: Vec<Frame> = parser.into_listener().blocks;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("PID"), Some("0xA053"));
+        assert_eq!(frames[0].get("XYZ"), Some("\u{fffd}1"));
+        assert_eq!(
+            frames[0].iter().map(|(l, _)| l).collect::<Vec<_>>(),
+            vec!["PID", "XYZ", "V"]
+        );
+        assert_eq!(frames[1].get("V"), Some("1"));
+        assert_eq!(frames[1].get("PID"), None);
+
+        assert_eq!(
+            &frames[1].to_block().unwrap()[..],
+            b"\r\nV\t1\r\nChecksum\t\x06"
+        );
+    }
+}
@@ -0,0 +1,641 @@
+//! HEX mode messages, used to read and write device registers
+//!
+//! A HEX message is a line starting with `:`, followed by a command
+//! nibble, the data bytes and a checksum byte, all hex encoded, and
+//! ends with a newline. The command nibble, data and checksum add up
+//! to `0x55`. Devices answer HEX commands sent to them, and also send
+//! asynchronous messages in between Text mode blocks.
+//!
+//! The [`Parser`](crate::Parser) decodes HEX messages it receives and
+//! passes them to [`Events::on_hex_frame`](crate::Events::on_hex_frame).
+//!
+//! ```rust
+//! use vedirect::hex::{HexFrame, Response, register};
+//!
+//! // read the absorption voltage
+//! let request = HexFrame::get(register::ABSORPTION_VOLTAGE.id);
+//! assert_eq!(request.to_string(), ":7F7ED006A\n");
+//!
+//! let reply = HexFrame::decode(b":7F7ED00A005C5").unwrap();
+//! match reply.response().unwrap() {
+//!     Response::Get { register, flags: 0, value } => {
+//!         assert_eq!(register, 0xEDF7);
+//!         assert_eq!(register::ABSORPTION_VOLTAGE.decode(value), Some(14.4));
+//!     }
+//!     other => panic!("unexpected {:?}", other),
+//! }
+//! ```
+
+use core::fmt;
+
+use crate::VEError;
+
+/// Maximum number of data bytes in a HEX message. The largest
+/// messages are the MPPT day history records, with 34 bytes.
+pub const MAX_HEX_DATA: usize = 64;
+
+/// Maximum length of a HEX message line, excluding the `:` and the
+/// line ending
+pub(crate) const MAX_HEX_LINE: usize = 1 + 2 * (MAX_HEX_DATA + 1);
+
+/// Commands sent to a device
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    Ping = 0x1,
+    AppVersion = 0x3,
+    ProductId = 0x4,
+    Restart = 0x6,
+    Get = 0x7,
+    Set = 0x8,
+}
+
+/// Flags in Get and Set responses
+pub mod flags {
+    pub const UNKNOWN_ID: u8 = 0x01;
+    pub const NOT_SUPPORTED: u8 = 0x02;
+    pub const PARAMETER_ERROR: u8 = 0x04;
+}
+
+/// A decoded HEX message
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct HexFrame {
+    pub command: u8,
+    pub data: heapless::Vec<u8, MAX_HEX_DATA>,
+}
+
+/// Meaning of a HEX message received from a device
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Response<'a> {
+    /// Answer to [`Command::AppVersion`] and [`Command::ProductId`]
+    Done(&'a [u8]),
+    /// The device did not recognise the command
+    Unknown,
+    /// The device could not process the command, e.g. a malformed
+    /// message
+    Error,
+    /// Answer to [`Command::Ping`], with the firmware version
+    Ping { version: u16 },
+    /// Answer to [`Command::Get`]. A non-zero `flags` (see [`flags`])
+    /// means `value` is not valid.
+    Get {
+        register: u16,
+        flags: u8,
+        value: &'a [u8],
+    },
+    /// Answer to [`Command::Set`], with the value now in the register
+    Set {
+        register: u16,
+        flags: u8,
+        value: &'a [u8],
+    },
+    /// Sent by the device when a register changes
+    Async {
+        register: u16,
+        flags: u8,
+        value: &'a [u8],
+    },
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    }
+}
+
+impl HexFrame {
+    pub fn new(command: Command, data: &[u8]) -> Self {
+        HexFrame {
+            command: command as u8,
+            data: heapless::Vec::from_slice(&data[..data.len().min(MAX_HEX_DATA)])
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn ping() -> Self {
+        HexFrame::new(Command::Ping, &[])
+    }
+
+    pub fn app_version() -> Self {
+        HexFrame::new(Command::AppVersion, &[])
+    }
+
+    pub fn product_id() -> Self {
+        HexFrame::new(Command::ProductId, &[])
+    }
+
+    /// Restart the device. It does not answer this command.
+    pub fn restart() -> Self {
+        HexFrame::new(Command::Restart, &[])
+    }
+
+    /// Read `register`
+    pub fn get(register: u16) -> Self {
+        let [lo, hi] = register.to_le_bytes();
+        HexFrame::new(Command::Get, &[lo, hi, 0])
+    }
+
+    /// Write `value` to `register`. See [`Register::encode`] for
+    /// encoding values.
+    pub fn set(register: u16, value: &[u8]) -> Self {
+        let [lo, hi] = register.to_le_bytes();
+        let mut frame = HexFrame::new(Command::Set, &[lo, hi, 0]);
+        let len = value.len().min(MAX_HEX_DATA - 3);
+        let _ = frame.data.extend_from_slice(&value[..len]);
+        frame
+    }
+
+    /// Checksum byte making the message add up to `0x55`
+    pub fn checksum(&self) -> u8 {
+        let sum = self
+            .data
+            .iter()
+            .fold(self.command, |sum, b| sum.wrapping_add(*b));
+        0x55u8.wrapping_sub(sum)
+    }
+
+    /// Decode a message from the text between the `:` and the line
+    /// ending, verifying its checksum
+    pub fn decode(line: &[u8]) -> Result<HexFrame, VEError> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = line.strip_prefix(b":").unwrap_or(line);
+        if line.len() < 3 || line.len().is_multiple_of(2) {
+            return Err(VEError::Parse(text!("HEX message has invalid length")));
+        }
+        let invalid = || VEError::Parse(text!("HEX message has invalid digit"));
+        let command = hex_digit(line[0]).ok_or_else(invalid)?;
+        let mut data = heapless::Vec::<u8, MAX_HEX_DATA>::new();
+        let mut sum = command;
+        let pairs = line[1..].chunks(2);
+        let count = pairs.len();
+        for (i, pair) in pairs.enumerate() {
+            let byte = (hex_digit(pair[0]).ok_or_else(invalid)? << 4)
+                | hex_digit(pair[1]).ok_or_else(invalid)?;
+            sum = sum.wrapping_add(byte);
+            if i + 1 < count {
+                data.push(byte).map_err(|_| VEError::CapacityExceeded)?;
+            }
+        }
+        if sum != 0x55 {
+            return Err(VEError::ChecksumError);
+        }
+        Ok(HexFrame { command, data })
+    }
+
+    /// Interpret a message received from a device
+    pub fn response(&self) -> Result<Response<'_>, VEError> {
+        let register = |data: &[u8]| -> Result<(u16, u8), VEError> {
+            if data.len() < 3 {
+                return Err(VEError::Parse(text!("HEX response too short")));
+            }
+            Ok((u16::from_le_bytes([data[0], data[1]]), data[2]))
+        };
+        let data = &self.data[..];
+        Ok(match self.command {
+            0x1 => Response::Done(data),
+            0x3 => Response::Unknown,
+            0x4 => Response::Error,
+            0x5 if data.len() >= 2 => Response::Ping {
+                version: u16::from_le_bytes([data[0], data[1]]),
+            },
+            0x7 => {
+                let (register, flags) = register(data)?;
+                Response::Get {
+                    register,
+                    flags,
+                    value: &data[3..],
+                }
+            }
+            0x8 => {
+                let (register, flags) = register(data)?;
+                Response::Set {
+                    register,
+                    flags,
+                    value: &data[3..],
+                }
+            }
+            0xA => {
+                let (register, flags) = register(data)?;
+                Response::Async {
+                    register,
+                    flags,
+                    value: &data[3..],
+                }
+            }
+            other => {
+                return Err(VEError::UnknownCode(text!("HEX response {:X}", other)));
+            }
+        })
+    }
+}
+
+/// Encodes the message as sent on the wire, including the `:` and
+/// the newline
+impl fmt::Display for HexFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, ":{:X}", self.command)?;
+        for byte in &self.data {
+            write!(f, "{:02X}", byte)?;
+        }
+        writeln!(f, "{:02X}", self.checksum())
+    }
+}
+
+/// Firmware version as sent in ping and version responses, e.g.
+/// `0x4129` is version 1.29
+pub fn format_version(version: u16) -> impl fmt::Display {
+    struct Version(u16);
+    impl fmt::Display for Version {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{:X}.{:02X}", (self.0 >> 8) & 0xF, self.0 & 0xFF)
+        }
+    }
+    Version(version)
+}
+
+/// How a register value is encoded. All values are little endian.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    U8,
+    U16,
+    I16,
+    U32,
+}
+
+impl Format {
+    pub fn size(&self) -> usize {
+        match self {
+            Format::U8 => 1,
+            Format::U16 | Format::I16 => 2,
+            Format::U32 => 4,
+        }
+    }
+}
+
+/// A device register, with the scale and unit of its value
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Register {
+    pub id: u16,
+    pub name: &'static str,
+    pub format: Format,
+    /// Raw units per [`Register::unit`], e.g. `100` for a voltage in
+    /// 0.01 V
+    pub divisor: f32,
+    pub unit: &'static str,
+    pub writable: bool,
+}
+
+impl Register {
+    /// Value in [`Register::unit`], or `None` if `raw` is too short
+    pub fn decode(&self, raw: &[u8]) -> Option<f32> {
+        let raw = raw.get(..self.format.size())?;
+        let value = match self.format {
+            Format::U8 => raw[0] as f32,
+            Format::U16 => u16::from_le_bytes([raw[0], raw[1]]) as f32,
+            Format::I16 => i16::from_le_bytes([raw[0], raw[1]]) as f32,
+            Format::U32 => u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as f32,
+        };
+        Some(value / self.divisor)
+    }
+
+    /// Raw bytes for a value in [`Register::unit`], or `None` if it
+    /// is out of range
+    pub fn encode(&self, value: f32) -> Option<heapless::Vec<u8, 4>> {
+        let raw = value * self.divisor;
+        // round half away from zero, without `f32::round` which needs std
+        let raw = if raw < 0.0 { raw - 0.5 } else { raw + 0.5 } as i64;
+        let (min, max) = match self.format {
+            Format::U8 => (0, u8::MAX as i64),
+            Format::U16 => (0, u16::MAX as i64),
+            Format::I16 => (i16::MIN as i64, i16::MAX as i64),
+            Format::U32 => (0, u32::MAX as i64),
+        };
+        if raw < min || raw > max {
+            return None;
+        }
+        let bytes = (raw as u32).to_le_bytes();
+        heapless::Vec::from_slice(&bytes[..self.format.size()]).ok()
+    }
+}
+
+/// Catalogue of commonly used registers of MPPT solar chargers and
+/// BMV battery monitors
+pub mod register {
+    use super::{Format, Register};
+
+    const fn reg(
+        id: u16,
+        name: &'static str,
+        format: Format,
+        divisor: f32,
+        unit: &'static str,
+        writable: bool,
+    ) -> Register {
+        Register {
+            id,
+            name,
+            format,
+            divisor,
+            unit,
+            writable,
+        }
+    }
+
+    pub const PRODUCT_ID: Register = reg(0x0100, "product-id", Format::U32, 1.0, "", false);
+    pub const DEVICE_MODE: Register = reg(0x0200, "device-mode", Format::U8, 1.0, "", true);
+    pub const DEVICE_STATE: Register = reg(0x0201, "device-state", Format::U8, 1.0, "", false);
+    pub const BATTERY_MAX_CURRENT: Register =
+        reg(0xEDF0, "battery-max-current", Format::U16, 10.0, "A", true);
+    pub const BATTERY_TYPE: Register = reg(0xEDF1, "battery-type", Format::U8, 1.0, "", true);
+    pub const EQUALISATION_VOLTAGE: Register = reg(
+        0xEDF4,
+        "equalisation-voltage",
+        Format::U16,
+        100.0,
+        "V",
+        true,
+    );
+    pub const FLOAT_VOLTAGE: Register = reg(0xEDF6, "float-voltage", Format::U16, 100.0, "V", true);
+    pub const ABSORPTION_VOLTAGE: Register =
+        reg(0xEDF7, "absorption-voltage", Format::U16, 100.0, "V", true);
+    pub const ABSORPTION_TIME: Register =
+        reg(0xEDFB, "absorption-time", Format::U16, 100.0, "h", true);
+    pub const BULK_TIME_LIMIT: Register =
+        reg(0xEDFC, "bulk-time-limit", Format::U16, 100.0, "h", true);
+    pub const BATTERY_VOLTAGE_SETTING: Register = reg(
+        0xEDEF,
+        "battery-voltage-setting",
+        Format::U8,
+        1.0,
+        "V",
+        true,
+    );
+    pub const PANEL_VOLTAGE: Register =
+        reg(0xEDBB, "panel-voltage", Format::U16, 100.0, "V", false);
+    pub const PANEL_POWER: Register = reg(0xEDBC, "panel-power", Format::U32, 100.0, "W", false);
+    pub const CHARGER_VOLTAGE: Register =
+        reg(0xEDD5, "charger-voltage", Format::U16, 100.0, "V", false);
+    pub const CHARGER_CURRENT: Register =
+        reg(0xEDD7, "charger-current", Format::U16, 10.0, "A", false);
+    pub const CHARGER_ERROR: Register = reg(0xEDDA, "charger-error", Format::U8, 1.0, "", false);
+    pub const CHARGER_TEMPERATURE: Register = reg(
+        0xEDDB,
+        "charger-temperature",
+        Format::I16,
+        100.0,
+        "°C",
+        false,
+    );
+    pub const YIELD_TODAY: Register = reg(0xEDD3, "yield-today", Format::U16, 100.0, "kWh", false);
+    pub const MAX_POWER_TODAY: Register =
+        reg(0xEDD2, "max-power-today", Format::U16, 1.0, "W", false);
+    pub const USER_YIELD: Register = reg(0xEDDC, "user-yield", Format::U32, 100.0, "kWh", false);
+    pub const LOAD_CURRENT: Register = reg(0xEDAD, "load-current", Format::U16, 10.0, "A", false);
+    pub const LOAD_OUTPUT_STATE: Register =
+        reg(0xEDA8, "load-output-state", Format::U8, 1.0, "", false);
+    pub const MAIN_VOLTAGE: Register = reg(0xED8D, "main-voltage", Format::I16, 100.0, "V", false);
+    pub const CURRENT: Register = reg(0xED8F, "current", Format::I16, 10.0, "A", false);
+    pub const STATE_OF_CHARGE: Register =
+        reg(0x0FFF, "state-of-charge", Format::U16, 100.0, "%", false);
+    pub const TIME_TO_GO: Register = reg(0x0FFE, "time-to-go", Format::U16, 1.0, "min", false);
+
+    /// All registers in the catalogue
+    pub const ALL: &[Register] = &[
+        PRODUCT_ID,
+        DEVICE_MODE,
+        DEVICE_STATE,
+        BATTERY_MAX_CURRENT,
+        BATTERY_TYPE,
+        EQUALISATION_VOLTAGE,
+        FLOAT_VOLTAGE,
+        ABSORPTION_VOLTAGE,
+        ABSORPTION_TIME,
+        BULK_TIME_LIMIT,
+        BATTERY_VOLTAGE_SETTING,
+        PANEL_VOLTAGE,
+        PANEL_POWER,
+        CHARGER_VOLTAGE,
+        CHARGER_CURRENT,
+        CHARGER_ERROR,
+        CHARGER_TEMPERATURE,
+        YIELD_TODAY,
+        MAX_POWER_TODAY,
+        USER_YIELD,
+        LOAD_CURRENT,
+        LOAD_OUTPUT_STATE,
+        MAIN_VOLTAGE,
+        CURRENT,
+        STATE_OF_CHARGE,
+        TIME_TO_GO,
+    ];
+
+    /// First MPPT day history register, holding today's record. The
+    /// record of `n` days ago is at `DAY_HISTORY + n`, for up to 30
+    /// days.
+    pub const DAY_HISTORY: u16 = 0x1050;
+
+    /// Find a register by name (e.g. `absorption-voltage`) or id (e.g.
+    /// `0xEDF7`)
+    pub fn find(name_or_id: &str) -> Option<Register> {
+        let id = name_or_id
+            .strip_prefix("0x")
+            .or_else(|| name_or_id.strip_prefix("0X"))
+            .and_then(|hex| u16::from_str_radix(hex, 16).ok());
+        ALL.iter()
+            .find(|r| Some(r.id) == id || r.name.eq_ignore_ascii_case(name_or_id))
+            .copied()
+    }
+}
+
+/// MPPT day history record, read from the
+/// [`register::DAY_HISTORY`] registers
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DayHistory {
+    /// Units: kWh
+    pub yield_kwh: f32,
+    /// Energy consumed by the load output. Units: kWh
+    pub consumed_kwh: f32,
+    /// Units: V
+    pub battery_voltage_max: f32,
+    /// Units: V
+    pub battery_voltage_min: f32,
+    /// Last four errors, most recent first
+    pub errors: [u8; 4],
+    /// Units: minutes
+    pub time_bulk: u16,
+    /// Units: minutes
+    pub time_absorption: u16,
+    /// Units: minutes
+    pub time_float: u16,
+    /// Units: W
+    pub max_power: u32,
+    /// Units: A
+    pub battery_current_max: f32,
+    /// Units: V
+    pub panel_voltage_max: f32,
+    pub day_sequence: u16,
+}
+
+impl DayHistory {
+    pub const SIZE: usize = 34;
+
+    /// Decode the value of a day history register
+    pub fn decode(raw: &[u8]) -> Option<DayHistory> {
+        if raw.len() < Self::SIZE {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+        Some(DayHistory {
+            yield_kwh: u32_at(1) as f32 / 100.0,
+            consumed_kwh: u32_at(5) as f32 / 100.0,
+            battery_voltage_max: u16_at(9) as f32 / 100.0,
+            battery_voltage_min: u16_at(11) as f32 / 100.0,
+            errors: [raw[14], raw[15], raw[16], raw[17]],
+            time_bulk: u16_at(18),
+            time_absorption: u16_at(20),
+            time_float: u16_at(22),
+            max_power: u32_at(24),
+            battery_current_max: u16_at(28) as f32 / 10.0,
+            panel_voltage_max: u16_at(30) as f32 / 100.0,
+            day_sequence: u16_at(32),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::{Events, Parser, MPPT};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(HexFrame::ping().to_string(), ":154\n");
+        assert_eq!(HexFrame::restart().to_string(), ":64F\n");
+        assert_eq!(
+            HexFrame::get(register::BATTERY_MAX_CURRENT.id).to_string(),
+            ":7F0ED0071\n"
+        );
+        let value = register::ABSORPTION_VOLTAGE.encode(14.4).unwrap();
+        assert_eq!(&value[..], &[0xA0, 0x05]);
+        assert_eq!(
+            HexFrame::set(register::ABSORPTION_VOLTAGE.id, &value).to_string(),
+            ":8F7ED00A005C4\n"
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        let frame = HexFrame::decode(b":51641F9\n").unwrap();
+        assert_eq!(
+            frame.response().unwrap(),
+            Response::Ping { version: 0x4116 }
+        );
+        assert_eq!(format_version(0x4116).to_string(), "1.16");
+
+        let frame = HexFrame::decode(
+            b":A4F1000010000000000AD000000AD000000E508AE05139D04FFFFFFFFFFFFFFFFFFFFFFFFFF4A",
+        )
+        .unwrap();
+        assert!(matches!(
+            frame.response().unwrap(),
+            Response::Async {
+                register: 0x104F,
+                flags: 0,
+                ..
+            }
+        ));
+
+        assert!(matches!(
+            HexFrame::decode(b":51641F8"),
+            Err(VEError::ChecksumError)
+        ));
+        assert!(matches!(HexFrame::decode(b":5164"), Err(VEError::Parse(_))));
+        assert!(matches!(
+            HexFrame::decode(b":516G1F9"),
+            Err(VEError::Parse(_))
+        ));
+
+        // round trip
+        let set = HexFrame::set(0xEDF0, &[0x64, 0x00]);
+        let line = set.to_string();
+        assert_eq!(HexFrame::decode(line.as_bytes()).unwrap(), set);
+    }
+
+    #[test]
+    fn test_register() {
+        assert_eq!(register::find("0xEDF7"), Some(register::ABSORPTION_VOLTAGE));
+        assert_eq!(
+            register::find("Float-Voltage"),
+            Some(register::FLOAT_VOLTAGE)
+        );
+        assert_eq!(register::find("nope"), None);
+
+        assert_eq!(register::CURRENT.decode(&[0xF6, 0xFF]), Some(-1.0));
+        assert_eq!(&register::CURRENT.encode(-1.0).unwrap()[..], &[0xF6, 0xFF]);
+        assert_eq!(register::BATTERY_TYPE.encode(256.0), None);
+        assert_eq!(register::FLOAT_VOLTAGE.decode(&[0x01]), None);
+    }
+
+    #[test]
+    fn test_day_history() {
+        let mut raw = [0u8; DayHistory::SIZE];
+        raw[1..5].copy_from_slice(&144u32.to_le_bytes());
+        raw[9..11].copy_from_slice(&1442u16.to_le_bytes());
+        raw[24..28].copy_from_slice(&250u32.to_le_bytes());
+        raw[32..34].copy_from_slice(&16u16.to_le_bytes());
+        let day = DayHistory::decode(&raw).unwrap();
+        assert_eq!(day.yield_kwh, 1.44);
+        assert_eq!(day.battery_voltage_max, 14.42);
+        assert_eq!(day.max_power, 250);
+        assert_eq!(day.day_sequence, 16);
+        assert_eq!(DayHistory::decode(&raw[1..]), None);
+    }
+
+    struct HexCollector {
+        frames: Vec<HexFrame>,
+        blocks: usize,
+        errors: usize,
+    }
+
+    impl Events<MPPT> for HexCollector {
+        fn on_complete_block(&mut self, _block: MPPT) {
+            self.blocks += 1;
+        }
+
+        fn on_hex_frame(&mut self, frame: HexFrame) {
+            self.frames.push(frame);
+        }
+
+        fn on_parse_error(&mut self, error: VEError, _parse_buf: &[u8]) {
+            assert!(matches!(error, VEError::ChecksumError));
+            self.errors += 1;
+        }
+    }
+
+    #[test]
+    fn test_parser_events() {
+        let stream = format!(
+            "{}:7F7ED00A005C5\n:51641F8\n{}:51641F9\n",
+            MPPT_BLOCK, MPPT_BLOCK
+        );
+        let mut collector = HexCollector {
+            frames: vec![],
+            blocks: 0,
+            errors: 0,
+        };
+        let mut parser = Parser::new(&mut collector);
+        for chunk in stream.as_bytes().chunks(5) {
+            let _ = parser.feed(chunk);
+        }
+        assert_eq!(collector.blocks, 2);
+        assert_eq!(collector.errors, 1);
+        assert_eq!(collector.frames.len(), 2);
+        assert_eq!(collector.frames[0].command, Command::Get as u8);
+        assert_eq!(collector.frames[1].command, 0x5);
+    }
+}
@@ -0,0 +1,269 @@
+//! Home Assistant MQTT discovery, enabled with the `mqtt` feature.
+//!
+//! Home Assistant creates entities for the devices announced on its
+//! discovery topics. With [`MqttConfig::with_discovery`], the
+//! [`Publisher`](crate::mqtt::Publisher) announces every field of a
+//! device the first time it receives data from it, so sensors do not
+//! have to be configured by hand. The entities of a device are grouped
+//! under its identifier in topics, its serial number unless configured
+//! otherwise, and use the status topic for their availability.
+
+use serde_json::{json, Value};
+
+use crate::{
+    json,
+    mqtt::{self, MqttConfig},
+    split_unit, Device, VEError,
+};
+
+/// Default discovery prefix of Home Assistant
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Fields describing the device rather than measurements
+const DIAGNOSTIC: &[&str] = &["firmware", "product_id", "serial_number", "day_sequence"];
+
+/// The discovery message of one entity
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entity {
+    /// Topic to publish the configuration to, retained
+    pub topic: String,
+    pub config: Value,
+}
+
+/// Discovery messages for every field of `device`, for data published
+/// with `config` under the identifier `id`, see
+/// [`MqttConfig::device_id`]
+pub fn entities(
+    device: &Device,
+    id: &str,
+    config: &MqttConfig,
+    discovery_prefix: &str,
+) -> Result<Vec<Entity>, VEError> {
+    let fields = json::device_fields(device)?;
+    let model = device.kind().name().to_uppercase();
+    let mut device_info = json!({
+        "identifiers": [format!("vedirect_{}", id)],
+        "name": format!("{} {}", model, id),
+        "manufacturer": "Victron Energy",
+        "model": model,
+    });
+    if let Some(serial) = device.serial_number() {
+        device_info["serial_number"] = serial.into();
+    }
+    if let Some(firmware) = fields.get("firmware").and_then(Value::as_u64) {
+        // sent as the digits of the version, e.g. 159 for 1.59
+        device_info["sw_version"] = format!("{}.{:02}", firmware / 100, firmware % 100).into();
+    }
+
+    Ok(fields
+        .iter()
+        .map(|(field, value)| {
+            let (name, unit) = split_unit(field);
+            let unique_id = format!("vedirect_{}_{}", id, name);
+            let mut entity = json!({
+                "name": title(name),
+                "unique_id": unique_id,
+                "object_id": unique_id,
+                "state_topic": format!("{}/{}/{}", config.prefix, id, name),
+                "availability_topic": config.status_topic(),
+                "payload_available": mqtt::ONLINE,
+                "payload_not_available": mqtt::OFFLINE,
+                "device": device_info,
+            });
+            let binary = value.is_boolean() || name.ends_with("_on");
+            if binary {
+                entity["payload_on"] = "true".into();
+                entity["payload_off"] = "false".into();
+                if name == "load_output_on" {
+                    entity["device_class"] = "power".into();
+                }
+            } else if DIAGNOSTIC.contains(&name) {
+                entity["entity_category"] = "diagnostic".into();
+            } else if value.is_number() || !unit.is_empty() {
+                let (device_class, state_class) = match unit {
+                    "V" => (Some("voltage"), "measurement"),
+                    "A" => (Some("current"), "measurement"),
+                    "W" => (Some("power"), "measurement"),
+                    // yesterday's yield goes down as well, which would
+                    // be taken for a meter reset. Home Assistant only
+                    // accepts the energy class for totals.
+                    "kWh" if name == "yield_yesterday" => (None, "measurement"),
+                    "kWh" => (Some("energy"), "total_increasing"),
+                    "%" => (Some("battery"), "measurement"),
+                    "min" => (Some("duration"), "measurement"),
+                    _ => (None, "measurement"),
+                };
+                if let Some(device_class) = device_class {
+                    entity["device_class"] = device_class.into();
+                }
+                entity["state_class"] = state_class.into();
+                if !unit.is_empty() {
+                    entity["unit_of_measurement"] = unit.into();
+                }
+            }
+            let component = if binary { "binary_sensor" } else { "sensor" };
+            Entity {
+                topic: format!(
+                    "{}/{}/vedirect_{}/{}/config",
+                    discovery_prefix, component, id, name
+                ),
+                config: entity,
+            }
+        })
+        .collect())
+}
+
+/// `battery_voltage` as `Battery voltage`
+fn title(name: &str) -> String {
+    let name = name.replace('_', " ");
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{parse, MPPT_BLOCK};
+    use crate::{Bmv700, MPPT};
+
+    fn mppt() -> MPPT {
+        parse(MPPT_BLOCK)
+    }
+
+    fn find<'a>(entities: &'a [Entity], topic: &str) -> &'a Value {
+        &entities
+            .iter()
+            .find(|e| e.topic == topic)
+            .unwrap_or_else(|| panic!("no entity {}", topic))
+            .config
+    }
+
+    #[test]
+    fn test_mppt_entities() {
+        let config = MqttConfig::new("localhost", 1883);
+        let entities = entities(
+            &Device::Mppt(mppt()),
+            "HQ2132QY2KR",
+            &config,
+            DISCOVERY_PREFIX,
+        )
+        .unwrap();
+        // every field, including the relay which this device does not have
+        assert_eq!(entities.len(), 20);
+
+        let voltage = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/battery_voltage/config",
+        );
+        assert_eq!(voltage["name"], "Battery voltage");
+        assert_eq!(voltage["device_class"], "voltage");
+        assert_eq!(voltage["state_class"], "measurement");
+        assert_eq!(voltage["unit_of_measurement"], "V");
+        assert_eq!(
+            voltage["state_topic"],
+            "vedirect/HQ2132QY2KR/battery_voltage"
+        );
+        assert_eq!(voltage["availability_topic"], "vedirect/status");
+        assert_eq!(voltage["unique_id"], "vedirect_HQ2132QY2KR_battery_voltage");
+        assert_eq!(
+            voltage["device"],
+            json!({
+                "identifiers": ["vedirect_HQ2132QY2KR"],
+                "name": "MPPT HQ2132QY2KR",
+                "manufacturer": "Victron Energy",
+                "model": "MPPT",
+                "serial_number": "HQ2132QY2KR",
+                "sw_version": "1.59",
+            })
+        );
+
+        let current = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/load_current/config",
+        );
+        assert_eq!(current["device_class"], "current");
+        let power = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/panel_power/config",
+        );
+        assert_eq!(power["device_class"], "power");
+        assert_eq!(power["unit_of_measurement"], "W");
+        let energy = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/yield_total/config",
+        );
+        assert_eq!(energy["device_class"], "energy");
+        assert_eq!(energy["state_class"], "total_increasing");
+        assert_eq!(energy["unit_of_measurement"], "kWh");
+        let today = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/yield_today/config",
+        );
+        assert_eq!(today["state_class"], "total_increasing");
+        let yesterday = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/yield_yesterday/config",
+        );
+        assert_eq!(yesterday["state_class"], "measurement");
+        assert!(yesterday.get("device_class").is_none());
+        assert_eq!(yesterday["unit_of_measurement"], "kWh");
+
+        let load = find(
+            &entities,
+            "homeassistant/binary_sensor/vedirect_HQ2132QY2KR/load_output_on/config",
+        );
+        assert_eq!(load["payload_on"], "true");
+        assert_eq!(load["device_class"], "power");
+        find(
+            &entities,
+            "homeassistant/binary_sensor/vedirect_HQ2132QY2KR/relay_on/config",
+        );
+
+        let state = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/state_of_operation/config",
+        );
+        assert!(state.get("state_class").is_none());
+        let serial = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/serial_number/config",
+        );
+        assert_eq!(serial["entity_category"], "diagnostic");
+        let firmware = find(
+            &entities,
+            "homeassistant/sensor/vedirect_HQ2132QY2KR/firmware/config",
+        );
+        assert!(firmware.get("state_class").is_none());
+    }
+
+    #[test]
+    fn test_bmv700_entities() {
+        let bmv = Device::Bmv700(Bmv700 {
+            voltage: 12.5,
+            power: -20,
+            consumed: Some("-5300".into()),
+            soc: None,
+            ttg: 600,
+        });
+        let config = MqttConfig::new("localhost", 1883).with_prefix("boat");
+        let entities = entities(&bmv, "house", &config, "ha").unwrap();
+        assert_eq!(entities.len(), 5);
+
+        let soc = find(&entities, "ha/sensor/vedirect_house/soc/config");
+        assert_eq!(soc["device_class"], "battery");
+        assert_eq!(soc["unit_of_measurement"], "%");
+        assert_eq!(soc["state_topic"], "boat/house/soc");
+        assert_eq!(soc["device"]["identifiers"], json!(["vedirect_house"]));
+        assert_eq!(soc["device"]["name"], "BMV700 house");
+        assert!(soc["device"].get("serial_number").is_none());
+        let consumed = find(&entities, "ha/sensor/vedirect_house/consumed/config");
+        assert_eq!(consumed["unit_of_measurement"], "mAh");
+        assert_eq!(consumed["state_class"], "measurement");
+        assert!(consumed.get("device_class").is_none());
+        let ttg = find(&entities, "ha/sensor/vedirect_house/time_to_go/config");
+        assert_eq!(ttg["device_class"], "duration");
+    }
+}
@@ -0,0 +1,524 @@
+//! InfluxDB line protocol output, enabled with the `influxdb` feature.
+//!
+//! [`line`] formats the data of a device as one line of the line
+//! protocol, e.g.
+//!
+//! ```text
+//! vedirect,device=mppt,serial=HQ2132QY2KR,product=0xA053 battery_voltage_v=12.54,panel_power_w=5i,load_output_on=true 1700000000000000000
+//! ```
+//!
+//! The kind of device, and when known its serial number and product
+//! ID, are tags. Every numeric and boolean value is a field, named as
+//! when serialized, so with its unit as a suffix. Text values such as
+//! the state of operation, and values which are not known, are left
+//! out. Timestamps are in nanoseconds, the default precision of the
+//! write API.
+//!
+//! A [`Writer`] is a parser listener posting the lines in batches to
+//! the write endpoint of an InfluxDB server, over plain HTTP:
+//!
+//! ```rust,no_run
+//! use vedirect::influxdb::{InfluxConfig, Writer};
+//! use vedirect::Parser;
+//!
+//! let config = InfluxConfig::new("http://localhost:8086/api/v2/write?org=home&bucket=solar")
+//!     .with_token("secret");
+//! let mut parser = Parser::new(Writer::new(config).unwrap());
+//! # let data = [];
+//! parser.feed(&data).unwrap();
+//! parser.listener_mut().flush().unwrap();
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::Value;
+
+use crate::{json, Device, Events, VEError};
+
+/// Default measurement name
+pub const MEASUREMENT: &str = "vedirect";
+
+/// Timeout of every step of a request to the server
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Timeout of connecting to the server, shorter as it is the step which
+/// hangs when the server cannot be reached
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Batches kept while the server cannot be reached, before dropping
+/// the oldest lines
+const MAX_PENDING_BATCHES: usize = 10;
+
+/// The data of `device` as a line of the line protocol, without a
+/// trailing newline. Without a timestamp, the server uses the time it
+/// receives the line.
+pub fn line(
+    device: &Device,
+    measurement: &str,
+    timestamp: Option<SystemTime>,
+) -> Result<String, VEError> {
+    let mut line = escape(measurement, &[',', ' ']);
+    let _ = write!(line, ",device={}", device.kind().name());
+    if let Some(serial) = device.serial_number() {
+        let _ = write!(line, ",serial={}", escape(serial, &[',', '=', ' ']));
+    }
+    if let Some(product) = device.product_id() {
+        let _ = write!(line, ",product={}", escape(product, &[',', '=', ' ']));
+    }
+
+    let mut separator = ' ';
+    for (name, value) in json::device_fields(device)? {
+        let value = match value {
+            // integers have to be marked as such, or they are stored as
+            // floats
+            Value::Number(n) if n.is_f64() => n.to_string(),
+            Value::Number(n) => format!("{}i", n),
+            Value::Bool(b) => b.to_string(),
+            // e.g. consumed amp hours, sent as text
+            Value::String(s) if s.parse::<f64>().is_ok() => s,
+            _ => continue,
+        };
+        let _ = write!(
+            line,
+            "{}{}={}",
+            separator,
+            escape(&name, &[',', '=', ' ']),
+            value
+        );
+        separator = ',';
+    }
+    if separator == ' ' {
+        return Err(VEError::Parse(text!("no numeric fields to write")));
+    }
+
+    if let Some(timestamp) = timestamp {
+        let nanos = timestamp
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| VEError::Parse(text!("timestamp before 1970")))?
+            .as_nanos();
+        let _ = write!(line, " {}", nanos);
+    }
+    Ok(line)
+}
+
+/// Server address and batching options
+#[derive(Clone, PartialEq, Debug)]
+pub struct InfluxConfig {
+    /// Write endpoint, e.g. `http://localhost:8086/api/v2/write?org=home&bucket=solar`,
+    /// or `http://localhost:8086/write?db=solar` for InfluxDB 1.x
+    pub url: String,
+    /// API token, sent as `Authorization: Token <token>`
+    pub token: Option<String>,
+    pub measurement: String,
+    /// Number of lines posted at once
+    pub batch_size: usize,
+    /// Maximum time lines wait for a batch to fill up
+    pub flush_interval: Duration,
+}
+
+impl InfluxConfig {
+    pub fn new(url: &str) -> Self {
+        InfluxConfig {
+            url: url.into(),
+            token: None,
+            measurement: MEASUREMENT.into(),
+            batch_size: 100,
+            flush_interval: Duration::from_secs(10),
+        }
+    }
+
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn with_measurement(mut self, measurement: &str) -> Self {
+        self.measurement = measurement.into();
+        self
+    }
+
+    pub fn with_batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+}
+
+/// Posts device data to an InfluxDB server in batches, see the
+/// [module documentation](self)
+///
+/// Lines are posted once a batch is full, or when a line is written
+/// after the flush interval elapsed. Lines which could not be posted
+/// are kept, up to ten batches, and posted again once the flush
+/// interval elapsed, so an unreachable server does not hold up every
+/// block. Lines
+/// still waiting when the writer is dropped are lost, unless
+/// [`flush`](Writer::flush) is called.
+pub struct Writer {
+    config: InfluxConfig,
+    endpoint: Endpoint,
+    pending: VecDeque<String>,
+    last_flush: Instant,
+    /// The last flush failed, so the next one waits for the interval
+    failing: bool,
+    error: Option<VEError>,
+}
+
+impl Writer {
+    /// Create a writer, checking the URL of the endpoint
+    pub fn new(config: InfluxConfig) -> Result<Self, VEError> {
+        let endpoint = Endpoint::parse(&config.url)?;
+        Ok(Writer {
+            config,
+            endpoint,
+            pending: VecDeque::new(),
+            last_flush: Instant::now(),
+            failing: false,
+            error: None,
+        })
+    }
+
+    pub fn config(&self) -> &InfluxConfig {
+        &self.config
+    }
+
+    /// Number of lines waiting to be posted
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Add the data of a device, received at `timestamp`, to the batch,
+    /// posting it if it is due
+    pub fn write(&mut self, device: &Device, timestamp: SystemTime) -> Result<(), VEError> {
+        let line = line(device, &self.config.measurement, Some(timestamp))?;
+        let max_pending = self.config.batch_size * MAX_PENDING_BATCHES;
+        while self.pending.len() >= max_pending {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(line);
+        let full = self.pending.len() >= self.config.batch_size && !self.failing;
+        if full || self.last_flush.elapsed() >= self.config.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Post all waiting lines
+    pub fn flush(&mut self) -> Result<(), VEError> {
+        self.last_flush = Instant::now();
+        let result = self.post_pending();
+        self.failing = result.is_err();
+        result
+    }
+
+    fn post_pending(&mut self) -> Result<(), VEError> {
+        while !self.pending.is_empty() {
+            let count = self.pending.len().min(self.config.batch_size);
+            let mut body = String::new();
+            for line in self.pending.iter().take(count) {
+                body.push_str(line);
+                body.push('\n');
+            }
+            self.post(&body)?;
+            self.pending.drain(..count);
+        }
+        Ok(())
+    }
+
+    /// The first error which occurred while writing blocks received as
+    /// a parser listener, if any
+    pub fn take_error(&mut self) -> Option<VEError> {
+        self.error.take()
+    }
+
+    fn post(&self, body: &str) -> Result<(), VEError> {
+        let endpoint = &self.endpoint;
+        let mut stream = connect(&endpoint.host, endpoint.port)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n",
+            endpoint.path,
+            endpoint.host,
+            endpoint.port,
+            body.len()
+        );
+        if let Some(token) = &self.config.token {
+            let _ = write!(request, "Authorization: Token {}\r\n", token);
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => {
+                // the body explains the error, e.g. a field type conflict
+                let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+                Err(VEError::Io(io::Error::other(format!(
+                    "InfluxDB responded {} {}",
+                    status,
+                    body.trim()
+                ))))
+            }
+        }
+    }
+}
+
+/// Connect to the first address of `host` which accepts the connection
+fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(io::ErrorKind::NotFound, format!("no address for {}", host));
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+impl Events<Device> for Writer {
+    fn on_complete_block(&mut self, block: Device) {
+        if let Err(e) = self.write(&block, SystemTime::now()) {
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+/// Host, port, and path with the query of an `http://` URL
+#[derive(Clone, PartialEq, Debug)]
+struct Endpoint {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Result<Self, VEError> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| VEError::Parse(text!("only http:// URLs are supported, not {}", url)))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| VEError::Parse(text!("invalid port in {}", url)))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(VEError::Parse(text!("no host in {}", url)));
+        }
+        Ok(Endpoint {
+            host: host.into(),
+            port,
+            path: path.into(),
+        })
+    }
+}
+
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{parse, MPPT_BLOCK};
+    use crate::{Bmv700, Parser};
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    fn bmv() -> Device {
+        Device::Bmv700(Bmv700 {
+            voltage: 12.5,
+            power: -20,
+            consumed: Some("-5300".into()),
+            soc: None,
+            ttg: 600,
+        })
+    }
+
+    /// A request received by the mock server
+    struct Request {
+        head: String,
+        body: String,
+    }
+
+    /// Serve `statuses.len()` requests, responding with those statuses
+    fn mock_server(statuses: Vec<&'static str>) -> (String, mpsc::Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/api/v2/write?org=home&bucket=solar",
+            listener.local_addr().unwrap()
+        );
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let error = if status.starts_with('2') {
+                    ""
+                } else {
+                    "{\"code\":\"internal error\"}"
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    error.len(),
+                    error
+                )
+                .unwrap();
+                let body = String::from_utf8(body).unwrap();
+                sender.send(Request { head, body }).unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    #[test]
+    fn test_line() {
+        let mppt = &parse::<Device>(MPPT_BLOCK);
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(
+            line(mppt, MEASUREMENT, Some(timestamp)).unwrap(),
+            "vedirect,device=mppt,serial=HQ2132QY2KR,product=0xA053 \
+             battery_voltage_v=12.54,panel_voltage_v=18.54,panel_power_w=5i,\
+             battery_current_a=0.04,load_current_a=0.3,load_output_on=true,\
+             yield_total_kwh=1.44,yield_today_kwh=0.01,max_power_today_w=6i,\
+             yield_yesterday_kwh=0.04,max_power_yesterday_w=14i,firmware=159i,\
+             day_sequence=16i 1700000000000000000"
+        );
+        assert_eq!(
+            line(&bmv(), "battery monitor", None).unwrap(),
+            "battery\\ monitor,device=bmv700 voltage_v=12.5,power_w=-20i,consumed_mah=-5300,time_to_go_min=600i"
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a b,c=d\\", &[',', '=', ' ']), "a\\ b\\,c\\=d\\\\");
+        assert_eq!(escape("a=b", &[',', ' ']), "a=b");
+    }
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(
+            Endpoint::parse("http://influx:8086/write?db=solar").unwrap(),
+            Endpoint {
+                host: "influx".into(),
+                port: 8086,
+                path: "/write?db=solar".into(),
+            }
+        );
+        assert_eq!(Endpoint::parse("http://influx").unwrap().port, 80);
+        assert!(Endpoint::parse("https://influx/write").is_err());
+        assert!(Endpoint::parse("http://influx:port/write").is_err());
+    }
+
+    #[test]
+    fn test_writer() {
+        let (url, requests) = mock_server(vec!["204 No Content", "204 No Content"]);
+        let config = InfluxConfig::new(&url)
+            .with_token("secret")
+            .with_batch_size(2)
+            .with_flush_interval(Duration::from_secs(3600));
+        let mut writer = Writer::new(config).unwrap();
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        writer.write(&bmv(), timestamp).unwrap();
+        assert_eq!(writer.pending(), 1);
+        writer.write(&bmv(), timestamp).unwrap();
+        assert_eq!(writer.pending(), 0);
+        let request = requests.recv().unwrap();
+        assert!(request
+            .head
+            .starts_with("POST /api/v2/write?org=home&bucket=solar HTTP/1.1\r\n"));
+        assert!(request.head.contains("Authorization: Token secret\r\n"));
+        assert_eq!(request.body.lines().count(), 2);
+        assert!(request.body.ends_with(" 1700000000000000000\n"));
+
+        writer.write(&bmv(), timestamp).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(requests.recv().unwrap().body.lines().count(), 1);
+        // nothing left to post
+        writer.flush().unwrap();
+    }
+
+    #[test]
+    fn test_writer_retry() {
+        let (url, requests) = mock_server(vec![
+            "500 Internal Server Error",
+            "204 No Content",
+            "204 No Content",
+        ]);
+        let config = InfluxConfig::new(&url).with_batch_size(2);
+        let mut parser = Parser::new(Writer::new(config).unwrap());
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+
+        let writer = parser.listener_mut();
+        let error = writer.take_error().unwrap().to_string();
+        assert!(error.contains("500 Internal Server Error"), "{}", error);
+        assert!(error.contains("internal error"), "{}", error);
+        assert_eq!(writer.pending(), 2);
+        assert_eq!(requests.recv().unwrap().body.lines().count(), 2);
+
+        // not posted again before the flush interval
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let writer = parser.listener_mut();
+        assert!(writer.take_error().is_none());
+        assert_eq!(writer.pending(), 3);
+        assert!(requests.try_recv().is_err());
+
+        writer.flush().unwrap();
+        assert_eq!(writer.pending(), 0);
+        assert_eq!(requests.recv().unwrap().body.lines().count(), 2);
+        assert_eq!(requests.recv().unwrap().body.lines().count(), 1);
+    }
+}
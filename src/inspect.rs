@@ -0,0 +1,306 @@
+//! Access to the fields of mapped data by name.
+//!
+//! [`Inspect`] lists the fields of a block as [`Value`]s, named as in
+//! [`Bmv700`] and [`MPPT`], or by label for [`Frame`]s. Enumerations
+//! are given by the name of their variant. Fields without a value, such
+//! as the state of charge of a battery monitor which is not
+//! synchronised, are left out.
+//!
+//! ```rust
+//! use vedirect::inspect::{Inspect, Value};
+//! use vedirect::Bmv700;
+//!
+//! let bmv = Bmv700 { voltage: 12.5, power: -100, consumed: None, soc: Some(80.0), ttg: -1 };
+//! assert_eq!(bmv.field("soc"), Some(Value::Number(80.0)));
+//! assert_eq!(bmv.fields().len(), 4);
+//! ```
+
+use std::fmt;
+
+use crate::{widen, Bmv700, Device, Frame, MPPT};
+
+/// Value of a field
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Number(f64),
+    Bool(bool),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Text(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+impl Value {
+    /// A number, `true`/`false` or `ON`/`OFF`, and text otherwise
+    pub fn parse(text: &str) -> Value {
+        if let Ok(number) = text.parse() {
+            return Value::Number(number);
+        }
+        match text {
+            "true" | "ON" | "on" => Value::Bool(true),
+            "false" | "OFF" | "off" => Value::Bool(false),
+            _ => Value::Text(text.trim_matches('"').into()),
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// Fields of [`Bmv700`], in the order of [`Inspect::fields`]
+pub const BMV700_FIELDS: &[&str] = &["voltage", "power", "consumed", "soc", "ttg"];
+
+/// Fields of [`MPPT`], in the order of [`Inspect::fields`]
+pub const MPPT_FIELDS: &[&str] = &[
+    "channel1_voltage",
+    "battery_current",
+    "panel_voltage",
+    "panel_power",
+    "load_current",
+    "load_output_state",
+    "off_reason",
+    "yield_total",
+    "yield_today",
+    "max_power_today",
+    "yield_yesterday",
+    "max_power_yesterday",
+    "error_code",
+    "state_of_operation",
+    "firmware",
+    "product_id",
+    "serial_number",
+    "day_sequence",
+    "tracker_mode",
+    "relay_state",
+];
+
+/// Names of the fields of the type named `kind`, `None` for types
+/// other than `Bmv700` and `MPPT`. The fields of a `Frame` are its
+/// labels.
+pub fn field_names(kind: &str) -> Option<&'static [&'static str]> {
+    match kind {
+        "Bmv700" => Some(BMV700_FIELDS),
+        "MPPT" => Some(MPPT_FIELDS),
+        _ => None,
+    }
+}
+
+/// Data whose fields can be accessed by name
+pub trait Inspect {
+    /// Name of the type, e.g. `Bmv700`
+    fn kind(&self) -> &'static str;
+
+    /// Serial number of the device, keeping devices of the same type apart
+    fn serial(&self) -> Option<&str>;
+
+    /// Names and values of the fields which have a value
+    fn fields(&self) -> Vec<(&str, Value)>;
+
+    /// Value of the field `name`, `None` if unknown or without value
+    fn field(&self, name: &str) -> Option<Value>;
+}
+
+/// The fields of `data` among `names` which have a value
+fn fields_named<'a>(data: &impl Inspect, names: &[&'a str]) -> Vec<(&'a str, Value)> {
+    names
+        .iter()
+        .filter_map(|name| Some((*name, data.field(name)?)))
+        .collect()
+}
+
+impl Inspect for Bmv700 {
+    fn kind(&self) -> &'static str {
+        "Bmv700"
+    }
+
+    fn serial(&self) -> Option<&str> {
+        None
+    }
+
+    fn fields(&self) -> Vec<(&str, Value)> {
+        fields_named(self, BMV700_FIELDS)
+    }
+
+    fn field(&self, name: &str) -> Option<Value> {
+        let value = match name {
+            "voltage" => Value::Number(widen(self.voltage)),
+            "power" => Value::Number(self.power.into()),
+            "consumed" => Value::Number(self.consumed.as_ref()?.parse().ok()?),
+            "soc" => Value::Number(widen(self.soc?)),
+            "ttg" => Value::Number(self.ttg.into()),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+impl Inspect for MPPT {
+    fn kind(&self) -> &'static str {
+        "MPPT"
+    }
+
+    fn serial(&self) -> Option<&str> {
+        Some(&self.serial_number)
+    }
+
+    fn fields(&self) -> Vec<(&str, Value)> {
+        fields_named(self, MPPT_FIELDS)
+    }
+
+    fn field(&self, name: &str) -> Option<Value> {
+        let value = match name {
+            "channel1_voltage" => Value::Number(widen(self.channel1_voltage)),
+            "battery_current" => Value::Number(widen(self.battery_current)),
+            "panel_voltage" => Value::Number(widen(self.panel_voltage)),
+            "panel_power" => Value::Number(self.panel_power.into()),
+            "load_current" => Value::Number(widen(self.load_current)),
+            "load_output_state" => Value::Bool(self.load_output_state),
+            "off_reason" => Value::Text(format!("{:?}", self.off_reason)),
+            "yield_total" => Value::Number(self.yield_total.into()),
+            "yield_today" => Value::Number(self.yield_today.into()),
+            "max_power_today" => Value::Number(self.max_power_today.into()),
+            "yield_yesterday" => Value::Number(self.yield_yesterday.into()),
+            "max_power_yesterday" => Value::Number(self.max_power_yesterday.into()),
+            "error_code" => Value::Text(format!("{:?}", self.error_code)),
+            "state_of_operation" => Value::Text(format!("{:?}", self.state_of_operation)),
+            "firmware" => Value::Number(self.firmware.into()),
+            "product_id" => Value::Text(self.product_id.to_string()),
+            "serial_number" => Value::Text(self.serial_number.to_string()),
+            "day_sequence" => Value::Number(self.day_sequence.into()),
+            "tracker_mode" => Value::Text(format!("{:?}", self.tracker_mode)),
+            "relay_state" => Value::Bool(self.relay_state?),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+impl Inspect for Device {
+    fn kind(&self) -> &'static str {
+        match self {
+            Device::Bmv700(bmv) => bmv.kind(),
+            Device::Mppt(mppt) => mppt.kind(),
+        }
+    }
+
+    fn serial(&self) -> Option<&str> {
+        self.serial_number()
+    }
+
+    fn fields(&self) -> Vec<(&str, Value)> {
+        match self {
+            Device::Bmv700(bmv) => bmv.fields(),
+            Device::Mppt(mppt) => mppt.fields(),
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<Value> {
+        match self {
+            Device::Bmv700(bmv) => bmv.field(name),
+            Device::Mppt(mppt) => mppt.field(name),
+        }
+    }
+}
+
+/// Fields by label, with their values as sent
+impl Inspect for Frame {
+    fn kind(&self) -> &'static str {
+        "Frame"
+    }
+
+    fn serial(&self) -> Option<&str> {
+        self.get("SER#")
+    }
+
+    fn fields(&self) -> Vec<(&str, Value)> {
+        self.iter()
+            .filter(|(label, _)| *label != "Checksum")
+            .map(|(label, value)| (label, Value::parse(value)))
+            .collect()
+    }
+    fn field(&self, name: &str) -> Option<Value> {
+        match name {
+            "Checksum" => None,
+            label => self.get(label).map(Value::parse),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, MPPT_BLOCK};
+
+    /// The MPPT block, with an off reason and an error
+    fn parse<D: crate::VEDirectData>() -> D {
+        fixtures::parse(
+            MPPT_BLOCK
+                .replace("OR\t0x00000000", "OR\t0x00000010")
+                .replace("ERR\t0", "ERR\t2"),
+        )
+    }
+
+    #[test]
+    fn test_mppt() {
+        let mppt: Device = parse();
+        assert_eq!(Inspect::kind(&mppt), "MPPT");
+        assert_eq!(mppt.serial(), Some("HQ2132QY2KR"));
+        assert_eq!(mppt.field("channel1_voltage"), Some(Value::Number(12.54)));
+        assert_eq!(mppt.field("load_output_state"), Some(Value::Bool(true)));
+        assert_eq!(
+            mppt.field("error_code"),
+            Some(Value::Text("BatteryVoltageTooHigh".into()))
+        );
+        assert_eq!(mppt.field("relay_state"), None);
+        assert_eq!(mppt.field("nonexistent"), None);
+        assert_eq!(mppt.fields().len(), 19);
+    }
+
+    #[test]
+    fn test_field_names() {
+        let mut mppt: MPPT = parse();
+        mppt.relay_state = Some(false);
+        let names: Vec<&str> = mppt.fields().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, MPPT_FIELDS);
+        let bmv = Bmv700 {
+            voltage: 12.5,
+            power: -100,
+            consumed: Some("-500".into()),
+            soc: Some(80.0),
+            ttg: -1,
+        };
+        let names: Vec<&str> = bmv.fields().iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, BMV700_FIELDS);
+        assert_eq!(field_names("Frame"), None);
+    }
+
+    #[test]
+    fn test_frame() {
+        let frame: Frame = parse();
+        assert_eq!(frame.kind(), "Frame");
+        assert_eq!(frame.serial(), Some("HQ2132QY2KR"));
+        assert_eq!(frame.field("V"), Some(Value::Number(12540.0)));
+        assert_eq!(frame.field("LOAD"), Some(Value::Bool(true)));
+        assert_eq!(frame.field("PID"), Some(Value::Text("0xA053".into())));
+        assert_eq!(frame.field("Checksum"), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Value::parse("-1.5"), Value::Number(-1.5));
+        assert_eq!(Value::parse("OFF"), Value::Bool(false));
+        assert_eq!(Value::parse("\"Float\""), Value::Text("Float".into()));
+        assert_eq!(Value::Number(30.0).to_string(), "30");
+    }
+}
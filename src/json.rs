@@ -0,0 +1,25 @@
+//! Conversion of mapped data to JSON, shared by the exporters
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{Device, VEError};
+
+/// Serialize to a JSON object. Going through the text keeps `f32`
+/// values short: `to_value` widens them, giving 12.539999961853027
+/// instead of 12.54.
+pub fn to_map(data: &impl Serialize) -> Result<Map<String, Value>, VEError> {
+    let json = serde_json::to_string(data).map_err(|e| VEError::Parse(text!("{}", e)))?;
+    match serde_json::from_str(&json) {
+        Ok(Value::Object(map)) => Ok(map),
+        _ => Err(VEError::Parse(text!("not serialized as an object"))),
+    }
+}
+
+/// The serialized fields of a device's data, without the device tag
+pub fn device_fields(device: &Device) -> Result<Map<String, Value>, VEError> {
+    match device {
+        Device::Bmv700(bmv) => to_map(bmv),
+        Device::Mppt(mppt) => to_map(mppt),
+    }
+}
@@ -1,11 +1,73 @@
 //! Parser for the Victron Energy "VE.Direct" text protocol.
+//!
+//! The crate is `no_std` compatible and does not allocate when built
+//! without the default `std` feature. Strings in errors and data
+//! types are then fixed capacity [`Text`] values, and the parser uses
+//! fixed size buffers (see [`Parser`] for what happens when they
+//! overflow).
 
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![forbid(unsafe_code)]
 //#![warn(missing_docs)]
 #![allow(clippy::upper_case_acronyms)]
 
+#[macro_use]
+mod text;
+
+// lets the test fixtures name the crate as the daemon's tests do
+#[cfg(test)]
+extern crate self as vedirect;
+
+#[cfg(feature = "std")]
+pub mod alarm;
+#[cfg(feature = "std")]
+pub mod capture;
+pub mod clock;
+#[cfg(feature = "csv")]
+pub mod csv;
 mod data;
+#[cfg(any(feature = "csv", feature = "signalk"))]
+mod datetime;
+#[cfg(feature = "std")]
+pub mod delta;
+mod device;
+#[cfg(any(feature = "embedded-io", feature = "embedded-hal-nb"))]
+mod embedded;
+mod encode;
+pub mod energy;
+mod fields;
+#[cfg(test)]
+mod fixtures;
+#[cfg(feature = "std")]
+mod frame;
+pub mod hex;
+#[cfg(feature = "mqtt")]
+pub mod homeassistant;
+#[cfg(feature = "influxdb")]
+pub mod influxdb;
+#[cfg(feature = "std")]
+pub mod inspect;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
 mod parser;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "std")]
+pub mod reconnect;
+#[cfg(feature = "signalk")]
+pub mod signalk;
+#[cfg(feature = "simulator")]
+pub mod simulator;
+#[cfg(feature = "std")]
+pub mod site;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "tokio")]
+mod stream;
 
 use thiserror::Error;
 
@@ -14,7 +76,7 @@ use thiserror::Error;
 pub enum VEError {
     /// General parsing errors
     #[error("error parsing data: {0}")]
-    Parse(String),
+    Parse(Text),
 
     /// A non-fatal error. The parser can be fed a stream of data in
     /// chunks; and a given chunk might, for example, stop in the
@@ -29,21 +91,52 @@ pub enum VEError {
 
     /// A required field was missing from the received data
     #[error("missing field from received data")]
-    MissingField(String),
+    MissingField(Text),
 
     /// A field was expected to be a boolean type but received
     /// differently formatted data
     #[error("ON or OFF value expected")]
-    OnOffExpected(String),
+    OnOffExpected(Text),
 
     /// Some fields are encoded as hexidecimal codes, and this error
     /// occurs if the received code is not recognized
     #[error("Unknown enum code")]
-    UnknownCode(String),
+    UnknownCode(Text),
+
+    /// A block did not fit in the parser's fixed capacity buffers
+    /// and was discarded
+    #[error("block exceeds parser capacity")]
+    CapacityExceeded,
+
+    /// Reading from the underlying device failed
+    #[cfg(feature = "std")]
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 // Re-export
+#[cfg(feature = "serde")]
+pub use data::split_unit;
+#[cfg(feature = "std")]
+pub use data::widen;
 pub use data::Bmv700;
+pub use data::VEDirectData;
 pub use data::MPPT;
+pub use data::{ErrorCode, OffReason, StateOfOperation, TrackerOperationMode};
+pub use device::{Device, DeviceKind};
+#[cfg(feature = "embedded-io")]
+pub use embedded::IoReader;
+#[cfg(feature = "embedded-hal-nb")]
+pub use embedded::NbReader;
+#[cfg(any(feature = "embedded-io", feature = "embedded-hal-nb"))]
+pub use embedded::ReadError;
+pub use encode::{BlockWriter, Encode};
+pub use fields::{Fields, Label, MAX_FIELDS, MAX_LABEL_LEN, MAX_VALUE_LEN};
+#[cfg(feature = "std")]
+pub use frame::Frame;
+pub use hex::HexFrame;
 pub use parser::Events;
-pub use parser::Parser;
+pub use parser::{Parser, DEFAULT_BUFFER_SIZE};
+#[cfg(feature = "tokio")]
+pub use stream::{BlockCodec, BlockStream};
+pub use text::Text;
@@ -0,0 +1,436 @@
+//! Modbus TCP server with the register layout of a Victron GX device,
+//! enabled with the `modbus` feature.
+//!
+//! [`Registers`] is a parser listener holding the latest data of every
+//! device at the registers a GX device uses for it, so software which
+//! reads VE.Direct devices from a GX can read them from any machine
+//! running [`serve`]:
+//!
+//! ```rust,no_run
+//! use std::{net::TcpListener, sync::{Arc, Mutex}};
+//! use vedirect::modbus::{self, Registers};
+//! use vedirect::Parser;
+//!
+//! let registers = Arc::new(Mutex::new(Registers::new()));
+//! modbus::serve(TcpListener::bind("0.0.0.0:502").unwrap(), registers.clone());
+//! let mut parser = Parser::new(registers);
+//! ```
+//!
+//! Battery monitors are served as `com.victronenergy.battery`:
+//!
+//! | register | value                        | type   | scale |
+//! |----------|------------------------------|--------|-------|
+//! | 258      | battery power, W             | int16  | 1     |
+//! | 259      | battery voltage, V           | uint16 | 100   |
+//! | 265      | consumed amp hours, Ah       | uint16 | -10   |
+//! | 266      | state of charge, %           | uint16 | 10    |
+//! | 303      | time to go, s                | uint16 | 0.01  |
+//!
+//! and solar chargers as `com.victronenergy.solarcharger`:
+//!
+//! | register | value                        | type   | scale |
+//! |----------|------------------------------|--------|-------|
+//! | 771      | battery voltage, V           | uint16 | 100   |
+//! | 772      | battery current, A           | int16  | 10    |
+//! | 775      | charge state                 | uint16 | 1     |
+//! | 776      | PV voltage, V                | uint16 | 100   |
+//! | 777      | PV current, A                | int16  | 10    |
+//! | 780      | relay state                  | uint16 | 1     |
+//! | 784      | yield today, kWh             | uint16 | 10    |
+//! | 785      | maximum power today, W       | uint16 | 1     |
+//! | 786      | yield yesterday, kWh         | uint16 | 10    |
+//! | 787      | maximum power yesterday, W   | uint16 | 1     |
+//! | 788      | error code                   | uint16 | 1     |
+//! | 789      | PV power, W                  | uint16 | 10    |
+//! | 790      | user yield, kWh              | uint16 | 10    |
+//! | 791      | MPP operation mode           | uint16 | 1     |
+//!
+//! The first battery monitor gets unit ID [`BATTERY_UNIT_ID`] and the
+//! first solar charger [`SOLAR_CHARGER_UNIT_ID`], as usual on a GX, and
+//! further devices the following free unit IDs, unless they are set with
+//! [`Registers::with_unit_id`].
+//!
+//! As on a GX, reading a register without a value, such as the state
+//! of charge of an unsynchronised battery monitor, fails with the
+//! exception "illegal data address", and reading from an unknown unit
+//! ID with "gateway target device failed to respond". Registers can be
+//! read with function 3 (holding registers) or 4 (input registers);
+//! writing is not supported.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+};
+
+use crate::{encode::scale, Bmv700, Device, DeviceKind, Events, MPPT};
+
+/// Unit ID of the first battery monitor
+pub const BATTERY_UNIT_ID: u8 = 245;
+/// Unit ID of the first solar charger
+pub const SOLAR_CHARGER_UNIT_ID: u8 = 226;
+
+/// Most registers read by one request
+const MAX_REGISTERS: u16 = 125;
+
+/// Exception codes of failed requests
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Exception {
+    IllegalFunction = 0x01,
+    IllegalDataAddress = 0x02,
+    IllegalDataValue = 0x03,
+    GatewayTargetFailed = 0x0B,
+}
+
+/// Registers of every device, see the [module documentation](self)
+#[derive(Clone, Debug, Default)]
+pub struct Registers {
+    /// Unit IDs by serial number, or by kind for devices without one
+    unit_ids: HashMap<String, u8>,
+    units: BTreeMap<u8, BTreeMap<u16, u16>>,
+}
+
+impl Registers {
+    pub fn new() -> Self {
+        Registers::default()
+    }
+
+    /// Serve the device with serial number `serial` on `unit_id`. For
+    /// battery monitors which do not send their serial number, `serial`
+    /// is their kind, e.g. `bmv700`.
+    pub fn with_unit_id(mut self, serial: &str, unit_id: u8) -> Self {
+        self.unit_ids.insert(serial.into(), unit_id);
+        self
+    }
+
+    /// Unit ID of a device, assigning one if it has none yet
+    pub fn unit_id(&mut self, device: &Device) -> u8 {
+        let key = device
+            .serial_number()
+            .unwrap_or_else(|| device.kind().name());
+        if let Some(unit_id) = self.unit_ids.get(key) {
+            return *unit_id;
+        }
+        let first = match device.kind() {
+            DeviceKind::Bmv700 => BATTERY_UNIT_ID,
+            DeviceKind::Mppt => SOLAR_CHARGER_UNIT_ID,
+        };
+        let unit_id = (first..=u8::MAX)
+            .chain(1..first)
+            .find(|id| !self.unit_ids.values().any(|used| used == id))
+            .unwrap_or(first);
+        self.unit_ids.insert(key.into(), unit_id);
+        unit_id
+    }
+
+    /// Replace the registers of a device
+    pub fn update(&mut self, device: &Device) {
+        let unit_id = self.unit_id(device);
+        let registers = match device {
+            Device::Bmv700(bmv) => battery_registers(bmv),
+            Device::Mppt(mppt) => solar_charger_registers(mppt),
+        };
+        self.units.insert(unit_id, registers.into_iter().collect());
+    }
+
+    /// Read `count` registers of `unit_id`, starting at `address`
+    pub fn read(&self, unit_id: u8, address: u16, count: u16) -> Result<Vec<u16>, Exception> {
+        if count == 0 || count > MAX_REGISTERS {
+            return Err(Exception::IllegalDataValue);
+        }
+        let registers = self
+            .units
+            .get(&unit_id)
+            .ok_or(Exception::GatewayTargetFailed)?;
+        (0..count)
+            .map(|i| {
+                let address = address
+                    .checked_add(i)
+                    .ok_or(Exception::IllegalDataAddress)?;
+                registers
+                    .get(&address)
+                    .copied()
+                    .ok_or(Exception::IllegalDataAddress)
+            })
+            .collect()
+    }
+
+    /// Answer a request, returning the response. Both are complete
+    /// Modbus TCP frames.
+    pub fn respond(&self, request: &[u8]) -> Option<Vec<u8>> {
+        // header: transaction, protocol and length, then the unit ID
+        if request.len() < 8 || request[2..4] != [0, 0] {
+            return None;
+        }
+        let unit_id = request[6];
+        let function = request[7];
+        let result = match (function, request.get(8..12)) {
+            (3 | 4, Some(pdu)) => {
+                let address = u16::from_be_bytes([pdu[0], pdu[1]]);
+                let count = u16::from_be_bytes([pdu[2], pdu[3]]);
+                self.read(unit_id, address, count)
+            }
+            (3 | 4, None) => Err(Exception::IllegalDataValue),
+            _ => Err(Exception::IllegalFunction),
+        };
+        let pdu = match result {
+            Ok(values) => {
+                let mut pdu = vec![function, (values.len() * 2) as u8];
+                pdu.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+                pdu
+            }
+            Err(exception) => vec![function | 0x80, exception as u8],
+        };
+        let mut response = request[..4].to_vec();
+        response.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        response.push(unit_id);
+        response.extend(pdu);
+        Some(response)
+    }
+}
+
+impl Events<Device> for Registers {
+    fn on_complete_block(&mut self, block: Device) {
+        self.update(&block)
+    }
+}
+
+/// Registers shared with a server, see [`serve`]
+impl Events<Device> for Arc<Mutex<Registers>> {
+    fn on_complete_block(&mut self, block: Device) {
+        lock(self).update(&block)
+    }
+}
+
+fn lock(registers: &Mutex<Registers>) -> std::sync::MutexGuard<'_, Registers> {
+    registers.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Serve the registers over Modbus TCP on `listener`, from background
+/// threads
+pub fn serve(listener: TcpListener, registers: Arc<Mutex<Registers>>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let registers = registers.clone();
+            // clients keep their connection open between requests
+            std::thread::spawn(move || {
+                let _ = handle(stream, &registers);
+            });
+        }
+    })
+}
+
+fn handle(mut stream: TcpStream, registers: &Mutex<Registers>) -> io::Result<()> {
+    loop {
+        let mut header = [0u8; 6];
+        match stream.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            other => other?,
+        }
+        let length = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        let mut request = header.to_vec();
+        request.resize(6 + length, 0);
+        stream.read_exact(&mut request[6..])?;
+        match lock(registers).respond(&request) {
+            Some(response) => stream.write_all(&response)?,
+            // not Modbus, e.g. another protocol on the wrong port
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Registers of the `com.victronenergy.battery` service
+pub fn battery_registers(bmv: &Bmv700) -> Vec<(u16, u16)> {
+    let mut registers = vec![
+        (258, signed(bmv.power as f32, 1.0)),
+        (259, unsigned(bmv.voltage, 100.0)),
+    ];
+    if let Some(ah) = bmv
+        .consumed
+        .as_ref()
+        .and_then(|mah| mah.parse::<f32>().ok())
+    {
+        registers.push((265, unsigned(ah / 1000.0, -10.0)));
+    }
+    if let Some(soc) = bmv.soc {
+        registers.push((266, unsigned(soc, 10.0)));
+    }
+    // -1 while the time to go is infinite
+    if bmv.ttg >= 0 {
+        registers.push((303, unsigned(bmv.ttg as f32 * 60.0, 0.01)));
+    }
+    registers
+}
+
+/// Registers of the `com.victronenergy.solarcharger` service
+pub fn solar_charger_registers(mppt: &MPPT) -> Vec<(u16, u16)> {
+    let mut registers = vec![
+        (771, unsigned(mppt.channel1_voltage, 100.0)),
+        (772, signed(mppt.battery_current, 10.0)),
+        (775, mppt.state_of_operation as u16),
+        (776, unsigned(mppt.panel_voltage, 100.0)),
+        // the yields are received in 0.01 kWh
+        (784, unsigned(mppt.yield_today as f32, 0.1)),
+        (785, unsigned(mppt.max_power_today as f32, 1.0)),
+        (786, unsigned(mppt.yield_yesterday as f32, 0.1)),
+        (787, unsigned(mppt.max_power_yesterday as f32, 1.0)),
+        (788, mppt.error_code as u16),
+        (789, unsigned(mppt.panel_power as f32, 10.0)),
+        (790, unsigned(mppt.yield_total as f32, 0.1)),
+        (791, mppt.tracker_mode as u16),
+    ];
+    if mppt.panel_voltage > 0.0 {
+        let current = mppt.panel_power as f32 / mppt.panel_voltage;
+        registers.push((777, signed(current, 10.0)));
+    }
+    if let Some(relay) = mppt.relay_state {
+        registers.push((780, u16::from(relay)));
+    }
+    registers.sort_unstable();
+    registers
+}
+
+fn unsigned(value: f32, factor: f32) -> u16 {
+    scale(value, factor).clamp(0, i64::from(u16::MAX)) as u16
+}
+
+fn signed(value: f32, factor: f32) -> u16 {
+    scale(value, factor).clamp(i64::from(i16::MIN), i64::from(i16::MAX)) as i16 as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::Parser;
+
+    fn bmv(soc: Option<f32>) -> Device {
+        Device::Bmv700(Bmv700 {
+            voltage: 12.28,
+            power: -28,
+            consumed: Some("-74900".into()),
+            soc,
+            ttg: 10350,
+        })
+    }
+
+    /// A Modbus TCP client reading registers
+    struct Client {
+        stream: TcpStream,
+        transaction: u16,
+    }
+
+    impl Client {
+        fn read(&mut self, unit_id: u8, address: u16, count: u16) -> Result<Vec<u16>, u8> {
+            self.transaction += 1;
+            let mut request = self.transaction.to_be_bytes().to_vec();
+            request.extend_from_slice(&[0, 0, 0, 6, unit_id, 3]);
+            request.extend_from_slice(&address.to_be_bytes());
+            request.extend_from_slice(&count.to_be_bytes());
+            self.stream.write_all(&request).unwrap();
+
+            let mut header = [0u8; 7];
+            self.stream.read_exact(&mut header).unwrap();
+            assert_eq!(header[..2], self.transaction.to_be_bytes());
+            assert_eq!(header[6], unit_id);
+            let length = u16::from_be_bytes([header[4], header[5]]);
+            let mut pdu = vec![0u8; usize::from(length) - 1];
+            self.stream.read_exact(&mut pdu).unwrap();
+            if pdu[0] == 3 | 0x80 {
+                return Err(pdu[1]);
+            }
+            assert_eq!(usize::from(pdu[1]), pdu.len() - 2);
+            Ok(pdu[2..]
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut registers = Registers::new();
+        registers.update(&bmv(Some(91.6)));
+        assert_eq!(
+            registers.read(BATTERY_UNIT_ID, 258, 2),
+            Ok(vec![(-28i16) as u16, 1228])
+        );
+        assert_eq!(registers.read(BATTERY_UNIT_ID, 265, 2), Ok(vec![749, 916]));
+        // 10350 minutes in units of 100 s
+        assert_eq!(registers.read(BATTERY_UNIT_ID, 303, 1), Ok(vec![6210]));
+        assert_eq!(
+            registers.read(BATTERY_UNIT_ID, 259, 2),
+            Err(Exception::IllegalDataAddress)
+        );
+        assert_eq!(
+            registers.read(BATTERY_UNIT_ID, 258, 200),
+            Err(Exception::IllegalDataValue)
+        );
+        assert_eq!(
+            registers.read(1, 258, 1),
+            Err(Exception::GatewayTargetFailed)
+        );
+        // an unsynchronised battery monitor
+        registers.update(&bmv(None));
+        assert_eq!(
+            registers.read(BATTERY_UNIT_ID, 266, 1),
+            Err(Exception::IllegalDataAddress)
+        );
+    }
+
+    #[test]
+    fn test_unit_ids() {
+        let mut parser = Parser::new(Registers::new().with_unit_id("other", SOLAR_CHARGER_UNIT_ID));
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let registers = parser.listener();
+        // the default was taken
+        assert_eq!(
+            registers.read(SOLAR_CHARGER_UNIT_ID + 1, 771, 2),
+            Ok(vec![1254, 0])
+        );
+    }
+
+    #[test]
+    fn test_serve() {
+        let registers = Arc::new(Mutex::new(Registers::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve(listener, registers.clone());
+
+        let mut parser = Parser::new(registers);
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let mut client = Client {
+            stream: TcpStream::connect(address).unwrap(),
+            transaction: 0,
+        };
+        assert_eq!(
+            client.read(SOLAR_CHARGER_UNIT_ID, 771, 2),
+            Ok(vec![1254, 0])
+        );
+        assert_eq!(
+            client.read(SOLAR_CHARGER_UNIT_ID, 775, 3),
+            Ok(vec![3, 1854, 3])
+        );
+        assert_eq!(
+            client.read(SOLAR_CHARGER_UNIT_ID, 784, 8),
+            Ok(vec![0, 6, 0, 14, 0, 50, 14, 2])
+        );
+        assert_eq!(client.read(SOLAR_CHARGER_UNIT_ID, 780, 1), Err(0x02));
+        assert_eq!(client.read(BATTERY_UNIT_ID, 259, 1), Err(0x0B));
+
+        // a function which is not supported
+        client
+            .stream
+            .write_all(&[0, 9, 0, 0, 0, 6, SOLAR_CHARGER_UNIT_ID, 6, 3, 3, 0, 1])
+            .unwrap();
+        let mut response = [0u8; 9];
+        client.stream.read_exact(&mut response).unwrap();
+        assert_eq!(
+            response,
+            [0, 9, 0, 0, 0, 3, SOLAR_CHARGER_UNIT_ID, 0x86, 0x01]
+        );
+    }
+}
@@ -0,0 +1,630 @@
+//! Publishing device data to an MQTT broker, enabled with the `mqtt`
+//! feature.
+//!
+//! A [`Publisher`] is a parser listener for [`Device`] data. For every
+//! block it publishes, under a prefix which defaults to `vedirect`:
+//!
+//! | topic                       | payload                                  |
+//! |-----------------------------|------------------------------------------|
+//! | `vedirect/<id>/<field>`     | the value of one field, e.g. `12.54`     |
+//! | `vedirect/<id>/state`       | all fields as a JSON object              |
+//! | `vedirect/status`           | `online` or `offline`, retained          |
+//!
+//! `<id>` is the serial number of the device. Devices which do not send
+//! one, such as BMV battery monitors, need an identifier set with
+//! [`MqttConfig::with_device_id`] or given to [`Publisher::publish_as`],
+//! so that several of them are kept apart. Field names are the serialized
+//! names without their unit, e.g. `battery_voltage` for
+//! `battery_voltage_v`. Fields without a value are not published.
+//!
+//! The status topic is the last will of the connection, so it changes
+//! to `offline` when the publisher disconnects for any reason. Once the
+//! connection is re-established, the status and the Home Assistant
+//! discovery configurations are published again with the next block.
+//!
+//! Devices can also be announced to Home Assistant, see
+//! [`homeassistant`](crate::homeassistant).
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use vedirect::mqtt::{MqttConfig, Publisher};
+//! use vedirect::Parser;
+//!
+//! let config = MqttConfig::new("localhost", 1883).with_min_interval(Duration::from_secs(10));
+//! let mut parser = Parser::new(Publisher::connect(config).unwrap());
+//! # let data = [];
+//! parser.feed(&data).unwrap();
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rumqttc::{Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::Value;
+
+use crate::{homeassistant, json, split_unit, Device, Events, VEError};
+
+/// Payload of the status topic while the publisher is connected
+pub const ONLINE: &str = "online";
+/// Payload of the status topic once the publisher disconnected
+pub const OFFLINE: &str = "offline";
+
+/// Connection to a broker, implemented for [`rumqttc::Client`] and
+/// [`ReconnectingClient`]
+pub trait MqttClient {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), VEError>;
+
+    /// Whether the connection was re-established since the last call,
+    /// after the broker published the last will
+    fn reconnected(&mut self) -> bool {
+        false
+    }
+}
+
+/// Messages are queued without blocking, and fail with
+/// [`io::ErrorKind::WouldBlock`] while the queue is full
+impl MqttClient for rumqttc::Client {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), VEError> {
+        rumqttc::Client::try_publish(self, topic, QoS::AtLeastOnce, retain, payload)
+            .map_err(|e| VEError::Io(io::Error::new(io::ErrorKind::WouldBlock, e)))
+    }
+}
+
+/// Client whose connection is handled by a background thread, see
+/// [`Publisher::connect`]
+///
+/// While the broker is unreachable and the queue is full, messages are
+/// dropped and counted. Dropping a retained message, such as the status
+/// or a discovery configuration, has them all published again with the
+/// next block.
+pub struct ReconnectingClient {
+    client: rumqttc::Client,
+    reconnected: Arc<AtomicBool>,
+    dropped: u64,
+}
+
+impl ReconnectingClient {
+    /// Number of messages dropped since the last call
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+impl MqttClient for ReconnectingClient {
+    fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), VEError> {
+        match MqttClient::publish(&mut self.client, topic, payload, retain) {
+            Err(VEError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.dropped += 1;
+                if retain {
+                    self.reconnected.store(true, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn reconnected(&mut self) -> bool {
+        self.reconnected.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Broker address and publishing options
+#[derive(Clone, PartialEq, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// First level of all topics
+    pub prefix: String,
+    /// Username and password
+    pub credentials: Option<(String, String)>,
+    /// Minimum time between two publications for the same device.
+    /// Blocks received sooner are dropped.
+    pub min_interval: Duration,
+    /// Prefix of the Home Assistant discovery topics, if devices should
+    /// be announced
+    pub discovery_prefix: Option<String>,
+    /// Identifier of devices without serial number
+    pub device_id: Option<String>,
+}
+
+impl MqttConfig {
+    pub fn new(host: &str, port: u16) -> Self {
+        MqttConfig {
+            host: host.into(),
+            port,
+            client_id: "vedirect".into(),
+            prefix: "vedirect".into(),
+            credentials: None,
+            min_interval: Duration::ZERO,
+            discovery_prefix: None,
+            device_id: None,
+        }
+    }
+
+    pub fn with_client_id(mut self, client_id: &str) -> Self {
+        self.client_id = client_id.into();
+        self
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_end_matches('/').into();
+        self
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Announce devices to Home Assistant, with its discovery prefix,
+    /// usually [`DISCOVERY_PREFIX`](crate::homeassistant::DISCOVERY_PREFIX)
+    pub fn with_discovery(mut self, discovery_prefix: &str) -> Self {
+        self.discovery_prefix = Some(discovery_prefix.trim_end_matches('/').into());
+        self
+    }
+
+    /// Identifier in topics of a device which does not send a serial
+    /// number, such as a BMV
+    pub fn with_device_id(mut self, device_id: &str) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+
+    /// Identifier of `device` in topics: its serial number, or the
+    /// configured identifier for devices which do not send one
+    pub fn device_id(&self, device: &Device) -> Result<String, VEError> {
+        let id = device
+            .serial_number()
+            .or(self.device_id.as_deref())
+            .ok_or_else(|| VEError::MissingField(text!("SER#")))?;
+        Ok(id.replace(['/', '+', '#'], "_"))
+    }
+
+    /// Topic of the retained `online`/`offline` status
+    pub fn status_topic(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    /// Options to connect to the broker, with the status topic as the
+    /// last will
+    pub fn mqtt_options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(&self.client_id, &self.host, self.port);
+        options
+            .set_keep_alive(Duration::from_secs(30))
+            .set_last_will(LastWill::new(
+                self.status_topic(),
+                OFFLINE,
+                QoS::AtLeastOnce,
+                true,
+            ));
+        if let Some((username, password)) = &self.credentials {
+            options.set_credentials(username, password);
+        }
+        options
+    }
+}
+
+/// Publishes device data, see the [module documentation](self)
+pub struct Publisher<C: MqttClient> {
+    client: C,
+    config: MqttConfig,
+    last_published: HashMap<String, Instant>,
+    announced: HashSet<String>,
+    error: Option<VEError>,
+}
+
+impl Publisher<ReconnectingClient> {
+    /// Connect to the broker. The connection is handled, and
+    /// re-established after failures, by a background thread which
+    /// runs until the publisher is dropped.
+    pub fn connect(config: MqttConfig) -> Result<Self, VEError> {
+        let (client, mut connection) = rumqttc::Client::new(config.mqtt_options(), 64);
+        let reconnected = Arc::new(AtomicBool::new(false));
+        let flag = reconnected.clone();
+        std::thread::spawn(move || {
+            let mut connected = false;
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        if connected {
+                            flag.store(true, Ordering::Relaxed);
+                        }
+                        connected = true;
+                    }
+                    Ok(_) => {}
+                    // retry the connection after a while
+                    Err(_) => std::thread::sleep(Duration::from_secs(1)),
+                }
+            }
+        });
+        Publisher::new(
+            ReconnectingClient {
+                client,
+                reconnected,
+                dropped: 0,
+            },
+            config,
+        )
+    }
+}
+
+impl<C: MqttClient> Publisher<C> {
+    /// Create a publisher on an established connection, publishing the
+    /// `online` status
+    pub fn new(mut client: C, config: MqttConfig) -> Result<Self, VEError> {
+        client.publish(&config.status_topic(), ONLINE.as_bytes(), true)?;
+        Ok(Publisher {
+            client,
+            config,
+            last_published: HashMap::new(),
+            announced: HashSet::new(),
+            error: None,
+        })
+    }
+
+    pub fn config(&self) -> &MqttConfig {
+        &self.config
+    }
+
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    pub fn client_mut(&mut self) -> &mut C {
+        &mut self.client
+    }
+
+    /// Publish the data of a device, unless it was published less than
+    /// the minimum interval ago. Returns whether it was published.
+    ///
+    /// Fails for devices without serial number unless an identifier was
+    /// configured, see [`MqttConfig::device_id`].
+    pub fn publish(&mut self, device: &Device) -> Result<bool, VEError> {
+        let id = self.config.device_id(device)?;
+        self.publish_as(&id, device)
+    }
+
+    /// Publish the data of a device under the identifier `id`, such as
+    /// the name of the port a battery monitor is connected to
+    pub fn publish_as(&mut self, id: &str, device: &Device) -> Result<bool, VEError> {
+        if self.client.reconnected() {
+            // the broker replaced the status with the last will, and
+            // may have lost the discovery configurations
+            let topic = self.config.status_topic();
+            self.client.publish(&topic, ONLINE.as_bytes(), true)?;
+            self.announced.clear();
+            self.last_published.clear();
+        }
+        let now = Instant::now();
+        if let Some(last) = self.last_published.get(id) {
+            if now.duration_since(*last) < self.config.min_interval {
+                return Ok(false);
+            }
+        }
+        if let Some(prefix) = &self.config.discovery_prefix {
+            if !self.announced.contains(id) {
+                for entity in homeassistant::entities(device, id, &self.config, prefix)? {
+                    let config = entity.config.to_string();
+                    self.client
+                        .publish(&entity.topic, config.as_bytes(), true)?;
+                }
+                self.announced.insert(id.into());
+            }
+        }
+        let fields = json::device_fields(device)?;
+        for (name, value) in &fields {
+            let payload = match value {
+                Value::Null => continue,
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            let topic = format!("{}/{}/{}", self.config.prefix, id, split_unit(name).0);
+            self.client.publish(&topic, payload.as_bytes(), false)?;
+        }
+        let state = Value::Object(fields).to_string();
+        let topic = format!("{}/{}/state", self.config.prefix, id);
+        self.client.publish(&topic, state.as_bytes(), false)?;
+        self.last_published.insert(id.into(), now);
+        Ok(true)
+    }
+
+    /// The first error which occurred while publishing blocks received
+    /// as a parser listener, if any
+    pub fn take_error(&mut self) -> Option<VEError> {
+        self.error.take()
+    }
+
+    /// Publish the `offline` status, returning the connection
+    pub fn close(mut self) -> Result<C, VEError> {
+        let topic = self.config.status_topic();
+        self.client.publish(&topic, OFFLINE.as_bytes(), true)?;
+        Ok(self.client)
+    }
+}
+
+impl<C: MqttClient> Events<Device> for Publisher<C> {
+    fn on_complete_block(&mut self, block: Device) {
+        if let Err(e) = self.publish(&block) {
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::{Bmv700, Parser};
+
+    /// A broker running in the test, keeping retained messages
+    #[derive(Default)]
+    struct Broker {
+        /// All messages received, in order
+        messages: Vec<(String, String)>,
+        retained: HashMap<String, String>,
+        will: Option<(String, String)>,
+        reconnected: bool,
+    }
+
+    impl Broker {
+        fn connect(options: &MqttOptions) -> Broker {
+            let will = options.last_will().map(|will| {
+                assert!(will.retain);
+                (
+                    will.topic,
+                    String::from_utf8(will.message.to_vec()).unwrap(),
+                )
+            });
+            Broker {
+                will,
+                ..Broker::default()
+            }
+        }
+
+        /// The client disconnected without closing the publisher
+        fn connection_lost(&mut self) {
+            if let Some((topic, message)) = self.will.clone() {
+                self.retained.insert(topic, message);
+            }
+        }
+
+        /// The client connected again, after the connection was lost
+        fn reconnect(&mut self) {
+            self.reconnected = true;
+        }
+
+        fn last(&self, topic: &str) -> Option<&str> {
+            self.messages
+                .iter()
+                .rev()
+                .find(|(t, _)| t == topic)
+                .map(|(_, m)| m.as_str())
+        }
+    }
+
+    impl MqttClient for Broker {
+        fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), VEError> {
+            let payload = String::from_utf8(payload.to_vec()).unwrap();
+            if retain {
+                self.retained.insert(topic.into(), payload.clone());
+            }
+            self.messages.push((topic.into(), payload));
+            Ok(())
+        }
+
+        fn reconnected(&mut self) -> bool {
+            std::mem::take(&mut self.reconnected)
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let config = MqttConfig::new("localhost", 1883).with_prefix("solar/");
+        let broker = Broker::connect(&config.mqtt_options());
+        let mut parser = Parser::new(Publisher::new(broker, config).unwrap());
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+
+        let broker = parser.listener().client();
+        assert_eq!(broker.retained["solar/status"], "online");
+        assert_eq!(
+            broker.last("solar/HQ2132QY2KR/battery_voltage"),
+            Some("12.54")
+        );
+        assert_eq!(broker.last("solar/HQ2132QY2KR/yield_total"), Some("1.44"));
+        assert_eq!(
+            broker.last("solar/HQ2132QY2KR/load_output_on"),
+            Some("true")
+        );
+        assert_eq!(
+            broker.last("solar/HQ2132QY2KR/state_of_operation"),
+            Some("Bulk")
+        );
+        let state: Value =
+            serde_json::from_str(broker.last("solar/HQ2132QY2KR/state").unwrap()).unwrap();
+        assert_eq!(state["battery_voltage_v"], 12.54);
+        assert_eq!(state["serial_number"], "HQ2132QY2KR");
+        // no retained data
+        assert_eq!(broker.retained.len(), 1);
+
+        let mut publisher = parser.into_listener();
+        assert!(publisher.take_error().is_none());
+        let broker = publisher.close().unwrap();
+        assert_eq!(broker.retained["solar/status"], "offline");
+
+        // the broker publishes the last will when the connection is lost
+        let config = MqttConfig::new("localhost", 1883);
+        let publisher = Publisher::new(Broker::connect(&config.mqtt_options()), config).unwrap();
+        let mut broker = publisher.client;
+        assert_eq!(broker.retained["vedirect/status"], "online");
+        broker.connection_lost();
+        assert_eq!(broker.retained["vedirect/status"], "offline");
+    }
+
+    #[test]
+    fn test_discovery() {
+        let config = MqttConfig::new("localhost", 1883).with_discovery("homeassistant/");
+        let mut parser = Parser::new(Publisher::new(Broker::default(), config).unwrap());
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+
+        let broker = parser.listener().client();
+        let discovery: Vec<_> = broker
+            .messages
+            .iter()
+            .filter(|(topic, _)| topic.starts_with("homeassistant/"))
+            .collect();
+        // announced once, before the data
+        assert_eq!(discovery.len(), 20);
+        assert_eq!(broker.messages[1].0, discovery[0].0);
+        let (topic, config) = discovery
+            .iter()
+            .find(|(topic, _)| topic.ends_with("/panel_power/config"))
+            .unwrap();
+        assert_eq!(broker.retained[topic], *config);
+        let config: Value = serde_json::from_str(config).unwrap();
+        assert_eq!(config["state_topic"], "vedirect/HQ2132QY2KR/panel_power");
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let config = MqttConfig::new("localhost", 1883)
+            .with_discovery("homeassistant")
+            .with_min_interval(Duration::from_secs(60));
+        let broker = Broker::connect(&config.mqtt_options());
+        let mut parser = Parser::new(Publisher::new(broker, config).unwrap());
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let broker = parser.listener_mut().client_mut();
+        broker.connection_lost();
+        broker
+            .retained
+            .retain(|topic, _| topic == "vedirect/status");
+        assert_eq!(broker.retained["vedirect/status"], "offline");
+        broker.reconnect();
+        broker.messages.clear();
+
+        // published again despite the minimum interval
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let broker = parser.listener().client();
+        assert_eq!(broker.messages[0].0, "vedirect/status");
+        assert_eq!(broker.retained["vedirect/status"], "online");
+        assert_eq!(broker.retained.len(), 21);
+        assert_eq!(broker.last("vedirect/HQ2132QY2KR/panel_power"), Some("5"));
+
+        // rate limited again until the next reconnection
+        let published = broker.messages.len();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        assert_eq!(parser.listener().client().messages.len(), published);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let config = MqttConfig::new("localhost", 1883)
+            .with_min_interval(Duration::from_millis(50))
+            .with_device_id("house");
+        let mut publisher = Publisher::new(Broker::default(), config).unwrap();
+        let bmv = Device::Bmv700(Bmv700 {
+            voltage: 12.5,
+            power: -20,
+            consumed: None,
+            soc: Some(95.5),
+            ttg: 600,
+        });
+        assert!(publisher.publish(&bmv).unwrap());
+        assert!(!publisher.publish(&bmv).unwrap());
+
+        let broker = publisher.client();
+        assert_eq!(broker.last("vedirect/house/soc"), Some("95.5"));
+        assert_eq!(broker.last("vedirect/house/consumed"), None);
+        let published = broker.messages.len();
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(publisher.publish(&bmv).unwrap());
+        assert_eq!(publisher.client().messages.len(), 2 * published - 1);
+    }
+
+    #[test]
+    fn test_device_id() {
+        let bmv = Device::Bmv700(Bmv700 {
+            voltage: 12.5,
+            power: -20,
+            consumed: None,
+            soc: None,
+            ttg: 600,
+        });
+        let config = MqttConfig::new("localhost", 1883);
+        let mut publisher = Publisher::new(Broker::default(), config).unwrap();
+        // several monitors would share one topic
+        assert!(matches!(
+            publisher.publish(&bmv),
+            Err(VEError::MissingField(label)) if label == "SER#"
+        ));
+        assert!(publisher.publish_as("starter", &bmv).unwrap());
+        assert_eq!(
+            publisher.client().last("vedirect/starter/voltage"),
+            Some("12.5")
+        );
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let config = MqttConfig::new("localhost", 1883).with_discovery("homeassistant");
+        // nothing takes messages off the queue, as if the broker was down
+        let (mut client, _connection) = rumqttc::Client::new(config.mqtt_options(), 1);
+        MqttClient::publish(&mut client, "vedirect/status", b"online", true).unwrap();
+        assert!(matches!(
+            MqttClient::publish(&mut client, "vedirect/status", b"online", true),
+            Err(VEError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock
+        ));
+
+        let (client, _connection) = rumqttc::Client::new(config.mqtt_options(), 1);
+        let client = ReconnectingClient {
+            client,
+            reconnected: Arc::new(AtomicBool::new(false)),
+            dropped: 0,
+        };
+        let mut parser = Parser::new(Publisher::new(client, config).unwrap());
+        // the parser is not blocked
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+
+        let mut publisher = parser.into_listener();
+        assert!(publisher.take_error().is_none());
+        let dropped = publisher.client_mut().take_dropped();
+        assert!(dropped > 40, "{} dropped", dropped);
+        assert_eq!(publisher.client_mut().take_dropped(), 0);
+        // the discovery configurations were dropped, so they are published
+        // again with the next block
+        assert!(publisher.client_mut().reconnected());
+    }
+
+    #[test]
+    fn test_mqtt_options() {
+        let options = MqttConfig::new("broker", 8883)
+            .with_client_id("boat")
+            .with_credentials("user", "secret")
+            .mqtt_options();
+        assert_eq!(options.client_id(), "boat");
+        assert_eq!(options.broker_address(), ("broker".into(), 8883));
+        let will = options.last_will().unwrap();
+        assert_eq!(will.topic, "vedirect/status");
+        assert_eq!(&will.message[..], b"offline");
+        assert_eq!(
+            options.credentials(),
+            Some(("user".into(), "secret".into()))
+        );
+    }
+}
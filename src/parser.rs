@@ -1,12 +1,14 @@
-use std::{collections::HashMap, marker::PhantomData};
+use core::{marker::PhantomData, time::Duration};
 
-use crate::{data, VEError};
+use crate::{
+    data,
+    fields::{FieldRef, Label},
+    hex::{HexFrame, MAX_HEX_LINE},
+    Fields, Text, VEError, MAX_FIELDS, MAX_LABEL_LEN, MAX_VALUE_LEN,
+};
 
-#[derive(Debug)]
-pub struct VEField {
-    pub label: String,
-    pub value: Vec<u8>,
-}
+/// Default capacity in bytes of the parser's block buffer
+pub const DEFAULT_BUFFER_SIZE: usize = 1024;
 
 /// Parser is fed with bytes, and sends events to the supplied
 /// `listener` object.
@@ -27,175 +29,345 @@ pub struct VEField {
 /// let mut parser = Parser::new(&mut listener);
 /// parser.feed(b"\r\nPID\t0xA053\r\nFW\t159").unwrap();
 /// ```
-
-pub struct Parser<'a, D: data::VEDirectData, E: Events<D>> {
-    first_parse: bool,
-    parse_buf: Vec<u8>,
-    fields: HashMap<String, Vec<u8>>,
-    listener: &'a mut E,
-    phanton: PhantomData<(&'a E, D)>,
+///
+/// The listener can be passed either by mutable reference (as above)
+/// or by value, in which case the parser owns it and it can be
+/// accessed with [`Parser::listener`] and [`Parser::into_listener`].
+///
+/// # Capacity
+///
+/// The parser does not allocate. Every byte is looked at once, and
+/// the labels and values of the block being received are stored in a
+/// buffer of `N` bytes, from which they are handed to
+/// [`VEDirectData::fill`](crate::VEDirectData::fill) without copying.
+///
+/// If a block has more than [`MAX_FIELDS`] fields, a label or value
+/// is longer than [`MAX_LABEL_LEN`] or [`MAX_VALUE_LEN`], or the
+/// labels and values of a block do not fit in the buffer, the whole
+/// block is discarded: the listener receives
+/// [`VEError::CapacityExceeded`] through [`Events::on_parse_error`]
+/// and the parser skips ahead to the start of the next block.
+pub struct Parser<D: data::VEDirectData, E: Events<D>, const N: usize = DEFAULT_BUFFER_SIZE> {
+    state: State,
+    skip_block: bool,
+    parse_buf: heapless::Vec<u8, N>,
+    fields: heapless::Vec<FieldRef, MAX_FIELDS>,
+    label_start: usize,
+    value_start: usize,
+    label: Label,
+    hex_buf: heapless::Vec<u8, MAX_HEX_LINE>,
+    hex_overflow: bool,
+    verify_checksum: bool,
+    /// Sum of the bytes of the block being received
+    checksum: u8,
+    listener: E,
+    phanton: PhantomData<D>,
 }
 
-pub trait Events<D: data::VEDirectData> {
+pub trait Events<D> {
     fn on_complete_block(&mut self, _block: D) {}
-    fn on_missing_field(&mut self, _label: String) {}
+    fn on_missing_field(&mut self, _label: Text) {}
     fn on_mapping_error(&mut self, _error: VEError) {}
     fn on_parse_error(&mut self, _error: VEError, _parse_buf: &[u8]) {}
+    /// A HEX mode message was received, see [`crate::hex`]. Messages
+    /// with an invalid checksum are reported to
+    /// [`Events::on_parse_error`] instead.
+    fn on_hex_frame(&mut self, _frame: HexFrame) {}
+    /// No block was mapped for `elapsed`, longer than the stale
+    /// timeout of a `reconnect::ReconnectingReader`.
+    /// Reported once, until a block is mapped again.
+    fn on_stale(&mut self, _elapsed: Duration) {}
+}
+
+impl<D, E: Events<D> + ?Sized> Events<D> for &mut E {
+    fn on_complete_block(&mut self, block: D) {
+        (**self).on_complete_block(block)
+    }
+    fn on_missing_field(&mut self, label: Text) {
+        (**self).on_missing_field(label)
+    }
+    fn on_mapping_error(&mut self, error: VEError) {
+        (**self).on_mapping_error(error)
+    }
+    fn on_parse_error(&mut self, error: VEError, parse_buf: &[u8]) {
+        (**self).on_parse_error(error, parse_buf)
+    }
+    fn on_hex_frame(&mut self, frame: HexFrame) {
+        (**self).on_hex_frame(frame)
+    }
+    fn on_stale(&mut self, elapsed: Duration) {
+        (**self).on_stale(elapsed)
+    }
+}
+
+/// Position in the byte stream
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    /// Skipping to the next CR, as we might have started somewhere in
+    /// the middle of a field or recovered from an error
+    Sync,
+    /// After a block's checksum, expecting the next field or a HEX
+    /// message
+    Idle,
+    /// Got CR, expecting the LF starting a field
+    FieldStart,
+    Label,
+    Value,
+    /// Expecting the single checksum byte ending the block
+    Checksum,
+    /// Inside a HEX mode message, which can periodically occur
+    Hex,
 }
 
 const CR: u8 = 13;
 const LF: u8 = 10;
 const TAB: u8 = 9;
 const COLON: u8 = 58;
-const A: u8 = 65;
 
-impl<'a, E: Events<D>, D: data::VEDirectData> Parser<'a, D, E> {
-    /// Create parser
-    pub fn new(listener: &'a mut E) -> Self {
+impl<E: Events<D>, D: data::VEDirectData> Parser<D, E> {
+    /// Create parser with the default buffer size
+    pub fn new(listener: E) -> Self {
+        Parser::with_buffer_size(listener)
+    }
+}
+
+impl<E: Events<D>, D: data::VEDirectData, const N: usize> Parser<D, E, N> {
+    // Field positions in the buffer are stored as u16
+    const BUFFER_SIZE_OK: () = assert!(N <= u16::MAX as usize, "buffer size too large");
+
+    /// Create parser with a block buffer of `N` bytes
+    ///
+    /// ```rust
+    /// # struct Listener;
+    /// # impl vedirect::Events<vedirect::MPPT> for Listener {}
+    /// let mut parser = vedirect::Parser::<vedirect::MPPT, _, 256>::with_buffer_size(Listener);
+    /// ```
+    pub fn with_buffer_size(listener: E) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::BUFFER_SIZE_OK;
         Parser {
-            first_parse: true,
-            parse_buf: Vec::new(),
-            fields: HashMap::new(),
+            state: State::Sync,
+            skip_block: false,
+            parse_buf: heapless::Vec::new(),
+            fields: heapless::Vec::new(),
+            label_start: 0,
+            value_start: 0,
+            label: Label::Other,
+            hex_buf: heapless::Vec::new(),
+            hex_overflow: false,
+            verify_checksum: false,
+            checksum: 0,
             listener,
             phanton: PhantomData,
         }
     }
 
-    fn parse_field(data: &[u8], read_pos: usize) -> Result<(VEField, usize), VEError> {
-        if read_pos + 1 >= data.len() {
-            return Err(VEError::NeedMoreData);
-        }
+    /// Check the checksum of every block, discarding blocks whose
+    /// bytes do not add up to 0 modulo 256 and reporting them as
+    /// [`VEError::ChecksumError`] through [`Events::on_parse_error`].
+    /// This is off by default.
+    pub fn with_checksum_verification(mut self, verify: bool) -> Self {
+        self.verify_checksum = verify;
+        self
+    }
 
-        let mut cp = read_pos;
-
-        if data[cp] == CR && data[cp + 1] == LF {
-            cp += 2;
-            match data[cp..].iter().position(|&c| c == TAB) {
-                Some(pos) => {
-                    let label =
-                        String::from_utf8((&data[cp..(cp + pos)]).to_vec()).map_err(|e| {
-                            VEError::Parse(format!("label string was invalid UTF-8: {}", e))
-                        })?;
-
-                    cp = cp + pos + 1; // +1 to skip TAB
-                    let endpos_res = data[cp..].iter().position(|&c| c == CR);
-                    match endpos_res {
-                        Some(endpos) => {
-                            let value = &data[cp..(cp + endpos)];
-                            Ok((
-                                VEField {
-                                    label,
-                                    value: value.to_vec(),
-                                },
-                                cp + endpos,
-                            ))
-                        }
-                        None => {
-                            if label == "Checksum" {
-                                let value = &data[cp..];
-                                Ok((
-                                    VEField {
-                                        label,
-                                        value: value.to_vec(),
-                                    },
-                                    data.len(),
-                                ))
-                            } else {
-                                Err(VEError::NeedMoreData)
-                            }
-                        }
-                    }
-                }
-                None => Err(VEError::NeedMoreData),
-            }
-        } else {
-            Err(VEError::Parse("Illegal field start".to_string()))
-        }
+    /// The listener receiving events from this parser
+    pub fn listener(&self) -> &E {
+        &self.listener
+    }
+
+    /// Mutable access to the listener receiving events from this parser
+    pub fn listener_mut(&mut self) -> &mut E {
+        &mut self.listener
+    }
+
+    /// Consume the parser, returning the listener
+    pub fn into_listener(self) -> E {
+        self.listener
+    }
+
+    /// Discard the block being received, as after reopening a port,
+    /// and wait for the start of the next one
+    pub fn reset(&mut self) {
+        self.state = State::Sync;
+        self.skip_block = false;
+        self.parse_buf.clear();
+        self.fields.clear();
+        self.hex_buf.clear();
+        self.hex_overflow = false;
+        self.checksum = 0;
     }
 
     /// Supply bytes from device to parser. See example on [`Parser`]
     /// or the `read_serial` example for details on how to use.
+    ///
+    /// Returns [`VEError::NeedMoreData`] if the parser has not yet
+    /// found the start of a field or HEX message, and `data` does not
+    /// contain one.
     pub fn feed(&mut self, data: &[u8]) -> Result<(), VEError> {
-        if self.first_parse {
-            // skip to first field start as we might have started somewhere in the middle
-            match data.iter().position(|&c| c == CR) {
-                Some(pos) => self.parse_buf.extend_from_slice(&data[pos..]),
-                None => return Err(VEError::NeedMoreData),
+        if self.state == State::Sync && !data.iter().any(|b| *b == CR || *b == COLON) {
+            return Err(VEError::NeedMoreData);
+        }
+        for &byte in data {
+            if let Err(e) = self.feed_byte(byte) {
+                self.recover(e);
             }
-            self.first_parse = false;
-        } else {
-            self.parse_buf.extend(data);
         }
+        Ok(())
+    }
 
-        let mut cp = 0;
-        loop {
-            // skip hex mode messages, those can periodically occur
-            while cp + 1 < self.parse_buf.len()
-                && self.parse_buf[cp] == COLON
-                && self.parse_buf[cp + 1] == A
-            {
-                match self.parse_buf[cp..].iter().position(|&c| c == LF) {
-                    Some(pos) => {
-                        if cp + pos + 1 < self.parse_buf.len() {
-                            cp = cp + pos + 1;
-                        } else {
-                            self.parse_buf.clear();
-                            return Ok(());
-                        }
-                    }
-                    None => return Ok(()),
+    fn feed_byte(&mut self, byte: u8) -> Result<(), VEError> {
+        if !matches!(self.state, State::Sync | State::Idle | State::Hex) {
+            self.checksum = self.checksum.wrapping_add(byte);
+        }
+        match self.state {
+            State::Sync => match byte {
+                CR => {
+                    self.checksum = CR;
+                    self.state = State::FieldStart;
+                }
+                // devices answer HEX commands before sending any block
+                COLON => self.state = State::Hex,
+                _ => {}
+            },
+            State::Idle => match byte {
+                CR => {
+                    self.checksum = CR;
+                    self.state = State::FieldStart;
+                }
+                COLON => self.state = State::Hex,
+                // tolerate trailing garbage after the checksum
+                _ => self.state = State::Sync,
+            },
+            State::FieldStart => {
+                if byte != LF {
+                    return Err(VEError::Parse(text!("Illegal field start")));
                 }
+                self.label_start = self.parse_buf.len();
+                self.state = State::Label;
             }
-
-            match Parser::<D, E>::parse_field(&self.parse_buf, cp) {
-                Ok((field, read_pos)) => {
-                    cp = read_pos;
-                    if &field.label == "Checksum" {
-                        match D::fill(&self.fields) {
-                            Ok(mapped) => {
-                                self.listener.on_complete_block(mapped);
-                                // block_complete(mapped);
-                                self.fields.clear();
-                            }
-                            Err(VEError::MissingField(label)) => {
-                                // we didn't get all needed fields to map
-                                // reset and hope for more in the next block
-                                self.listener.on_missing_field(label);
-                                self.fields.clear(); // reset fields
-                                self.parse_buf.drain(0..cp);
-                                cp = 0;
-                            }
-                            Err(e) => self.listener.on_mapping_error(e),
-                        }
+            State::Label => {
+                if byte == TAB {
+                    let label = &self.parse_buf[self.label_start..];
+                    core::str::from_utf8(label).map_err(|e| {
+                        VEError::Parse(text!("label string was invalid UTF-8: {}", e))
+                    })?;
+                    self.label = Label::from_bytes(label);
+                    if self.label == Label::Checksum {
+                        self.parse_buf.truncate(self.label_start);
+                        self.state = State::Checksum;
                     } else {
-                        self.fields.insert(field.label, field.value);
+                        self.value_start = self.parse_buf.len();
+                        self.state = State::Value;
                     }
+                } else if self.parse_buf.len() - self.label_start >= MAX_LABEL_LEN {
+                    return Err(VEError::CapacityExceeded);
+                } else {
+                    self.push(byte)?;
                 }
-                Err(VEError::NeedMoreData) => {
-                    let clear_range = if cp > self.parse_buf.len() {
-                        self.parse_buf.len()
-                    } else {
-                        cp
-                    };
-                    self.parse_buf.drain(0..clear_range);
-                    break;
+            }
+            State::Value => {
+                if byte == CR {
+                    self.complete_field()?;
+                    self.state = State::FieldStart;
+                } else if self.parse_buf.len() - self.value_start >= MAX_VALUE_LEN {
+                    return Err(VEError::CapacityExceeded);
+                } else {
+                    self.push(byte)?;
                 }
-                Err(e) => {
-                    self.listener.on_parse_error(e, &self.parse_buf);
-                    self.parse_buf.clear();
-                    self.fields.clear(); // reset fields
-                    self.first_parse = true;
-                    break;
+            }
+            State::Checksum => {
+                self.complete_block();
+                self.state = State::Idle;
+            }
+            State::Hex => {
+                if byte == LF {
+                    self.state = State::Idle;
+                    self.complete_hex();
+                } else if self.hex_buf.push(byte).is_err() {
+                    self.hex_overflow = true;
                 }
             }
         }
         Ok(())
     }
+
+    fn push(&mut self, byte: u8) -> Result<(), VEError> {
+        self.parse_buf
+            .push(byte)
+            .map_err(|_| VEError::CapacityExceeded)
+    }
+
+    fn complete_field(&mut self) -> Result<(), VEError> {
+        if self.skip_block {
+            self.parse_buf.clear();
+            return Ok(());
+        }
+        // lengths are limited by MAX_LABEL_LEN/MAX_VALUE_LEN, and
+        // `N` by the u16 positions
+        let field = FieldRef {
+            label: self.label,
+            label_start: self.label_start as u16,
+            value_start: self.value_start as u16,
+            value_end: self.parse_buf.len() as u16,
+        };
+        self.fields
+            .push(field)
+            .map_err(|_| VEError::CapacityExceeded)
+    }
+
+    fn complete_block(&mut self) {
+        if self.skip_block {
+            // end of a discarded block
+            self.skip_block = false;
+        } else if self.verify_checksum && self.checksum != 0 {
+            self.listener
+                .on_parse_error(VEError::ChecksumError, &self.parse_buf);
+        } else {
+            match D::fill(&Fields::new(&self.parse_buf, &self.fields)) {
+                Ok(mapped) => self.listener.on_complete_block(mapped),
+                Err(VEError::MissingField(label)) => {
+                    // we didn't get all needed fields to map
+                    // reset and hope for more in the next block
+                    self.listener.on_missing_field(label)
+                }
+                Err(e) => self.listener.on_mapping_error(e),
+            }
+        }
+        self.parse_buf.clear();
+        self.fields.clear();
+    }
+
+    fn complete_hex(&mut self) {
+        let result = if self.hex_overflow {
+            Err(VEError::CapacityExceeded)
+        } else {
+            HexFrame::decode(&self.hex_buf)
+        };
+        match result {
+            Ok(frame) => self.listener.on_hex_frame(frame),
+            Err(e) => self.listener.on_parse_error(e, &self.hex_buf),
+        }
+        self.hex_buf.clear();
+        self.hex_overflow = false;
+    }
+
+    fn recover(&mut self, error: VEError) {
+        if let VEError::CapacityExceeded = error {
+            self.skip_block = true;
+        }
+        self.listener.on_parse_error(error, &self.parse_buf);
+        self.parse_buf.clear();
+        self.fields.clear(); // reset fields
+        self.state = State::Sync;
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::len_zero)]
 mod tests {
     use super::*;
+    use crate::fixtures::MPPT_BLOCK;
 
     struct CollectorBmv700 {
         data: Vec<data::Bmv700>,
@@ -222,48 +394,119 @@ mod tests {
 
         // Should have some data remaining
         assert!(parser.parse_buf.len() > 0);
-        assert_eq!(parser.parse_buf.len(), 2);
+        assert_eq!(&parser.parse_buf[..], b"PID0xA381");
         // Got one block valid data
         assert_eq!(collector.data.len(), 1);
     }
 
     #[test]
-    fn test_parse_field() {
-        let data = "\r\nPID\t0xA053\r\nFW\t159\r\nChecksum\t?".as_bytes();
+    fn test_checksum_verification() {
+        // blocks from a BMV-712, with their original checksums
+        let main = "\r\nPID\t0xA381\r\nV\t12282\r\nVS\t29\r\nI\t-2288\r\nP\t-28\r\nCE\t-74900\r\nSOC\t916\r\nTTG\t10350\r\nAlarm\tOFF\r\nRelay\tOFF\r\nAR\t0\r\nBMV\t712 Smart\r\nFW\t0403\r\nChecksum\t~";
+        let history = "\r\nH1\t-76138\r\nH2\t-76138\r\nH3\t0\r\nH4\t0\r\nH5\t0\r\nH6\t-1876218\r\nH7\t12171\r\nH8\t20418\r\nH9\t1199744\r\nH10\t0\r\nH11\t0\r\nH12\t0\r\nH15\t20\r\nH16\t21033\r\nH17\t2404\r\nH18\t2415\r\nChecksum\t\u{3}";
+        let mut parser = Parser::new(CollectorRaw {
+            blocks: vec![],
+            errors: vec![],
+        })
+        .with_checksum_verification(true);
+
+        // starting in the middle of a block, with an async HEX message
+        parser.feed(b"12\r\nChecksum\tX:A0102000543\n").unwrap();
+        parser.feed(main.as_bytes()).unwrap();
+        parser.feed(history.as_bytes()).unwrap();
+        parser
+            .feed(main.replace("12282", "12283").as_bytes())
+            .unwrap();
+        parser.feed(main.as_bytes()).unwrap();
+        let collector = parser.listener();
+        assert_eq!(collector.blocks.len(), 3);
+        assert_eq!(collector.blocks[1].fields[0].0, "H1");
+        assert_eq!(
+            collector.errors,
+            vec![
+                "checksum did not match recieved data",
+                "checksum did not match recieved data"
+            ]
+        );
 
-        let (field, read_pos) =
-            Parser::<data::Bmv700, CollectorBmv700>::parse_field(data, 0).unwrap();
-        assert_eq!(field.label, "PID");
-        assert_eq!(field.value, "0xA053".as_bytes());
-        assert_eq!(read_pos, 12);
+        // off by default
+        let mut parser = Parser::new(CollectorRaw {
+            blocks: vec![],
+            errors: vec![],
+        });
+        parser
+            .feed(main.replace("12282", "12283").as_bytes())
+            .unwrap();
+        assert_eq!(parser.listener().blocks.len(), 1);
+    }
 
-        let (field, read_pos) =
-            Parser::<data::Bmv700, CollectorBmv700>::parse_field(data, 12).unwrap();
-        assert_eq!(field.label, "FW");
-        assert_eq!(field.value, "159".as_bytes());
-        assert_eq!(read_pos, 20);
+    /// Collects the raw fields of every block
+    struct RawBlock {
+        fields: Vec<(String, Vec<u8>)>,
+    }
 
-        assert_eq!(
-            Parser::<data::Bmv700, CollectorBmv700>::parse_field(data, 19)
-                .err()
-                .unwrap()
-                .to_string(),
-            "error parsing data: Illegal field start".to_string()
-        );
+    impl data::VEDirectData for RawBlock {
+        fn fill(fields: &Fields<'_>) -> Result<Self, VEError> {
+            Ok(RawBlock {
+                fields: fields
+                    .iter()
+                    .map(|(l, v)| (String::from_utf8(l.to_vec()).unwrap(), v.to_vec()))
+                    .collect(),
+            })
+        }
+    }
+
+    struct CollectorRaw {
+        blocks: Vec<RawBlock>,
+        errors: Vec<String>,
+    }
 
-        let (field, read_pos) =
-            Parser::<data::Bmv700, CollectorBmv700>::parse_field(data, 20).unwrap();
-        assert_eq!(field.label, "Checksum");
-        assert_eq!(read_pos, 32);
+    impl Events<RawBlock> for CollectorRaw {
+        fn on_complete_block(&mut self, block: RawBlock) {
+            self.blocks.push(block);
+        }
 
-        let data = "\r\nFW\t159".as_bytes();
+        fn on_parse_error(&mut self, error: VEError, _parse_buf: &[u8]) {
+            self.errors.push(error.to_string());
+        }
+    }
+
+    #[test]
+    fn test_parse_field() {
+        let data = "\r\nPID\t0xA053\r\nFW\t159\r\nChecksum\t?".as_bytes();
+        let mut parser = Parser::new(CollectorRaw {
+            blocks: vec![],
+            errors: vec![],
+        });
+
+        // Fields are complete once the CR starting the next one arrives
+        parser.feed(&data[..18]).unwrap();
+        assert_eq!(&parser.parse_buf[..], b"PID0xA053FW1");
+        assert_eq!(parser.fields.len(), 1);
+
+        parser.feed(&data[18..]).unwrap();
+        let collector = parser.listener();
+        assert_eq!(collector.blocks.len(), 1);
+        let fields = &collector.blocks[0].fields;
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].0, "PID");
+        assert_eq!(fields[0].1, "0xA053".as_bytes());
+        assert_eq!(fields[1].0, "FW");
+        assert_eq!(fields[1].1, "159".as_bytes());
+        assert!(collector.errors.is_empty());
+
+        // A field start must be CR LF
+        parser.feed(b"\r9\r\nFW\t159").unwrap();
         assert_eq!(
-            Parser::<data::Bmv700, CollectorBmv700>::parse_field(data, 0)
-                .err()
-                .unwrap()
-                .to_string(),
-            "Need more data to parse successfully".to_string()
+            parser.listener().errors,
+            vec!["error parsing data: Illegal field start".to_string()]
         );
+
+        // Re-synchronised on the following CR
+        assert_eq!(&parser.parse_buf[..], b"FW159");
+        parser.feed(b"\r\nChecksum\tX").unwrap();
+        assert_eq!(parser.listener().blocks.len(), 2);
+        assert_eq!(parser.listener().blocks[1].fields[0].0, "FW");
     }
 
     struct CollectorMPPT {
@@ -365,6 +608,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reset() {
+        let mut collector = CollectorMPPT { data: vec![] };
+        let mut parser = Parser::new(&mut collector);
+        // the port is reopened in the middle of a block, and the
+        // device sends the end of another one
+        parser.feed(&MPPT_BLOCK.as_bytes()[..40]).unwrap();
+        parser.reset();
+        assert!(parser.parse_buf.is_empty());
+        parser.feed(&MPPT_BLOCK.as_bytes()[40..]).unwrap();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        assert_eq!(collector.data.len(), 1);
+        assert_eq!(collector.data[0].serial_number, "HQ2132QY2KR");
+    }
+
     #[test]
     fn test_incomplete_block_reset() {
         let datas = vec![
@@ -462,4 +720,86 @@ mod tests {
             crate::data::TrackerOperationMode::MPPTrackerActive
         );
     }
+
+    struct CapacityCollector {
+        blocks: usize,
+        capacity_errors: usize,
+    }
+
+    impl Events<data::MPPT> for CapacityCollector {
+        fn on_complete_block(&mut self, _block: data::MPPT) {
+            self.blocks += 1;
+        }
+
+        fn on_parse_error(&mut self, error: VEError, _parse_buf: &[u8]) {
+            assert!(matches!(error, VEError::CapacityExceeded));
+            self.capacity_errors += 1;
+        }
+    }
+
+    #[test]
+    fn test_too_many_fields() {
+        let mut oversized = String::from(MPPT_BLOCK.trim_end_matches("\r\nChecksum\t?"));
+        for i in 0..crate::MAX_FIELDS {
+            oversized.push_str(&format!("\r\nX{}\t0", i));
+        }
+        oversized.push_str("\r\nChecksum\t?");
+
+        let mut parser = Parser::new(CapacityCollector {
+            blocks: 0,
+            capacity_errors: 0,
+        });
+        parser.feed(oversized.as_bytes()).unwrap();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+
+        // The oversized block is dropped entirely, the next one is fine
+        let collector = parser.into_listener();
+        assert_eq!(collector.capacity_errors, 1);
+        assert_eq!(collector.blocks, 1);
+    }
+
+    #[test]
+    fn test_small_buffer() {
+        // Labels and values of MPPT_BLOCK are 114 bytes
+        let mut parser = Parser::<_, _, 128>::with_buffer_size(CapacityCollector {
+            blocks: 0,
+            capacity_errors: 0,
+        });
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        assert_eq!(parser.listener().blocks, 1);
+
+        // A block which does not fit in the buffer is skipped
+        let long_serial = MPPT_BLOCK.replace("HQ2132QY2KR", &"Q".repeat(30));
+        parser.feed(long_serial.as_bytes()).unwrap();
+        assert_eq!(parser.listener().capacity_errors, 1);
+        assert_eq!(parser.listener().blocks, 1);
+
+        // As is a block with a value longer than allowed
+        let long_serial = MPPT_BLOCK.replace("HQ2132QY2KR", &"Q".repeat(MAX_VALUE_LEN + 1));
+        let mut parser = Parser::new(parser.into_listener());
+        parser.feed(long_serial.as_bytes()).unwrap();
+        assert_eq!(parser.listener().capacity_errors, 2);
+        assert_eq!(parser.listener().blocks, 1);
+
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        assert_eq!(parser.listener().blocks, 2);
+    }
+
+    #[test]
+    fn test_byte_by_byte() {
+        let stream = format!(
+            "{}{}",
+            MPPT_BLOCK,
+            MPPT_BLOCK.replace("Checksum\t?", "Checksum\t\r")
+        );
+        let mut parser = Parser::<_, _, 128>::with_buffer_size(CapacityCollector {
+            blocks: 0,
+            capacity_errors: 0,
+        });
+        for byte in stream.as_bytes() {
+            parser.feed(&[*byte]).unwrap();
+        }
+        // the parse error handler asserts no other errors occur
+        assert_eq!(parser.listener().blocks, 2);
+    }
 }
@@ -0,0 +1,428 @@
+//! Prometheus metrics, enabled with the `prometheus` feature.
+//!
+//! [`Metrics`] is a parser listener keeping the latest data of every
+//! device, and counting the blocks and errors received. It renders them
+//! in the Prometheus text exposition format, which can be served by
+//! any HTTP server, or by the small one started with [`serve`]:
+//!
+//! ```rust,no_run
+//! use std::{net::TcpListener, sync::{Arc, Mutex}};
+//! use vedirect::prometheus::{self, Metrics};
+//! use vedirect::Parser;
+//!
+//! let metrics = Arc::new(Mutex::new(Metrics::new()));
+//! prometheus::serve(TcpListener::bind("0.0.0.0:9109").unwrap(), metrics.clone());
+//! let mut parser = Parser::new(metrics).with_checksum_verification(true);
+//! ```
+//!
+//! Readings are exported as gauges named after their field and unit,
+//! e.g. `vedirect_battery_voltage_volts`, labelled with the kind of
+//! device and, when known, its serial number and product ID. Devices
+//! which send no serial number, such as BMV battery monitors, are kept
+//! apart by the identifier given to [`Metrics::record_as`], e.g. the
+//! name of their port, exported as the `id` label. Fields
+//! with text values are exported as `_info` metrics with the text as
+//! a label, and booleans as 0 or 1.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use serde_json::{Map, Value};
+
+use crate::{json, split_unit, Device, Events, HexFrame, Text, VEError};
+
+/// Prefix of all metric names
+const PREFIX: &str = "vedirect";
+
+/// Fields exported as labels rather than metrics
+const LABELS: &[&str] = &["serial_number", "product_id"];
+
+/// Fields which only increase, exported as counters
+const COUNTERS: &[&str] = &["yield_total"];
+
+/// Latest readings and parser statistics
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    /// Labels and fields of every device, by their labels
+    devices: BTreeMap<String, Map<String, Value>>,
+    blocks: u64,
+    checksum_errors: u64,
+    missing_fields: u64,
+    mapping_errors: u64,
+    parse_errors: u64,
+    hex_frames: u64,
+    /// Blocks mapped but not exported, which is a bug of the exporter
+    export_errors: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Replace the readings of a device
+    pub fn update(&mut self, device: &Device) -> Result<(), VEError> {
+        self.update_labelled(labels(None, device), device)
+    }
+
+    /// Count a block received from the device identified by `id`, such
+    /// as the name of the port it is connected to, and replace its
+    /// readings
+    pub fn record_as(&mut self, id: &str, device: &Device) {
+        self.record(labels(Some(id), device), device);
+    }
+
+    fn record(&mut self, labels: String, device: &Device) {
+        self.blocks += 1;
+        if self.update_labelled(labels, device).is_err() {
+            self.export_errors += 1;
+        }
+    }
+
+    fn update_labelled(&mut self, labels: String, device: &Device) -> Result<(), VEError> {
+        let fields = json::device_fields(device)?;
+        self.devices.insert(labels, fields);
+        Ok(())
+    }
+
+    /// Render all metrics in the text exposition format
+    pub fn render(&self) -> String {
+        // samples of every metric, in the order they are first seen
+        let mut families: Vec<(String, &str, Vec<String>)> = vec![];
+        for (labels, fields) in &self.devices {
+            for (field, value) in fields {
+                let (name, unit) = split_unit(field);
+                if LABELS.contains(&name) {
+                    continue;
+                }
+                let (metric, sample_labels, value) = match value {
+                    Value::Number(n) => (metric_name(name, unit), labels.clone(), n.to_string()),
+                    Value::Bool(b) => (
+                        metric_name(name, unit),
+                        labels.clone(),
+                        u8::from(*b).to_string(),
+                    ),
+                    // e.g. consumed amp hours, sent as text
+                    Value::String(s) if s.parse::<f64>().is_ok() => {
+                        (metric_name(name, unit), labels.clone(), s.clone())
+                    }
+                    Value::String(s) => (
+                        format!("{}_{}_info", PREFIX, name),
+                        format!("{},{}=\"{}\"", labels, name, escape(s)),
+                        "1".into(),
+                    ),
+                    _ => continue,
+                };
+                let kind = if COUNTERS.contains(&name) {
+                    "counter"
+                } else {
+                    "gauge"
+                };
+                let sample = format!("{}{{{}}} {}", metric, sample_labels, value);
+                match families.iter_mut().find(|(m, _, _)| *m == metric) {
+                    Some((_, _, samples)) => samples.push(sample),
+                    None => families.push((metric, kind, vec![sample])),
+                }
+            }
+        }
+
+        let mut out = String::new();
+        for (metric, kind, samples) in families {
+            let _ = writeln!(out, "# TYPE {} {}", metric, kind);
+            for sample in samples {
+                let _ = writeln!(out, "{}", sample);
+            }
+        }
+        let counters = [
+            ("blocks", "Blocks received and mapped", self.blocks),
+            (
+                "checksum_errors",
+                "Blocks with an invalid checksum",
+                self.checksum_errors,
+            ),
+            (
+                "missing_fields",
+                "Blocks missing a field needed to map them",
+                self.missing_fields,
+            ),
+            (
+                "mapping_errors",
+                "Blocks with a field which could not be mapped",
+                self.mapping_errors,
+            ),
+            (
+                "parse_errors",
+                "Other errors in the received data",
+                self.parse_errors,
+            ),
+            ("hex_frames", "HEX messages received", self.hex_frames),
+            (
+                "export_errors",
+                "Blocks which could not be exported",
+                self.export_errors,
+            ),
+        ];
+        for (name, help, count) in counters {
+            let _ = writeln!(out, "# HELP {}_{}_total {}", PREFIX, name, help);
+            let _ = writeln!(out, "# TYPE {}_{}_total counter", PREFIX, name);
+            let _ = writeln!(out, "{}_{}_total {}", PREFIX, name, count);
+        }
+        out
+    }
+}
+
+impl Events<Device> for Metrics {
+    fn on_complete_block(&mut self, block: Device) {
+        self.record(labels(None, &block), &block);
+    }
+
+    fn on_missing_field(&mut self, _label: Text) {
+        self.missing_fields += 1;
+    }
+
+    fn on_mapping_error(&mut self, _error: VEError) {
+        self.mapping_errors += 1;
+    }
+
+    fn on_parse_error(&mut self, error: VEError, _parse_buf: &[u8]) {
+        match error {
+            VEError::ChecksumError => self.checksum_errors += 1,
+            _ => self.parse_errors += 1,
+        }
+    }
+
+    fn on_hex_frame(&mut self, _frame: HexFrame) {
+        self.hex_frames += 1;
+    }
+}
+
+/// Metrics shared with a server, see [`serve`]
+impl Events<Device> for Arc<Mutex<Metrics>> {
+    fn on_complete_block(&mut self, block: Device) {
+        lock(self).on_complete_block(block)
+    }
+
+    fn on_missing_field(&mut self, label: Text) {
+        lock(self).on_missing_field(label)
+    }
+
+    fn on_mapping_error(&mut self, error: VEError) {
+        lock(self).on_mapping_error(error)
+    }
+
+    fn on_parse_error(&mut self, error: VEError, parse_buf: &[u8]) {
+        lock(self).on_parse_error(error, parse_buf)
+    }
+
+    fn on_hex_frame(&mut self, frame: HexFrame) {
+        lock(self).on_hex_frame(frame)
+    }
+}
+
+fn lock(metrics: &Mutex<Metrics>) -> std::sync::MutexGuard<'_, Metrics> {
+    // the metrics stay consistent if a thread panicked while holding them
+    metrics.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Serve the metrics over HTTP on `listener`, at `/metrics`, from a
+/// background thread
+pub fn serve(listener: TcpListener, metrics: Arc<Mutex<Metrics>>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let metrics = metrics.clone();
+            // a slow or misbehaving client only delays its own request
+            std::thread::spawn(move || {
+                let _ = respond(stream, &metrics);
+            });
+        }
+    })
+}
+
+fn respond(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", lock(metrics).render()),
+        (Some("GET"), _) => ("404 Not Found", "Metrics are served at /metrics\n".into()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// e.g. `vedirect_battery_voltage_volts`
+fn metric_name(name: &str, unit: &str) -> String {
+    let unit = match unit {
+        "V" => "_volts",
+        "A" => "_amperes",
+        "W" => "_watts",
+        "kWh" => "_kwh",
+        "mAh" => "_milliamp_hours",
+        "%" => "_percent",
+        "min" => "_minutes",
+        _ => "",
+    };
+    format!("{}_{}{}", PREFIX, name, unit)
+}
+
+/// Labels of the samples of a device
+fn labels(id: Option<&str>, device: &Device) -> String {
+    let mut labels = format!("device=\"{}\"", device.kind().name());
+    if let Some(id) = id {
+        let _ = write!(labels, ",id=\"{}\"", escape(id));
+    }
+    if let Some(serial) = device.serial_number() {
+        let _ = write!(labels, ",serial=\"{}\"", escape(serial));
+    }
+    if let Some(product) = device.product_id() {
+        let _ = write!(labels, ",product=\"{}\"", escape(product));
+    }
+    labels
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::{Bmv700, Parser};
+    use std::io::Read;
+
+    #[test]
+    fn test_render() {
+        let mut parser = Parser::new(Metrics::new());
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(b"\r\nV\t12540\r\nChecksum\t?").unwrap();
+        parser.feed(b"\r9\r\n").unwrap();
+        let mut metrics = parser.into_listener();
+        metrics
+            .update(&Device::Bmv700(Bmv700 {
+                voltage: 12.5,
+                power: -20,
+                consumed: Some("-5300".into()),
+                soc: None,
+                ttg: 600,
+            }))
+            .unwrap();
+
+        let text = metrics.render();
+        let mppt = r#"{device="mppt",serial="HQ2132QY2KR",product="0xA053"}"#;
+        for line in [
+            "# TYPE vedirect_battery_voltage_volts gauge".to_string(),
+            format!("vedirect_battery_voltage_volts{} 12.54", mppt),
+            "vedirect_voltage_volts{device=\"bmv700\"} 12.5".into(),
+            format!("vedirect_battery_current_amperes{} 0.04", mppt),
+            format!("vedirect_panel_power_watts{} 5", mppt),
+            "# TYPE vedirect_yield_total_kwh counter".into(),
+            format!("vedirect_yield_total_kwh{} 1.44", mppt),
+            format!("vedirect_load_output_on{} 1", mppt),
+            format!(
+                "vedirect_state_of_operation_info{{{},state_of_operation=\"Bulk\"}} 1",
+                &mppt[1..mppt.len() - 1]
+            ),
+            "vedirect_consumed_milliamp_hours{device=\"bmv700\"} -5300".into(),
+            "vedirect_blocks_total 1".into(),
+            "vedirect_missing_fields_total 1".into(),
+            "vedirect_parse_errors_total 1".into(),
+            "vedirect_checksum_errors_total 0".into(),
+            "vedirect_export_errors_total 0".into(),
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} in\n{}", line, text);
+        }
+        // unknown values, and labels
+        assert!(!text.contains("soc_percent"));
+        assert!(!text.contains("vedirect_serial_number"));
+        // one TYPE line per metric
+        assert_eq!(
+            text.matches("# TYPE vedirect_battery_voltage_volts ")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_record_as() {
+        let bmv = |voltage| {
+            Device::Bmv700(Bmv700 {
+                voltage,
+                power: -20,
+                consumed: None,
+                soc: None,
+                ttg: 600,
+            })
+        };
+        let mut metrics = Metrics::new();
+        // two monitors without serial number
+        metrics.record_as("house", &bmv(12.5));
+        metrics.record_as("starter", &bmv(12.7));
+        metrics.record_as("house", &bmv(12.4));
+
+        let text = metrics.render();
+        for line in [
+            "vedirect_voltage_volts{device=\"bmv700\",id=\"house\"} 12.4",
+            "vedirect_voltage_volts{device=\"bmv700\",id=\"starter\"} 12.7",
+            "vedirect_blocks_total 3",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} in\n{}", line, text);
+        }
+        assert_eq!(text.matches("vedirect_voltage_volts{").count(), 2);
+    }
+
+    #[test]
+    fn test_serve() {
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        serve(listener, metrics.clone());
+
+        let mut parser = Parser::new(metrics).with_checksum_verification(true);
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+
+        // a client which never sends its request
+        let _idle = TcpStream::connect(address).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            // shorter than the read timeout of the server
+            stream
+                .set_read_timeout(Some(Duration::from_secs(3)))
+                .unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("\nvedirect_checksum_errors_total 1\n"));
+        assert!(response.contains("\nvedirect_blocks_total 0\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -0,0 +1,358 @@
+//! Reading from sources which come and go, such as USB serial
+//! adapters.
+//!
+//! A [`ReconnectingReader`] opens its source with a closure, feeds
+//! what it reads to a [`Parser`], and when reading fails drops the
+//! source and opens it again, waiting longer after each failure. The
+//! parser is reset after reconnecting, so no block is made of data
+//! from before and after.
+//!
+//! When no block has been mapped for the stale timeout, whether the
+//! source is gone or sends nothing valid, the listener receives
+//! [`Events::on_stale`] once, until a block is mapped again:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use vedirect::reconnect::ReconnectingReader;
+//! use vedirect::{Bmv700, Events};
+//!
+//! struct Listener;
+//!
+//! impl Events<Bmv700> for Listener {
+//!     fn on_complete_block(&mut self, block: Bmv700) {
+//!         println!("{:?}", block);
+//!     }
+//!
+//!     fn on_stale(&mut self, elapsed: Duration) {
+//!         println!("no data for {:?}", elapsed);
+//!     }
+//! }
+//!
+//! let open = || {
+//!     serialport::new("/dev/ttyUSB0", 19_200)
+//!         .timeout(Duration::from_secs(1))
+//!         .open()
+//!         .map_err(std::io::Error::from)
+//! };
+//! let mut reader = ReconnectingReader::new(open, Listener)
+//!     .with_stale_timeout(Duration::from_secs(10));
+//! loop {
+//!     if let Err(e) = reader.poll() {
+//!         eprintln!("{}", e);
+//!     }
+//! }
+//! ```
+
+use std::{
+    io::{self, Read},
+    time::{Duration, Instant},
+};
+
+use crate::{data::VEDirectData, hex::HexFrame, Events, Parser, Text, VEError};
+
+/// Default delay before the first attempt to reopen a source
+pub const DEFAULT_MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Default longest delay between attempts to reopen a source
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Default time without a mapped block after which data is stale
+pub const DEFAULT_STALE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What [`ReconnectingReader::poll`] did
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    /// The source was opened
+    Opened,
+    /// Bytes were read from the source and fed to the parser, 0 when
+    /// reading timed out
+    Read(usize),
+    /// Waiting before the next attempt to open the source
+    Waiting,
+}
+
+/// Forwards events to the listener, noting when a block was mapped
+struct Watch<E> {
+    listener: E,
+    mapped: bool,
+}
+
+impl<D: VEDirectData, E: Events<D>> Events<D> for Watch<E> {
+    fn on_complete_block(&mut self, block: D) {
+        self.mapped = true;
+        self.listener.on_complete_block(block)
+    }
+    fn on_missing_field(&mut self, label: Text) {
+        self.listener.on_missing_field(label)
+    }
+    fn on_mapping_error(&mut self, error: VEError) {
+        self.listener.on_mapping_error(error)
+    }
+    fn on_parse_error(&mut self, error: VEError, parse_buf: &[u8]) {
+        self.listener.on_parse_error(error, parse_buf)
+    }
+    fn on_hex_frame(&mut self, frame: HexFrame) {
+        self.listener.on_hex_frame(frame)
+    }
+    fn on_stale(&mut self, elapsed: Duration) {
+        self.listener.on_stale(elapsed)
+    }
+}
+
+/// Reads a source opened by `F` into a parser, see the
+/// [module documentation](self)
+pub struct ReconnectingReader<D: VEDirectData, E: Events<D>, R, F> {
+    open: F,
+    source: Option<R>,
+    parser: Parser<D, Watch<E>>,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    /// Delay before the next attempt after a failure
+    backoff: Duration,
+    next_attempt: Instant,
+    stale_timeout: Duration,
+    last_block: Instant,
+    stale: bool,
+}
+
+impl<D, E, R, F> ReconnectingReader<D, E, R, F>
+where
+    D: VEDirectData,
+    E: Events<D>,
+    R: Read,
+    F: FnMut() -> io::Result<R>,
+{
+    /// Read sources returned by `open`, which is first called by
+    /// [`poll`](Self::poll). Reads should time out, so staleness can be
+    /// detected while the source is silent.
+    pub fn new(open: F, listener: E) -> Self {
+        let now = Instant::now();
+        ReconnectingReader {
+            open,
+            source: None,
+            parser: Parser::new(Watch {
+                listener,
+                mapped: false,
+            }),
+            min_backoff: DEFAULT_MIN_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            backoff: DEFAULT_MIN_BACKOFF,
+            next_attempt: now,
+            stale_timeout: DEFAULT_STALE_TIMEOUT,
+            last_block: now,
+            stale: false,
+        }
+    }
+
+    /// Wait `min` after the first failure, doubling the delay after
+    /// each following one up to `max`
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self.backoff = min;
+        self
+    }
+
+    /// Report [`Events::on_stale`] when no block was mapped for `timeout`
+    pub fn with_stale_timeout(mut self, timeout: Duration) -> Self {
+        self.stale_timeout = timeout;
+        self
+    }
+
+    /// Open the source if needed, or read from it once.
+    ///
+    /// Returns the error when the source could not be opened or read,
+    /// in which case it is opened again by a later call, after the
+    /// backoff delay. While waiting, this sleeps until the next attempt
+    /// or until the data becomes stale, whichever comes first.
+    pub fn poll(&mut self) -> io::Result<Status> {
+        let status = match &mut self.source {
+            Some(source) => {
+                let mut buf = [0u8; 256];
+                match source.read(&mut buf) {
+                    Ok(0) => Err(self.disconnect(io::ErrorKind::UnexpectedEof.into())),
+                    Ok(count) => {
+                        // errors in the data are reported to the listener
+                        let _ = self.parser.feed(&buf[..count]);
+                        Ok(Status::Read(count))
+                    }
+                    Err(e) if is_timeout(&e) => Ok(Status::Read(0)),
+                    Err(e) => Err(self.disconnect(e)),
+                }
+            }
+            None => {
+                let now = Instant::now();
+                if now < self.next_attempt {
+                    let stale_at = self.last_block + self.stale_timeout;
+                    let wake = match self.stale {
+                        false if stale_at > now => self.next_attempt.min(stale_at),
+                        _ => self.next_attempt,
+                    };
+                    std::thread::sleep(wake - now);
+                }
+                if Instant::now() < self.next_attempt {
+                    Ok(Status::Waiting)
+                } else {
+                    match (self.open)() {
+                        Ok(source) => {
+                            self.source = Some(source);
+                            self.parser.reset();
+                            Ok(Status::Opened)
+                        }
+                        Err(e) => Err(self.failed(e)),
+                    }
+                }
+            }
+        };
+        self.check_stale();
+        status
+    }
+
+    fn disconnect(&mut self, error: io::Error) -> io::Error {
+        self.source = None;
+        self.failed(error)
+    }
+
+    fn failed(&mut self, error: io::Error) -> io::Error {
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(self.max_backoff);
+        error
+    }
+
+    fn check_stale(&mut self) {
+        let now = Instant::now();
+        let watch = self.parser.listener_mut();
+        if watch.mapped {
+            watch.mapped = false;
+            self.last_block = now;
+            self.stale = false;
+            // the source works, reconnect quickly the next time
+            self.backoff = self.min_backoff;
+        } else if !self.stale && now - self.last_block >= self.stale_timeout {
+            self.stale = true;
+            watch.listener.on_stale(now - self.last_block);
+        }
+    }
+
+    /// Whether the source is open
+    pub fn is_connected(&self) -> bool {
+        self.source.is_some()
+    }
+
+    /// Whether no block was mapped for the stale timeout
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// The listener receiving events from the parser
+    pub fn listener(&self) -> &E {
+        &self.parser.listener().listener
+    }
+
+    /// Mutable access to the listener receiving events from the parser
+    pub fn listener_mut(&mut self) -> &mut E {
+        &mut self.parser.listener_mut().listener
+    }
+
+    /// Consume the reader, returning the listener
+    pub fn into_listener(self) -> E {
+        self.parser.into_listener().listener
+    }
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Collector, MPPT_BLOCK};
+    use crate::MPPT;
+    use std::io::Cursor;
+
+    /// A source returning its chunks, one per read, then timing out
+    struct Chunks(Vec<io::Result<Vec<u8>>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let chunk = self.0.remove(0)?;
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let block = MPPT_BLOCK.as_bytes();
+        let mut sources = vec![
+            Err(io::ErrorKind::NotFound.into()),
+            // unplugged in the middle of a block
+            Ok(Chunks(vec![
+                Ok(block.to_vec()),
+                Ok(block[..60].to_vec()),
+                Err(io::ErrorKind::BrokenPipe.into()),
+            ])),
+            Ok(Chunks(vec![Ok(block[60..].to_vec()), Ok(block.to_vec())])),
+        ];
+        let open = move || sources.remove(0);
+        let backoff = Duration::from_millis(5);
+        let mut reader = ReconnectingReader::new(open, Collector::<MPPT>::default())
+            .with_backoff(backoff, Duration::from_millis(20));
+
+        let error = reader.poll().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(!reader.is_connected());
+        let started = Instant::now();
+        assert_eq!(reader.poll().unwrap(), Status::Opened);
+        assert!(started.elapsed() >= backoff);
+        assert_eq!(reader.poll().unwrap(), Status::Read(block.len()));
+        assert_eq!(reader.poll().unwrap(), Status::Read(60));
+        assert_eq!(reader.poll().unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert!(!reader.is_connected());
+        assert_eq!(reader.poll().unwrap(), Status::Opened);
+        assert!(reader.is_connected());
+        assert_eq!(reader.poll().unwrap(), Status::Read(block.len() - 60));
+        assert_eq!(reader.poll().unwrap(), Status::Read(block.len()));
+        assert_eq!(reader.poll().unwrap(), Status::Read(0));
+
+        // the end of the interrupted block was not mixed with its start
+        let collector = reader.into_listener();
+        assert_eq!(collector.blocks.len(), 2);
+        assert!(collector.stale.is_empty());
+    }
+
+    #[test]
+    fn test_stale() {
+        let timeout = Duration::from_millis(20);
+        let mut reader = ReconnectingReader::new(
+            || Ok(Cursor::new(MPPT_BLOCK.repeat(2))),
+            Collector::<MPPT>::default(),
+        )
+        .with_stale_timeout(timeout)
+        .with_backoff(Duration::from_secs(60), Duration::from_secs(60));
+
+        assert_eq!(reader.poll().unwrap(), Status::Opened);
+        while reader.poll().is_ok() {}
+        assert!(!reader.is_stale());
+        assert_eq!(reader.listener().blocks.len(), 2);
+
+        // the source is gone, waiting to reopen it is cut short to
+        // report stale data on time
+        let started = Instant::now();
+        assert_eq!(reader.poll().unwrap(), Status::Waiting);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(reader.is_stale());
+        let stale = &reader.listener().stale;
+        assert_eq!(stale.len(), 1);
+        assert!(stale[0] >= timeout);
+
+        // reported once
+        reader.check_stale();
+        assert_eq!(reader.listener().stale.len(), 1);
+    }
+}
@@ -0,0 +1,308 @@
+//! Signal K delta output, enabled with the `signalk` feature.
+//!
+//! [`delta`] converts the data of a device to a Signal K delta message
+//! updating the paths of the vessel, in the units of the specification:
+//!
+//! | device          | path                                                    | unit  |
+//! |-----------------|---------------------------------------------------------|-------|
+//! | battery monitor | `electrical.batteries.<id>.voltage`                     | V     |
+//! |                 | `electrical.batteries.<id>.power`                       | W     |
+//! |                 | `electrical.batteries.<id>.capacity.stateOfCharge`      | ratio |
+//! |                 | `electrical.batteries.<id>.capacity.dischargeSinceFull` | C     |
+//! |                 | `electrical.batteries.<id>.capacity.timeRemaining`      | s     |
+//! | solar charger   | `electrical.solar.<id>.voltage`                         | V     |
+//! |                 | `electrical.solar.<id>.current`                         | A     |
+//! |                 | `electrical.solar.<id>.panelVoltage`                    | V     |
+//! |                 | `electrical.solar.<id>.panelCurrent`                    | A     |
+//! |                 | `electrical.solar.<id>.panelPower`                      | W     |
+//! |                 | `electrical.solar.<id>.chargingMode`                    |       |
+//! |                 | `electrical.solar.<id>.load`                            |       |
+//! |                 | `electrical.solar.<id>.loadCurrent`                     | A     |
+//! |                 | `electrical.solar.<id>.yieldToday`                      | J     |
+//!
+//! `<id>` is the serial number of the device, or its kind (`bmv700`) for
+//! devices which do not send one, unless set with
+//! [`DeltaWriter::with_id`]. Values which are not known are left out.
+//!
+//! A [`DeltaWriter`] is a parser listener writing the deltas as lines
+//! of JSON to any [`Write`], such as the TCP connection to a Signal K
+//! server, or the standard output of a plugin:
+//!
+//! ```rust,no_run
+//! use vedirect::signalk::DeltaWriter;
+//! use vedirect::Parser;
+//!
+//! let mut parser = Parser::new(DeltaWriter::new(std::io::stdout()).with_id("bmv700", "house"));
+//! # let data = [];
+//! parser.feed(&data).unwrap();
+//! ```
+
+use std::{collections::HashMap, io::Write, time::SystemTime};
+
+use serde_json::{json, Value};
+
+use crate::{datetime, widen, Bmv700, Device, Events, StateOfOperation, VEError, MPPT};
+
+/// Default label of the source of the deltas
+pub const SOURCE_LABEL: &str = "vedirect";
+
+/// Joules in a kWh
+const JOULES_PER_KWH: f64 = 3_600_000.0;
+
+/// A delta updating the values of a device, known as `id`, from the
+/// source `label`
+pub fn delta(
+    device: &Device,
+    id: &str,
+    label: &str,
+    timestamp: SystemTime,
+) -> Result<Value, VEError> {
+    let (prefix, values) = match device {
+        Device::Bmv700(bmv) => (format!("electrical.batteries.{}", id), battery_values(bmv)),
+        Device::Mppt(mppt) => (format!("electrical.solar.{}", id), solar_values(mppt)),
+    };
+    let values: Vec<Value> = values
+        .into_iter()
+        .map(|(path, value)| json!({ "path": format!("{}.{}", prefix, path), "value": value }))
+        .collect();
+    Ok(json!({
+        "context": "vessels.self",
+        "updates": [{
+            "source": { "label": label, "type": "VE.Direct" },
+            "timestamp": datetime::rfc3339(timestamp)?,
+            "values": values,
+        }],
+    }))
+}
+
+fn battery_values(bmv: &Bmv700) -> Vec<(&'static str, Value)> {
+    let mut values = vec![
+        ("voltage", widen(bmv.voltage).into()),
+        ("power", bmv.power.into()),
+    ];
+    if let Some(soc) = bmv.soc {
+        values.push(("capacity.stateOfCharge", widen(soc / 100.0).into()));
+    }
+    // consumed amp hours are negative, in mAh, of 3.6 C
+    if let Some(mah) = bmv.consumed.as_ref().and_then(|ce| ce.parse::<f64>().ok()) {
+        values.push(("capacity.dischargeSinceFull", (-mah * 3.6).into()));
+    }
+    // -1 while the time to go is infinite
+    if bmv.ttg >= 0 {
+        values.push(("capacity.timeRemaining", (bmv.ttg * 60).into()));
+    }
+    values
+}
+
+fn solar_values(mppt: &MPPT) -> Vec<(&'static str, Value)> {
+    let mut values = vec![
+        ("voltage", widen(mppt.channel1_voltage).into()),
+        ("current", widen(mppt.battery_current).into()),
+        ("panelVoltage", widen(mppt.panel_voltage).into()),
+    ];
+    if mppt.panel_voltage > 0.0 {
+        let current = mppt.panel_power as f32 / mppt.panel_voltage;
+        values.push((
+            "panelCurrent",
+            widen((current * 100.0).round() / 100.0).into(),
+        ));
+    }
+    values.extend([
+        ("panelPower", mppt.panel_power.into()),
+        (
+            "chargingMode",
+            charging_mode(mppt.state_of_operation).into(),
+        ),
+        (
+            "load",
+            if mppt.load_output_state { "on" } else { "off" }.into(),
+        ),
+        ("loadCurrent", widen(mppt.load_current).into()),
+        // received in 0.01 kWh
+        (
+            "yieldToday",
+            (f64::from(mppt.yield_today) / 100.0 * JOULES_PER_KWH).into(),
+        ),
+    ]);
+    values
+}
+
+/// One of the charging modes of the specification
+fn charging_mode(state: StateOfOperation) -> &'static str {
+    match state {
+        StateOfOperation::Bulk => "bulk",
+        StateOfOperation::Absorption | StateOfOperation::RepeatedAbsorption => "acceptance",
+        StateOfOperation::Float | StateOfOperation::Storage => "float",
+        StateOfOperation::Equalize | StateOfOperation::AutoEqualize => "equalize",
+        _ => "other",
+    }
+}
+
+/// Writes a delta per block to `W`, one per line, see the
+/// [module documentation](self)
+pub struct DeltaWriter<W: Write> {
+    writer: W,
+    label: String,
+    /// Ids of devices, by serial number or by kind
+    ids: HashMap<String, String>,
+    error: Option<VEError>,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    pub fn new(writer: W) -> Self {
+        DeltaWriter {
+            writer,
+            label: SOURCE_LABEL.into(),
+            ids: HashMap::new(),
+            error: None,
+        }
+    }
+
+    /// Label of the source of the deltas
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /// Use `id` in the paths of the device with serial number `serial`,
+    /// or for devices which do not send one, of the kind `serial`, e.g.
+    /// `bmv700`
+    pub fn with_id(mut self, serial: &str, id: &str) -> Self {
+        self.ids.insert(serial.into(), id.into());
+        self
+    }
+
+    /// Write the delta of a device, received at `timestamp`
+    pub fn write(&mut self, device: &Device, timestamp: SystemTime) -> Result<(), VEError> {
+        let key = device
+            .serial_number()
+            .unwrap_or_else(|| device.kind().name());
+        let id = self.ids.get(key).map(String::as_str).unwrap_or(key);
+        let delta = delta(device, id, &self.label, timestamp)?;
+        writeln!(self.writer, "{}", delta)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// The first error which occurred while writing blocks received as
+    /// a parser listener, if any
+    pub fn take_error(&mut self) -> Option<VEError> {
+        self.error.take()
+    }
+}
+
+impl<W: Write> Events<Device> for DeltaWriter<W> {
+    fn on_complete_block(&mut self, block: Device) {
+        if let Err(e) = self.write(&block, SystemTime::now()) {
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::Parser;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn values(delta: &Value) -> Vec<(String, Value)> {
+        delta["updates"][0]["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| (v["path"].as_str().unwrap().to_string(), v["value"].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_battery_delta() {
+        let bmv = Device::Bmv700(Bmv700 {
+            voltage: 12.28,
+            power: -28,
+            consumed: Some("-74900".into()),
+            soc: Some(91.6),
+            ttg: 10350,
+        });
+        let timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let delta = delta(&bmv, "house", SOURCE_LABEL, timestamp).unwrap();
+        assert_eq!(delta["context"], "vessels.self");
+        assert_eq!(
+            delta["updates"][0]["source"],
+            json!({ "label": "vedirect", "type": "VE.Direct" })
+        );
+        assert_eq!(delta["updates"][0]["timestamp"], "2023-11-14T22:13:20Z");
+        assert_eq!(
+            values(&delta),
+            vec![
+                ("electrical.batteries.house.voltage".into(), json!(12.28)),
+                ("electrical.batteries.house.power".into(), json!(-28)),
+                (
+                    "electrical.batteries.house.capacity.stateOfCharge".into(),
+                    json!(0.916)
+                ),
+                (
+                    "electrical.batteries.house.capacity.dischargeSinceFull".into(),
+                    json!(269640.0)
+                ),
+                (
+                    "electrical.batteries.house.capacity.timeRemaining".into(),
+                    json!(621000)
+                ),
+            ]
+        );
+
+        // not synchronised, and not discharging
+        let bmv = Device::Bmv700(Bmv700 {
+            voltage: 12.28,
+            power: 5,
+            consumed: None,
+            soc: None,
+            ttg: -1,
+        });
+        assert_eq!(
+            values(&super::delta(&bmv, "house", "", timestamp).unwrap()).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_writer() {
+        let mut parser = Parser::new(DeltaWriter::new(vec![]).with_label("cabin"));
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        let mut writer = parser.into_listener();
+        assert!(writer.take_error().is_none());
+
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert_eq!(output.lines().count(), 2);
+        let delta: Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        assert_eq!(delta["updates"][0]["source"]["label"], "cabin");
+        let values = values(&delta);
+        let value = |path: &str| {
+            values
+                .iter()
+                .find(|(p, _)| p == &format!("electrical.solar.HQ2132QY2KR.{}", path))
+                .unwrap_or_else(|| panic!("no {} in {:?}", path, values))
+                .1
+                .clone()
+        };
+        assert_eq!(value("voltage"), json!(12.54));
+        assert_eq!(value("current"), json!(0.04));
+        assert_eq!(value("panelVoltage"), json!(18.54));
+        assert_eq!(value("panelCurrent"), json!(0.27));
+        assert_eq!(value("panelPower"), json!(5));
+        assert_eq!(value("chargingMode"), json!("bulk"));
+        assert_eq!(value("load"), json!("on"));
+        assert_eq!(value("loadCurrent"), json!(0.3));
+        assert_eq!(value("yieldToday"), json!(36000.0));
+    }
+}
@@ -0,0 +1,1045 @@
+//! Simulated VE.Direct devices, enabled with the `simulator` feature.
+//!
+//! A [`Simulator`] produces the Text mode output of a BMV battery
+//! monitor, MPPT solar charger or Phoenix inverter, following a
+//! [`Scenario`] such as a day of solar charging. Blocks have their
+//! labels in the order sent by real devices and correct checksums,
+//! battery monitors send their history block every few blocks and
+//! solar chargers interleave asynchronous `:A` HEX messages. HEX Get
+//! and Set commands are answered from a simulated register set.
+//!
+//! On Linux the simulator can be served on a pseudo-terminal, so
+//! programs reading a serial port can be tested without hardware:
+//!
+//! ```rust,no_run
+//! use std::time::Duration;
+//! use vedirect::simulator::{Profile, Scenario, Simulator};
+//!
+//! let sim = Simulator::new(Profile::Mppt, Scenario::sunrise());
+//! let server = sim.serve_pty(Duration::from_secs(1)).unwrap();
+//! println!("simulated MPPT on {}", server.path());
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    f32::consts::PI,
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    hex::{flags, register, Command, DayHistory, HexFrame, Register},
+    Encode, ErrorCode, Frame, Label, OffReason, StateOfOperation, TrackerOperationMode, VEError,
+    MPPT,
+};
+
+/// Kind of device to simulate
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Profile {
+    /// BMV-700 battery monitor
+    Bmv700,
+    /// SmartSolar MPPT 100|20 solar charger
+    Mppt,
+    /// Phoenix Inverter 12V 250VA
+    Phoenix,
+}
+
+impl Profile {
+    pub fn product_id(&self) -> u16 {
+        match self {
+            Profile::Bmv700 => 0x0203,
+            Profile::Mppt => 0xA053,
+            Profile::Phoenix => 0xA231,
+        }
+    }
+
+    /// Firmware version, as sent in HEX mode
+    pub fn firmware(&self) -> u16 {
+        match self {
+            Profile::Bmv700 => 0x4308,
+            Profile::Mppt => 0x4159,
+            Profile::Phoenix => 0x4114,
+        }
+    }
+}
+
+/// What happens to the simulated system over time
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Scenario {
+    /// Solar charging: panel power follows a sine curve during the
+    /// first half of every `day_length`, and is zero during the night.
+    /// A constant load is connected to the battery.
+    Sunrise {
+        peak_power_w: f32,
+        day_length: Duration,
+        load_a: f32,
+    },
+    /// A full battery discharging at a constant current
+    Discharge { load_a: f32 },
+}
+
+impl Scenario {
+    /// A 250 W solar array over a 24 hour day, with a 1 A load
+    pub fn sunrise() -> Scenario {
+        Scenario::Sunrise {
+            peak_power_w: 250.0,
+            day_length: Duration::from_secs(24 * 3600),
+            load_a: 1.0,
+        }
+    }
+
+    /// A 5 A load
+    pub fn discharge() -> Scenario {
+        Scenario::Discharge { load_a: 5.0 }
+    }
+}
+
+/// Physical state of the simulated system
+#[derive(Clone, PartialEq, Debug)]
+pub struct SystemState {
+    /// Units: V
+    pub battery_voltage: f32,
+    /// Positive when charging. Units: A
+    pub battery_current: f32,
+    /// Units: V
+    pub panel_voltage: f32,
+    /// Units: W
+    pub panel_power: f32,
+    /// Units: A
+    pub load_current: f32,
+    /// Units: Ah
+    pub capacity_ah: f32,
+    /// Units: Ah
+    pub consumed_ah: f32,
+    /// Units: kWh
+    pub yield_total: f32,
+    /// Units: kWh
+    pub yield_today: f32,
+    /// Units: W
+    pub max_power_today: f32,
+    /// Lowest and highest battery voltage seen today. Units: V
+    pub battery_voltage_range: (f32, f32),
+    /// Number of days simulated
+    pub day: u16,
+}
+
+impl SystemState {
+    /// State of charge, from 0 to 1
+    pub fn soc(&self) -> f32 {
+        (1.0 - self.consumed_ah / self.capacity_ah).clamp(0.0, 1.0)
+    }
+}
+
+/// Statistics of a finished day, for the day history registers
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Day {
+    yield_kwh: f32,
+    max_power: f32,
+    battery_voltage_range: (f32, f32),
+    sequence: u16,
+}
+
+/// A simulated device
+pub struct Simulator {
+    profile: Profile,
+    scenario: Scenario,
+    state: SystemState,
+    elapsed: Duration,
+    blocks: u64,
+    history_every: u32,
+    async_every: Option<u32>,
+    serial: String,
+    settings: BTreeMap<u16, Vec<u8>>,
+    days: Vec<Day>,
+    input: Vec<u8>,
+}
+
+impl Simulator {
+    pub fn new(profile: Profile, scenario: Scenario) -> Self {
+        let consumed_ah = match scenario {
+            Scenario::Sunrise { .. } => 100.0,
+            Scenario::Discharge { .. } => 0.0,
+        };
+        let mut sim = Simulator {
+            profile,
+            scenario,
+            state: SystemState {
+                battery_voltage: 0.0,
+                battery_current: 0.0,
+                panel_voltage: 0.0,
+                panel_power: 0.0,
+                load_current: 0.0,
+                capacity_ah: 200.0,
+                consumed_ah,
+                yield_total: 0.0,
+                yield_today: 0.0,
+                max_power_today: 0.0,
+                battery_voltage_range: (f32::MAX, 0.0),
+                day: 0,
+            },
+            elapsed: Duration::ZERO,
+            blocks: 0,
+            history_every: 1,
+            async_every: match profile {
+                Profile::Mppt => Some(5),
+                _ => None,
+            },
+            serial: String::from("HQ2132QY2KR"),
+            settings: BTreeMap::new(),
+            days: vec![],
+            input: vec![],
+        };
+        let settings: &[(Register, f32)] = match profile {
+            Profile::Mppt => &[
+                (register::BATTERY_MAX_CURRENT, 20.0),
+                (register::BATTERY_TYPE, 1.0),
+                (register::ABSORPTION_VOLTAGE, 14.4),
+                (register::FLOAT_VOLTAGE, 13.8),
+                (register::EQUALISATION_VOLTAGE, 16.2),
+                (register::ABSORPTION_TIME, 6.0),
+                (register::BULK_TIME_LIMIT, 10.0),
+                (register::BATTERY_VOLTAGE_SETTING, 12.0),
+                (register::DEVICE_MODE, 1.0),
+            ],
+            Profile::Phoenix => &[(register::DEVICE_MODE, 2.0)],
+            Profile::Bmv700 => &[],
+        };
+        for (register, value) in settings {
+            sim.set_register(register, *value);
+        }
+        sim.advance(Duration::ZERO);
+        sim
+    }
+
+    /// Send the battery monitor history block after every `n` main
+    /// blocks. Real monitors alternate them, which is the default.
+    pub fn with_history_every(mut self, n: u32) -> Self {
+        self.history_every = n.max(1);
+        self
+    }
+
+    /// Send an asynchronous HEX message after every `n` blocks, or
+    /// never. Only solar chargers send them by default.
+    pub fn with_async_every(mut self, n: Option<u32>) -> Self {
+        self.async_every = n.filter(|n| *n > 0);
+        self
+    }
+
+    pub fn with_serial(mut self, serial: &str) -> Self {
+        self.serial = serial.into();
+        self
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    pub fn state(&self) -> &SystemState {
+        &self.state
+    }
+
+    /// Simulated time since the start
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    fn set_register(&mut self, register: &Register, value: f32) {
+        if let Some(raw) = register.encode(value) {
+            self.settings.insert(register.id, raw.to_vec());
+        }
+    }
+
+    fn setting(&self, register: &Register) -> Option<f32> {
+        register.decode(self.settings.get(&register.id)?)
+    }
+
+    /// Advance the simulated system by `dt`
+    pub fn advance(&mut self, dt: Duration) {
+        let hours = dt.as_secs_f32() / 3600.0;
+        self.elapsed += dt;
+        let absorption = self.settings_absorption();
+        let s = &mut self.state;
+        match self.scenario {
+            Scenario::Sunrise {
+                peak_power_w,
+                day_length,
+                load_a,
+            } => {
+                let day = (self.elapsed.as_secs_f64() / day_length.as_secs_f64()) as u16;
+                if day != s.day {
+                    self.days.insert(
+                        0,
+                        Day {
+                            yield_kwh: s.yield_today,
+                            max_power: s.max_power_today,
+                            battery_voltage_range: s.battery_voltage_range,
+                            sequence: s.day,
+                        },
+                    );
+                    self.days.truncate(30);
+                    s.day = day;
+                    s.yield_today = 0.0;
+                    s.max_power_today = 0.0;
+                    s.battery_voltage_range = (f32::MAX, 0.0);
+                }
+                let phase = (self.elapsed.as_secs_f64() / day_length.as_secs_f64()).fract() as f32;
+                s.panel_power = (peak_power_w * (2.0 * PI * phase).sin()).max(0.0);
+                s.panel_voltage = if s.panel_power > 0.0 {
+                    17.5 + 2.0 * (s.panel_power / peak_power_w)
+                } else {
+                    0.0
+                };
+                s.load_current = load_a;
+            }
+            Scenario::Discharge { load_a } => {
+                s.panel_power = 0.0;
+                s.panel_voltage = 0.0;
+                s.load_current = load_a;
+            }
+        }
+        // resting voltage from the state of charge, sagging under load
+        let rest = 11.8 + 1.0 * s.soc();
+        let charge_current = s.panel_power * 0.97 / rest.max(1.0);
+        s.battery_current = charge_current - s.load_current;
+        s.consumed_ah = (s.consumed_ah - s.battery_current * hours).clamp(0.0, s.capacity_ah);
+        s.battery_voltage = (rest + 0.03 * s.battery_current).clamp(10.5, absorption);
+        s.yield_today += s.panel_power * hours / 1000.0;
+        s.yield_total += s.panel_power * hours / 1000.0;
+        s.max_power_today = s.max_power_today.max(s.panel_power);
+        s.battery_voltage_range = (
+            s.battery_voltage_range.0.min(s.battery_voltage),
+            s.battery_voltage_range.1.max(s.battery_voltage),
+        );
+    }
+
+    fn settings_absorption(&self) -> f32 {
+        self.setting(&register::ABSORPTION_VOLTAGE).unwrap_or(14.4)
+    }
+
+    /// Charge state (`CS`) as sent by chargers and inverters
+    fn charge_state(&self) -> u8 {
+        match self.profile {
+            Profile::Phoenix => 9,
+            _ if self.state.panel_power <= 0.0 => 0,
+            _ if self.state.battery_voltage >= self.settings_absorption() => 4,
+            _ => 3,
+        }
+    }
+
+    /// The main block of the device
+    fn main_block(&self) -> Vec<u8> {
+        let block = match self.profile {
+            Profile::Bmv700 => frame(self.bmv_fields()).to_block(),
+            Profile::Mppt => self.mppt().to_block(),
+            Profile::Phoenix => frame(self.phoenix_fields()).to_block(),
+        };
+        block.expect("simulated blocks fit the buffer")
+    }
+
+    fn mppt(&self) -> MPPT {
+        let s = &self.state;
+        let day = |f: fn(&Day) -> f32| self.days.first().map_or(0.0, f);
+        let fw = self.profile.firmware();
+        MPPT {
+            channel1_voltage: s.battery_voltage,
+            panel_voltage: s.panel_voltage,
+            panel_power: s.panel_power.round() as i32,
+            battery_current: (s.battery_current + s.load_current).max(0.0),
+            load_current: s.load_current,
+            load_output_state: true,
+            relay_state: None,
+            off_reason: if s.panel_power > 0.0 {
+                OffReason::None
+            } else {
+                OffReason::NoInputPower
+            },
+            yield_total: (s.yield_total * 100.0) as i32,
+            yield_today: (s.yield_today * 100.0) as i32,
+            max_power_today: s.max_power_today.round() as i32,
+            yield_yesterday: (day(|d| d.yield_kwh) * 100.0) as i32,
+            max_power_yesterday: day(|d| d.max_power).round() as i32,
+            error_code: ErrorCode::NoError,
+            state_of_operation: StateOfOperation::from_repr(self.charge_state().into())
+                .unwrap_or(StateOfOperation::Off),
+            // the digits of the version, e.g. 159 for 1.59
+            firmware: (fw >> 8 & 0xF) * 100 + (fw >> 4 & 0xF) * 10 + (fw & 0xF),
+            product_id: self.product_id(),
+            serial_number: self.serial.clone(),
+            day_sequence: s.day,
+            tracker_mode: if s.panel_power > 0.0 {
+                TrackerOperationMode::MPPTrackerActive
+            } else {
+                TrackerOperationMode::Off
+            },
+        }
+    }
+
+    fn product_id(&self) -> String {
+        format!("0x{:04X}", self.profile.product_id())
+    }
+
+    fn bmv_fields(&self) -> Vec<(Label, String)> {
+        let s = &self.state;
+        let mv = |v: f32| format!("{}", (v * 1000.0).round() as i64);
+        let pid = self.product_id();
+        let ttg = if s.battery_current < 0.0 {
+            (s.capacity_ah - s.consumed_ah) / -s.battery_current * 60.0
+        } else {
+            -1.0
+        };
+        vec![
+            (Label::PID, pid),
+            (Label::V, mv(s.battery_voltage)),
+            (Label::I, mv(s.battery_current)),
+            (
+                Label::P,
+                format!("{}", (s.battery_voltage * s.battery_current).round()),
+            ),
+            (Label::CE, format!("{}", (-s.consumed_ah * 1000.0).round())),
+            (Label::SOC, format!("{}", (s.soc() * 1000.0).round())),
+            (Label::TTG, format!("{}", ttg.round())),
+            (Label::Alarm, "OFF".into()),
+            (Label::Relay, "OFF".into()),
+            (Label::AR, "0".into()),
+            (Label::BMV, "700".into()),
+            (
+                Label::FW,
+                format!("{:04X}", self.profile.firmware() & 0xFFF),
+            ),
+        ]
+    }
+
+    fn phoenix_fields(&self) -> Vec<(Label, String)> {
+        let s = &self.state;
+        let mv = |v: f32| format!("{}", (v * 1000.0).round() as i64);
+        let pid = self.product_id();
+        let ac_power = s.load_current * s.battery_voltage * 0.9;
+        vec![
+            (Label::PID, pid),
+            (
+                Label::FW,
+                format!("{:04X}", self.profile.firmware() & 0xFFF),
+            ),
+            (Label::SerialNumber, self.serial.clone()),
+            (Label::MODE, "2".into()),
+            (Label::CS, format!("{}", self.charge_state())),
+            (Label::AcOutV, "23000".into()),
+            (
+                Label::AcOutI,
+                format!("{}", (ac_power / 230.0 * 10.0).round()),
+            ),
+            (Label::AcOutS, format!("{}", ac_power.round())),
+            (Label::V, mv(s.battery_voltage)),
+            (Label::AR, "0".into()),
+            (Label::WARN, "0".into()),
+            (Label::OR, "0x00000000".into()),
+        ]
+    }
+
+    fn history_fields(&self) -> Vec<(Label, String)> {
+        let s = &self.state;
+        let deepest = -(s.capacity_ah * 0.6 * 1000.0).round();
+        let (min, max) = s.battery_voltage_range;
+        vec![
+            (Label::H1, format!("{}", deepest)),
+            (Label::H2, format!("{}", (-s.consumed_ah * 1000.0).round())),
+            (Label::H3, format!("{}", (deepest / 2.0).round())),
+            (Label::H4, "45".into()),
+            (Label::H5, "0".into()),
+            (Label::H6, "-8973000".into()),
+            (Label::H7, format!("{}", (min * 1000.0).round())),
+            (Label::H8, format!("{}", (max * 1000.0).round())),
+            (Label::H9, format!("{}", self.elapsed.as_secs())),
+            (Label::H10, "12".into()),
+            (Label::H11, "0".into()),
+            (Label::H12, "0".into()),
+            (Label::H17, "10450".into()),
+            (Label::H18, "11210".into()),
+        ]
+    }
+
+    /// Advance by `dt` and return the data the device sends: the next
+    /// block, followed by a history block or asynchronous HEX message
+    /// when due
+    pub fn next_frame(&mut self, dt: Duration) -> Vec<u8> {
+        self.advance(dt);
+        self.blocks += 1;
+        let mut out = self.main_block();
+        if self.profile == Profile::Bmv700
+            && self.blocks.is_multiple_of(u64::from(self.history_every))
+        {
+            out.extend(
+                frame(self.history_fields())
+                    .to_block()
+                    .expect("simulated blocks fit the buffer"),
+            );
+        }
+        if let Some(n) = self.async_every {
+            if self.blocks.is_multiple_of(u64::from(n)) {
+                let id = register::CHARGER_VOLTAGE.id;
+                if let Some(value) = self.get_register(id) {
+                    let [lo, hi] = id.to_le_bytes();
+                    let mut frame = HexFrame {
+                        command: 0xA,
+                        data: heapless::Vec::new(),
+                    };
+                    let _ = frame.data.extend_from_slice(&[lo, hi, 0]);
+                    let _ = frame.data.extend_from_slice(&value);
+                    out.extend(frame.to_string().into_bytes());
+                }
+            }
+        }
+        out
+    }
+
+    /// Current value of a register
+    fn get_register(&self, id: u16) -> Option<Vec<u8>> {
+        let s = &self.state;
+        let live = |register: Register, value: f32| Some(register.encode(value)?.to_vec());
+        if id == register::PRODUCT_ID.id {
+            return Some(u32::from(self.profile.product_id()).to_le_bytes().to_vec());
+        }
+        match self.profile {
+            Profile::Mppt => {
+                if (register::DAY_HISTORY..register::DAY_HISTORY + 31).contains(&id) {
+                    return self.day_history((id - register::DAY_HISTORY) as usize);
+                }
+                match id {
+                    0xEDBB => live(register::PANEL_VOLTAGE, s.panel_voltage),
+                    0xEDBC => live(register::PANEL_POWER, s.panel_power),
+                    0xEDD5 => live(register::CHARGER_VOLTAGE, s.battery_voltage),
+                    0xEDD7 => live(
+                        register::CHARGER_CURRENT,
+                        (s.battery_current + s.load_current).max(0.0),
+                    ),
+                    0xEDDA => live(register::CHARGER_ERROR, 0.0),
+                    0xEDD3 => live(register::YIELD_TODAY, s.yield_today),
+                    0xEDD2 => live(register::MAX_POWER_TODAY, s.max_power_today),
+                    0xEDDC => live(register::USER_YIELD, s.yield_total),
+                    0xEDAD => live(register::LOAD_CURRENT, s.load_current),
+                    0xEDA8 => live(register::LOAD_OUTPUT_STATE, 1.0),
+                    0x0201 => live(register::DEVICE_STATE, f32::from(self.charge_state())),
+                    _ => self.settings.get(&id).cloned(),
+                }
+            }
+            Profile::Bmv700 => match id {
+                0xED8D => live(register::MAIN_VOLTAGE, s.battery_voltage),
+                0xED8F => live(register::CURRENT, s.battery_current),
+                0x0FFF => live(register::STATE_OF_CHARGE, s.soc() * 100.0),
+                0x0FFE => live(
+                    register::TIME_TO_GO,
+                    if s.battery_current < 0.0 {
+                        (s.capacity_ah - s.consumed_ah) / -s.battery_current * 60.0
+                    } else {
+                        0.0
+                    },
+                ),
+                _ => self.settings.get(&id).cloned(),
+            },
+            Profile::Phoenix => match id {
+                0x0201 => live(register::DEVICE_STATE, f32::from(self.charge_state())),
+                _ => self.settings.get(&id).cloned(),
+            },
+        }
+    }
+
+    /// Raw day history record of `days_ago`, today being 0
+    fn day_history(&self, days_ago: usize) -> Option<Vec<u8>> {
+        let today = Day {
+            yield_kwh: self.state.yield_today,
+            max_power: self.state.max_power_today,
+            battery_voltage_range: self.state.battery_voltage_range,
+            sequence: self.state.day,
+        };
+        let day = if days_ago == 0 {
+            today
+        } else {
+            *self.days.get(days_ago - 1)?
+        };
+        let mut raw = vec![0u8; DayHistory::SIZE];
+        raw[1..5].copy_from_slice(&((day.yield_kwh * 100.0) as u32).to_le_bytes());
+        let (min, max) = day.battery_voltage_range;
+        let min = if min > max { 0.0 } else { min };
+        raw[9..11].copy_from_slice(&((max * 100.0).round() as u16).to_le_bytes());
+        raw[11..13].copy_from_slice(&((min * 100.0).round() as u16).to_le_bytes());
+        raw[24..28].copy_from_slice(&(day.max_power.round() as u32).to_le_bytes());
+        raw[32..34].copy_from_slice(&day.sequence.to_le_bytes());
+        Some(raw)
+    }
+
+    /// Answer a HEX command, or `None` if the device does not answer
+    fn answer(&mut self, request: &HexFrame) -> Option<HexFrame> {
+        let reply = |command: u8, data: &[u8]| {
+            let mut frame = HexFrame {
+                command,
+                data: heapless::Vec::new(),
+            };
+            let _ = frame.data.extend_from_slice(data);
+            Some(frame)
+        };
+        let version = self.profile.firmware().to_le_bytes();
+        let register = |data: &[u8]| u16::from_le_bytes([data[0], data[1]]);
+        match request.command {
+            c if c == Command::Ping as u8 => reply(0x5, &version),
+            c if c == Command::AppVersion as u8 => reply(0x1, &version),
+            c if c == Command::ProductId as u8 => {
+                let [lo, hi] = self.profile.product_id().to_le_bytes();
+                reply(0x1, &[lo, hi, 0x40])
+            }
+            c if c == Command::Restart as u8 => {
+                self.state.yield_today = 0.0;
+                None
+            }
+            c if c == Command::Get as u8 && request.data.len() >= 3 => {
+                let id = register(&request.data);
+                let [lo, hi] = id.to_le_bytes();
+                match self.get_register(id) {
+                    Some(value) => {
+                        let mut data = vec![lo, hi, 0];
+                        data.extend(value);
+                        reply(0x7, &data)
+                    }
+                    None => reply(0x7, &[lo, hi, flags::UNKNOWN_ID]),
+                }
+            }
+            c if c == Command::Set as u8 && request.data.len() >= 3 => {
+                let id = register(&request.data);
+                let [lo, hi] = id.to_le_bytes();
+                let value = &request.data[3..];
+                let known = register::ALL.iter().find(|r| r.id == id);
+                let flags = match known {
+                    _ if !self.settings.contains_key(&id) && self.get_register(id).is_some() => {
+                        flags::NOT_SUPPORTED
+                    }
+                    Some(r) if r.writable && self.settings.contains_key(&id) => {
+                        if value.len() == r.format.size() {
+                            self.settings.insert(id, value.to_vec());
+                            0
+                        } else {
+                            flags::PARAMETER_ERROR
+                        }
+                    }
+                    Some(_) => flags::NOT_SUPPORTED,
+                    None => flags::UNKNOWN_ID,
+                };
+                let mut data = vec![lo, hi, flags];
+                if flags == 0 {
+                    data.extend_from_slice(value);
+                }
+                reply(0x8, &data)
+            }
+            c if c == Command::Get as u8 || c == Command::Set as u8 => reply(0x4, &[0xAA, 0xAA]),
+            other => reply(0x3, &[other]),
+        }
+    }
+
+    /// Process data sent to the device, returning its answers to the
+    /// HEX commands received
+    pub fn handle_input(&mut self, data: &[u8]) -> Vec<u8> {
+        self.input.extend_from_slice(data);
+        let mut out = vec![];
+        while let Some(end) = self.input.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.input.drain(..=end).collect();
+            let start = match line.iter().position(|b| *b == b':') {
+                Some(start) => start,
+                None => continue,
+            };
+            let answer = match HexFrame::decode(&line[start..]) {
+                Ok(request) => self.answer(&request),
+                Err(_) => {
+                    let mut frame = HexFrame {
+                        command: 0x4,
+                        data: heapless::Vec::new(),
+                    };
+                    let _ = frame.data.extend_from_slice(&[0xAA, 0xAA]);
+                    Some(frame)
+                }
+            };
+            if let Some(answer) = answer {
+                out.extend(answer.to_string().into_bytes());
+            }
+        }
+        // a device would discard garbage which is not a HEX command
+        if self.input.len() > 1024 {
+            self.input.clear();
+        }
+        out
+    }
+
+    /// Act as the device on `port` until `stop` is set: send a frame
+    /// every `interval` and answer HEX commands. `port` should have a
+    /// short read timeout, so frames are sent on time.
+    pub fn serve<P: Read + Write>(
+        &mut self,
+        port: &mut P,
+        interval: Duration,
+        stop: &AtomicBool,
+    ) -> io::Result<()> {
+        let mut buf = [0u8; 256];
+        let mut next = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            if Instant::now() >= next {
+                port.write_all(&self.next_frame(interval))?;
+                port.flush()?;
+                next += interval;
+            }
+            match port.read(&mut buf) {
+                Ok(count) => {
+                    let answer = self.handle_input(&buf[..count]);
+                    port.write_all(&answer)?;
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut
+                            | io::ErrorKind::WouldBlock
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve the simulated device on a new pseudo-terminal, in a
+    /// background thread. Open [`PtyServer::path`] like a serial port
+    /// to talk to the device.
+    #[cfg(unix)]
+    pub fn serve_pty(mut self, interval: Duration) -> Result<PtyServer, VEError> {
+        use serialport::SerialPort;
+
+        let (mut master, slave) = serialport::TTYPort::pair().map_err(io::Error::from)?;
+        master
+            .set_timeout(Duration::from_millis(10))
+            .map_err(io::Error::from)?;
+        let path = slave
+            .name()
+            .ok_or_else(|| VEError::Parse(text!("pseudo-terminal has no name")))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::spawn(move || {
+            // ends when the terminal is closed
+            let _ = self.serve(&mut master, interval, &thread_stop);
+        });
+        Ok(PtyServer {
+            path,
+            stop,
+            thread: Some(thread),
+            _slave: slave,
+        })
+    }
+}
+
+/// A simulated device served on a pseudo-terminal. The device stops
+/// when this is dropped.
+#[cfg(unix)]
+pub struct PtyServer {
+    path: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    _slave: serialport::TTYPort,
+}
+
+#[cfg(unix)]
+impl PtyServer {
+    /// Path of the terminal, e.g. `/dev/pts/3`
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Drop for PtyServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn frame(fields: Vec<(Label, String)>) -> Frame {
+    Frame {
+        fields: fields
+            .into_iter()
+            .map(|(label, value)| (label.to_string(), value))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::Collector;
+    use crate::{hex::Response, Bmv700, Frame, Parser, MPPT};
+
+    /// Check every block adds up to 0, as required by the protocol
+    fn assert_checksums(data: &[u8]) {
+        let mut sum = 0u8;
+        let mut blocks = 0;
+        let mut i = 0;
+        while i < data.len() {
+            if data[i] == b':' {
+                // skip HEX messages
+                i += data[i..].iter().position(|b| *b == b'\n').unwrap() + 1;
+                continue;
+            }
+            sum = sum.wrapping_add(data[i]);
+            if data[..=i].ends_with(b"Checksum\t") {
+                sum = sum.wrapping_add(data[i + 1]);
+                assert_eq!(sum, 0, "block {} checksum", blocks);
+                sum = 0;
+                blocks += 1;
+                i += 1;
+            }
+            i += 1;
+        }
+        assert!(blocks > 0);
+    }
+
+    #[test]
+    fn test_mppt_sunrise() {
+        let mut sim = Simulator::new(Profile::Mppt, Scenario::sunrise());
+        let mut parser = Parser::new(Collector::<MPPT>::default());
+        let mut raw = vec![];
+        // a day and a half, every 10 minutes
+        for _ in 0..216 {
+            let frame = sim.next_frame(Duration::from_secs(600));
+            raw.extend_from_slice(&frame);
+            parser.feed(&frame).unwrap();
+        }
+        assert_checksums(&raw);
+
+        let collector = parser.listener();
+        assert_eq!(collector.parse_errors, 0);
+        assert_eq!(collector.blocks.len(), 216);
+        assert!(!collector.hex.is_empty());
+        let noon = &collector.blocks[35];
+        assert!(noon.panel_power > 200, "{:?}", noon);
+        assert!(noon.panel_voltage > 17.0);
+        assert_eq!(noon.serial_number, "HQ2132QY2KR");
+        let night = &collector.blocks[100];
+        assert_eq!(night.panel_power, 0);
+        assert_eq!(night.state_of_operation, crate::StateOfOperation::Off);
+        // second day
+        let last = collector.blocks.last().unwrap();
+        assert_eq!(last.day_sequence, 1);
+        assert!(last.yield_yesterday > 100, "{:?}", last);
+        assert!(last.yield_total >= last.yield_yesterday + last.yield_today);
+
+        // label order of real devices
+        let mut frames = Parser::new(Collector::<Frame>::default());
+        frames
+            .feed(&sim.next_frame(Duration::from_secs(1)))
+            .unwrap();
+        let labels: Vec<_> = frames.listener().blocks[0]
+            .iter()
+            .map(|(l, _)| l.to_string())
+            .collect();
+        assert_eq!(
+            labels.join(","),
+            "PID,FW,SER#,V,I,VPV,PPV,CS,MPPT,OR,ERR,LOAD,IL,H19,H20,H21,H22,H23,HSDS"
+        );
+    }
+
+    #[test]
+    fn test_bmv_discharge() {
+        let mut sim = Simulator::new(Profile::Bmv700, Scenario::discharge()).with_history_every(3);
+        let mut parser = Parser::new(Collector::<Frame>::default());
+        let mut raw = vec![];
+        for _ in 0..6 {
+            let frame = sim.next_frame(Duration::from_secs(3600));
+            raw.extend_from_slice(&frame);
+            parser.feed(&frame).unwrap();
+        }
+        assert_checksums(&raw);
+        let frames = &parser.listener().blocks;
+        // two history blocks
+        assert_eq!(frames.len(), 8);
+        assert_eq!(frames.iter().filter(|f| f.get("H1").is_some()).count(), 2);
+        assert_eq!(frames[3].get("H1"), Some("-120000"));
+        assert_eq!(frames[4].get("SOC"), Some("900"));
+        assert_eq!(frames[4].get("CE"), Some("-20000"));
+        assert_eq!(frames[4].get("TTG"), Some("2160"));
+        assert!(parser.listener().hex.is_empty());
+
+        let mut parser = Parser::new(Collector::<Bmv700>::default());
+        parser.feed(&raw).unwrap();
+        assert_eq!(parser.listener().blocks.len(), 6);
+        assert_eq!(parser.listener().blocks[5].soc, Some(85.0));
+    }
+
+    #[test]
+    fn test_bmv_round_trip() {
+        let mut sim = Simulator::new(Profile::Bmv700, Scenario::discharge());
+        let mut parser = Parser::new(Collector::<Bmv700>::default());
+        parser
+            .feed(&sim.next_frame(Duration::from_secs(1)))
+            .unwrap();
+        let bmv = parser.listener().blocks[0].clone();
+        assert!((bmv.voltage - sim.state().battery_voltage).abs() < 0.001);
+        assert!(bmv.voltage > 11.0 && bmv.voltage < 14.0, "{:?}", bmv);
+
+        // encoded again, the block is read the same by the parser and
+        // identified as a BMV
+        let mut parser = Parser::new(Collector::<crate::Device>::default());
+        parser.feed(&bmv.to_block().unwrap()).unwrap();
+        assert_eq!(parser.listener().blocks, vec![crate::Device::Bmv700(bmv)]);
+    }
+
+    #[test]
+    fn test_phoenix() {
+        let mut sim = Simulator::new(Profile::Phoenix, Scenario::discharge());
+        let frame = sim.next_frame(Duration::from_secs(1));
+        assert_checksums(&frame);
+        let mut parser = Parser::new(Collector::<Frame>::default());
+        parser.feed(&frame).unwrap();
+        let block = &parser.listener().blocks[0];
+        assert_eq!(block.get("PID"), Some("0xA231"));
+        assert_eq!(block.get("MODE"), Some("2"));
+        assert_eq!(block.get("AC_OUT_V"), Some("23000"));
+    }
+
+    fn answer(sim: &mut Simulator, request: HexFrame) -> HexFrame {
+        let out = sim.handle_input(request.to_string().as_bytes());
+        HexFrame::decode(&out).unwrap()
+    }
+
+    #[test]
+    fn test_hex_commands() {
+        let mut sim = Simulator::new(Profile::Mppt, Scenario::sunrise());
+        sim.advance(Duration::from_secs(6 * 3600));
+
+        let ping = answer(&mut sim, HexFrame::ping());
+        assert_eq!(ping.response().unwrap(), Response::Ping { version: 0x4159 });
+
+        let pid = answer(&mut sim, HexFrame::product_id());
+        assert_eq!(pid.response().unwrap(), Response::Done(&[0x53, 0xA0, 0x40]));
+
+        let get = answer(&mut sim, HexFrame::get(register::ABSORPTION_VOLTAGE.id));
+        assert_eq!(
+            get.response().unwrap(),
+            Response::Get {
+                register: 0xEDF7,
+                flags: 0,
+                value: &[0xA0, 0x05]
+            }
+        );
+
+        let set = answer(&mut sim, HexFrame::set(0xEDF7, &[0x8C, 0x05]));
+        assert!(matches!(
+            set.response().unwrap(),
+            Response::Set {
+                register: 0xEDF7,
+                flags: 0,
+                value: [0x8C, 0x05]
+            }
+        ));
+        let get = answer(&mut sim, HexFrame::get(0xEDF7));
+        assert!(matches!(
+            get.response().unwrap(),
+            Response::Get {
+                value: [0x8C, 0x05],
+                ..
+            }
+        ));
+
+        // live values are read only
+        let panel = answer(&mut sim, HexFrame::get(register::PANEL_POWER.id));
+        match panel.response().unwrap() {
+            Response::Get {
+                flags: 0, value, ..
+            } => {
+                assert_eq!(register::PANEL_POWER.decode(value), Some(250.0))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let set = answer(
+            &mut sim,
+            HexFrame::set(register::PANEL_POWER.id, &[0, 0, 0, 0]),
+        );
+        assert!(matches!(
+            set.response().unwrap(),
+            Response::Set {
+                flags: flags::NOT_SUPPORTED,
+                ..
+            }
+        ));
+
+        let unknown = answer(&mut sim, HexFrame::get(0x1234));
+        assert!(matches!(
+            unknown.response().unwrap(),
+            Response::Get {
+                flags: flags::UNKNOWN_ID,
+                ..
+            }
+        ));
+
+        let today = answer(&mut sim, HexFrame::get(register::DAY_HISTORY));
+        match today.response().unwrap() {
+            Response::Get {
+                flags: 0, value, ..
+            } => {
+                let day = DayHistory::decode(value).unwrap();
+                assert_eq!(day.day_sequence, 0);
+                assert_eq!(day.max_power, 250);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // invalid checksum
+        let out = sim.handle_input(b":154\n:155\n");
+        assert_eq!(&out[..], b":55941B6\n:4AAAAFD\n");
+        assert!(sim.handle_input(b":64F\n").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_serve_pty() {
+        let sim = Simulator::new(Profile::Mppt, Scenario::sunrise());
+        let server = sim.serve_pty(Duration::from_millis(50)).unwrap();
+
+        // like the read_serial example
+        let mut port = serialport::new(server.path(), 19_200)
+            .timeout(Duration::from_millis(100))
+            .open()
+            .unwrap();
+        port.write_all(
+            HexFrame::get(register::FLOAT_VOLTAGE.id)
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let mut parser = Parser::new(Collector::<MPPT>::default());
+        let mut buf = [0u8; 256];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while parser.listener().blocks.len() < 3 || parser.listener().hex.is_empty() {
+            assert!(Instant::now() < deadline, "timed out");
+            match port.read(&mut buf) {
+                Ok(count) => {
+                    let _ = parser.feed(&buf[..count]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+        let collector = parser.listener();
+        assert_eq!(collector.parse_errors, 0);
+        assert_eq!(collector.blocks[0].serial_number, "HQ2132QY2KR");
+        assert!(collector.hex.iter().any(|f| matches!(
+            f.response(),
+            Ok(Response::Get {
+                register: 0xEDF6,
+                flags: 0,
+                ..
+            })
+        )));
+        drop(server);
+    }
+}
@@ -0,0 +1,283 @@
+//! Energy balance of a site with a battery monitor and solar chargers
+//! on the same bus.
+//!
+//! A [`SiteModel`] keeps the latest timestamped block of the battery
+//! monitor and of each solar charger, by serial number, and derives a
+//! [`Balance`] from them:
+//!
+//! - the solar input, the sum of the chargers' panel power `PPV`
+//! - the battery's net flow, its power `P`, positive while charging
+//! - the DC load, inferred as solar input minus battery flow. It
+//!   includes the conversion losses of the chargers.
+//! - the autonomy, the time until the battery is empty at the present
+//!   discharge, from its state of charge and capacity if it was set
+//!   with [`SiteModel::with_capacity`], and otherwise the battery
+//!   monitor's time-to-go `TTG`
+//!
+//! Blocks older than the maximum age, relative to the latest block,
+//! are left out, so a charger which stopped reporting does not count
+//! forever.
+//!
+//! ```rust
+//! use vedirect::clock::{SystemClock, Timestamper};
+//! use vedirect::site::SiteModel;
+//! use vedirect::{Device, Parser};
+//!
+//! let mut site = SiteModel::new().with_capacity(200.0)?;
+//! let mut parser = Parser::<Device, _>::new(Timestamper::new(SystemClock, &mut site));
+//! # let data = [];
+//! parser.feed(&data).ok();
+//! println!("{:?}", site.balance());
+//! # Ok::<(), vedirect::VEError>(())
+//! ```
+
+use std::time::Duration;
+
+use crate::{
+    clock::{Timestamp, Timestamped},
+    Bmv700, Device, Events, VEError, MPPT,
+};
+
+/// Default age after which blocks are left out of the balance
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Derived view of a site, see the [module documentation](self)
+#[derive(Clone, PartialEq, Default, Debug)]
+pub struct Balance {
+    /// Panel power of all solar chargers, in W
+    pub solar_power: f32,
+    /// Number of solar chargers in `solar_power`
+    pub chargers: usize,
+    /// Power into the battery, negative while discharging, in W
+    pub battery_power: Option<f32>,
+    pub battery_voltage: Option<f32>,
+    /// State of charge of the battery, in %
+    pub soc: Option<f32>,
+    /// Solar input minus battery flow, in W
+    pub load_power: Option<f32>,
+    /// Time until the battery is empty, while it is discharging
+    pub autonomy: Option<Duration>,
+}
+
+/// Latest blocks of the devices of a site, see the
+/// [module documentation](self)
+#[derive(Clone, Debug)]
+pub struct SiteModel<T> {
+    battery: Option<Timestamped<Bmv700, T>>,
+    chargers: Vec<Timestamped<MPPT, T>>,
+    /// Battery capacity in Ah
+    capacity: Option<f32>,
+    max_age: Duration,
+}
+
+impl<T: Timestamp> Default for SiteModel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Timestamp> SiteModel<T> {
+    pub fn new() -> Self {
+        SiteModel {
+            battery: None,
+            chargers: Vec::new(),
+            capacity: None,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+
+    /// Estimate the autonomy from the state of charge and a battery
+    /// capacity of `amp_hours`, instead of using the time-to-go of the
+    /// battery monitor. The capacity must be positive.
+    pub fn with_capacity(mut self, amp_hours: f32) -> Result<Self, VEError> {
+        if !(amp_hours.is_finite() && amp_hours > 0.0) {
+            return Err(VEError::Parse(text!(
+                "battery capacity of {} Ah",
+                amp_hours
+            )));
+        }
+        self.capacity = Some(amp_hours);
+        Ok(self)
+    }
+
+    /// Leave out blocks older than `max_age`, relative to the latest block
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn update_battery(&mut self, block: Timestamped<Bmv700, T>) {
+        self.battery = Some(block);
+    }
+
+    pub fn update_charger(&mut self, block: Timestamped<MPPT, T>) {
+        let serial = &block.data.serial_number;
+        match self
+            .chargers
+            .iter_mut()
+            .find(|c| &c.data.serial_number == serial)
+        {
+            Some(charger) => *charger = block,
+            None => self.chargers.push(block),
+        }
+    }
+
+    pub fn update(&mut self, block: Timestamped<Device, T>) {
+        let received = block.received;
+        match block.data {
+            Device::Bmv700(data) => self.update_battery(Timestamped { received, data }),
+            Device::Mppt(data) => self.update_charger(Timestamped { received, data }),
+        }
+    }
+
+    /// The balance of the blocks received so far
+    pub fn balance(&self) -> Balance {
+        let latest = self
+            .chargers
+            .iter()
+            .map(|c| c.received)
+            .chain(self.battery.as_ref().map(|b| b.received))
+            .reduce(|a, b| if b.duration_since(a).is_some() { b } else { a });
+        let Some(latest) = latest else {
+            return Balance::default();
+        };
+        // timestamps later than the latest one cannot occur
+        let recent = |received: T| {
+            latest
+                .duration_since(received)
+                .is_some_and(|age| age <= self.max_age)
+        };
+
+        let mut balance = Balance::default();
+        for charger in self.chargers.iter().filter(|c| recent(c.received)) {
+            balance.solar_power += charger.data.panel_power as f32;
+            balance.chargers += 1;
+        }
+        if let Some(battery) = self.battery.as_ref().filter(|b| recent(b.received)) {
+            let battery = &battery.data;
+            let power = battery.power as f32;
+            balance.battery_power = Some(power);
+            balance.battery_voltage = Some(battery.voltage);
+            balance.soc = battery.soc;
+            balance.load_power = Some(balance.solar_power - power);
+            if power < 0.0 {
+                balance.autonomy = self.autonomy(battery);
+            }
+        }
+        balance
+    }
+
+    fn autonomy(&self, battery: &Bmv700) -> Option<Duration> {
+        match (self.capacity, battery.soc) {
+            (Some(capacity), Some(soc)) if battery.voltage > 0.0 => {
+                let current = -(battery.power as f32) / battery.voltage;
+                let hours = capacity * soc / 100.0 / current;
+                // none while charging
+                Duration::try_from_secs_f32(hours * 3600.0).ok()
+            }
+            // -1 while the time to go is infinite
+            _ if battery.ttg >= 0 => Some(Duration::from_secs(battery.ttg as u64 * 60)),
+            _ => None,
+        }
+    }
+}
+
+impl<T: Timestamp> Events<Timestamped<Device, T>> for SiteModel<T> {
+    fn on_complete_block(&mut self, block: Timestamped<Device, T>) {
+        self.update(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{parse, MPPT_BLOCK};
+
+    fn mppt(serial: &str, panel_power: i32) -> MPPT {
+        let mut mppt: MPPT = parse(MPPT_BLOCK);
+        mppt.serial_number = serial.into();
+        mppt.panel_power = panel_power;
+        mppt
+    }
+
+    fn battery(power: i32) -> Bmv700 {
+        Bmv700 {
+            voltage: 12.5,
+            power,
+            consumed: Some("-50000".into()),
+            soc: Some(75.0),
+            ttg: 600,
+        }
+    }
+
+    fn at<D>(seconds: u64, data: D) -> Timestamped<D, Duration> {
+        Timestamped {
+            received: Duration::from_secs(seconds),
+            data,
+        }
+    }
+
+    #[test]
+    fn test_balance() {
+        let mut site = SiteModel::new();
+        assert_eq!(site.balance(), Balance::default());
+
+        site.update_charger(at(0, mppt("HQ1", 300)));
+        site.update_charger(at(1, mppt("HQ2", 150)));
+        site.update_charger(at(2, mppt("HQ1", 200)));
+        let balance = site.balance();
+        assert_eq!(balance.solar_power, 350.0);
+        assert_eq!(balance.chargers, 2);
+        assert_eq!(balance.load_power, None);
+
+        site.update(at(3, Device::Bmv700(battery(250))));
+        let balance = site.balance();
+        assert_eq!(balance.battery_power, Some(250.0));
+        assert_eq!(balance.battery_voltage, Some(12.5));
+        assert_eq!(balance.soc, Some(75.0));
+        assert_eq!(balance.load_power, Some(100.0));
+        // charging
+        assert_eq!(balance.autonomy, None);
+    }
+
+    #[test]
+    fn test_autonomy() {
+        // 100 W at 12.5 V is 8 A, from 75% of 200 Ah
+        let mut site = SiteModel::new().with_capacity(200.0).unwrap();
+        site.update_charger(at(0, mppt("HQ1", 0)));
+        site.update_battery(at(0, battery(-100)));
+        let balance = site.balance();
+        assert_eq!(balance.load_power, Some(100.0));
+        assert_eq!(
+            balance.autonomy,
+            Some(Duration::from_secs(18 * 3600 + 45 * 60))
+        );
+
+        // none while charging, or without any current
+        site.update_battery(at(1, battery(100)));
+        assert_eq!(site.balance().autonomy, None);
+        site.update_battery(at(2, battery(0)));
+        assert_eq!(site.balance().autonomy, None);
+        for invalid in [0.0, -200.0, f32::NAN, f32::INFINITY] {
+            assert!(SiteModel::<Duration>::new().with_capacity(invalid).is_err());
+        }
+
+        // the time-to-go of the battery monitor without a capacity
+        let mut site = SiteModel::new();
+        site.update_battery(at(0, battery(-100)));
+        assert_eq!(site.balance().autonomy, Some(Duration::from_secs(36_000)));
+    }
+
+    #[test]
+    fn test_max_age() {
+        let mut site = SiteModel::new().with_max_age(Duration::from_secs(30));
+        site.update_charger(at(0, mppt("HQ1", 300)));
+        site.update_battery(at(20, battery(100)));
+        site.update_charger(at(40, mppt("HQ2", 50)));
+        let balance = site.balance();
+        // HQ1 stopped reporting
+        assert_eq!(balance.solar_power, 50.0);
+        assert_eq!(balance.chargers, 1);
+        assert_eq!(balance.load_power, Some(-50.0));
+    }
+}
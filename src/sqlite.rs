@@ -0,0 +1,635 @@
+//! Storing readings and history in an SQLite database, enabled with the
+//! `sqlite` feature.
+//!
+//! A [`Store`] is a parser listener for [`Record`]s, which are the data
+//! of a device or the history block of a BMV. It keeps:
+//!
+//! | table          | rows                                                      |
+//! |----------------|-----------------------------------------------------------|
+//! | `readings`     | every block received, with its fields as a JSON object    |
+//! | `bmv_history`  | the latest history block of a BMV, for every day          |
+//! | `mppt_history` | day history records of solar chargers, read over HEX      |
+//!
+//! Rows are written in transactions committed every
+//! [commit interval](Store::with_commit_interval), a minute by default,
+//! rather than one write to the disk per block. The rows of the last
+//! interval are lost when the power is cut.
+//!
+//! Rows are keyed by the serial number of the device, or by its kind
+//! (`bmv700`) for devices which do not send one. Timestamps are in
+//! seconds since the Unix epoch, and grouped into days in local time,
+//! as days of solar yield are.
+//!
+//! ```rust,no_run
+//! use vedirect::sqlite::Store;
+//! use vedirect::Parser;
+//!
+//! let mut parser = Parser::new(Store::open("/var/lib/vedirect/readings.db").unwrap());
+//! # let data = [];
+//! parser.feed(&data).unwrap();
+//!
+//! for day in parser.listener().yield_per_day("HQ2132QY2KR", 7).unwrap() {
+//!     println!("{}: {} kWh", day.date, day.yield_kwh);
+//! }
+//! ```
+
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection};
+use serde_json::{Map, Value};
+
+use crate::{
+    hex::DayHistory, json, Device, DeviceKind, Events, Fields, Frame, Label, VEDirectData, VEError,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS readings (
+    serial TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    battery_voltage REAL NOT NULL,
+    -- kWh, solar chargers only
+    yield_today REAL,
+    data TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS readings_serial_timestamp ON readings (serial, timestamp);
+CREATE TABLE IF NOT EXISTS bmv_history (
+    serial TEXT NOT NULL,
+    date TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (serial, date)
+);
+CREATE TABLE IF NOT EXISTS mppt_history (
+    serial TEXT NOT NULL,
+    date TEXT NOT NULL,
+    day_sequence INTEGER NOT NULL,
+    yield_kwh REAL NOT NULL,
+    consumed_kwh REAL NOT NULL,
+    battery_voltage_max REAL NOT NULL,
+    battery_voltage_min REAL NOT NULL,
+    max_power INTEGER NOT NULL,
+    time_bulk INTEGER NOT NULL,
+    time_absorption INTEGER NOT NULL,
+    time_float INTEGER NOT NULL,
+    battery_current_max REAL NOT NULL,
+    panel_voltage_max REAL NOT NULL,
+    PRIMARY KEY (serial, date)
+);
+";
+
+/// Default of [`Store::with_commit_interval`]
+pub const DEFAULT_COMMIT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Data stored by a [`Store`]
+#[derive(Clone, PartialEq, Debug)]
+pub enum Record {
+    Device(Device),
+    /// History block of a BMV, sent without a product ID after its main
+    /// block, with the labels `H1` to `H18`
+    BmvHistory(Frame),
+}
+
+impl VEDirectData for Record {
+    fn fill(fields: &Fields<'_>) -> Result<Self, VEError> {
+        if DeviceKind::detect(fields).is_none() && fields.contains_key(Label::H1) {
+            Frame::fill(fields).map(Record::BmvHistory)
+        } else {
+            Device::fill(fields).map(Record::Device)
+        }
+    }
+}
+
+/// Solar yield of a day
+#[derive(Clone, PartialEq, Debug)]
+pub struct DailyYield {
+    /// e.g. `2023-11-14`
+    pub date: String,
+    pub yield_kwh: f64,
+}
+
+/// Battery voltage range of a day
+#[derive(Clone, PartialEq, Debug)]
+pub struct DailyVoltage {
+    /// e.g. `2023-11-14`
+    pub date: String,
+    pub min: f64,
+    pub max: f64,
+}
+
+/// An SQLite database of readings and history, see the
+/// [module documentation](self)
+pub struct Store {
+    connection: Connection,
+    commit_interval: Duration,
+    /// When the open transaction began, if any
+    transaction: Option<Instant>,
+    /// Key of the device whose main block was received last, which the
+    /// following history block belongs to
+    last_id: Option<String>,
+    error: Option<VEError>,
+}
+
+impl Store {
+    /// Open or create the database at `path`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, VEError> {
+        let connection = Connection::open(path).map_err(db_error)?;
+        // fewer writes, for SD cards
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .map_err(db_error)?;
+        Store::new(connection)
+    }
+
+    /// Use an open connection, creating the tables if needed
+    pub fn new(connection: Connection) -> Result<Self, VEError> {
+        connection.execute_batch(SCHEMA).map_err(db_error)?;
+        Ok(Store {
+            connection,
+            commit_interval: DEFAULT_COMMIT_INTERVAL,
+            transaction: None,
+            last_id: None,
+            error: None,
+        })
+    }
+
+    /// Commit the rows written at most this often,
+    /// [`DEFAULT_COMMIT_INTERVAL`] by default. Every commit is a write to
+    /// the disk, which wears out SD cards; zero commits every row.
+    pub fn with_commit_interval(mut self, commit_interval: Duration) -> Self {
+        self.commit_interval = commit_interval;
+        self
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Commit the rows written since the last commit
+    pub fn commit(&mut self) -> Result<(), VEError> {
+        if self.transaction.take().is_some() {
+            self.connection.execute_batch("COMMIT").map_err(db_error)?;
+        }
+        Ok(())
+    }
+
+    /// Run `write` in the open transaction, beginning one if needed, and
+    /// commit once it is older than the commit interval
+    fn write<T>(
+        &mut self,
+        write: impl FnOnce(&Connection) -> rusqlite::Result<T>,
+    ) -> Result<T, VEError> {
+        if self.transaction.is_none() {
+            self.connection.execute_batch("BEGIN").map_err(db_error)?;
+            self.transaction = Some(Instant::now());
+        }
+        let result = write(&self.connection).map_err(db_error)?;
+        if self
+            .transaction
+            .is_some_and(|began| began.elapsed() >= self.commit_interval)
+        {
+            self.commit()?;
+        }
+        Ok(result)
+    }
+
+    /// Store the data of a device, received at `timestamp`
+    pub fn insert(&mut self, device: &Device, timestamp: SystemTime) -> Result<(), VEError> {
+        let (battery_voltage, yield_today) = match device {
+            Device::Bmv700(bmv) => (bmv.voltage, None),
+            Device::Mppt(mppt) => (
+                mppt.channel1_voltage,
+                Some(f64::from(mppt.yield_today) / 100.0),
+            ),
+        };
+        let data = Value::Object(json::device_fields(device)?).to_string();
+        let id = device_id(device);
+        let timestamp = seconds(timestamp)?;
+        self.write(|connection| {
+            connection.execute(
+                "INSERT INTO readings
+                 (serial, kind, timestamp, battery_voltage, yield_today, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    id,
+                    device.kind().name(),
+                    timestamp,
+                    f64::from(battery_voltage),
+                    yield_today,
+                    data
+                ],
+            )
+        })?;
+        self.last_id = Some(id.into());
+        Ok(())
+    }
+
+    /// Store the history block of a BMV, received at `timestamp`,
+    /// replacing the one stored earlier that day
+    pub fn insert_bmv_history(
+        &mut self,
+        serial: &str,
+        history: &Frame,
+        timestamp: SystemTime,
+    ) -> Result<(), VEError> {
+        let data: Map<String, Value> = history
+            .iter()
+            .map(|(label, value)| {
+                let value = match value.parse::<i64>() {
+                    Ok(n) => n.into(),
+                    Err(_) => value.into(),
+                };
+                (label.to_string(), value)
+            })
+            .collect();
+        let timestamp = seconds(timestamp)?;
+        self.write(|connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO bmv_history (serial, date, timestamp, data)
+                 VALUES (?1, date(?2, 'unixepoch', 'localtime'), ?2, ?3)",
+                params![serial, timestamp, Value::Object(data).to_string()],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Store the day history record of a solar charger, read at
+    /// `timestamp` from the register of `days_ago` days before, see
+    /// [`register::DAY_HISTORY`](crate::hex::register::DAY_HISTORY)
+    pub fn insert_mppt_history(
+        &mut self,
+        serial: &str,
+        days_ago: u16,
+        history: &DayHistory,
+        timestamp: SystemTime,
+    ) -> Result<(), VEError> {
+        let timestamp = seconds(timestamp)?;
+        self.write(|connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO mppt_history
+                 (serial, date, day_sequence, yield_kwh, consumed_kwh,
+                  battery_voltage_max, battery_voltage_min, max_power, time_bulk,
+                  time_absorption, time_float, battery_current_max, panel_voltage_max)
+                 VALUES (?1, date(?2, 'unixepoch', 'localtime', ?3), ?4, ?5, ?6, ?7, ?8,
+                         ?9, ?10, ?11, ?12, ?13, ?14)",
+                params![
+                    serial,
+                    timestamp,
+                    format!("-{} days", days_ago),
+                    history.day_sequence,
+                    f64::from(history.yield_kwh),
+                    f64::from(history.consumed_kwh),
+                    f64::from(history.battery_voltage_max),
+                    f64::from(history.battery_voltage_min),
+                    history.max_power,
+                    history.time_bulk,
+                    history.time_absorption,
+                    history.time_float,
+                    f64::from(history.battery_current_max),
+                    f64::from(history.panel_voltage_max),
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Solar yield of the device `serial` for every day of the last
+    /// `days` days with data, including today, oldest first. Days with
+    /// a history record use it, others the highest yield of the day
+    /// reported in the readings.
+    pub fn yield_per_day(&self, serial: &str, days: u32) -> Result<Vec<DailyYield>, VEError> {
+        self.yield_per_day_at(serial, days, SystemTime::now())
+    }
+
+    fn yield_per_day_at(
+        &self,
+        serial: &str,
+        days: u32,
+        now: SystemTime,
+    ) -> Result<Vec<DailyYield>, VEError> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "WITH first (date) AS (SELECT date(?2, 'unixepoch', 'localtime', ?3))
+                 SELECT date, coalesce(max(history), max(reading)) FROM (
+                     SELECT date, yield_kwh AS history, NULL AS reading FROM mppt_history
+                     WHERE serial = ?1 AND date >= (SELECT date FROM first)
+                     UNION ALL
+                     SELECT date(timestamp, 'unixepoch', 'localtime') AS date,
+                            NULL, yield_today FROM readings
+                     WHERE serial = ?1 AND yield_today IS NOT NULL
+                       AND date >= (SELECT date FROM first)
+                 )
+                 GROUP BY date ORDER BY date",
+            )
+            .map_err(db_error)?;
+        let rows = statement
+            .query_map(params![serial, seconds(now)?, first_day(days)], |row| {
+                Ok(DailyYield {
+                    date: row.get(0)?,
+                    yield_kwh: row.get(1)?,
+                })
+            })
+            .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+
+    /// Lowest and highest battery voltage of the device `serial` for
+    /// every day of the last `days` days with data, including today,
+    /// oldest first, from both the readings and the history records
+    pub fn battery_voltage_per_day(
+        &self,
+        serial: &str,
+        days: u32,
+    ) -> Result<Vec<DailyVoltage>, VEError> {
+        self.battery_voltage_per_day_at(serial, days, SystemTime::now())
+    }
+
+    fn battery_voltage_per_day_at(
+        &self,
+        serial: &str,
+        days: u32,
+        now: SystemTime,
+    ) -> Result<Vec<DailyVoltage>, VEError> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "WITH first (date) AS (SELECT date(?2, 'unixepoch', 'localtime', ?3))
+                 SELECT date, min(low), max(high) FROM (
+                     SELECT date, battery_voltage_min AS low, battery_voltage_max AS high
+                     FROM mppt_history
+                     WHERE serial = ?1 AND date >= (SELECT date FROM first)
+                     UNION ALL
+                     SELECT date(timestamp, 'unixepoch', 'localtime') AS date,
+                            battery_voltage, battery_voltage FROM readings
+                     WHERE serial = ?1 AND date >= (SELECT date FROM first)
+                 )
+                 GROUP BY date ORDER BY date",
+            )
+            .map_err(db_error)?;
+        let rows = statement
+            .query_map(params![serial, seconds(now)?, first_day(days)], |row| {
+                Ok(DailyVoltage {
+                    date: row.get(0)?,
+                    min: row.get(1)?,
+                    max: row.get(2)?,
+                })
+            })
+            .map_err(db_error)?;
+        rows.collect::<Result<_, _>>().map_err(db_error)
+    }
+
+    /// The first error which occurred while storing blocks received as
+    /// a parser listener, if any
+    pub fn take_error(&mut self) -> Option<VEError> {
+        self.error.take()
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        let _ = self.commit();
+    }
+}
+
+impl Events<Record> for Store {
+    fn on_complete_block(&mut self, block: Record) {
+        let now = SystemTime::now();
+        let result = match &block {
+            Record::Device(device) => self.insert(device, now),
+            Record::BmvHistory(history) => {
+                let serial = self
+                    .last_id
+                    .clone()
+                    .unwrap_or_else(|| DeviceKind::Bmv700.name().into());
+                self.insert_bmv_history(&serial, history, now)
+            }
+        };
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+/// Key of `device` in the tables: its serial number if it sends one,
+/// its kind otherwise
+fn device_id(device: &Device) -> &str {
+    device
+        .serial_number()
+        .unwrap_or_else(|| device.kind().name())
+}
+
+/// Date modifier of the first of the last `days` days
+fn first_day(days: u32) -> String {
+    format!("-{} days", days.saturating_sub(1))
+}
+
+fn seconds(timestamp: SystemTime) -> Result<i64, VEError> {
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .map_err(|_| VEError::Parse(text!("timestamp before 1970")))
+}
+
+fn db_error(error: rusqlite::Error) -> VEError {
+    VEError::Io(io::Error::other(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{parse, MPPT_BLOCK};
+    use crate::{Bmv700, Parser, MPPT};
+    use std::time::Duration;
+
+    const BMV_BLOCK: &str = "\r\nPID\t0xA381\r\nV\t12282\r\nP\t-28\r\nCE\t-74900\r\nSOC\t916\r\nTTG\t10350\r\nChecksum\t?";
+    const HISTORY_BLOCK: &str =
+        "\r\nH1\t-76138\r\nH2\t-76138\r\nH9\t1199744\r\nH18\t2415\r\nChecksum\t?";
+
+    /// Noon in local time, as days are grouped in, `n` days after
+    /// 2023-11-14
+    fn day(n: u64) -> SystemTime {
+        let seconds: u64 = Connection::open_in_memory()
+            .unwrap()
+            .query_row(
+                "SELECT CAST(strftime('%s', '2023-11-14 12:00', ?1, 'utc') AS INTEGER)",
+                [format!("+{} days", n)],
+                |row| row.get(0),
+            )
+            .unwrap();
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn store() -> Store {
+        Store::new(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn mppt(voltage: f32, yield_today: i32) -> Device {
+        let mut mppt: MPPT = parse(MPPT_BLOCK);
+        mppt.channel1_voltage = voltage;
+        mppt.yield_today = yield_today;
+        Device::Mppt(mppt)
+    }
+
+    #[test]
+    fn test_listener() {
+        let mut parser = Parser::new(store());
+        parser.feed(MPPT_BLOCK.as_bytes()).unwrap();
+        parser.feed(BMV_BLOCK.as_bytes()).unwrap();
+        parser.feed(HISTORY_BLOCK.as_bytes()).unwrap();
+        let mut store = parser.into_listener();
+        assert!(store.take_error().is_none());
+
+        let connection = store.connection();
+        let (kind, voltage, yield_today, data): (String, f64, f64, String) = connection
+            .query_row(
+                "SELECT kind, battery_voltage, yield_today, data FROM readings
+                 WHERE serial = 'HQ2132QY2KR'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(kind, "mppt");
+        assert!((voltage - 12.54).abs() < 1e-6);
+        assert_eq!(yield_today, 0.01);
+        let data: Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(data["panel_power_w"], 5);
+        assert_eq!(data["state_of_operation"], "Bulk");
+
+        let yield_today: Option<f64> = connection
+            .query_row(
+                "SELECT yield_today FROM readings WHERE serial = 'bmv700'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(yield_today, None);
+        let history: String = connection
+            .query_row(
+                "SELECT data FROM bmv_history WHERE serial = 'bmv700'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(
+            history,
+            r#"{"H1":-76138,"H2":-76138,"H9":1199744,"H18":2415}"#
+        );
+    }
+
+    #[test]
+    fn test_commit_interval() {
+        let path = std::env::temp_dir().join(format!("vedirect-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let count = || -> i64 {
+            Connection::open(&path)
+                .unwrap()
+                .query_row("SELECT count(*) FROM readings", [], |row| row.get(0))
+                .unwrap()
+        };
+        let mut store = Store::open(&path).unwrap();
+        store.insert(&mppt(12.5, 0), day(0)).unwrap();
+        store.insert(&mppt(12.6, 0), day(0)).unwrap();
+        assert_eq!(count(), 0);
+        store.commit().unwrap();
+        assert_eq!(count(), 2);
+
+        let mut store = store.with_commit_interval(Duration::ZERO);
+        store.insert(&mppt(12.7, 0), day(0)).unwrap();
+        assert_eq!(count(), 3);
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_yield_per_day() {
+        let mut store = store();
+        let serial = "HQ2132QY2KR";
+        // from the readings, over the day
+        for (hour, yield_today) in [(0, 0), (2, 120), (4, 250), (5, 250)] {
+            let timestamp = day(0) + Duration::from_secs(hour * 3600);
+            store.insert(&mppt(12.5, yield_today), timestamp).unwrap();
+        }
+        store.insert(&mppt(12.5, 80), day(1)).unwrap();
+        // history records take precedence, and fill the gaps
+        let mut history = DayHistory::decode(&[0; DayHistory::SIZE]).unwrap();
+        history.yield_kwh = 0.9;
+        store
+            .insert_mppt_history(serial, 1, &history, day(1))
+            .unwrap();
+        history.yield_kwh = 3.0;
+        store
+            .insert_mppt_history(serial, 4, &history, day(1))
+            .unwrap();
+        // another device
+        store.insert(&mppt(12.5, 999), day(1)).unwrap();
+        store
+            .connection()
+            .execute(
+                "UPDATE readings SET serial = 'other' WHERE yield_today > 9",
+                [],
+            )
+            .unwrap();
+
+        let days = store.yield_per_day_at(serial, 3, day(1)).unwrap();
+        let expected = [("2023-11-14", 0.9), ("2023-11-15", 0.8)];
+        assert_eq!(days.len(), expected.len(), "{:?}", days);
+        for (day, (date, yield_kwh)) in days.iter().zip(expected) {
+            assert_eq!(day.date, date);
+            assert!((day.yield_kwh - yield_kwh).abs() < 1e-6, "{:?}", day);
+        }
+
+        let days = store.yield_per_day_at(serial, 30, day(1)).unwrap();
+        assert_eq!(days.len(), 3);
+        assert_eq!(days[0].date, "2023-11-11");
+        assert!(store
+            .yield_per_day_at(serial, 1, day(2))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_battery_voltage_per_day() {
+        let mut store = store();
+        let bmv = |voltage| {
+            Device::Bmv700(Bmv700 {
+                voltage,
+                power: -20,
+                consumed: None,
+                soc: None,
+                ttg: 600,
+            })
+        };
+        for (hour, voltage) in [(0, 12.5), (3, 11.9), (6, 13.2), (9, 12.6)] {
+            let timestamp = day(0) + Duration::from_secs(hour * 3600);
+            store.insert(&bmv(voltage), timestamp).unwrap();
+        }
+        store.insert(&bmv(12.8), day(1)).unwrap();
+        let days = store
+            .battery_voltage_per_day_at("bmv700", 7, day(1))
+            .unwrap();
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].date, "2023-11-14");
+        assert!((days[0].min - 11.9).abs() < 1e-6);
+        assert!((days[0].max - 13.2).abs() < 1e-6);
+        assert!((days[1].min - 12.8).abs() < 1e-6);
+
+        // including the range of a history record
+        let mut history = DayHistory::decode(&[0; DayHistory::SIZE]).unwrap();
+        history.battery_voltage_min = 11.5;
+        history.battery_voltage_max = 12.9;
+        store
+            .insert_mppt_history("bmv700", 0, &history, day(1))
+            .unwrap();
+        let days = store
+            .battery_voltage_per_day_at("bmv700", 1, day(1))
+            .unwrap();
+        assert_eq!(days.len(), 1);
+        assert!((days[0].min - 11.5).abs() < 1e-6);
+        assert!((days[0].max - 12.9).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,190 @@
+//! Async support for tokio, enabled with the `tokio` feature.
+//!
+//! [`BlockCodec`] implements the tokio-util [`Decoder`] trait on top
+//! of the [`Parser`] state machine, so it can be used with
+//! `FramedRead` or any other tokio-util framing. [`BlockStream`] wraps
+//! an [`AsyncRead`] (serial port, TCP socket, ...) and yields mapped
+//! blocks as a [`Stream`].
+//!
+//! # Example
+//! ```rust,no_run
+//! use futures_util::StreamExt;
+//! use vedirect::{BlockStream, MPPT};
+//!
+//! # async fn run() {
+//! let mut blocks = BlockStream::<_, MPPT>::new(tokio::io::stdin());
+//! while let Some(block) = blocks.next().await {
+//!     match block {
+//!         Ok(mppt) => println!("{:#?}", mppt),
+//!         Err(e) => eprintln!("{}", e),
+//!     }
+//! }
+//! # }
+//! ```
+
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::BytesMut;
+use futures_core::Stream;
+use tokio::io::AsyncRead;
+use tokio_util::codec::{Decoder, FramedRead};
+
+use crate::{data::VEDirectData, Events, Parser, VEError};
+
+/// Collects events from the parser until the decoder hands them out
+struct Queue<D> {
+    items: VecDeque<Result<D, VEError>>,
+}
+
+impl<D: VEDirectData> Events<D> for Queue<D> {
+    fn on_complete_block(&mut self, block: D) {
+        self.items.push_back(Ok(block));
+    }
+
+    // Partial blocks are expected whenever the stream is joined
+    // mid-block, or when a device splits its data over several blocks,
+    // so these are not surfaced as errors.
+    fn on_missing_field(&mut self, _label: crate::Text) {}
+
+    fn on_mapping_error(&mut self, error: VEError) {
+        self.items.push_back(Err(error));
+    }
+
+    fn on_parse_error(&mut self, error: VEError, _parse_buf: &[u8]) {
+        self.items.push_back(Err(error));
+    }
+}
+
+/// tokio-util [`Decoder`] producing one item per mapped block.
+///
+/// Mapping and parse errors are non-fatal and are returned as
+/// `Err` items, so the framed stream keeps running after them. Only
+/// I/O errors terminate the stream.
+pub struct BlockCodec<D: VEDirectData> {
+    parser: Parser<D, Queue<D>>,
+}
+
+impl<D: VEDirectData> BlockCodec<D> {
+    /// Create codec
+    pub fn new() -> Self {
+        BlockCodec {
+            parser: Parser::new(Queue {
+                items: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl<D: VEDirectData> Default for BlockCodec<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: VEDirectData> Decoder for BlockCodec<D> {
+    type Item = Result<D, VEError>;
+    type Error = VEError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if !src.is_empty() {
+            match self.parser.feed(src) {
+                Ok(()) | Err(VEError::NeedMoreData) => {}
+                Err(e) => return Err(e),
+            }
+            // The parser keeps its own buffer of incomplete data
+            src.clear();
+        }
+        Ok(self.parser.listener_mut().items.pop_front())
+    }
+}
+
+/// [`Stream`] of mapped blocks read from an [`AsyncRead`] source
+pub struct BlockStream<R: AsyncRead, D: VEDirectData> {
+    inner: FramedRead<R, BlockCodec<D>>,
+}
+
+impl<R: AsyncRead, D: VEDirectData> BlockStream<R, D> {
+    /// Create stream reading from `reader`
+    pub fn new(reader: R) -> Self {
+        BlockStream {
+            inner: FramedRead::new(reader, BlockCodec::new()),
+        }
+    }
+
+    /// Consume the stream, returning the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+}
+
+impl<R: AsyncRead + Unpin, D: VEDirectData + Unpin> Stream for BlockStream<R, D> {
+    type Item = Result<D, VEError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(item))) => Poll::Ready(Some(item)),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::MPPT_BLOCK;
+    use crate::{Bmv700, MPPT};
+    use futures_util::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_decoder() {
+        let mut codec = BlockCodec::<MPPT>::new();
+        let mut buf = BytesMut::from(&MPPT_BLOCK.as_bytes()[..40]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+
+        buf.extend_from_slice(&MPPT_BLOCK.as_bytes()[40..]);
+        let block = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(block.channel1_voltage, 12.54);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_decoder_mapping_error() {
+        let mut codec = BlockCodec::<Bmv700>::new();
+        let mut buf = BytesMut::from(
+            "\r\nP\t123\r\nCE\t53\r\nSOC\t452\r\nTTG\tabc\r\nV\t232\r\nChecksum\t12".as_bytes(),
+        );
+        assert!(codec.decode(&mut buf).unwrap().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_duplex_stream() {
+        let (mut device, host) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            for chunk in MPPT_BLOCK.as_bytes().chunks(17) {
+                device.write_all(chunk).await.unwrap();
+            }
+            device
+                .write_all(MPPT_BLOCK.replace("\r\nI\t40", "\r\nI\t110").as_bytes())
+                .await
+                .unwrap();
+        });
+
+        let blocks: Vec<_> = BlockStream::<_, MPPT>::new(host).collect().await;
+        writer.await.unwrap();
+
+        assert_eq!(blocks.len(), 2);
+        let first = blocks[0].as_ref().unwrap();
+        assert_eq!(first.battery_current, 0.04);
+        assert_eq!(first.serial_number, "HQ2132QY2KR");
+        let second = blocks[1].as_ref().unwrap();
+        assert_eq!(second.battery_current, 0.11);
+    }
+}
@@ -0,0 +1,53 @@
+//! Owned strings which work with and without the `std` feature
+
+#[cfg(not(feature = "std"))]
+use core::fmt::Write;
+
+/// Capacity of [`Text`] when built without the `std` feature. Longer
+/// strings (e.g. detailed error messages) are truncated.
+#[cfg(not(feature = "std"))]
+pub const TEXT_CAPACITY: usize = 64;
+
+/// Owned string used in errors and data types. This is a [`String`]
+/// with the `std` feature, and a fixed capacity `heapless::String`
+/// otherwise.
+#[cfg(feature = "std")]
+pub type Text = std::string::String;
+
+/// Owned string used in errors and data types. This is a [`String`]
+/// with the `std` feature, and a fixed capacity `heapless::String`
+/// otherwise.
+#[cfg(not(feature = "std"))]
+pub type Text = heapless::String<TEXT_CAPACITY>;
+
+/// Like `format!`, but producing a [`Text`]
+macro_rules! text {
+    ($($arg:tt)*) => {
+        $crate::text::format(format_args!($($arg)*))
+    };
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn format(args: core::fmt::Arguments) -> Text {
+    std::fmt::format(args)
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) fn format(args: core::fmt::Arguments) -> Text {
+    struct Truncating(Text);
+
+    impl Write for Truncating {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            for c in s.chars() {
+                if self.0.push(c).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let mut out = Truncating(Text::new());
+    let _ = out.write_fmt(args);
+    out.0
+}